@@ -13,5 +13,7 @@ fn main() {
         netdev: { not(any(target_os = "espidf", all(target_family = "wasm", target_os = "unknown"))) },
         // BSD-derived platforms that share the `AF_ROUTE` routing-socket code.
         bsd: { any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "macos", target_os = "ios") },
+        // illumos and Solaris, which have a routing socket with its own message layout.
+        solarish: { any(target_os = "illumos", target_os = "solaris") },
     }
 }
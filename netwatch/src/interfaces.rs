@@ -9,22 +9,30 @@
 use std::{
     collections::HashMap,
     fmt,
+    hash::{Hash, Hasher},
     net::{IpAddr, Ipv4Addr},
+    time::Duration,
 };
 
 pub(crate) use ipnet::{Ipv4Net, Ipv6Net};
 use n0_future::time::Instant;
 
-use crate::ip::{LocalAddresses, is_link_local};
+use crate::{
+    IpFamily,
+    ip::{LocalAddresses, is_link_local},
+};
 
-// Each platform module provides the same three entry points, reached through
-// the `platform` alias: `get_state()`, `default_route()` and `home_router()`.
+// Each platform module provides the same five entry points, reached through
+// the `platform` alias: `get_state(options)`, `interfaces()`, `default_route()`,
+// `all_default_routes()` and `home_router()`.
 // The `netdev`-capable modules share enumeration via `netdev_impl`.
 #[cfg(netdev)]
 mod netdev_impl;
 
 #[cfg(bsd)]
 pub(super) mod bsd;
+#[cfg(solarish)]
+mod illumos;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 mod linux;
 #[cfg(posix_minimal)]
@@ -36,8 +44,14 @@ mod windows;
 
 #[cfg(bsd)]
 use self::bsd as platform;
+#[cfg(bsd)]
+pub use self::bsd::{Addr, RouteMessage, default_routes, gateways};
+#[cfg(solarish)]
+use self::illumos as platform;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use self::linux as platform;
+#[cfg(target_os = "linux")]
+pub(crate) use self::linux::{Error as LinuxError, routing_table};
 #[cfg(posix_minimal)]
 use self::posix_minimal as platform;
 #[cfg(wasm_browser)]
@@ -51,6 +65,11 @@ use self::windows as platform;
 /// interface flags it is derived from the platform's notion of "up".
 const IFF_UP: u32 = 0x1;
 
+/// The interface flag bit indicating that an interface is a loopback interface.
+///
+/// Matches the POSIX `IFF_LOOPBACK` value.
+const IFF_LOOPBACK: u32 = 0x8;
+
 /// State flags for a single IPv6 address.
 ///
 /// Hand-kept mirror of netdev's `Ipv6AddrFlags`, so the `interfaces` API is
@@ -65,6 +84,7 @@ const IFF_UP: u32 = 0x1;
 ///
 /// [`<linux/if_addr.h>`]: https://github.com/torvalds/linux/blob/master/include/uapi/linux/if_addr.h
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ipv6AddrFlags {
     /// The address's preferred lifetime has expired.
     ///
@@ -116,8 +136,54 @@ pub struct Ipv6AddrFlags {
     pub permanent: bool,
 }
 
+/// The kind of link an interface provides.
+///
+/// A coarse mirror of netdev's `InterfaceType`, so the `interfaces` API is
+/// identical on platforms built without `netdev`. Interfaces whose type the
+/// platform doesn't report, or that fit none of the kinds below, are
+/// [`InterfaceType::Other`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum InterfaceType {
+    /// A loopback interface.
+    Loopback,
+    /// A wired Ethernet interface.
+    Ethernet,
+    /// An IEEE 802.11 wireless LAN interface.
+    Wifi,
+    /// A mobile broadband modem.
+    Cellular,
+    /// A tunnel, such as a VPN.
+    ///
+    /// Besides the interfaces the platform reports as tunnels, this includes
+    /// interfaces named like common tunnels (see [`StateOptions::exclude_tunnels`]),
+    /// since some platforms report no type for them.
+    Tunnel,
+    /// A point-to-point serial link, such as PPP or SLIP.
+    PointToPoint,
+    /// Any other or unknown kind of interface.
+    #[default]
+    Other,
+}
+
+/// Traffic counters of an interface since it came up, see [`Interface::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InterfaceStats {
+    /// Bytes received.
+    pub rx_bytes: u64,
+    /// Bytes transmitted.
+    pub tx_bytes: u64,
+    /// Packets received.
+    pub rx_packets: u64,
+    /// Packets transmitted.
+    pub tx_packets: u64,
+}
+
 /// An IP network on one of the machine's interfaces, either IPv4 or IPv6.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IpNet {
     /// An IPv4 network.
     V4(Ipv4Net),
@@ -167,6 +233,30 @@ impl PartialEq for IpNet {
 }
 impl Eq for IpNet {}
 
+impl Hash for IpNet {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // the netmask follows from the prefix length, so it is left out like in `eq`
+        match self {
+            IpNet::V4(net) => {
+                0u8.hash(state);
+                net.addr().hash(state);
+                net.prefix_len().hash(state);
+            }
+            IpNet::V6 {
+                net,
+                scope_id,
+                flags,
+            } => {
+                1u8.hash(state);
+                net.addr().hash(state);
+                net.prefix_len().hash(state);
+                scope_id.hash(state);
+                flags.hash(state);
+            }
+        }
+    }
+}
+
 impl IpNet {
     /// The IP address of this structure.
     pub fn addr(&self) -> IpAddr {
@@ -175,10 +265,32 @@ impl IpNet {
             IpNet::V6 { net, .. } => IpAddr::V6(net.addr()),
         }
     }
+
+    /// Whether `ip` is part of this network.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self, ip) {
+            (IpNet::V4(net), IpAddr::V4(ip)) => net.contains(&ip),
+            (IpNet::V6 { net, .. }, IpAddr::V6(ip)) => net.contains(&ip),
+            _ => false,
+        }
+    }
+
+    /// A network of just `ip`.
+    fn host(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => IpNet::V4(ip.into()),
+            IpAddr::V6(ip) => IpNet::V6 {
+                net: ip.into(),
+                scope_id: 0,
+                flags: Ipv6AddrFlags::default(),
+            },
+        }
+    }
 }
 
 /// Represents a network interface.
 #[derive(Debug, Clone, derive_more::Display)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[display("{index}. {name} up={} addrs={addrs:?}", self.is_up())]
 pub struct Interface {
     /// The interface name, such as `eth0` or `en0`.
@@ -194,6 +306,17 @@ pub struct Interface {
     mac_addr: Option<[u8; 6]>,
     /// The IP networks assigned to the interface.
     addrs: Vec<IpNet>,
+    /// Maximum transmission unit in bytes, if known.
+    mtu: Option<u32>,
+    /// Transmit link speed in bits per second, if known.
+    transmit_speed: Option<u64>,
+    /// Receive link speed in bits per second, if known.
+    receive_speed: Option<u64>,
+    /// The kind of link the interface provides.
+    interface_type: InterfaceType,
+    /// Gateways reached through the interface, IPv4 first.
+    #[cfg_attr(feature = "serde", serde(default))]
+    gateways: Vec<IpAddr>,
 }
 
 impl PartialEq for Interface {
@@ -207,6 +330,16 @@ impl PartialEq for Interface {
 
 impl Eq for Interface {}
 
+impl Hash for Interface {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // only the fields compared by `eq`
+        self.index.hash(state);
+        self.name.hash(state);
+        self.flags.hash(state);
+        self.mac_addr.hash(state);
+    }
+}
+
 impl Interface {
     /// Is this interface up?
     pub fn is_up(&self) -> bool {
@@ -218,11 +351,92 @@ impl Interface {
         &self.name
     }
 
+    /// The OS-assigned index of the interface, also used as scope of its link-local addresses.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Is this a loopback interface?
+    pub fn is_loopback(&self) -> bool {
+        self.flags & IFF_LOOPBACK != 0
+    }
+
+    /// The hardware (MAC) address of the interface, if it has one.
+    pub fn mac(&self) -> Option<[u8; 6]> {
+        self.mac_addr
+    }
+
+    /// The maximum transmission unit of the interface in bytes, if known.
+    pub fn mtu(&self) -> Option<u32> {
+        self.mtu
+    }
+
+    /// The transmit link speed of the interface in bits per second, if known.
+    ///
+    /// Often unavailable for virtual adapters.
+    pub fn transmit_speed(&self) -> Option<u64> {
+        self.transmit_speed
+    }
+
+    /// The receive link speed of the interface in bits per second, if known.
+    ///
+    /// Often unavailable for virtual adapters.
+    pub fn receive_speed(&self) -> Option<u64> {
+        self.receive_speed
+    }
+
+    /// The kind of link the interface provides.
+    pub fn interface_type(&self) -> InterfaceType {
+        self.interface_type
+    }
+
+    /// Reads the current traffic counters of the interface.
+    ///
+    /// Only available on Linux and Android, `None` elsewhere or if the counters can't be read.
+    pub fn stats(&self) -> Option<InterfaceStats> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let stats = linux::interface_stats(&self.name);
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let stats = None;
+        stats
+    }
+
+    /// Is this a wireless LAN interface?
+    pub fn is_wifi(&self) -> bool {
+        self.interface_type == InterfaceType::Wifi
+    }
+
+    /// Is this a mobile broadband interface?
+    pub fn is_cellular(&self) -> bool {
+        self.interface_type == InterfaceType::Cellular
+    }
+
+    /// Is this a tunnel interface, such as a VPN?
+    pub fn is_tunnel(&self) -> bool {
+        self.interface_type == InterfaceType::Tunnel
+    }
+
+    /// Does this interface connect to a single peer, as tunnels and PPP links do?
+    pub fn is_point_to_point(&self) -> bool {
+        matches!(
+            self.interface_type,
+            InterfaceType::Tunnel | InterfaceType::PointToPoint
+        )
+    }
+
     /// A list of all ip addresses of this interface.
     pub fn addrs(&self) -> impl Iterator<Item = IpNet> + '_ {
         self.addrs.iter().cloned()
     }
 
+    /// The gateways reached through this interface, IPv4 first.
+    ///
+    /// Usually only the interface of the default route has one. Empty where the platform doesn't
+    /// report them.
+    pub fn gateways(&self) -> &[IpAddr] {
+        &self.gateways
+    }
+
     /// Creates a fake interface for usage in tests.
     ///
     /// This allows tests to be independent of the host interfaces.
@@ -235,15 +449,35 @@ impl Interface {
             addrs: vec![IpNet::V4(
                 Ipv4Net::new(Ipv4Addr::new(192, 168, 0, 189), 24).unwrap(),
             )],
+            mtu: Some(1500),
+            transmit_speed: Some(1_000_000_000),
+            receive_speed: Some(1_000_000_000),
+            interface_type: InterfaceType::Ethernet,
+            gateways: vec![Ipv4Addr::new(192, 168, 0, 1).into()],
         }
     }
 }
 
+/// Returns all the current machine's network interfaces, ordered by index.
+///
+/// Unlike [`State::new`] this only enumerates the interfaces, without detecting the default
+/// route or the home router.
+pub fn all() -> Vec<Interface> {
+    let mut interfaces = platform::interfaces();
+    interfaces.sort_by_key(Interface::index);
+    interfaces
+}
+
 /// Intended to store the state of the machine's network interfaces, routing table, and
 /// other network configuration. For now it's pretty basic.
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct State {
     /// Maps from an interface name to the interface.
+    ///
+    /// Interfaces that are down are included, unless left out with
+    /// [`StateOptions::exclude_down`]. Their addresses don't count towards
+    /// [`State::have_v4`] and [`State::have_v6`], nor as major changes.
     pub interfaces: HashMap<String, Interface>,
     /// List of machine's local IP addresses.
     pub local_addresses: LocalAddresses,
@@ -256,7 +490,11 @@ pub struct State {
     pub have_v4: bool,
 
     /// Whether the current network interface is considered "expensive", which currently means LTE/etc
-    /// instead of Wifi. This field is not populated by `State::new`.
+    /// instead of Wifi.
+    ///
+    /// `State::new` sets this when the [default route interface](State::default_route_interface)
+    /// is a cellular one, which is detected on Android, macOS, iOS and Windows. Elsewhere cellular
+    /// interfaces are not recognized and this is always `false`, the caller can populate it.
     pub is_expensive: bool,
 
     /// The interface name for the machine's default route.
@@ -267,6 +505,9 @@ pub struct State {
     pub default_route_interface: Option<String>,
 
     /// Monotonic timestamp, when an unsuspend was detected.
+    ///
+    /// Not serialized, as it is meaningless outside of this process.
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub last_unsuspend: Option<Instant>,
 }
 
@@ -291,12 +532,89 @@ impl fmt::Display for State {
     }
 }
 
+/// Name prefixes of VPN and tunnel interfaces, excluded with [`StateOptions::exclude_tunnels`].
+const TUNNEL_PREFIXES: &[&str] = &["wg", "tailscale", "utun", "tun"];
+
+/// Whether `name` looks like the name of a VPN or tunnel interface.
+fn is_tunnel_name(name: &str) -> bool {
+    TUNNEL_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// Options to build a [`State`] with, see [`State::with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct StateOptions {
+    /// Names of interfaces to leave out of the [`State`].
+    ///
+    /// A name ending in `*` matches all interfaces starting with what precedes it, e.g. `"docker*"`.
+    pub exclude_interfaces: Vec<String>,
+    /// Whether to leave out VPN and tunnel interfaces, recognized by their name (`wg*`,
+    /// `tailscale*`, `utun*` and `tun*`).
+    pub exclude_tunnels: bool,
+    /// Whether to leave out interfaces that are down.
+    pub exclude_down: bool,
+    /// Limits on fetching the routing table to find the default route.
+    ///
+    /// Only used on BSD platforms, macOS and iOS, which fetch the routing table as a whole.
+    pub routing_table: RoutingTableLimits,
+}
+
+/// Limits on fetching the routing table, see [`StateOptions::routing_table`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutingTableLimits {
+    /// Largest routing table, in bytes, that is fetched. Larger ones are not allocated.
+    pub max_size: usize,
+    /// How long fetching may take, including the retries when the table grows while it's
+    /// fetched.
+    pub timeout: Duration,
+}
+
+impl RoutingTableLimits {
+    /// Default for [`Self::max_size`], plenty for routing tables of tens of thousands of routes.
+    pub const DEFAULT_MAX_SIZE: usize = 8 * 1024 * 1024;
+    /// Default for [`Self::timeout`].
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+}
+
+impl Default for RoutingTableLimits {
+    fn default() -> Self {
+        Self {
+            max_size: Self::DEFAULT_MAX_SIZE,
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl StateOptions {
+    /// Whether the interface called `name` is left out of the [`State`].
+    pub fn excludes(&self, name: &str) -> bool {
+        if self.exclude_tunnels && is_tunnel_name(name) {
+            return true;
+        }
+        self.exclude_interfaces
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+}
+
 impl State {
     /// Returns the state of all the current machine's network interfaces.
     ///
-    /// It does not set the returned `State::is_expensive`. The caller can populate that.
+    /// See [`State::is_expensive`] for the platforms where it's detected.
     pub async fn new() -> Self {
-        platform::get_state().await
+        Self::with_options(&StateOptions::default()).await
+    }
+
+    /// Like [`State::new`], but leaving out the interfaces excluded by `options`.
+    ///
+    /// Excluded interfaces neither count towards [`State::have_v4`] and [`State::have_v6`] nor
+    /// are reported as [`State::default_route_interface`].
+    pub async fn with_options(options: &StateOptions) -> Self {
+        platform::get_state(options).await
     }
 
     /// Creates a fake interface state for usage in tests.
@@ -316,8 +634,64 @@ impl State {
         }
     }
 
+    /// Creates a fake interface state in which [`HomeRouter::from_state`] finds `home_router`.
+    ///
+    /// Its only interface reaches the gateway and has the home router's local ip, if any.
+    pub fn fake_with_home_router(home_router: &HomeRouter) -> Self {
+        let mut state = Self::fake();
+        let iface = state
+            .interfaces
+            .values_mut()
+            .next()
+            .expect("fake state has an interface");
+        iface.gateways = vec![home_router.gateway];
+        iface.addrs = home_router.my_ip.map(IpNet::host).into_iter().collect();
+        state
+    }
+
+    /// Returns our address on the network `gateway` is part of, if any.
+    ///
+    /// On multi-homed hosts this is the address the gateway expects requests from, which is not
+    /// necessarily the machine's preferred local address.
+    pub fn local_ip_for(&self, gateway: IpAddr) -> Option<IpAddr> {
+        local_ip_on_subnet(self.interfaces.values(), gateway)
+    }
+
+    /// Returns all our addresses on the network `gateway` is part of.
+    ///
+    /// Ordered by interface index, so the first one is [`State::local_ip_for`]. More than one
+    /// means several interfaces, or several addresses of one, reach the gateway.
+    pub fn local_ips_for(&self, gateway: IpAddr) -> Vec<IpAddr> {
+        local_ips_on_subnet(self.interfaces.values(), gateway).collect()
+    }
+
+    /// Whether the machine is likely online.
+    ///
+    /// That is, there is a [default route interface](State::default_route_interface) and a usable
+    /// IPv4 or IPv6 address. Browsers hide addresses, there the placeholder interface being up
+    /// reflects `navigator.onLine` instead.
+    pub fn is_online(&self) -> bool {
+        let Some(name) = &self.default_route_interface else {
+            return false;
+        };
+        if cfg!(wasm_browser) {
+            return self.interfaces.get(name).is_some_and(Interface::is_up);
+        }
+        self.have_v4 || self.have_v6
+    }
+
     /// Is this a major change compared to the `old` one?.
     pub fn is_major_change(&self, old: &State) -> bool {
+        self.is_major_change_ignoring(old, &[])
+    }
+
+    /// Like [`State::is_major_change`], but changes of interfaces whose name starts with one of
+    /// the `uninteresting` prefixes are ignored, e.g. `"veth"` or `"docker"`.
+    pub fn is_major_change_ignoring(&self, old: &State, uninteresting: &[String]) -> bool {
+        let is_interesting = |name: &str| {
+            is_interesting_interface(name)
+                && !uninteresting.iter().any(|prefix| name.starts_with(prefix))
+        };
         if self.have_v6 != old.have_v6
             || self.have_v4 != old.have_v4
             || self.is_expensive != old.is_expensive
@@ -327,20 +701,25 @@ impl State {
         }
 
         for (iname, i) in &old.interfaces {
-            if !is_interesting_interface(i.name()) {
+            if !is_interesting(i.name()) {
                 continue;
             }
             let Some(i2) = self.interfaces.get(iname) else {
                 return true;
             };
-            if i != i2 || !prefixes_major_equal(i.addrs(), i2.addrs()) {
+            if i != i2 {
+                return true;
+            }
+            // equal interfaces are either both up or both down, the stale addresses of down
+            // interfaces don't matter
+            if i.is_up() && !prefixes_major_equal(i.addrs(), i2.addrs()) {
                 return true;
             }
         }
 
         // Check for new interesting interfaces not present in old state
         for (iname, i) in &self.interfaces {
-            if !is_interesting_interface(i.name()) {
+            if !is_interesting(i.name()) {
                 continue;
             }
             if !old.interfaces.contains_key(iname) {
@@ -371,17 +750,40 @@ pub(crate) fn is_interesting_interface(name: &str) -> bool {
 
 /// The details about a default route.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct DefaultRouteDetails {
     /// The interface name.
     ///
     /// It's like "eth0" (Linux), "Ethernet 2" (Windows), "en0" (macOS).
     pub interface_name: String,
+    /// The metric of the route, lower is preferred, if the platform reports it.
+    pub metric: Option<u32>,
 }
 
 impl DefaultRouteDetails {
     /// Reads the default route from the current system and returns the details.
+    ///
+    /// Prefers an IPv4 default route over an IPv6 one, see [`Self::with_preference`].
     pub async fn new() -> Option<Self> {
-        platform::default_route().await
+        Self::with_preference(IpFamily::V4).await
+    }
+
+    /// Reads the default route of the `prefer`red family, falling back to the other one.
+    ///
+    /// Windows only reports IPv4 default routes, so the preference has no effect there.
+    pub async fn with_preference(prefer: IpFamily) -> Option<Self> {
+        platform::default_route(prefer).await
+    }
+
+    /// Reads all IPv4 and IPv6 default routes from the current system.
+    ///
+    /// Routes are ordered by metric, lowest first, followed by those without one in the order
+    /// the system lists them.
+    pub async fn all() -> Vec<Self> {
+        let mut routes = platform::all_default_routes().await;
+        routes.sort_by_key(|route| (route.metric.is_none(), route.metric));
+        routes
     }
 }
 
@@ -392,7 +794,8 @@ pub async fn default_route_interface() -> Option<String> {
 
 /// Likely IPs of the residential router, and the ip address of the current
 /// machine using it.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HomeRouter {
     /// IP of the router.
     pub gateway: IpAddr,
@@ -406,11 +809,88 @@ impl HomeRouter {
     /// In addition, it returns the IP address of the current machine on
     /// the LAN using that gateway.
     /// This is used as the destination for UPnP, NAT-PMP, PCP, etc queries.
-    pub fn new() -> Option<Self> {
-        platform::home_router()
+    ///
+    /// Reading the routing table can block, so outside the browser it's done on tokio's blocking
+    /// thread pool.
+    pub async fn new() -> Option<Self> {
+        #[cfg(not(wasm_browser))]
+        {
+            tokio::task::spawn_blocking(platform::home_router)
+                .await
+                .unwrap_or_else(|err| {
+                    tracing::warn!("failed to find the home router: {err:?}");
+                    None
+                })
+        }
+        #[cfg(wasm_browser)]
+        {
+            platform::home_router()
+        }
+    }
+
+    /// Creates a fake home router for usage in tests.
+    ///
+    /// The gateway is on the network of [`State::fake`]'s interface, with its address as ours.
+    pub fn fake() -> Self {
+        Self {
+            gateway: Ipv4Addr::new(192, 168, 0, 1).into(),
+            my_ip: Some(Ipv4Addr::new(192, 168, 0, 189).into()),
+        }
+    }
+
+    /// Derives the home router from an already obtained [`State`].
+    ///
+    /// Unlike [`HomeRouter::new`] this doesn't query the OS, which is cheap when a fresh [`State`]
+    /// is at hand, e.g. one provided by a [`crate::netmon::Monitor`]. The gateway is the one
+    /// reached through the default route's interface, or else through the first interface that
+    /// is up and has one. Returns `None` if no interface reports a gateway.
+    pub fn from_state(state: &State) -> Option<Self> {
+        let default_route_interface = state
+            .default_route_interface
+            .as_ref()
+            .and_then(|name| state.interfaces.get(name));
+        let mut interfaces: Vec<_> = state.interfaces.values().collect();
+        interfaces.sort_by_key(|iface| iface.index);
+        let iface = default_route_interface
+            .into_iter()
+            .chain(interfaces)
+            .find(|iface| iface.is_up() && !iface.gateways.is_empty())?;
+        let gateway = iface.gateways[0];
+        // our address on the gateway's network, or else one on the interface reaching it
+        let my_ip = state.local_ip_for(gateway).or_else(|| {
+            iface
+                .addrs()
+                .map(|net| net.addr())
+                .find(|ip| ip.is_ipv4() == gateway.is_ipv4())
+        });
+        Some(Self { gateway, my_ip })
     }
 }
 
+/// Finds an address of an up interface on the same subnet as `gateway`.
+///
+/// Interfaces are visited in index order so the result is stable.
+fn local_ip_on_subnet<'a>(
+    interfaces: impl Iterator<Item = &'a Interface>,
+    gateway: IpAddr,
+) -> Option<IpAddr> {
+    local_ips_on_subnet(interfaces, gateway).next()
+}
+
+/// Addresses of up interfaces on the same subnet as `gateway`, in interface index order.
+fn local_ips_on_subnet<'a>(
+    interfaces: impl Iterator<Item = &'a Interface>,
+    gateway: IpAddr,
+) -> impl Iterator<Item = IpAddr> + 'a {
+    let mut interfaces: Vec<_> = interfaces.filter(|iface| iface.is_up()).collect();
+    interfaces.sort_by_key(|iface| iface.index);
+    interfaces
+        .into_iter()
+        .flat_map(Interface::addrs)
+        .filter(move |net| net.contains(gateway) && net.addr() != gateway)
+        .map(|net| net.addr())
+}
+
 /// Checks whether `a` and `b` are equal after ignoring uninteresting
 /// things, like link-local, loopback and multicast addresses.
 fn prefixes_major_equal(a: impl Iterator<Item = IpNet>, b: impl Iterator<Item = IpNet>) -> bool {
@@ -465,6 +945,39 @@ mod tests {
         assert!(new.is_major_change(&old));
     }
 
+    #[test]
+    fn test_is_major_change_down_interface() {
+        let mut old = State::fake();
+        let mut down = Interface::fake();
+        down.index = 10;
+        down.name = "eth1".to_string();
+        down.flags &= !IFF_UP;
+        old.interfaces.insert("eth1".to_string(), down.clone());
+
+        // stale addresses of a down interface coming and going don't matter
+        let mut new = old.clone();
+        new.interfaces.get_mut("eth1").unwrap().addrs.clear();
+        assert!(!new.is_major_change(&old));
+
+        // the interface coming up does
+        let mut new = old.clone();
+        new.interfaces.get_mut("eth1").unwrap().flags |= IFF_UP;
+        assert!(new.is_major_change(&old));
+    }
+
+    #[test]
+    fn test_is_major_change_ignoring_uninteresting() {
+        let old = State::fake();
+        let mut new = State::fake();
+        let mut iface = Interface::fake();
+        iface.index = 10;
+        iface.name = "veth1234".to_string();
+        new.interfaces.insert("veth1234".to_string(), iface);
+        assert!(new.is_major_change(&old));
+        assert!(!new.is_major_change_ignoring(&old, &["veth".to_string()]));
+        assert!(new.is_major_change_ignoring(&old, &["docker".to_string()]));
+    }
+
     #[tokio::test]
     async fn test_default_route() {
         let default_route = DefaultRouteDetails::new()
@@ -473,12 +986,213 @@ mod tests {
         println!("default_route: {default_route:#?}");
     }
 
+    #[tokio::test]
+    async fn test_default_route_with_preference() {
+        for prefer in [IpFamily::V4, IpFamily::V6] {
+            if let Some(route) = DefaultRouteDetails::with_preference(prefer).await {
+                assert!(!route.interface_name.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_all_default_routes() {
+        let routes = DefaultRouteDetails::all().await;
+        assert!(routes.is_sorted_by_key(|route| (route.metric.is_none(), route.metric)));
+        for route in routes {
+            assert!(!route.interface_name.is_empty());
+        }
+    }
+
     #[tokio::test]
     async fn test_likely_home_router() {
-        let home_router = HomeRouter::new().expect("missing home router");
+        let home_router = HomeRouter::new().await.expect("missing home router");
         println!("home router: {home_router:#?}");
     }
 
+    #[tokio::test]
+    async fn test_all_interfaces() {
+        let interfaces = all();
+        assert!(interfaces.is_sorted_by_key(Interface::index));
+
+        let state = State::new().await;
+        for iface in &interfaces {
+            assert_eq!(state.interfaces.get(iface.name()), Some(iface));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_interface_stats_loopback() {
+        let loopback = all()
+            .into_iter()
+            .find(Interface::is_loopback)
+            .expect("missing loopback interface");
+        assert!(loopback.stats().is_some());
+    }
+
+    #[test]
+    fn test_state_options_excludes() {
+        let options = StateOptions::default();
+        assert!(!options.excludes("wg0"));
+
+        let options = StateOptions {
+            exclude_interfaces: vec!["eth1".to_string(), "docker*".to_string()],
+            exclude_tunnels: true,
+            ..Default::default()
+        };
+        for name in ["wg0", "tailscale0", "utun3", "tun0", "eth1", "docker0"] {
+            assert!(options.excludes(name), "{name}");
+        }
+        for name in ["eth0", "eth10", "en0", "wlan0"] {
+            assert!(!options.excludes(name), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_with_options() {
+        let state = State::new().await;
+        let Some(excluded) = state.interfaces.keys().next().cloned() else {
+            return;
+        };
+        let options = StateOptions {
+            exclude_interfaces: vec![excluded.clone()],
+            ..Default::default()
+        };
+        let filtered = State::with_options(&options).await;
+        assert!(!filtered.interfaces.contains_key(&excluded));
+        assert_ne!(filtered.default_route_interface, Some(excluded));
+    }
+
+    #[tokio::test]
+    async fn test_state_excludes_down() {
+        let options = StateOptions {
+            exclude_down: true,
+            ..Default::default()
+        };
+        let state = State::with_options(&options).await;
+        assert!(state.interfaces.values().all(Interface::is_up));
+    }
+
+    #[test]
+    fn test_interface_accessors() {
+        let iface = Interface::fake();
+        assert_eq!(iface.index(), 2);
+        assert_eq!(iface.mac(), Some([2, 3, 4, 5, 6, 7]));
+        assert!(!iface.is_loopback());
+        assert_eq!(iface.interface_type(), InterfaceType::Ethernet);
+        assert!(!iface.is_wifi());
+        assert!(!iface.is_cellular());
+        assert!(!iface.is_tunnel());
+        assert!(!iface.is_point_to_point());
+        assert_eq!(iface.mtu(), Some(1500));
+        assert_eq!(iface.transmit_speed(), Some(1_000_000_000));
+        assert_eq!(iface.receive_speed(), Some(1_000_000_000));
+    }
+
+    #[test]
+    fn test_home_router_from_state() {
+        let mut state = State::fake();
+        assert_eq!(HomeRouter::from_state(&state), Some(HomeRouter::fake()));
+        let home_router = HomeRouter::fake();
+        assert_eq!(state.local_ip_for(home_router.gateway), home_router.my_ip);
+
+        // an interface that is down doesn't reach its gateway
+        let wifi = state.interfaces.get_mut("wifi0").unwrap();
+        wifi.flags &= !IFF_UP;
+        assert_eq!(HomeRouter::from_state(&state), None);
+
+        // the local ip need not be on the gateway's network
+        let home_router = HomeRouter {
+            gateway: "fe80::1".parse().unwrap(),
+            my_ip: Some("2001:db8::2".parse().unwrap()),
+        };
+        let state = State::fake_with_home_router(&home_router);
+        assert_eq!(HomeRouter::from_state(&state), Some(home_router));
+    }
+
+    #[test]
+    fn test_local_ip_for() {
+        let mut state = State::fake();
+        let gateway = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(
+            state.local_ip_for(gateway),
+            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 189)))
+        );
+        assert_eq!(
+            state.local_ip_for(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            None
+        );
+
+        // a VPN interface with a lower index must not be picked for the LAN gateway
+        let vpn = Interface {
+            name: String::from("tun0"),
+            index: 1,
+            flags: IFF_UP,
+            mac_addr: None,
+            addrs: vec![IpNet::V4(
+                Ipv4Net::new(Ipv4Addr::new(10, 8, 0, 2), 24).unwrap(),
+            )],
+            mtu: None,
+            transmit_speed: None,
+            receive_speed: None,
+            interface_type: InterfaceType::Tunnel,
+            gateways: Vec::new(),
+        };
+        state.interfaces.insert(vpn.name.clone(), vpn);
+        assert_eq!(
+            state.local_ip_for(gateway),
+            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 189)))
+        );
+        assert_eq!(
+            state.local_ip_for(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1))),
+            Some(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)))
+        );
+    }
+
+    #[test]
+    fn test_local_ips_for() {
+        let mut state = State::fake();
+        let gateway = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(
+            state.local_ips_for(gateway),
+            [IpAddr::V4(Ipv4Addr::new(192, 168, 0, 189))]
+        );
+        assert!(
+            state
+                .local_ips_for(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+                .is_empty()
+        );
+
+        // a second interface on the LAN, e.g. wifi next to ethernet
+        let wlan = Interface {
+            name: String::from("wlan0"),
+            index: 3,
+            flags: IFF_UP,
+            mac_addr: None,
+            addrs: vec![IpNet::V4(
+                Ipv4Net::new(Ipv4Addr::new(192, 168, 0, 42), 24).unwrap(),
+            )],
+            mtu: None,
+            transmit_speed: None,
+            receive_speed: None,
+            interface_type: InterfaceType::Wifi,
+            gateways: Vec::new(),
+        };
+        state.interfaces.insert(wlan.name.clone(), wlan);
+        assert_eq!(
+            state.local_ips_for(gateway),
+            [
+                IpAddr::V4(Ipv4Addr::new(192, 168, 0, 189)),
+                IpAddr::V4(Ipv4Addr::new(192, 168, 0, 42)),
+            ]
+        );
+        assert_eq!(
+            state.local_ip_for(gateway),
+            state.local_ips_for(gateway).first().copied()
+        );
+    }
+
     #[test]
     fn test_prefixes_major_equal() {
         use std::net::Ipv4Addr;
@@ -514,4 +1228,51 @@ mod tests {
             vec![a1.clone(), a2.clone(), a3.clone()].into_iter(),
         ));
     }
+
+    #[test]
+    fn test_hash_matches_eq() {
+        use std::collections::HashSet;
+
+        let net = IpNet::V4(Ipv4Net::new(Ipv4Addr::new(192, 168, 0, 1), 24).unwrap());
+        let other_prefix = IpNet::V4(Ipv4Net::new(Ipv4Addr::new(192, 168, 0, 1), 16).unwrap());
+        let nets: HashSet<_> = [net.clone(), net, other_prefix].into_iter().collect();
+        assert_eq!(nets.len(), 2);
+
+        // interfaces differing only in fields `eq` ignores are the same
+        let iface = Interface::fake();
+        let mut renumbered = Interface::fake();
+        renumbered.addrs.clear();
+        renumbered.mtu = None;
+        let mut other = Interface::fake();
+        other.index += 1;
+        let ifaces: HashSet<_> = [iface, renumbered, other].into_iter().collect();
+        assert_eq!(ifaces.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_roundtrip() {
+        let mut state = State::fake();
+        state.last_unsuspend = Some(Instant::now());
+        let json = serde_json::to_string(&state).unwrap();
+        let restored: State = serde_json::from_str(&json).unwrap();
+        // the unsuspend time only makes sense within the process
+        assert_eq!(restored.last_unsuspend, None);
+        state.last_unsuspend = None;
+        assert_eq!(restored, state);
+        let iface = &state.interfaces["wifi0"];
+        assert_eq!(
+            format!("{:?}", restored.interfaces["wifi0"]),
+            format!("{iface:?}")
+        );
+
+        let route = DefaultRouteDetails {
+            interface_name: String::from("eth0"),
+            metric: Some(100),
+        };
+        let json = serde_json::to_string(&route).unwrap();
+        let restored: DefaultRouteDetails = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.interface_name, route.interface_name);
+        assert_eq!(restored.metric, route.metric);
+    }
 }
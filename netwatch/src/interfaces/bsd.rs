@@ -4,8 +4,9 @@
 
 use std::{
     collections::HashMap,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::LazyLock,
+    time::{Duration, Instant},
 };
 
 use libc::{AF_INET, AF_INET6, AF_LINK, AF_ROUTE, AF_UNSPEC, CTL_NET, c_int, uintptr_t};
@@ -16,8 +17,9 @@ use libc::{
 use n0_error::{e, ensure, stack_error};
 use tracing::warn;
 
-pub(super) use super::netdev_impl::get_state;
-use super::{DefaultRouteDetails, HomeRouter};
+pub(super) use super::netdev_impl::{get_state, interfaces};
+use super::{DefaultRouteDetails, HomeRouter, RoutingTableLimits};
+use crate::IpFamily;
 
 #[cfg(target_os = "freebsd")]
 mod freebsd;
@@ -37,16 +39,45 @@ mod macos;
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 use self::macos::*;
 
-pub async fn default_route() -> Option<DefaultRouteDetails> {
-    let idx = default_route_interface_index()?;
+pub async fn default_route(prefer: IpFamily) -> Option<DefaultRouteDetails> {
+    default_route_within(prefer, &RoutingTableLimits::default()).await
+}
+
+/// Like [`default_route`], but fetching the routing table within `limits`.
+pub(super) async fn default_route_within(
+    prefer: IpFamily,
+    limits: &RoutingTableLimits,
+) -> Option<DefaultRouteDetails> {
+    let idx = default_route_interface_index(prefer, limits)?;
     let interfaces = netdev::get_interfaces();
     let iface = interfaces.into_iter().find(|i| i.index == idx)?;
 
     Some(DefaultRouteDetails {
         interface_name: iface.name,
+        metric: None,
     })
 }
 
+/// Returns all default routes, in the order of the routing table.
+///
+/// Route messages carry no metric, so none is reported.
+pub async fn all_default_routes() -> Vec<DefaultRouteDetails> {
+    let Some(rib) = fetch_routing_table(&RoutingTableLimits::default()) else {
+        return Vec::new();
+    };
+    let interfaces = netdev::get_interfaces();
+    default_routes(&rib)
+        .iter()
+        .filter_map(|rm| {
+            let iface = interfaces.iter().find(|i| i.index == rm.index as u32)?;
+            Some(DefaultRouteDetails {
+                interface_name: iface.name.clone(),
+                metric: None,
+            })
+        })
+        .collect()
+}
+
 /// Locates the home router via the routing table.
 ///
 /// `netdev` cannot yet determine the default gateway on BSD platforms (see
@@ -54,37 +85,41 @@ pub async fn default_route() -> Option<DefaultRouteDetails> {
 /// routing table directly. The local IP still comes from `netdev`.
 pub(super) fn home_router() -> Option<HomeRouter> {
     let gateway = likely_home_router()?;
+    let my_ip = match gateway {
+        // a link-local gateway is on the subnet of every interface with a link-local address,
+        // only the one it's scoped to reaches it
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            let interfaces = interfaces();
+            super::local_ip_on_subnet(
+                interfaces
+                    .iter()
+                    .filter(|iface| iface.index() == addr.scope_id()),
+                gateway.ip(),
+            )
+        }
+        _ => None,
+    }
+    .or_else(|| super::netdev_impl::local_ip_for(gateway.ip()));
     Some(HomeRouter {
-        gateway,
-        my_ip: super::netdev_impl::local_ip(),
+        gateway: gateway.ip(),
+        my_ip,
     })
 }
 
-fn likely_home_router() -> Option<IpAddr> {
-    let rib = fetch_routing_table()?;
-    let msgs = parse_routing_table(&rib)?;
-    for rm in msgs {
-        if !is_default_gateway(&rm) {
-            continue;
-        }
-
-        if let Some(gw) = rm.addrs.get(RTAX_GATEWAY as usize) {
-            if let Addr::Inet4 { ip } = gw {
-                return Some(IpAddr::V4(*ip));
-            }
-
-            if let Addr::Inet6 { ip, .. } = gw {
-                return Some(IpAddr::V6(*ip));
-            }
-        }
-    }
-    None
+/// Returns the gateway of the first default route.
+///
+/// The zone of an IPv6 gateway is kept as scope id, see [`Addr::socket_addr`].
+fn likely_home_router() -> Option<SocketAddr> {
+    let rib = fetch_routing_table(&RoutingTableLimits::default())?;
+    default_routes(&rib)
+        .iter()
+        .find_map(|rm| rm.addrs.get(RTAX_GATEWAY as usize)?.socket_addr(0))
 }
 
 /// Returns the index of the network interface that
-/// owns the default route. It returns the first IPv4 or IPv6 default route it
-/// finds (it does not prefer one or the other).
-fn default_route_interface_index() -> Option<u32> {
+/// owns the default route. It returns the first default route of the `prefer`red
+/// family, or else the first default route of the other one.
+fn default_route_interface_index(prefer: IpFamily, limits: &RoutingTableLimits) -> Option<u32> {
     // $ netstat -nr
     // Routing tables
     // Internet:
@@ -99,14 +134,45 @@ fn default_route_interface_index() -> Option<u32> {
     // c       RTF_PRCLONING    Protocol-specified generate new routes on use
     // I       RTF_IFSCOPE      Route is associated with an interface scope
 
-    let rib = fetch_routing_table()?;
-    let msgs = parse_routing_table(&rib)?;
-    for rm in msgs {
-        if is_default_gateway(&rm) {
-            return Some(rm.index as u32);
-        }
-    }
-    None
+    let rib = fetch_routing_table(limits)?;
+    let routes = default_routes(&rib);
+    routes
+        .iter()
+        .find(|rm| route_family(rm) == Some(prefer))
+        .or_else(|| routes.first())
+        .map(|rm| rm.index as u32)
+}
+
+/// Returns the address family of a route's destination.
+fn route_family(rm: &RouteMessage) -> Option<IpFamily> {
+    rm.addrs.get(RTAX_DST as usize)?.ip().map(IpFamily::from)
+}
+
+/// Returns the default routes in a raw routing table dump.
+///
+/// The dump must be in the format the routing table is fetched in on this platform,
+/// i.e. a `NET_RT_DUMP` sysctl, or `NET_RT_DUMP2` on macOS and iOS. A dump that
+/// fails to parse contains no routes.
+pub fn default_routes(rib: &[u8]) -> Vec<RouteMessage> {
+    parse_routing_table(rib)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(is_default_gateway)
+        .collect()
+}
+
+/// Returns the gateways of the default routes in a raw routing table dump.
+///
+/// See [`default_routes`] for the expected format of the dump.
+pub fn gateways(rib: &[u8]) -> Vec<IpAddr> {
+    default_routes(rib)
+        .iter()
+        .filter_map(|rm| match rm.addrs.get(RTAX_GATEWAY as usize)? {
+            Addr::Inet4 { ip } => Some(IpAddr::V4(*ip)),
+            Addr::Inet6 { ip, .. } => Some(IpAddr::V6(*ip)),
+            _ => None,
+        })
+        .collect()
 }
 
 const V4_DEFAULT: [u8; 4] = [0u8; 4];
@@ -151,8 +217,8 @@ fn is_default_gateway(rm: &RouteMessage) -> bool {
 }
 
 #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
-fn fetch_routing_table() -> Option<Vec<u8>> {
-    match fetch_rib(AF_UNSPEC, libc::NET_RT_DUMP, 0) {
+fn fetch_routing_table(limits: &RoutingTableLimits) -> Option<Vec<u8>> {
+    match fetch_rib(AF_UNSPEC, libc::NET_RT_DUMP, 0, limits) {
         Ok(res) => Some(res),
         Err(err) => {
             warn!("fetch_rib failed: {:?}", err);
@@ -163,7 +229,8 @@ fn fetch_routing_table() -> Option<Vec<u8>> {
 
 #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
 fn parse_routing_table(rib: &[u8]) -> Option<Vec<RouteMessage>> {
-    match parse_rib(libc::NET_RT_IFLIST, rib) {
+    // Parse with the same RIB type the table was fetched with, see `fetch_routing_table`.
+    match parse_rib(libc::NET_RT_DUMP, rib) {
         Ok(res) => {
             let res = res
                 .into_iter()
@@ -182,9 +249,9 @@ fn parse_routing_table(rib: &[u8]) -> Option<Vec<RouteMessage>> {
 }
 
 #[cfg(any(target_os = "macos", target_os = "ios",))]
-fn fetch_routing_table() -> Option<Vec<u8>> {
+fn fetch_routing_table(limits: &RoutingTableLimits) -> Option<Vec<u8>> {
     const NET_RT_DUMP2: i32 = 7;
-    match fetch_rib(libc::AF_UNSPEC, NET_RT_DUMP2, 0) {
+    match fetch_rib(libc::AF_UNSPEC, NET_RT_DUMP2, 0, limits) {
         Ok(res) => Some(res),
         Err(err) => {
             warn!("fetch_rib failed: {:?}", err);
@@ -254,6 +321,13 @@ pub enum WireMessage {
     InterfaceAnnounce(InterfaceAnnounceMessage),
 }
 
+/// Safely read a byte from a slice.
+fn u8_at(data: &[u8], index: usize) -> Result<u8, RouteError> {
+    data.get(index)
+        .copied()
+        .ok_or_else(|| e!(RouteError::MessageTooShort))
+}
+
 /// Safely convert a some bytes from a slice into a u16.
 fn u16_from_ne_range(
     data: &[u8],
@@ -294,8 +368,8 @@ impl WireFormat {
                     .map_err(|_| e!(RouteError::InvalidMessage))?;
                 let addrs = parse_addrs(attrs, parse_kernel_inet_addr, &data[self.body_off..])?;
                 let mut m = RouteMessage {
-                    version: data[2] as _,
-                    r#type: data[3] as _,
+                    version: u8_at(data, 2)? as _,
+                    r#type: u8_at(data, 3)? as _,
                     flags: u32_from_ne_range(data, 8..12)?,
                     index: u16_from_ne_range(data, 4..6)?,
                     id: u32_from_ne_range(data, 16..20)? as _,
@@ -326,8 +400,8 @@ impl WireFormat {
                 )?;
 
                 let mut m = RouteMessage {
-                    version: data[2] as _,
-                    r#type: data[3] as _,
+                    version: u8_at(data, 2)? as _,
+                    r#type: u8_at(data, 3)? as _,
                     flags: u32_from_ne_range(data, 16..20)?,
                     index: u16_from_ne_range(data, 6..8)?,
                     id: u32_from_ne_range(data, 24..28)? as _,
@@ -355,8 +429,8 @@ impl WireFormat {
                 let addr = parse_link_addr(&data[self.body_off..])?;
                 let name = addr.name().map(|s| s.to_string());
                 let m = InterfaceMessage {
-                    version: data[2] as _,
-                    r#type: data[3] as _,
+                    version: u8_at(data, 2)? as _,
+                    r#type: u8_at(data, 3)? as _,
                     flags: u32_from_ne_range(data, 8..12)? as _,
                     index: u16_from_ne_range(data, 12..14)? as _,
                     ext_off: self.ext_off,
@@ -383,8 +457,8 @@ impl WireFormat {
                 )?;
 
                 let m = InterfaceAddrMessage {
-                    version: data[2] as _,
-                    r#type: data[3] as _,
+                    version: u8_at(data, 2)? as _,
+                    r#type: u8_at(data, 3)? as _,
                     flags: u32_from_ne_range(data, 8..12)? as _,
                     index: index as _,
                     addrs,
@@ -402,8 +476,8 @@ impl WireFormat {
                     &data[self.body_off..],
                 )?;
                 let m = InterfaceMulticastAddrMessage {
-                    version: data[2] as _,
-                    r#type: data[3] as _,
+                    version: u8_at(data, 2)? as _,
+                    r#type: u8_at(data, 3)? as _,
                     flags: u32_from_ne_range(data, 8..12)? as _,
                     index: u16_from_ne_range(data, 12..14)? as _,
                     addrs,
@@ -415,20 +489,19 @@ impl WireFormat {
                 let l = u16_from_ne_range(data, ..2)?;
                 ensure!(data.len() >= l as usize, RouteError::InvalidMessage);
 
-                let mut name = String::new();
-                for i in 0..16 {
-                    if data[6 + i] != 0 {
-                        continue;
-                    }
-                    name = std::str::from_utf8(&data[6..6 + i])
+                let name_field = data
+                    .get(6..22)
+                    .ok_or_else(|| e!(RouteError::MessageTooShort))?;
+                let name = match name_field.iter().position(|&c| c == 0) {
+                    Some(len) => std::str::from_utf8(&name_field[..len])
                         .map_err(|_| e!(RouteError::InvalidAddress))?
-                        .to_string();
-                    break;
-                }
+                        .to_string(),
+                    None => String::new(),
+                };
 
                 let m = InterfaceAnnounceMessage {
-                    version: data[2] as _,
-                    r#type: data[3] as _,
+                    version: u8_at(data, 2)? as _,
+                    r#type: u8_at(data, 3)? as _,
                     index: u16_from_ne_range(data, 4..6)? as _,
                     what: u16_from_ne_range(data, 22..24)? as _,
                     name,
@@ -474,12 +547,12 @@ pub fn parse_rib(typ: RIBType, data: &[u8]) -> Result<Vec<WireMessage>, RouteErr
         let l = u16_from_ne_range(b, ..2)?;
         ensure!(l != 0, RouteError::InvalidMessage);
         ensure!(b.len() >= l as usize, RouteError::MessageTooShort);
-        if b[2] as i32 != ROUTING_STACK.rtm_version {
+        if u8_at(b, 2)? as i32 != ROUTING_STACK.rtm_version {
             b = &b[l as usize..];
             nskips += 1;
             continue;
         }
-        match ROUTING_STACK.wire_formats.get(&(b[3] as i32)) {
+        match ROUTING_STACK.wire_formats.get(&(u8_at(b, 3)? as i32)) {
             Some(w) => {
                 let m = w.parse(typ, &b[..l as usize])?;
                 match m {
@@ -626,6 +699,13 @@ pub enum RouteError {
     InvalidAddress {},
     #[error("invalid rib type {rib_type}")]
     InvalidRibType { rib_type: RIBType },
+    /// The routing information base is larger than [`RoutingTableLimits::max_size`], so it was
+    /// not fetched.
+    #[error("rib of {size} bytes exceeds the maximum of {max_size} bytes")]
+    RibTooLarge { size: usize, max_size: usize },
+    /// Fetching the routing information base took longer than [`RoutingTableLimits::timeout`].
+    #[error("fetching the rib timed out after {timeout:?}")]
+    Timeout { timeout: Duration },
     #[error("io error calling '{name}'")]
     Io {
         #[error(std_err)]
@@ -643,7 +723,19 @@ pub enum RouteError {
 /// flags. When RIBType is related to network interfaces, arg might be
 /// an interface index or a set of interface flags. In most cases, zero
 /// means a wildcard.
-fn fetch_rib(af: i32, typ: RIBType, arg: i32) -> Result<Vec<u8>, RouteError> {
+///
+/// Fails with [`RouteError::RibTooLarge`] if the base is larger than `limits.max_size`. The
+/// size is checked again on every retry, so that a base that keeps growing between the calls
+/// can't be allocated beyond it either. A single `sysctl` can't be interrupted, so
+/// `limits.timeout` is checked between the calls, failing with [`RouteError::Timeout`].
+fn fetch_rib(
+    af: i32,
+    typ: RIBType,
+    arg: i32,
+    limits: &RoutingTableLimits,
+) -> Result<Vec<u8>, RouteError> {
+    let RoutingTableLimits { max_size, timeout } = *limits;
+    let start = Instant::now();
     let mut round = 0;
     loop {
         round += 1;
@@ -670,6 +762,8 @@ fn fetch_rib(af: i32, typ: RIBType, arg: i32) -> Result<Vec<u8>, RouteError> {
             // nothing available
             return Ok(Vec::new());
         }
+        ensure!(n <= max_size, RouteError::RibTooLarge { size: n, max_size });
+        ensure!(start.elapsed() < timeout, RouteError::Timeout { timeout });
         let mut b = vec![0u8; n];
         let err = unsafe {
             libc::sysctl(
@@ -740,13 +834,25 @@ impl Addr {
         }
     }
 
+    /// Returns the IP of an internet address.
+    ///
+    /// The zone of an IPv6 address is dropped, see [`Addr::socket_addr`] to keep it.
     pub fn ip(&self) -> Option<IpAddr> {
         match self {
             Addr::Inet4 { ip } => Some(IpAddr::V4(*ip)),
-            Addr::Inet6 { ip, .. } => {
-                // TODO: how to add the zone?
-                Some(IpAddr::V6(*ip))
-            }
+            Addr::Inet6 { ip, .. } => Some(IpAddr::V6(*ip)),
+            _ => None,
+        }
+    }
+
+    /// Returns an internet address as a socket address with the given port.
+    ///
+    /// The zone of an IPv6 address becomes its scope id, so that link-local addresses remain
+    /// addressable.
+    pub fn socket_addr(&self, port: u16) -> Option<SocketAddr> {
+        match self {
+            Addr::Inet4 { ip } => Some(SocketAddrV4::new(*ip, port).into()),
+            Addr::Inet6 { ip, zone } => Some(SocketAddrV6::new(*ip, port, 0, *zone).into()),
             _ => None,
         }
     }
@@ -799,7 +905,9 @@ where
                     addrs.push(a);
                     let ll = roundup(l as usize);
                     if b.len() < ll {
-                        b = &b[l as usize..];
+                        b = b
+                            .get(l as usize..)
+                            .ok_or_else(|| e!(RouteError::MessageTooShort))?;
                     } else {
                         b = &b[ll..];
                     }
@@ -886,7 +994,8 @@ fn parse_kernel_inet_addr(af: i32, b: &[u8]) -> Result<(i32, Addr), RouteError>
     // - The kernel form appends leading bytes to the prefix field
     //   to make the <length, prefix> tuple to be conformed with
     //   the routing message boundary
-    let mut l = b[0] as usize;
+    let len = *b.first().ok_or_else(|| e!(RouteError::MessageTooShort))?;
+    let mut l = len as usize;
 
     #[cfg(any(target_os = "macos", target_os = "ios"))]
     {
@@ -906,7 +1015,7 @@ fn parse_kernel_inet_addr(af: i32, b: &[u8]) -> Result<(i32, Addr), RouteError>
     const OFF4: usize = 4; // offset of in_addr
     const OFF6: usize = 8; // offset of in6_addr
 
-    let addr = if b[0] as usize == SIZEOF_SOCKADDR_INET6 {
+    let addr = if len as usize == SIZEOF_SOCKADDR_INET6 {
         let octets: [u8; 16] = b
             .get(OFF6..OFF6 + 16)
             .and_then(|s| TryInto::try_into(s).ok())
@@ -918,11 +1027,12 @@ fn parse_kernel_inet_addr(af: i32, b: &[u8]) -> Result<(i32, Addr), RouteError>
         if l - 1 < OFF6 {
             octets[..l - 1].copy_from_slice(&b[1..l]);
         } else {
-            octets.copy_from_slice(&b[l - OFF6..l]);
+            // only the leading bytes of the prefix fit in the message
+            octets[..OFF6].copy_from_slice(&b[l - OFF6..l]);
         }
         let ip = Ipv6Addr::from(octets);
         Addr::Inet6 { ip, zone: 0 }
-    } else if b[0] as usize == SIZEOF_SOCKADDR_INET {
+    } else if len as usize == SIZEOF_SOCKADDR_INET {
         let octets: [u8; 4] = b
             .get(OFF4..OFF4 + 4)
             .and_then(|s| TryInto::try_into(s).ok())
@@ -944,7 +1054,7 @@ fn parse_kernel_inet_addr(af: i32, b: &[u8]) -> Result<(i32, Addr), RouteError>
         Addr::Inet4 { ip }
     };
 
-    Ok((b[0] as _, addr))
+    Ok((len as _, addr))
 }
 
 fn parse_link_addr(b: &[u8]) -> Result<Addr, RouteError> {
@@ -975,9 +1085,12 @@ fn parse_kernel_link_addr(_: i32, b: &[u8]) -> Result<(usize, Addr), RouteError>
     //
     // On some platforms, all-bit-one of length field means "don't
     // care".
-    let mut nlen = b[1] as usize;
-    let mut alen = b[2] as usize;
-    let mut slen = b[3] as usize;
+    let Some(&[_, nlen, alen, slen]) = b.first_chunk::<4>() else {
+        return Err(e!(RouteError::MessageTooShort));
+    };
+    let mut nlen = nlen as usize;
+    let mut alen = alen as usize;
+    let mut slen = slen as usize;
 
     if nlen == 0xff {
         nlen = 0;
@@ -1056,9 +1169,90 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_addr_socket_addr() {
+        let ip = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        let addr = Addr::Inet6 { ip, zone: 4 };
+        assert_eq!(addr.ip(), Some(IpAddr::V6(ip)));
+        assert_eq!(
+            addr.socket_addr(5351),
+            Some(SocketAddrV6::new(ip, 5351, 0, 4).into())
+        );
+
+        let ip = Ipv4Addr::new(192, 168, 1, 1);
+        assert_eq!(
+            Addr::Inet4 { ip }.socket_addr(5351),
+            Some(SocketAddrV4::new(ip, 5351).into())
+        );
+        let link = Addr::Link {
+            index: 1,
+            name: None,
+            addr: None,
+        };
+        assert_eq!(link.socket_addr(5351), None);
+    }
+
+    #[test]
+    fn test_parse_kernel_inet_addr_ipv6_prefix() {
+        // a prefix longer than the in6_addr offset, not a full sockaddr_in6
+        let mut b = [0u8; 16];
+        b[0] = 12;
+        let (_, addr) = parse_kernel_inet_addr(AF_INET6, &b).unwrap();
+        assert!(matches!(addr, Addr::Inet6 { .. }), "{addr:?}");
+
+        assert!(matches!(
+            parse_kernel_inet_addr(AF_INET6, &[]),
+            Err(RouteError::MessageTooShort { .. })
+        ));
+        assert!(matches!(
+            parse_kernel_link_addr(AF_LINK, &[0, 0]),
+            Err(RouteError::MessageTooShort { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_rib_never_panics() {
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let rib_type = libc::NET_RT_IFLIST2;
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        let rib_type = libc::NET_RT_IFLIST;
+
+        // xorshift, so that a failing buffer can be reproduced
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        // every truncation of a real message, with its length adjusted to match
+        let rib = fetch_rib(AF_UNSPEC, rib_type, 0, &RoutingTableLimits::default()).unwrap();
+        let msg_len = u16_from_ne_range(&rib, ..2).unwrap() as usize;
+        for len in 0..=msg_len {
+            let mut msg = rib[..len].to_vec();
+            if len >= 2 {
+                msg[..2].copy_from_slice(&(len as u16).to_ne_bytes());
+            }
+            let _ = parse_rib(rib_type, &msg);
+        }
+
+        // random messages of the current version, so that they reach the message parsers
+        for _ in 0..10_000 {
+            let len = (next() % 256) as usize;
+            let mut buf: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            if len >= 4 {
+                let msg_len = (next() % (len as u64 + 1)) as u16;
+                buf[..2].copy_from_slice(&msg_len.to_ne_bytes());
+                buf[2] = ROUTING_STACK.rtm_version as u8;
+            }
+            let _ = parse_rib(rib_type, &buf);
+        }
+    }
+
     #[test]
     fn test_fetch_parse_routing_table() {
-        let rib_raw = fetch_routing_table().unwrap();
+        let rib_raw = fetch_routing_table(&RoutingTableLimits::default()).unwrap();
         assert!(!rib_raw.is_empty());
         println!("got rib: {}", rib_raw.len());
         let rib_parsed = parse_routing_table(&rib_raw).unwrap();
@@ -1066,6 +1260,103 @@ mod tests {
         assert!(!rib_parsed.is_empty());
     }
 
+    #[test]
+    fn test_fetch_rib_limits() {
+        let limits = RoutingTableLimits::default();
+        let rib = fetch_rib(AF_UNSPEC, libc::NET_RT_IFLIST, 0, &limits).unwrap();
+        assert!(!rib.is_empty());
+
+        let too_small = RoutingTableLimits {
+            max_size: 1,
+            ..limits
+        };
+        let res = fetch_rib(AF_UNSPEC, libc::NET_RT_IFLIST, 0, &too_small);
+        assert!(
+            matches!(res, Err(RouteError::RibTooLarge { max_size: 1, .. })),
+            "{res:?}"
+        );
+
+        let too_short = RoutingTableLimits {
+            timeout: Duration::ZERO,
+            ..limits
+        };
+        let res = fetch_rib(AF_UNSPEC, libc::NET_RT_IFLIST, 0, &too_short);
+        assert!(matches!(res, Err(RouteError::Timeout { .. })), "{res:?}");
+    }
+
+    #[test]
+    fn test_routing_table_rib_type_is_valid() {
+        // the routing table is fetched with NET_RT_DUMP, parsing it must accept the same type
+        assert!(is_valid_rib_type(libc::NET_RT_DUMP));
+    }
+
+    #[test]
+    #[cfg(target_os = "openbsd")]
+    fn test_openbsd_invalid_rib_types() {
+        assert!(!is_valid_rib_type(NET_RT_STATS));
+        assert!(!is_valid_rib_type(NET_RT_TABLE));
+        assert!(is_valid_rib_type(libc::NET_RT_IFLIST));
+    }
+
+    /// A `NET_RT_DUMP` of an OpenBSD routing table with the IPv4 default route
+    /// `default via 192.168.1.1 dev <index 1>`, little endian.
+    ///
+    /// Provenance: this is not a capture yet. No OpenBSD host was at hand, so the buffer was
+    /// assembled by hand from the `struct rt_msghdr`, `struct rt_metrics` and
+    /// `struct sockaddr_in` layouts of OpenBSD's `<net/route.h>` and `<netinet/in.h>`, holding only
+    /// the default route. Replace it by running `capture_openbsd_routing_table` on an
+    /// OpenBSD host whose default route is the one above. The tests only look for that route, so
+    /// they hold for a capture with more routes.
+    #[cfg(all(target_os = "openbsd", target_endian = "little"))]
+    const OPENBSD_DEFAULT_ROUTE_RIB: &[u8] = include_bytes!("bsd/testdata/openbsd_net_rt_dump.bin");
+
+    /// Overwrites [`OPENBSD_DEFAULT_ROUTE_RIB`] with the routing table of this host.
+    ///
+    /// Run with `cargo test -p netwatch capture_openbsd_routing_table -- --ignored`.
+    #[test]
+    #[ignore = "overwrites the OpenBSD routing table fixture"]
+    #[cfg(all(target_os = "openbsd", target_endian = "little"))]
+    fn capture_openbsd_routing_table() {
+        let rib = fetch_routing_table(&RoutingTableLimits::default()).expect("routing table");
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/interfaces/bsd/testdata/openbsd_net_rt_dump.bin"
+        );
+        std::fs::write(path, rib).expect("fixture is written");
+    }
+
+    #[test]
+    #[cfg(all(target_os = "openbsd", target_endian = "little"))]
+    fn test_parse_openbsd_default_route() {
+        let msgs = parse_routing_table(OPENBSD_DEFAULT_ROUTE_RIB).expect("valid rib");
+        let gateway = Addr::Inet4 {
+            ip: Ipv4Addr::new(192, 168, 1, 1),
+        };
+        let route = msgs
+            .iter()
+            .find(|route| route.addrs.get(RTAX_GATEWAY as usize) == Some(&gateway))
+            .expect("default route is parsed");
+        assert!(is_default_gateway(route));
+        assert_eq!(route.index, 1);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "openbsd", target_endian = "little"))]
+    fn test_default_routes_and_gateways() {
+        let routes = default_routes(OPENBSD_DEFAULT_ROUTE_RIB);
+        assert!(routes.iter().any(|route| route.index == 1));
+        assert!(
+            gateways(OPENBSD_DEFAULT_ROUTE_RIB)
+                .contains(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)))
+        );
+    }
+
+    #[test]
+    fn test_default_routes_of_invalid_rib() {
+        assert!(default_routes(&[0xff; 8]).is_empty());
+        assert!(gateways(&[]).is_empty());
+    }
+
     struct ParseAddrsTest {
         attrs: i32,
         #[allow(clippy::type_complexity)]
@@ -45,7 +45,7 @@ pub const RTM_80211INFO: c_int = 0x15;
 pub const RTM_SOURCE: c_int = 0x16;
 
 // socket.h
-pub const NET_RT_STATS: c_int = 5;
+pub const NET_RT_STATS: c_int = 4;
 pub const NET_RT_TABLE: c_int = 5;
 
 pub const SIZEOF_SOCKADDR_STORAGE: usize = 0x80;
@@ -0,0 +1,244 @@
+//! Default route lookup on illumos and Solaris.
+//!
+//! These platforms have a BSD-style `PF_ROUTE` socket, but no sysctl to dump the
+//! routing table and a different message layout than the BSDs. Instead of parsing
+//! the whole table, the kernel is asked for the route to the unspecified address
+//! with a single `RTM_GET` message, like `route get default` does.
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use libc::{AF_INET, AF_INET6, AF_LINK, PF_ROUTE, c_int};
+use socket2::{Domain, Socket, Type};
+use tracing::warn;
+
+pub(super) use super::netdev_impl::{get_state, interfaces};
+use super::{DefaultRouteDetails, HomeRouter};
+use crate::IpFamily;
+
+// Not exposed by `libc` for these platforms, values from `<net/route.h>`.
+const RTM_VERSION: u8 = 3;
+const RTM_GET: u8 = 0x4;
+const RTF_UP: c_int = 0x1;
+const RTF_GATEWAY: c_int = 0x2;
+const RTA_DST: c_int = 0x1;
+const RTA_GATEWAY: c_int = 0x2;
+const RTA_NETMASK: c_int = 0x4;
+const RTA_IFP: c_int = 0x10;
+/// Number of `RTA_*` bits that may be set in a message, up to `RTA_SRC`.
+const RTAX_MAX: usize = 9;
+
+/// Size of `struct rt_msghdr`, including its `struct rt_metrics`.
+const RT_MSGHDR_LEN: usize = 76;
+const SOCKADDR_IN_LEN: usize = 16;
+const SOCKADDR_IN6_LEN: usize = 32;
+const SOCKADDR_DL_LEN: usize = 252;
+
+/// How long to wait for the kernel to answer the query.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub async fn default_route(prefer: IpFamily) -> Option<DefaultRouteDetails> {
+    let idx = default_route_interface_index(prefer)?;
+    let interfaces = netdev::get_interfaces();
+    let iface = interfaces.into_iter().find(|i| i.index == idx)?;
+
+    Some(DefaultRouteDetails {
+        interface_name: iface.name,
+        metric: None,
+    })
+}
+
+/// Returns the IPv4 default route followed by the IPv6 one.
+///
+/// The routing socket of these platforms can't dump the routing table, it only answers
+/// `RTM_GET` with the route the kernel picks. So each family reports at most one default route,
+/// the one its traffic leaves through.
+pub async fn all_default_routes() -> Vec<DefaultRouteDetails> {
+    let interfaces = netdev::get_interfaces();
+    [AF_INET, AF_INET6]
+        .into_iter()
+        .filter_map(query_default_route)
+        .filter_map(|route| {
+            let idx = route.index?;
+            let iface = interfaces.iter().find(|i| i.index == idx)?;
+            Some(DefaultRouteDetails {
+                interface_name: iface.name.clone(),
+                metric: None,
+            })
+        })
+        .collect()
+}
+
+/// Locates the home router via the routing socket.
+///
+/// `netdev` cannot determine the default gateway on these platforms, so the kernel
+/// is queried directly. The local IP still comes from `netdev`.
+pub(super) fn home_router() -> Option<HomeRouter> {
+    let gateway = default_gateway_route(IpFamily::V4)?.gateway?;
+    Some(HomeRouter {
+        gateway,
+        my_ip: super::netdev_impl::local_ip_for(gateway),
+    })
+}
+
+/// Returns the index of the network interface that owns the default route,
+/// preferring the one of the `prefer`red family.
+fn default_route_interface_index(prefer: IpFamily) -> Option<u32> {
+    default_gateway_route(prefer)?.index
+}
+
+/// The parts of a default route we care about.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DefaultRoute {
+    /// Index of the outgoing interface.
+    index: Option<u32>,
+    /// Address of the next hop.
+    gateway: Option<IpAddr>,
+}
+
+/// Looks up the default route of the `prefer`red family, falling back to the other one.
+fn default_gateway_route(prefer: IpFamily) -> Option<DefaultRoute> {
+    [prefer, prefer.other()]
+        .into_iter()
+        .map(|family| match family {
+            IpFamily::V4 => AF_INET,
+            IpFamily::V6 => AF_INET6,
+        })
+        .find_map(query_default_route)
+}
+
+/// Looks up the default route of the given address family, if there is one.
+fn query_default_route(family: c_int) -> Option<DefaultRoute> {
+    match get_default_route(family) {
+        Ok(route) => Some(route),
+        Err(err) if err.raw_os_error() == Some(libc::ESRCH) => None,
+        Err(err) => {
+            warn!("failed to query the default route: {err}");
+            None
+        }
+    }
+}
+
+/// Asks the kernel for the default route of the given address family.
+///
+/// Fails with `ESRCH` if there is no such route.
+fn get_default_route(family: c_int) -> io::Result<DefaultRoute> {
+    let mut socket = Socket::new(Domain::from(PF_ROUTE), Type::RAW, None)?;
+    socket.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    let pid = std::process::id() as i32;
+    let seq = 1;
+    socket.write_all(&encode_request(family, seq))?;
+
+    // The socket also receives the messages of other processes, skip them.
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let n = socket.read(&mut buf)?;
+        let msg = &buf[..n];
+        if msg.len() < RT_MSGHDR_LEN || msg[2] != RTM_VERSION || msg[3] != RTM_GET {
+            continue;
+        }
+        if i32_at(msg, 16) != pid || i32_at(msg, 20) != seq {
+            continue;
+        }
+        let errno = i32_at(msg, 24);
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        return Ok(parse_reply(msg));
+    }
+}
+
+/// Encodes an `RTM_GET` query for the default route of `family`.
+///
+/// The interface is requested with an empty `RTA_IFP` link address.
+fn encode_request(family: c_int, seq: i32) -> Vec<u8> {
+    let addr_len = match family {
+        AF_INET6 => SOCKADDR_IN6_LEN,
+        _ => SOCKADDR_IN_LEN,
+    };
+    let mut msg = vec![0u8; RT_MSGHDR_LEN];
+    msg[2] = RTM_VERSION;
+    msg[3] = RTM_GET;
+    msg[8..12].copy_from_slice(&(RTF_UP | RTF_GATEWAY).to_ne_bytes());
+    msg[12..16].copy_from_slice(&(RTA_DST | RTA_NETMASK | RTA_IFP).to_ne_bytes());
+    msg[20..24].copy_from_slice(&seq.to_ne_bytes());
+    // Destination and netmask are both the unspecified address.
+    for _ in 0..2 {
+        let mut addr = vec![0u8; roundup(addr_len)];
+        addr[..2].copy_from_slice(&(family as u16).to_ne_bytes());
+        msg.extend_from_slice(&addr);
+    }
+    let mut ifp = vec![0u8; roundup(SOCKADDR_DL_LEN)];
+    ifp[..2].copy_from_slice(&(AF_LINK as u16).to_ne_bytes());
+    msg.extend_from_slice(&ifp);
+
+    let len = msg.len() as u16;
+    msg[..2].copy_from_slice(&len.to_ne_bytes());
+    msg
+}
+
+/// Extracts the outgoing interface and gateway from an `RTM_GET` reply.
+fn parse_reply(msg: &[u8]) -> DefaultRoute {
+    let mut route = DefaultRoute {
+        index: Some(u16_at(msg, 4) as u32).filter(|&i| i != 0),
+        gateway: None,
+    };
+    let addrs = i32_at(msg, 12);
+    let mut rest = &msg[RT_MSGHDR_LEN.min(msg.len())..];
+    for bit in (0..RTAX_MAX).map(|i| 1 << i) {
+        if addrs & bit == 0 {
+            continue;
+        }
+        if rest.len() < 2 {
+            break;
+        }
+        // Unlike on the BSDs, sockaddrs carry no length, so it follows from the family.
+        let family = u16_at(rest, 0) as c_int;
+        let len = match family {
+            AF_INET => SOCKADDR_IN_LEN,
+            AF_INET6 => SOCKADDR_IN6_LEN,
+            AF_LINK => SOCKADDR_DL_LEN,
+            _ => break,
+        };
+        if rest.len() < len {
+            break;
+        }
+        match (bit, family) {
+            (RTA_GATEWAY, AF_INET) => {
+                let ip: [u8; 4] = rest[4..8].try_into().expect("checked length");
+                route.gateway = Some(Ipv4Addr::from(ip).into());
+            }
+            (RTA_GATEWAY, AF_INET6) => {
+                let ip: [u8; 16] = rest[8..24].try_into().expect("checked length");
+                route.gateway = Some(Ipv6Addr::from(ip).into());
+            }
+            (RTA_IFP, AF_LINK) => {
+                let index = u16_at(rest, 2) as u32;
+                if index != 0 {
+                    route.index = Some(index);
+                }
+            }
+            _ => {}
+        }
+        rest = &rest[roundup(len).min(rest.len())..];
+    }
+    route
+}
+
+/// Rounds a sockaddr length up to the alignment of addresses in routing messages.
+fn roundup(len: usize) -> usize {
+    let align = std::mem::size_of::<libc::c_long>();
+    len.div_ceil(align) * align
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn i32_at(buf: &[u8], offset: usize) -> i32 {
+    i32::from_ne_bytes(buf[offset..offset + 4].try_into().expect("4 bytes"))
+}
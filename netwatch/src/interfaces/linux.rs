@@ -6,8 +6,17 @@ use tokio::{
     io::{AsyncBufReadExt, BufReader},
 };
 
-use super::DefaultRouteDetails;
-pub(super) use super::netdev_impl::{get_state, home_router};
+#[cfg(target_os = "linux")]
+pub(crate) use self::sane::routing_table;
+pub(super) use super::netdev_impl::{get_state, home_router, interfaces};
+use super::{DefaultRouteDetails, InterfaceStats};
+#[cfg(any(target_os = "android", test))]
+use super::{
+    IFF_LOOPBACK, IFF_UP, Interface, InterfaceType, IpNet, Ipv6AddrFlags, Ipv6Net, is_tunnel_name,
+};
+use crate::IpFamily;
+#[cfg(any(target_os = "android", test))]
+use crate::ip::is_unicast_link_local;
 
 #[stack_error(derive, add_meta, from_sources, std_sources)]
 #[non_exhaustive]
@@ -26,6 +35,14 @@ pub enum Error {
     MissingDestinationField {},
     #[error("mask field is missing")]
     MissingMaskField {},
+    #[error("prefix length field is missing")]
+    MissingPrefixLenField {},
+    #[error("metric field is missing")]
+    MissingMetricField {},
+    #[error("flags field is missing")]
+    MissingFlagsField {},
+    #[error("{field} field is not valid hex")]
+    InvalidHexField { field: &'static str },
     #[cfg(not(target_os = "android"))]
     #[error("netlink")]
     Netlink {
@@ -41,27 +58,56 @@ pub enum Error {
     },
 }
 
-pub async fn default_route() -> Option<DefaultRouteDetails> {
-    // /proc/net/route only contains IPv4 routes. If it finds one, return it.
-    // If it returns Ok(None) (no IPv4 default route) or Err (file unreadable),
-    // fall through to netlink which checks both IPv4 and IPv6.
-    if let Ok(Some(route)) = default_route_proc().await {
-        return Some(route);
+pub async fn default_route(prefer: IpFamily) -> Option<DefaultRouteDetails> {
+    // /proc/net/route only contains IPv4 routes, /proc/net/ipv6_route only IPv6 ones.
+    // Check the preferred family first, then the other. If neither has a default
+    // route or both are unreadable, fall through to netlink which checks both
+    // families in the same order.
+    for family in [prefer, prefer.other()] {
+        let res = match family {
+            IpFamily::V4 => default_route_proc().await,
+            IpFamily::V6 => default_route_proc_v6().await,
+        };
+        if let Ok(Some(route)) = res {
+            return Some(route);
+        }
     }
 
     #[cfg(target_os = "android")]
     let res = android::default_route().await;
 
     #[cfg(not(target_os = "android"))]
-    let res = sane::default_route().await;
+    let res = sane::default_route(prefer).await;
 
     res.ok().flatten()
 }
 
 const PROC_NET_ROUTE_PATH: &str = "/proc/net/route";
 
+/// Returns every default route in `/proc/net/route` and `/proc/net/ipv6_route`.
+///
+/// If neither has one, or both are unreadable, falls back to the platform specific lookup.
+pub async fn all_default_routes() -> Vec<DefaultRouteDetails> {
+    let mut routes = default_routes_proc().await.unwrap_or_default();
+    routes.extend(default_routes_proc_v6().await.unwrap_or_default());
+    if !routes.is_empty() {
+        return routes;
+    }
+
+    #[cfg(target_os = "android")]
+    let res = android::default_route().await.map(Vec::from_iter);
+
+    #[cfg(not(target_os = "android"))]
+    let res = sane::default_routes().await;
+
+    res.unwrap_or_default()
+}
+
 async fn default_route_proc() -> Result<Option<DefaultRouteDetails>, Error> {
-    const ZERO_ADDR: &str = "00000000";
+    Ok(default_routes_proc().await?.into_iter().next())
+}
+
+async fn default_routes_proc() -> Result<Vec<DefaultRouteDetails>, Error> {
     let file = File::open(PROC_NET_ROUTE_PATH).await?;
 
     // Explicitly set capacity, this is min(4096, DEFAULT_BUF_SIZE):
@@ -78,40 +124,148 @@ async fn default_route_proc() -> Result<Option<DefaultRouteDetails>, Error> {
     // read it all in one call.
     let reader = BufReader::with_capacity(8 * 1024, file);
     let mut lines_iter = reader.lines();
+    let mut routes = Vec::new();
     while let Some(line) = lines_iter.next_line().await? {
-        if !line.contains(ZERO_ADDR) {
-            continue;
+        if let Some((iface, metric)) = parse_ipv4_default_route(&line)? {
+            routes.push(DefaultRouteDetails {
+                interface_name: iface.to_string(),
+                metric,
+            });
         }
-        let mut fields = line.split_ascii_whitespace();
-        let iface = fields.next().ok_or_else(|| e!(Error::MissingIfaceField))?;
-        let destination = fields
-            .next()
-            .ok_or_else(|| e!(Error::MissingDestinationField))?;
-        let mask = fields.nth(5).ok_or_else(|| e!(Error::MissingMaskField))?;
-        // if iface.starts_with("tailscale") || iface.starts_with("wg") {
-        //     continue;
-        // }
-        if destination == ZERO_ADDR && mask == ZERO_ADDR {
-            return Ok(Some(DefaultRouteDetails {
+    }
+    Ok(routes)
+}
+
+/// Parses a line of `/proc/net/route`, returning the interface and metric if it's a default
+/// route.
+///
+/// Lines hold the interface, destination, gateway, flags, reference and use counts, metric and
+/// mask, followed by fields we don't use. Addresses and flags are hex, the other numbers decimal.
+fn parse_ipv4_default_route(line: &str) -> Result<Option<(&str, Option<u32>)>, Error> {
+    const ZERO_ADDR: &str = "00000000";
+    if !line.contains(ZERO_ADDR) {
+        return Ok(None);
+    }
+    let mut fields = line.split_ascii_whitespace();
+    let iface = fields.next().ok_or_else(|| e!(Error::MissingIfaceField))?;
+    let destination = fields
+        .next()
+        .ok_or_else(|| e!(Error::MissingDestinationField))?;
+    let metric = fields.nth(4).and_then(|metric| metric.parse().ok());
+    let mask = fields.next().ok_or_else(|| e!(Error::MissingMaskField))?;
+    // if iface.starts_with("tailscale") || iface.starts_with("wg") {
+    //     continue;
+    // }
+    let is_default = destination == ZERO_ADDR && mask == ZERO_ADDR;
+    Ok(is_default.then_some((iface, metric)))
+}
+
+const PROC_NET_IPV6_ROUTE_PATH: &str = "/proc/net/ipv6_route";
+
+async fn default_route_proc_v6() -> Result<Option<DefaultRouteDetails>, Error> {
+    Ok(default_routes_proc_v6().await?.into_iter().next())
+}
+
+async fn default_routes_proc_v6() -> Result<Vec<DefaultRouteDetails>, Error> {
+    let file = File::open(PROC_NET_IPV6_ROUTE_PATH).await?;
+
+    // Read it all at once, see default_routes_proc.
+    let reader = BufReader::with_capacity(8 * 1024, file);
+    let mut lines_iter = reader.lines();
+    let mut routes = Vec::new();
+    while let Some(line) = lines_iter.next_line().await? {
+        if let Some((iface, metric)) = parse_ipv6_default_route(&line)? {
+            routes.push(DefaultRouteDetails {
                 interface_name: iface.to_string(),
-            }));
+                metric: Some(metric),
+            });
         }
     }
-    Ok(None)
+    Ok(routes)
+}
+
+/// Parses a line of `/proc/net/ipv6_route`, returning the interface and metric if it's a default
+/// route.
+///
+/// Lines hold the destination and its prefix length, the source and its prefix length, the next
+/// hop, the metric, the reference and use counts, the flags and the interface. Addresses are 32
+/// hex digits without separators, the other numbers are hex as well, e.g.
+/// `00000000000000000000000000000000 00 00000000000000000000000000000000 00
+/// fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0`
+fn parse_ipv6_default_route(line: &str) -> Result<Option<(&str, u32)>, Error> {
+    const ZERO_ADDR: &str = "00000000000000000000000000000000";
+    /// The route is usable.
+    const RTF_UP: u32 = 0x0001;
+    /// The route rejects packets, e.g. the `unreachable` default route on `lo`.
+    const RTF_REJECT: u32 = 0x0200;
+
+    let mut fields = line.split_ascii_whitespace();
+    let destination = fields
+        .next()
+        .ok_or_else(|| e!(Error::MissingDestinationField))?;
+    let prefix_len = fields
+        .next()
+        .ok_or_else(|| e!(Error::MissingPrefixLenField))?;
+    let metric = fields.nth(3).ok_or_else(|| e!(Error::MissingMetricField))?;
+    let flags = fields.nth(2).ok_or_else(|| e!(Error::MissingFlagsField))?;
+    let iface = fields.next().ok_or_else(|| e!(Error::MissingIfaceField))?;
+
+    let prefix_len = u8::from_str_radix(prefix_len, 16).map_err(|_| {
+        e!(Error::InvalidHexField {
+            field: "prefix length"
+        })
+    })?;
+    let metric = u32::from_str_radix(metric, 16)
+        .map_err(|_| e!(Error::InvalidHexField { field: "metric" }))?;
+    let flags = u32::from_str_radix(flags, 16)
+        .map_err(|_| e!(Error::InvalidHexField { field: "flags" }))?;
+    let is_default = destination == ZERO_ADDR && prefix_len == 0;
+    let is_usable = flags & RTF_UP != 0 && flags & RTF_REJECT == 0;
+    Ok((is_default && is_usable).then_some((iface, metric)))
+}
+
+const SYS_CLASS_NET_PATH: &str = "/sys/class/net";
+
+/// Reads the traffic counters of the interface called `name` from sysfs.
+///
+/// Returns `None` if there is no such interface or its counters can't be read.
+pub(super) fn interface_stats(name: &str) -> Option<InterfaceStats> {
+    // the name becomes part of the path, don't let it escape the interface's directory
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return None;
+    }
+    let dir = std::path::Path::new(SYS_CLASS_NET_PATH)
+        .join(name)
+        .join("statistics");
+    let read = |counter: &str| -> Option<u64> {
+        std::fs::read_to_string(dir.join(counter))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some(InterfaceStats {
+        rx_bytes: read("rx_bytes")?,
+        tx_bytes: read("tx_bytes")?,
+        rx_packets: read("rx_packets")?,
+        tx_packets: read("tx_packets")?,
+    })
 }
 
 #[cfg(target_os = "android")]
-mod android {
+pub(super) mod android {
     use tokio::process::Command;
 
     use super::*;
 
+    /// Where to look for the `ip` command.
+    const IP_PATHS: &[&str] = &["/system/bin/ip", "/system/xbin/ip", "ip"];
+
     /// Try find the default route by parsing the "ip route" command output.
     ///
     /// We use this on Android where /proc/net/route can be missing entries or have locked-down
     /// permissions.  See also comments in <https://github.com/tailscale/tailscale/pull/666>.
     pub async fn default_route() -> Result<Option<DefaultRouteDetails>, Error> {
-        const IP_PATHS: &[&str] = &["/system/bin/ip", "/system/xbin/ip", "ip"];
         for path in IP_PATHS {
             let output = match Command::new(path)
                 .args(["route", "show", "table", "0"])
@@ -128,6 +282,7 @@ mod android {
             let stdout = std::string::String::from_utf8_lossy(&output.stdout);
             let details = parse_android_ip_route(&stdout).map(|iface| DefaultRouteDetails {
                 interface_name: iface.to_string(),
+                metric: None,
             });
             return Ok(details);
         }
@@ -138,22 +293,52 @@ mod android {
             )
         }))
     }
+
+    /// Enumerates the interfaces by parsing the "ip addr" command output.
+    ///
+    /// Used where the interfaces can't be enumerated directly, as in some Android permission
+    /// sandboxes. Blocks until the command exits, so async callers must run it with
+    /// [`tokio::task::spawn_blocking`].
+    pub fn interfaces() -> Vec<Interface> {
+        for path in IP_PATHS {
+            match std::process::Command::new(path)
+                .args(["addr", "show"])
+                .output()
+            {
+                Ok(output) => {
+                    let stdout = std::string::String::from_utf8_lossy(&output.stdout);
+                    return parse_android_ip_addr(&stdout);
+                }
+                Err(err) => {
+                    tracing::debug!(%path, ?err, "ip command not available, trying next");
+                }
+            }
+        }
+        tracing::warn!("ip command not found at any known path");
+        Vec::new()
+    }
 }
 
 #[cfg(not(target_os = "android"))]
 mod sane {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
     use n0_error::e;
     use n0_future::{Either, StreamExt, TryStream};
     use netlink_packet_core::{NLM_F_DUMP, NLM_F_REQUEST, NetlinkMessage};
     use netlink_packet_route::{
         AddressFamily, RouteNetlinkMessage,
         link::{LinkAttribute, LinkMessage},
-        route::{RouteAttribute, RouteHeader, RouteMessage, RouteProtocol, RouteScope, RouteType},
+        route::{
+            RouteAddress, RouteAttribute, RouteHeader, RouteMessage, RouteProtocol, RouteScope,
+            RouteType,
+        },
     };
     use netlink_sys::protocols::NETLINK_ROUTE;
     use tracing::{Instrument, info_span};
 
     use super::*;
+    use crate::ip::RouteEntry;
 
     type Handle = netlink_proto::ConnectionHandle<RouteNetlinkMessage>;
 
@@ -173,25 +358,51 @@ mod sane {
         }};
     }
 
-    pub async fn default_route() -> Result<Option<DefaultRouteDetails>, Error> {
+    /// Returns the first default route of the `prefer`red family, or else of the other one.
+    pub async fn default_route(prefer: IpFamily) -> Result<Option<DefaultRouteDetails>, Error> {
         let (connection, handle, _receiver) =
             netlink_proto::new_connection::<RouteNetlinkMessage>(NETLINK_ROUTE)?;
 
         let task = tokio::spawn(connection.instrument(info_span!("netlink.conn")));
 
-        let default = default_route_netlink_family(&handle, AddressFamily::Inet).await?;
-        let default = match default {
-            Some(default) => Some(default),
-            None => {
-                default_route_netlink_family(&handle, netlink_packet_route::AddressFamily::Inet6)
+        let res = async {
+            for family in [prefer, prefer.other()] {
+                let family = match family {
+                    IpFamily::V4 => AddressFamily::Inet,
+                    IpFamily::V6 => AddressFamily::Inet6,
+                };
+                let default = default_routes_netlink_family(&handle, family)
                     .await?
+                    .into_iter()
+                    .next();
+                if default.is_some() {
+                    return Ok(default);
+                }
             }
-        };
+            Ok(None)
+        }
+        .await;
         task.abort();
         task.await.ok();
-        Ok(default.map(|(name, _index)| DefaultRouteDetails {
-            interface_name: name,
-        }))
+        res
+    }
+
+    /// Returns the IPv4 default routes followed by the IPv6 ones.
+    pub async fn default_routes() -> Result<Vec<DefaultRouteDetails>, Error> {
+        let (connection, handle, _receiver) =
+            netlink_proto::new_connection::<RouteNetlinkMessage>(NETLINK_ROUTE)?;
+
+        let task = tokio::spawn(connection.instrument(info_span!("netlink.conn")));
+
+        let res = async {
+            let mut routes = default_routes_netlink_family(&handle, AddressFamily::Inet).await?;
+            routes.extend(default_routes_netlink_family(&handle, AddressFamily::Inet6).await?);
+            Ok(routes)
+        }
+        .await;
+        task.abort();
+        task.await.ok();
+        res
     }
 
     fn get_route(
@@ -221,14 +432,15 @@ mod sane {
         message
     }
 
-    /// Returns the `(name, index)` of the interface for the default route.
-    async fn default_route_netlink_family(
+    /// Returns the default routes of the given family.
+    async fn default_routes_netlink_family(
         handle: &Handle,
         family: netlink_packet_route::AddressFamily,
-    ) -> Result<Option<(String, u32)>, Error> {
+    ) -> Result<Vec<DefaultRouteDetails>, Error> {
         let msg = create_route_message(family);
         let mut routes = get_route(handle.clone(), msg);
 
+        let mut defaults = Vec::new();
         while let Some(route) = routes.try_next().await? {
             let route_attrs = route.attributes;
 
@@ -250,16 +462,95 @@ mod sane {
                 RouteAttribute::Oif(index) => Some(*index),
                 _ => None,
             });
+            let metric = route_attrs.iter().find_map(|attr| match attr {
+                RouteAttribute::Priority(metric) => Some(*metric),
+                _ => None,
+            });
 
             if let Some(index) = index {
                 if index == 0 {
                     continue;
                 }
                 let name = iface_by_index(handle, index).await?;
-                return Ok(Some((name, index)));
+                defaults.push(DefaultRouteDetails {
+                    interface_name: name,
+                    metric,
+                });
+            }
+        }
+        Ok(defaults)
+    }
+
+    /// Dumps the IPv4 and IPv6 routes of all routing tables.
+    pub async fn routing_table() -> Result<Vec<RouteEntry>, Error> {
+        let (connection, handle, _receiver) =
+            netlink_proto::new_connection::<RouteNetlinkMessage>(NETLINK_ROUTE)?;
+
+        let task = tokio::spawn(connection.instrument(info_span!("netlink.conn")));
+
+        let res = async {
+            let mut entries = routes_netlink_family(&handle, AddressFamily::Inet).await?;
+            entries.extend(routes_netlink_family(&handle, AddressFamily::Inet6).await?);
+            Ok(entries)
+        }
+        .await;
+        task.abort();
+        task.await.ok();
+        res
+    }
+
+    async fn routes_netlink_family(
+        handle: &Handle,
+        family: AddressFamily,
+    ) -> Result<Vec<RouteEntry>, Error> {
+        let mut msg = RouteMessage::default();
+        msg.header.address_family = family;
+        let mut routes = get_route(handle.clone(), msg);
+
+        let mut entries = Vec::new();
+        while let Some(route) = routes.try_next().await? {
+            entries.extend(route_entry(route));
+        }
+        Ok(entries)
+    }
+
+    /// Converts a netlink route message, skipping routes which are not IPv4 or IPv6.
+    fn route_entry(route: RouteMessage) -> Option<RouteEntry> {
+        let destination = match route.header.address_family {
+            AddressFamily::Inet => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            AddressFamily::Inet6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => return None,
+        };
+        let mut entry = RouteEntry {
+            destination,
+            prefix_len: route.header.destination_prefix_length,
+            gateway: None,
+            oif: None,
+            // Table ids above 255 only fit in the `RTA_TABLE` attribute.
+            table: route.header.table.into(),
+            metric: None,
+        };
+        for attr in route.attributes {
+            match attr {
+                RouteAttribute::Destination(addr) => {
+                    entry.destination = route_address_ip(addr)?;
+                }
+                RouteAttribute::Gateway(addr) => entry.gateway = route_address_ip(addr),
+                RouteAttribute::Oif(index) => entry.oif = Some(index),
+                RouteAttribute::Table(table) => entry.table = table,
+                RouteAttribute::Priority(metric) => entry.metric = Some(metric),
+                _ => {}
             }
         }
-        Ok(None)
+        Some(entry)
+    }
+
+    fn route_address_ip(addr: RouteAddress) -> Option<IpAddr> {
+        match addr {
+            RouteAddress::Inet(ip) => Some(ip.into()),
+            RouteAddress::Inet6(ip) => Some(ip.into()),
+            _ => None,
+        }
     }
 
     fn get_link(
@@ -307,12 +598,84 @@ mod sane {
 
         #[tokio::test]
         async fn test_default_route_netlink() {
-            let route = default_route().await.unwrap();
+            let route = default_route(IpFamily::V4).await.unwrap();
             // assert!(route.is_some());
             if let Some(route) = route {
                 assert!(!route.interface_name.is_empty());
             }
         }
+
+        #[tokio::test]
+        async fn test_default_route_netlink_prefer_v6() {
+            let route = default_route(IpFamily::V6).await.unwrap();
+            let v6 = default_routes_netlink_family_once(AddressFamily::Inet6).await;
+            if let Some(first) = v6.first() {
+                assert_eq!(route.unwrap().interface_name, first.interface_name);
+            }
+        }
+
+        async fn default_routes_netlink_family_once(
+            family: AddressFamily,
+        ) -> Vec<DefaultRouteDetails> {
+            let (connection, handle, _receiver) =
+                netlink_proto::new_connection::<RouteNetlinkMessage>(NETLINK_ROUTE).unwrap();
+            let task = tokio::spawn(connection);
+            let routes = default_routes_netlink_family(&handle, family)
+                .await
+                .unwrap();
+            task.abort();
+            routes
+        }
+
+        #[tokio::test]
+        async fn test_routing_table() {
+            let routes = routing_table().await.unwrap();
+            for route in routes {
+                let max_prefix_len = if route.destination.is_ipv4() { 32 } else { 128 };
+                assert!(route.prefix_len <= max_prefix_len, "{route:?}");
+            }
+        }
+
+        #[test]
+        fn test_route_entry() {
+            let mut msg = RouteMessage::default();
+            msg.header.address_family = AddressFamily::Inet;
+            msg.header.table = RouteHeader::RT_TABLE_MAIN;
+            msg.attributes = vec![
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(192, 168, 1, 1))),
+                RouteAttribute::Oif(2),
+                RouteAttribute::Priority(100),
+            ];
+            let entry = route_entry(msg).unwrap();
+            assert_eq!(
+                entry,
+                RouteEntry {
+                    destination: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    prefix_len: 0,
+                    gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+                    oif: Some(2),
+                    table: u32::from(RouteHeader::RT_TABLE_MAIN),
+                    metric: Some(100),
+                }
+            );
+
+            let mut msg = RouteMessage::default();
+            msg.header.address_family = AddressFamily::Inet6;
+            msg.header.destination_prefix_length = 64;
+            msg.attributes = vec![
+                RouteAttribute::Destination(RouteAddress::Inet6("fd00::".parse().unwrap())),
+                RouteAttribute::Table(1000),
+            ];
+            let entry = route_entry(msg).unwrap();
+            assert_eq!(entry.destination, "fd00::".parse::<IpAddr>().unwrap());
+            assert_eq!(entry.prefix_len, 64);
+            assert_eq!(entry.table, 1000);
+            assert_eq!(entry.gateway, None);
+
+            let mut msg = RouteMessage::default();
+            msg.header.address_family = AddressFamily::Mpls;
+            assert!(route_entry(msg).is_none());
+        }
     }
 }
 
@@ -334,10 +697,150 @@ fn parse_android_ip_route(stdout: &str) -> Option<&str> {
     None
 }
 
+/// Parses the output of the android `/system/bin/ip addr` command into interfaces.
+///
+/// Each interface starts with a line like `2: wlan0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500
+/// ...`, followed by indented `link/ether`, `inet` and `inet6` lines describing it.
+#[cfg(any(target_os = "android", test))]
+fn parse_android_ip_addr(stdout: &str) -> Vec<Interface> {
+    let mut interfaces: Vec<Interface> = Vec::new();
+    for line in stdout.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            interfaces.extend(parse_android_ip_addr_header(line));
+            continue;
+        }
+        let Some(iface) = interfaces.last_mut() else {
+            continue;
+        };
+        let mut fields = line.split_ascii_whitespace();
+        match fields.next() {
+            Some("link/ether") => iface.mac_addr = fields.next().and_then(parse_mac),
+            Some("inet") => {
+                if let Some(net) = fields.next().and_then(|net| net.parse().ok()) {
+                    iface.addrs.push(IpNet::V4(net));
+                }
+            }
+            Some("inet6") => {
+                let Some(net) = fields.next().and_then(|net| net.parse::<Ipv6Net>().ok()) else {
+                    continue;
+                };
+                let attrs: Vec<&str> = fields.collect();
+                let flags = Ipv6AddrFlags {
+                    deprecated: attrs.contains(&"deprecated"),
+                    temporary: attrs.contains(&"temporary"),
+                    tentative: attrs.contains(&"tentative"),
+                    duplicated: attrs.contains(&"dadfailed"),
+                    permanent: !attrs.contains(&"dynamic"),
+                };
+                let scope_id = if is_unicast_link_local(net.addr()) {
+                    iface.index
+                } else {
+                    0
+                };
+                iface.addrs.push(IpNet::V6 {
+                    net,
+                    scope_id,
+                    flags,
+                });
+            }
+            _ => {}
+        }
+    }
+    // sorted like the interfaces enumerated with netdev, so that snapshots compare equal
+    for iface in &mut interfaces {
+        iface
+            .addrs
+            .sort_by_key(|net| (net.addr().is_ipv6(), net.addr()));
+    }
+    interfaces
+}
+
+/// Parses the line starting an interface in the `ip addr` output.
+#[cfg(any(target_os = "android", test))]
+fn parse_android_ip_addr_header(line: &str) -> Option<Interface> {
+    let mut fields = line.split_ascii_whitespace();
+    let index = fields.next()?.strip_suffix(':')?.parse().ok()?;
+    let name = fields.next()?.strip_suffix(':')?;
+    // virtual interfaces are named after their parent, as in `rmnet_data0@rmnet_ipa0`
+    let name = name.split_once('@').map_or(name, |(name, _parent)| name);
+    let flags = fields
+        .next()?
+        .strip_prefix('<')?
+        .strip_suffix('>')?
+        .split(',')
+        .fold(0, |flags, flag| match flag {
+            "UP" => flags | IFF_UP,
+            "LOOPBACK" => flags | IFF_LOOPBACK,
+            _ => flags,
+        });
+    let mtu = fields
+        .skip_while(|field| *field != "mtu")
+        .nth(1)
+        .and_then(|mtu| mtu.parse().ok());
+    Some(Interface {
+        name: name.to_string(),
+        index,
+        flags,
+        mac_addr: None,
+        addrs: Vec::new(),
+        mtu,
+        transmit_speed: None,
+        receive_speed: None,
+        interface_type: android_interface_type(name, flags),
+        gateways: Vec::new(),
+    })
+}
+
+/// Guesses the kind of an interface from its name, which `ip addr` doesn't report.
+#[cfg(any(target_os = "android", test))]
+fn android_interface_type(name: &str, flags: u32) -> InterfaceType {
+    const CELLULAR_PREFIXES: &[&str] = &["rmnet", "ccmni", "v4-rmnet"];
+    if flags & IFF_LOOPBACK != 0 {
+        InterfaceType::Loopback
+    } else if name.starts_with("wlan") {
+        InterfaceType::Wifi
+    } else if CELLULAR_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    {
+        InterfaceType::Cellular
+    } else if is_tunnel_name(name) {
+        InterfaceType::Tunnel
+    } else if name.starts_with("eth") {
+        InterfaceType::Ethernet
+    } else {
+        InterfaceType::Other
+    }
+}
+
+/// Parses a MAC address written as colon separated hex bytes.
+#[cfg(any(target_os = "android", test))]
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut octets = [0; 6];
+    let mut parts = mac.split(':');
+    for octet in &mut octets {
+        *octet = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(octets)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::net::IpAddr;
+
     use super::*;
 
+    #[test]
+    fn test_interface_stats() {
+        let stats = interface_stats("lo").expect("loopback has stats");
+        assert!(stats.rx_bytes >= stats.rx_packets);
+        assert!(stats.tx_bytes >= stats.tx_packets);
+
+        assert!(interface_stats("no-such-interface").is_none());
+        assert!(interface_stats("../lo").is_none());
+        assert!(interface_stats("..").is_none());
+    }
+
     #[tokio::test]
     async fn test_default_route_proc() {
         let route = default_route_proc().await.unwrap();
@@ -347,10 +850,142 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_default_route_proc_v6() {
+        // not every host has IPv6 enabled
+        if let Ok(Some(route)) = default_route_proc_v6().await {
+            assert!(!route.interface_name.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_parse_ipv4_default_route() {
+        let header =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT";
+        assert_eq!(parse_ipv4_default_route(header).unwrap(), None);
+
+        let default = "eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0";
+        assert_eq!(
+            parse_ipv4_default_route(default).unwrap(),
+            Some(("eth0", Some(100)))
+        );
+
+        let not_default = "eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0";
+        assert_eq!(parse_ipv4_default_route(not_default).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_ipv6_default_route() {
+        let default = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+                       fe800000000000000000000000000001 00000400 00000001 00000000 00000003     eth0";
+        assert_eq!(
+            parse_ipv6_default_route(default).unwrap(),
+            Some(("eth0", 0x400))
+        );
+
+        // the kernel's unreachable default route
+        let unreachable = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+                           00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo";
+        assert_eq!(parse_ipv6_default_route(unreachable).unwrap(), None);
+
+        let not_default = "fd000000000000000000000000000000 40 00000000000000000000000000000000 00 \
+                           00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0";
+        assert_eq!(parse_ipv6_default_route(not_default).unwrap(), None);
+
+        let truncated = "00000000000000000000000000000000 00 00000000000000000000000000000000";
+        assert!(parse_ipv6_default_route(truncated).is_err());
+    }
+
     #[test]
     fn test_parse_android_ip_route() {
         let stdout = "default via 10.0.2.2. dev radio0 table 1016 proto static mtu 1500";
         let iface = parse_android_ip_route(stdout).unwrap();
         assert_eq!(iface, "radio0");
     }
+
+    #[test]
+    fn test_parse_android_ip_addr() {
+        let stdout = "\
+1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue state UNKNOWN group default qlen 1000
+    link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00
+    inet 127.0.0.1/8 scope host lo
+       valid_lft forever preferred_lft forever
+    inet6 ::1/128 scope host
+       valid_lft forever preferred_lft forever
+2: dummy0: <BROADCAST,NOARP> mtu 1500 qdisc noop state DOWN group default qlen 1000
+    link/ether 8a:f4:3a:29:c6:2e brd ff:ff:ff:ff:ff:ff
+14: rmnet_data0@rmnet_ipa0: <UP,LOWER_UP> mtu 1500 qdisc mq state UNKNOWN group default qlen 1000
+    link/[530]
+    inet6 2001:db8::5/64 scope global dynamic noprefixroute
+       valid_lft 7199sec preferred_lft 7199sec
+    inet6 fe80::aa6b:2cd3:9a6b:1/64 scope link
+       valid_lft forever preferred_lft forever
+30: wlan0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc mq state UP group default qlen 3000
+    link/ether 02:00:00:44:55:66 brd ff:ff:ff:ff:ff:ff
+    inet6 2001:db8::1234/64 scope global temporary deprecated dynamic
+       valid_lft 85000sec preferred_lft 0sec
+    inet 192.168.1.23/24 brd 192.168.1.255 scope global wlan0
+       valid_lft forever preferred_lft forever
+";
+        let interfaces = parse_android_ip_addr(stdout);
+        let names: Vec<_> = interfaces.iter().map(Interface::name).collect();
+        assert_eq!(names, ["lo", "dummy0", "rmnet_data0", "wlan0"]);
+
+        let lo = &interfaces[0];
+        assert_eq!(lo.index(), 1);
+        assert!(lo.is_up() && lo.is_loopback());
+        assert_eq!(lo.interface_type(), InterfaceType::Loopback);
+        assert_eq!(lo.mtu(), Some(65536));
+        assert_eq!(lo.addrs().count(), 2);
+
+        let dummy = &interfaces[1];
+        assert!(!dummy.is_up());
+        assert_eq!(dummy.mac(), Some([0x8a, 0xf4, 0x3a, 0x29, 0xc6, 0x2e]));
+        assert_eq!(dummy.addrs().count(), 0);
+
+        let rmnet = &interfaces[2];
+        assert!(rmnet.is_cellular());
+        assert_eq!(rmnet.mac(), None);
+        let addrs: Vec<_> = rmnet.addrs().collect();
+        let IpNet::V6 {
+            scope_id, flags, ..
+        } = &addrs[0]
+        else {
+            panic!("expected ipv6 address");
+        };
+        assert_eq!(addrs[0].addr(), "2001:db8::5".parse::<IpAddr>().unwrap());
+        assert_eq!(*scope_id, 0);
+        assert!(!flags.permanent);
+        let IpNet::V6 { scope_id, .. } = &addrs[1] else {
+            panic!("expected ipv6 address");
+        };
+        assert_eq!(*scope_id, 14);
+
+        let wlan = &interfaces[3];
+        assert!(wlan.is_up() && wlan.is_wifi());
+        assert_eq!(wlan.mac(), Some([2, 0, 0, 0x44, 0x55, 0x66]));
+        let addrs: Vec<_> = wlan.addrs().map(|net| net.addr()).collect();
+        assert_eq!(
+            addrs,
+            [
+                "192.168.1.23".parse::<IpAddr>().unwrap(),
+                "2001:db8::1234".parse().unwrap()
+            ]
+        );
+        let Some(IpNet::V6 { flags, .. }) = wlan.addrs().nth(1) else {
+            panic!("expected ipv6 address");
+        };
+        assert!(flags.temporary && flags.deprecated && !flags.tentative);
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        assert_eq!(
+            parse_mac("02:00:00:44:55:0a"),
+            Some([2, 0, 0, 0x44, 0x55, 0x0a])
+        );
+        assert_eq!(parse_mac("02:00:00:44:55"), None);
+        assert_eq!(parse_mac("02:00:00:44:55:66:77"), None);
+        assert_eq!(parse_mac("zz:00:00:44:55:66"), None);
+    }
 }
@@ -9,12 +9,9 @@
 
 use std::net::IpAddr;
 
-use super::{Interface, IpNet, Ipv6AddrFlags, State};
+use super::{Interface, InterfaceType, IpNet, Ipv6AddrFlags, State, StateOptions};
 use crate::ip::{LocalAddresses, is_link_local, is_private, is_private_v6};
 
-const IFF_UP: u32 = 0x1;
-const IFF_LOOPBACK: u32 = 0x8;
-
 /// Converts netdev's IPv6 address flags into our mirrored [`Ipv6AddrFlags`].
 ///
 /// This is a free function rather than a `From` impl on purpose: a public
@@ -30,6 +27,32 @@ fn to_ipv6_addr_flags(flags: netdev::interface::ipv6_addr_flags::Ipv6AddrFlags)
     }
 }
 
+/// Classifies netdev's interface type into our coarser [`InterfaceType`].
+///
+/// Some platforms report no type for tunnels, so those are also recognized by
+/// their name.
+fn to_interface_type(
+    if_type: netdev::interface::types::InterfaceType,
+    name: &str,
+) -> InterfaceType {
+    use netdev::interface::types::InterfaceType as Netdev;
+
+    match if_type {
+        Netdev::Loopback => InterfaceType::Loopback,
+        Netdev::Ethernet
+        | Netdev::Ethernet3Megabit
+        | Netdev::FastEthernetT
+        | Netdev::FastEthernetFx
+        | Netdev::GigabitEthernet => InterfaceType::Ethernet,
+        Netdev::Wireless80211 => InterfaceType::Wifi,
+        Netdev::Wwan | Netdev::Wwanpp | Netdev::Wwanpp2 => InterfaceType::Cellular,
+        Netdev::Tunnel => InterfaceType::Tunnel,
+        Netdev::Ppp | Netdev::Slip => InterfaceType::PointToPoint,
+        _ if super::is_tunnel_name(name) => InterfaceType::Tunnel,
+        _ => InterfaceType::Other,
+    }
+}
+
 /// Converts a [`netdev::Interface`] into our platform-agnostic [`Interface`].
 ///
 /// Addresses are sorted (IPv4 first, then IPv6, each by address) so that
@@ -62,26 +85,75 @@ fn to_interface(iface: netdev::Interface) -> Interface {
     let mut addrs = v4;
     addrs.append(&mut v6);
 
+    let gateways = iface
+        .gateway
+        .as_ref()
+        .map(|gateway| {
+            let v4 = gateway.ipv4.iter().copied().map(IpAddr::V4);
+            v4.chain(gateway.ipv6.iter().copied().map(IpAddr::V6))
+                .collect()
+        })
+        .unwrap_or_default();
+
     Interface {
+        interface_type: to_interface_type(iface.if_type, &iface.name),
         name: iface.name,
         index: iface.index,
         flags: iface.flags,
         mac_addr: iface.mac_addr.as_ref().map(|a| a.octets()),
         addrs,
+        mtu: iface.mtu,
+        transmit_speed: iface.transmit_speed,
+        receive_speed: iface.receive_speed,
+        gateways,
+    }
+}
+
+/// Enumerates the machine's network interfaces.
+///
+/// Under some Android permission sandboxes `netdev` finds no interfaces, there they are parsed
+/// from the output of the `ip` command instead.
+pub(super) fn interfaces() -> Vec<Interface> {
+    let interfaces: Vec<_> = netdev::interface::get_interfaces()
+        .into_iter()
+        .map(to_interface)
+        .collect();
+    #[cfg(target_os = "android")]
+    if interfaces.is_empty() {
+        tracing::debug!("netdev found no interfaces, falling back to the ip command");
+        return super::linux::android::interfaces();
     }
+    interfaces
 }
 
 /// Enumerates the machine's network interfaces and assembles the [`State`].
-pub(super) async fn get_state() -> State {
-    let raw = netdev::interface::get_interfaces();
-    let local_addresses = local_addresses(&raw);
+pub(super) async fn get_state(options: &StateOptions) -> State {
+    // the fallback to the `ip` command blocks until it exits
+    #[cfg(target_os = "android")]
+    let mut all = match tokio::task::spawn_blocking(interfaces).await {
+        Ok(all) => all,
+        Err(e) => {
+            tracing::warn!("failed to enumerate interfaces: {e}");
+            Vec::new()
+        }
+    };
+    #[cfg(not(target_os = "android"))]
+    let mut all = interfaces();
+    all.retain(|iface| !options.excludes(iface.name()));
+    let local_addresses = local_addresses(&all);
 
     let mut interfaces = std::collections::HashMap::new();
+    let mut cellular = std::collections::HashSet::new();
     let mut have_v6 = false;
     let mut have_v4 = false;
 
-    for raw in raw {
-        let iface = to_interface(raw);
+    for iface in all {
+        if options.exclude_down && !iface.is_up() {
+            continue;
+        }
+        if iface.is_cellular() {
+            cellular.insert(iface.name().to_string());
+        }
         if iface.is_up() {
             for pfx in iface.addrs() {
                 let addr = pfx.addr();
@@ -95,14 +167,26 @@ pub(super) async fn get_state() -> State {
         interfaces.insert(iface.name().to_string(), iface);
     }
 
-    let default_route_interface = super::default_route_interface().await;
+    #[cfg(bsd)]
+    let default_route =
+        super::bsd::default_route_within(crate::IpFamily::V4, &options.routing_table).await;
+    #[cfg(not(bsd))]
+    let default_route = super::DefaultRouteDetails::new().await;
+    let default_route_interface = default_route
+        .map(|route| route.interface_name)
+        .filter(|name| !options.excludes(name))
+        .filter(|name| !options.exclude_down || interfaces.contains_key(name));
+    // traffic is metered when it leaves through a cellular interface
+    let is_expensive = default_route_interface
+        .as_ref()
+        .is_some_and(|name| cellular.contains(name));
 
     State {
         interfaces,
         local_addresses,
         have_v4,
         have_v6,
-        is_expensive: false,
+        is_expensive,
         default_route_interface,
         last_unsuspend: None,
     }
@@ -125,7 +209,7 @@ pub(super) fn home_router() -> Option<super::HomeRouter> {
 
     Some(super::HomeRouter {
         gateway,
-        my_ip: local_ip(),
+        my_ip: local_ip_for(gateway),
     })
 }
 
@@ -170,24 +254,24 @@ fn is_usable_v6(ip: &IpAddr) -> bool {
 }
 
 /// The local IP address of this machine, as reported by `netdev`.
-pub(super) fn local_ip() -> Option<IpAddr> {
+fn local_ip() -> Option<IpAddr> {
     netdev::net::ip::get_local_ipaddr()
 }
 
-const fn is_up(interface: &netdev::Interface) -> bool {
-    interface.flags & IFF_UP != 0
-}
-
-const fn is_loopback(interface: &netdev::Interface) -> bool {
-    interface.flags & IFF_LOOPBACK != 0
+/// Returns our address on the network `gateway` is part of, falling back to [`local_ip`].
+///
+/// On multi-homed hosts the preferred local address can be on another interface than the one
+/// reaching the gateway, e.g. a VPN.
+pub(super) fn local_ip_for(gateway: IpAddr) -> Option<IpAddr> {
+    super::local_ip_on_subnet(interfaces().iter(), gateway).or_else(local_ip)
 }
 
-/// Builds the machine's [`LocalAddresses`] from a raw `netdev` interface list.
+/// Builds the machine's [`LocalAddresses`] from an interface list.
 ///
 /// If there are no regular addresses it falls back to IPv4 link-local or IPv6
 /// unique-local addresses, because we know of environments where these are used
 /// with NAT to provide connectivity.
-fn local_addresses(ifaces: &[netdev::Interface]) -> LocalAddresses {
+fn local_addresses(ifaces: &[Interface]) -> LocalAddresses {
     let mut loopback = Vec::new();
     let mut regular4 = Vec::new();
     let mut regular6 = Vec::new();
@@ -195,18 +279,13 @@ fn local_addresses(ifaces: &[netdev::Interface]) -> LocalAddresses {
     let mut ula6 = Vec::new();
 
     for iface in ifaces {
-        if !is_up(iface) {
+        if !iface.is_up() {
             // Skip down interfaces
             continue;
         }
-        let ifc_is_loopback = is_loopback(iface);
-        let addrs = iface
-            .ipv4
-            .iter()
-            .map(|a| IpAddr::V4(a.addr()))
-            .chain(iface.ipv6.iter().map(|a| IpAddr::V6(a.addr())));
-
-        for ip in addrs {
+        let ifc_is_loopback = iface.is_loopback();
+
+        for ip in iface.addrs().map(|net| net.addr()) {
             let ip = ip.to_canonical();
 
             if ip.is_loopback() || ifc_is_loopback {
@@ -258,7 +337,7 @@ impl LocalAddresses {
     /// IPv6 unique-local addresses, because we know of environments where these
     /// are used with NAT to provide connectivity.
     pub fn new() -> Self {
-        local_addresses(&netdev::interface::get_interfaces())
+        local_addresses(&interfaces())
     }
 }
 
@@ -5,10 +5,10 @@
 
 use std::collections::HashMap;
 
-use super::{DefaultRouteDetails, HomeRouter, State};
-use crate::ip::LocalAddresses;
+use super::{DefaultRouteDetails, HomeRouter, Interface, State, StateOptions};
+use crate::{IpFamily, ip::LocalAddresses};
 
-pub(super) async fn get_state() -> State {
+pub(super) async fn get_state(_options: &StateOptions) -> State {
     State {
         interfaces: HashMap::new(),
         local_addresses: LocalAddresses::default(),
@@ -20,10 +20,18 @@ pub(super) async fn get_state() -> State {
     }
 }
 
-pub(super) async fn default_route() -> Option<DefaultRouteDetails> {
+pub(super) fn interfaces() -> Vec<Interface> {
+    Vec::new()
+}
+
+pub(super) async fn default_route(_prefer: IpFamily) -> Option<DefaultRouteDetails> {
     None
 }
 
+pub(super) async fn all_default_routes() -> Vec<DefaultRouteDetails> {
+    Vec::new()
+}
+
 pub(super) fn home_router() -> Option<HomeRouter> {
     None
 }
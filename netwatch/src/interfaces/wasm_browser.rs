@@ -6,8 +6,10 @@
 
 use js_sys::{JsString, Reflect};
 
-use super::{DefaultRouteDetails, HomeRouter, IFF_UP, Interface, State};
-use crate::ip::LocalAddresses;
+use super::{
+    DefaultRouteDetails, HomeRouter, IFF_UP, Interface, InterfaceType, State, StateOptions,
+};
+use crate::{IpFamily, ip::LocalAddresses};
 
 /// The name of the single placeholder interface we report in the browser.
 pub(crate) const BROWSER_INTERFACE: &str = "browserif";
@@ -33,17 +35,33 @@ fn navigator_online() -> bool {
     }
 }
 
-pub(super) async fn get_state() -> State {
+/// The placeholder interface, up as long as the browser is online.
+fn browser_interface() -> Interface {
     let is_up = navigator_online();
     tracing::debug!(onLine = is_up, "Fetched globalThis.navigator.onLine");
 
-    let iface = Interface {
+    Interface {
         name: BROWSER_INTERFACE.to_string(),
         index: 0,
         flags: if is_up { IFF_UP } else { 0 },
         mac_addr: None,
         addrs: Vec::new(),
-    };
+        mtu: None,
+        transmit_speed: None,
+        receive_speed: None,
+        interface_type: InterfaceType::Other,
+        gateways: Vec::new(),
+    }
+}
+
+pub(super) fn interfaces() -> Vec<Interface> {
+    vec![browser_interface()]
+}
+
+// the placeholder interface is never a tunnel, and being down only means the browser is offline,
+// there is nothing to exclude
+pub(super) async fn get_state(_options: &StateOptions) -> State {
+    let iface = browser_interface();
 
     State {
         interfaces: [(BROWSER_INTERFACE.to_string(), iface)]
@@ -58,12 +76,17 @@ pub(super) async fn get_state() -> State {
     }
 }
 
-pub(super) async fn default_route() -> Option<DefaultRouteDetails> {
+pub(super) async fn default_route(_prefer: IpFamily) -> Option<DefaultRouteDetails> {
     Some(DefaultRouteDetails {
         interface_name: BROWSER_INTERFACE.to_string(),
+        metric: None,
     })
 }
 
+pub(super) async fn all_default_routes() -> Vec<DefaultRouteDetails> {
+    default_route(IpFamily::V4).await.into_iter().collect()
+}
+
 pub(super) fn home_router() -> Option<HomeRouter> {
     None
 }
@@ -6,13 +6,15 @@ use tracing::warn;
 use wmi::{FilterValue, WMIConnection};
 
 use super::DefaultRouteDetails;
-pub(super) use super::netdev_impl::{get_state, home_router};
+pub(super) use super::netdev_impl::{get_state, home_router, interfaces};
+use crate::IpFamily;
 
 /// API Docs: <https://learn.microsoft.com/en-us/previous-versions/windows/desktop/wmiiprouteprov/win32-ip4routetable>
 #[derive(Deserialize, Debug)]
 #[allow(non_camel_case_types, non_snake_case)]
 struct Win32_IP4RouteTable {
     Name: String,
+    Metric1: i32,
 }
 
 #[stack_error(derive, add_meta, std_sources, from_sources)]
@@ -28,21 +30,29 @@ pub enum Error {
 }
 
 fn get_default_route() -> Result<DefaultRouteDetails, Error> {
+    get_default_routes()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| e!(Error::NoRoute))
+}
+
+fn get_default_routes() -> Result<Vec<DefaultRouteDetails>, Error> {
     let wmi_con = WMIConnection::new()?;
 
     let query: HashMap<_, _> = [("Destination".into(), FilterValue::Str("0.0.0.0"))].into();
-    let route: Win32_IP4RouteTable = wmi_con
-        .filtered_query(&query)?
-        .drain(..)
-        .next()
-        .ok_or_else(|| e!(Error::NoRoute))?;
+    let routes: Vec<Win32_IP4RouteTable> = wmi_con.filtered_query(&query)?;
 
-    Ok(DefaultRouteDetails {
-        interface_name: route.Name,
-    })
+    Ok(routes
+        .into_iter()
+        .map(|route| DefaultRouteDetails {
+            interface_name: route.Name,
+            // -1 marks an unused metric
+            metric: u32::try_from(route.Metric1).ok(),
+        })
+        .collect())
 }
 
-pub async fn default_route() -> Option<DefaultRouteDetails> {
+pub async fn default_route(_prefer: IpFamily) -> Option<DefaultRouteDetails> {
     // WMI uses COM which can deadlock on a tokio worker thread.
     match tokio::task::spawn_blocking(get_default_route).await {
         Ok(Ok(route)) => Some(route),
@@ -56,3 +66,18 @@ pub async fn default_route() -> Option<DefaultRouteDetails> {
         }
     }
 }
+
+pub async fn all_default_routes() -> Vec<DefaultRouteDetails> {
+    // WMI uses COM which can deadlock on a tokio worker thread.
+    match tokio::task::spawn_blocking(get_default_routes).await {
+        Ok(Ok(routes)) => routes,
+        Ok(Err(err)) => {
+            warn!("failed to retrieve default routes: {:#?}", err);
+            Vec::new()
+        }
+        Err(err) => {
+            warn!("default routes task panicked: {:#?}", err);
+            Vec::new()
+        }
+    }
+}
@@ -2,11 +2,15 @@
 
 use std::net::{IpAddr, Ipv6Addr};
 
+#[cfg(target_os = "linux")]
+use n0_error::stack_error;
+
 /// List of machine's IP addresses.
 ///
 /// The netdev-based constructors live in [`crate::interfaces`]'s `netdev_impl`
 /// module; on platforms without `netdev` this is only ever the empty default.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LocalAddresses {
     /// Loopback addresses.
     pub loopback: Vec<IpAddr>,
@@ -14,6 +18,40 @@ pub struct LocalAddresses {
     pub regular: Vec<IpAddr>,
 }
 
+/// A single entry of the system routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    /// Destination network, the unspecified address for default routes.
+    pub destination: IpAddr,
+    /// Prefix length of the destination network.
+    pub prefix_len: u8,
+    /// Next hop, if the destination is not directly reachable.
+    pub gateway: Option<IpAddr>,
+    /// Index of the outgoing interface.
+    pub oif: Option<u32>,
+    /// Routing table the route belongs to.
+    pub table: u32,
+    /// Route metric, lower values are preferred.
+    pub metric: Option<u32>,
+}
+
+/// Error returned by [`routing_table`].
+#[cfg(target_os = "linux")]
+#[stack_error(derive, add_meta, from_sources)]
+#[non_exhaustive]
+pub enum RoutingTableError {
+    #[error("netlink route dump failed")]
+    Netlink {
+        source: crate::interfaces::LinuxError,
+    },
+}
+
+/// Returns the IPv4 and IPv6 routes of all routing tables, queried via rtnetlink.
+#[cfg(target_os = "linux")]
+pub async fn routing_table() -> Result<Vec<RouteEntry>, RoutingTableError> {
+    Ok(crate::interfaces::routing_table().await?)
+}
+
 /// Reports whether `ip` is a private address, according to RFC 1918
 /// (IPv4 addresses) and RFC 4193 (IPv6 addresses). That is, it reports whether
 /// ip is in 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, or fc00::/7.
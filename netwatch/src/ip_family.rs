@@ -34,6 +34,14 @@ impl IpFamily {
             Self::V6 => Ipv6Addr::LOCALHOST.into(),
         }
     }
+
+    /// Returns the other family.
+    pub fn other(&self) -> Self {
+        match self {
+            Self::V4 => Self::V6,
+            Self::V6 => Self::V4,
+        }
+    }
 }
 
 #[cfg(not(wasm_browser))]
@@ -1,8 +1,11 @@
 //! Monitoring of networking interfaces and route changes.
 
+use std::net::IpAddr;
+
 use n0_error::{e, stack_error};
 use n0_future::task::{self, AbortOnDropHandle};
-use n0_watcher::Watchable;
+use n0_future::{Stream, time::Duration};
+use n0_watcher::{Watchable, Watcher as _};
 use tokio::sync::{mpsc, oneshot};
 
 mod actor;
@@ -12,7 +15,8 @@ mod android;
 mod bsd;
 #[cfg(target_os = "linux")]
 mod linux;
-#[cfg(posix_minimal)]
+// illumos and Solaris have no route monitoring yet either.
+#[cfg(any(posix_minimal, solarish))]
 mod posix_minimal;
 #[cfg(wasm_browser)]
 mod wasm_browser;
@@ -31,6 +35,49 @@ pub struct Monitor {
     interface_state: Watchable<State>,
 }
 
+/// Capacity of the channel of each [`InterfaceEvent`] subscriber.
+const INTERFACE_EVENT_CHANNEL_CAPACITY: usize = 32;
+
+/// A change of a single interface address, see [`Monitor::subscribe_interface_events`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InterfaceEvent {
+    /// `addr` was added to the interface with the given index.
+    AddrAdded { index: u32, addr: IpAddr },
+    /// `addr` was removed from the interface with the given index.
+    AddrRemoved { index: u32, addr: IpAddr },
+}
+
+/// Default window in which network changes are coalesced, see [`MonitorOptions::debounce`].
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Options to create a [`Monitor`] with, see [`Monitor::with_options`].
+#[derive(Debug, Clone)]
+pub struct MonitorOptions {
+    /// Window in which network changes are coalesced into a single state update.
+    ///
+    /// OSes report changes piecemeal, e.g. a DHCP renewal touching several addresses produces a
+    /// message per address. The state is only recomputed once no further change arrived within
+    /// this window.
+    pub debounce: Duration,
+    /// Name prefixes of interfaces whose changes alone are not worth reacting to, e.g. `"veth"`
+    /// or `"docker"` on hosts running many containers.
+    ///
+    /// When set, the state is only updated on a major change, see
+    /// [`State::is_major_change_ignoring`]. By default every interface is interesting and any
+    /// change updates the state.
+    pub uninteresting_interfaces: Vec<String>,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        MonitorOptions {
+            debounce: DEFAULT_DEBOUNCE,
+            uninteresting_interfaces: Vec::new(),
+        }
+    }
+}
+
 #[stack_error(derive, add_meta, from_sources)]
 #[non_exhaustive]
 pub enum Error {
@@ -55,7 +102,16 @@ impl From<oneshot::error::RecvError> for Error {
 impl Monitor {
     /// Create a new monitor.
     pub async fn new() -> Result<Self, Error> {
-        let actor = Actor::new().await?;
+        Self::with_options(MonitorOptions::default()).await
+    }
+
+    /// Create a new monitor with the given options.
+    pub async fn with_options(options: MonitorOptions) -> Result<Self, Error> {
+        let MonitorOptions {
+            debounce,
+            uninteresting_interfaces,
+        } = options;
+        let actor = Actor::new(debounce, uninteresting_interfaces).await?;
         let actor_tx = actor.subscribe();
         let interface_state = actor.state().clone();
 
@@ -75,16 +131,67 @@ impl Monitor {
         self.interface_state.watch()
     }
 
+    /// Subscribe to changes of the default route interface, e.g. switching from WiFi to
+    /// Ethernet.
+    ///
+    /// Yields the name of the new default route interface, see
+    /// [`State::default_route_interface`], whenever it differs from the previous one. Other
+    /// changes of the [`State`] are not reported.
+    pub fn default_route_changes(&self) -> impl Stream<Item = Option<String>> + Unpin + use<> {
+        self.interface_state
+            .watch()
+            .map(|state| state.default_route_interface)
+            .stream_updates_only()
+    }
+
+    /// Watches whether the machine is online, see [`State::is_online`].
+    ///
+    /// The value is recomputed on every change of the [`State`].
+    pub fn online(&self) -> n0_watcher::Map<n0_watcher::Direct<State>, bool> {
+        self.interface_state.watch().map(|state| state.is_online())
+    }
+
+    /// Subscribe to the addresses added to and removed from each interface.
+    ///
+    /// Events are reported as the OS announces them, before changes are coalesced into a new
+    /// [`State`], for apps that bind a socket per address. Only Linux reports addresses
+    /// individually, elsewhere no event is ever received.
+    ///
+    /// Events are dropped for a subscriber that does not keep up. Dropping the receiver
+    /// unsubscribes.
+    pub async fn subscribe_interface_events(
+        &self,
+    ) -> Result<mpsc::Receiver<InterfaceEvent>, Error> {
+        let (events_tx, events_rx) = mpsc::channel(INTERFACE_EVENT_CHANNEL_CAPACITY);
+        self.actor_tx
+            .send(ActorMessage::Subscribe { events_tx })
+            .await?;
+        Ok(events_rx)
+    }
+
     /// Potential change detected outside
     pub async fn network_change(&self) -> Result<(), Error> {
         self.actor_tx.send(ActorMessage::NetworkChange).await?;
         Ok(())
     }
+
+    /// Recomputes the [`State`] right away and returns it.
+    ///
+    /// Unlike [`Monitor::network_change`], this returns only once the fresh state is what
+    /// [`Monitor::interface_state`] reports. Changes to uninteresting interfaces are not
+    /// filtered out, see [`MonitorOptions::uninteresting_interfaces`].
+    pub async fn refresh(&self) -> Result<State, Error> {
+        let (state_tx, state_rx) = oneshot::channel();
+        self.actor_tx
+            .send(ActorMessage::Refresh { state_tx })
+            .await?;
+        Ok(state_rx.await?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use n0_watcher::Watcher as _;
+    use n0_future::StreamExt;
 
     use super::*;
 
@@ -96,4 +203,71 @@ mod tests {
         let current = sub.get();
         println!("current state: {current}");
     }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let mon = Monitor::new().await.unwrap();
+        let state = mon.refresh().await.unwrap();
+        assert_eq!(mon.interface_state().get(), state);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_interface_events() {
+        let mon = Monitor::new().await.unwrap();
+        let mut events = mon.subscribe_interface_events().await.unwrap();
+        // the subscription stays open while the monitor runs
+        assert!(
+            events
+                .try_recv()
+                .is_err_and(|e| e == mpsc::error::TryRecvError::Empty)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_monitor_with_options() {
+        let mon = Monitor::with_options(MonitorOptions {
+            debounce: Duration::from_millis(10),
+            uninteresting_interfaces: vec!["veth".to_string()],
+        })
+        .await
+        .unwrap();
+        // a burst of changes is coalesced by the actor
+        for _ in 0..3 {
+            mon.network_change().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_online() {
+        let mon = Monitor::new().await.unwrap();
+        let mut online = mon.online();
+
+        let mut state = mon.interface_state.get();
+        state.default_route_interface = Some("test0".to_string());
+        state.have_v4 = true;
+        mon.interface_state.set(state.clone()).ok();
+        assert!(online.get());
+
+        state.default_route_interface = None;
+        mon.interface_state.set(state).ok();
+        assert!(!online.get());
+    }
+
+    #[tokio::test]
+    async fn test_default_route_changes() {
+        let mon = Monitor::new().await.unwrap();
+        let mut changes = mon.default_route_changes();
+
+        // changes of other parts of the state are not reported
+        let mut state = mon.interface_state.get();
+        state.have_v6 = !state.have_v6;
+        mon.interface_state.set(state.clone()).ok();
+
+        state.default_route_interface = Some("test0".to_string());
+        mon.interface_state.set(state).ok();
+        let change = tokio::time::timeout(std::time::Duration::from_secs(1), changes.next())
+            .await
+            .expect("change is reported");
+        assert_eq!(change, Some(Some("test0".to_string())));
+    }
 }
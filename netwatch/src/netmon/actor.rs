@@ -2,16 +2,17 @@ use n0_future::time::{self, Duration, Instant};
 use n0_watcher::Watchable;
 pub(super) use os::Error;
 use os::RouteMonitor;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, trace};
 
+use super::InterfaceEvent;
 #[cfg(target_os = "android")]
 use super::android as os;
 #[cfg(bsd)]
 use super::bsd as os;
 #[cfg(target_os = "linux")]
 use super::linux as os;
-#[cfg(posix_minimal)]
+#[cfg(any(posix_minimal, solarish))]
 use super::posix_minimal as os;
 #[cfg(wasm_browser)]
 use super::wasm_browser as os;
@@ -25,6 +26,9 @@ pub(super) enum NetworkMessage {
     /// A change was detected.
     #[allow(dead_code)]
     Change,
+    /// An interface address changed, which is also a change.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    Interface(InterfaceEvent),
 }
 
 /// How often we execute a check for big jumps in wall time.
@@ -46,16 +50,32 @@ pub(super) struct Actor {
     #[allow(dead_code)]
     route_monitor: RouteMonitor,
     mon_receiver: mpsc::Receiver<NetworkMessage>,
+    /// Window in which changes are coalesced into a single state update.
+    debounce: Duration,
+    /// Name prefixes of interfaces whose changes alone don't update the state.
+    uninteresting_interfaces: Vec<String>,
     actor_receiver: mpsc::Receiver<ActorMessage>,
     actor_sender: mpsc::Sender<ActorMessage>,
+    /// Subscribers to [`InterfaceEvent`]s.
+    event_subscribers: Vec<mpsc::Sender<InterfaceEvent>>,
 }
 
 pub(super) enum ActorMessage {
     NetworkChange,
+    Subscribe {
+        events_tx: mpsc::Sender<InterfaceEvent>,
+    },
+    /// Recompute the state right away, replying with it.
+    Refresh {
+        state_tx: oneshot::Sender<State>,
+    },
 }
 
 impl Actor {
-    pub(super) async fn new() -> Result<Self, os::Error> {
+    pub(super) async fn new(
+        debounce: Duration,
+        uninteresting_interfaces: Vec<String>,
+    ) -> Result<Self, os::Error> {
         let interface_state = State::new().await;
         let wall_time = Instant::now();
 
@@ -68,8 +88,11 @@ impl Actor {
             wall_time,
             route_monitor,
             mon_receiver,
+            debounce,
+            uninteresting_interfaces,
             actor_receiver,
             actor_sender,
+            event_subscribers: Vec::new(),
         })
     }
 
@@ -82,11 +105,9 @@ impl Actor {
     }
 
     pub(super) async fn run(mut self) {
-        const DEBOUNCE: Duration = Duration::from_millis(250);
-
         let mut pending_change = false;
         let mut pending_time_jump = false;
-        let debounce = time::sleep(DEBOUNCE);
+        let debounce = time::sleep(self.debounce);
         tokio::pin!(debounce);
         let mut wall_time_interval = time::interval(POLL_WALL_TIME_INTERVAL);
 
@@ -101,7 +122,7 @@ impl Actor {
                     trace!("tick: wall_time_interval");
                     if self.check_wall_time_advance() {
                         pending_time_jump = true;
-                        debounce.as_mut().reset(Instant::now() + DEBOUNCE);
+                        debounce.as_mut().reset(Instant::now() + self.debounce);
                     }
                 }
                 event = self.mon_receiver.recv() => {
@@ -109,7 +130,13 @@ impl Actor {
                         Some(NetworkMessage::Change) => {
                             trace!("network activity detected");
                             pending_change = true;
-                            debounce.as_mut().reset(Instant::now() + DEBOUNCE);
+                            debounce.as_mut().reset(Instant::now() + self.debounce);
+                        }
+                        Some(NetworkMessage::Interface(event)) => {
+                            trace!(?event, "interface address change detected");
+                            self.emit(event);
+                            pending_change = true;
+                            debounce.as_mut().reset(Instant::now() + self.debounce);
                         }
                         None => {
                             debug!("shutting down, network monitor receiver gone");
@@ -122,7 +149,18 @@ impl Actor {
                         Some(ActorMessage::NetworkChange) => {
                             trace!("external network activity detected");
                             pending_change = true;
-                            debounce.as_mut().reset(Instant::now() + DEBOUNCE);
+                            debounce.as_mut().reset(Instant::now() + self.debounce);
+                        }
+                        Some(ActorMessage::Subscribe { events_tx }) => {
+                            self.event_subscribers.push(events_tx);
+                        }
+                        Some(ActorMessage::Refresh { state_tx }) => {
+                            trace!("refresh requested");
+                            let state = self.refresh().await;
+                            // the fresh state covers any change still being debounced
+                            pending_change = false;
+                            // we don't care if the requester is no longer there
+                            state_tx.send(state).ok();
                         }
                         None => {
                             debug!("shutting down, actor receiver gone");
@@ -134,6 +172,26 @@ impl Actor {
         }
     }
 
+    /// Sends `event` to the subscribers, dropping those that are gone.
+    fn emit(&mut self, event: InterfaceEvent) {
+        self.event_subscribers
+            .retain(|tx| match tx.try_send(event) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    trace!("interface event subscriber is lagging, dropping event");
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+    }
+
+    /// Recomputes the state, updating it even if only uninteresting interfaces changed.
+    async fn refresh(&mut self) -> State {
+        let new_state = State::new().await;
+        self.interface_state.set(new_state.clone()).ok();
+        new_state
+    }
+
     async fn handle_potential_change(&mut self, time_jumped: bool) {
         trace!("potential change");
 
@@ -146,6 +204,11 @@ impl Actor {
             // No major changes, continue on
             debug!("no changes detected");
             return;
+        } else if !self.uninteresting_interfaces.is_empty()
+            && !new_state.is_major_change_ignoring(old_state, &self.uninteresting_interfaces)
+        {
+            debug!("no major changes detected");
+            return;
         }
 
         self.interface_state.set(new_state).ok();
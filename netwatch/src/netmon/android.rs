@@ -1,20 +1,74 @@
 use n0_error::stack_error;
+use n0_future::{
+    task::AbortOnDropHandle,
+    time::{self, Duration},
+};
 use tokio::sync::mpsc;
+use tracing::{trace, warn};
 
 use super::actor::NetworkMessage;
+use crate::interfaces::{self, Interface, IpNet};
 
 #[stack_error(derive, add_meta)]
 pub struct Error;
 
+/// How often the interfaces are checked for changes.
+///
+/// Spawning the `ip` command is not free, this trades timeliness for battery usage.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub(super) struct RouteMonitor {
-    _sender: mpsc::Sender<NetworkMessage>,
+    _handle: AbortOnDropHandle<()>,
 }
 
 impl RouteMonitor {
-    pub(super) fn new(_sender: mpsc::Sender<NetworkMessage>) -> Result<Self, Error> {
-        // Very sad monitor. Android doesn't allow us to do this
+    pub(super) fn new(sender: mpsc::Sender<NetworkMessage>) -> Result<Self, Error> {
+        // Android doesn't allow us to subscribe to route changes, so we poll the interfaces.
+        let handle = tokio::task::spawn(poll_interfaces(sender));
+        Ok(RouteMonitor {
+            _handle: AbortOnDropHandle::new(handle),
+        })
+    }
+}
 
-        Ok(RouteMonitor { _sender })
+/// The interfaces along with their addresses, which [`Interface`]'s equality ignores.
+type Snapshot = Vec<(Interface, Vec<IpNet>)>;
+
+/// Reports a change whenever the interfaces differ from the previous poll.
+async fn poll_interfaces(sender: mpsc::Sender<NetworkMessage>) {
+    let mut last = snapshot().await;
+    let mut interval = time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    // the first tick completes right away
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = snapshot().await;
+        if current == last {
+            continue;
+        }
+        trace!("interfaces changed");
+        if sender.send(NetworkMessage::Change).await.is_err() {
+            break;
+        }
+        last = current;
     }
 }
+
+/// Enumerates the interfaces, which may run the `ip` command.
+async fn snapshot() -> Snapshot {
+    let interfaces = tokio::task::spawn_blocking(interfaces::all)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("failed to enumerate interfaces: {err:?}");
+            Vec::new()
+        });
+    interfaces
+        .into_iter()
+        .map(|iface| {
+            let addrs = iface.addrs().collect();
+            (iface, addrs)
+        })
+        .collect()
+}
@@ -16,10 +16,11 @@ use n0_future::{
 use netlink_packet_core::{NetlinkMessage, NetlinkPayload};
 use netlink_packet_route::{RouteNetlinkMessage, address, route};
 use netlink_sys::{AsyncSocket, SocketAddr};
+use rand::RngExt;
 use tokio::sync::mpsc;
 use tracing::{trace, warn};
 
-use super::actor::NetworkMessage;
+use super::{InterfaceEvent, actor::NetworkMessage};
 use crate::ip::is_link_local;
 
 #[derive(Debug)]
@@ -34,6 +35,16 @@ pub enum Error {
     Io { source: std::io::Error },
 }
 
+/// Delay before reconnecting after the netlink connection was lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Initial backoff after failing to set up the netlink socket, grown exponentially toward
+/// [`MAX_SETUP_BACKOFF`] on repeated failures.
+const INITIAL_SETUP_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Maximum backoff between netlink setup attempts.
+const MAX_SETUP_BACKOFF: Duration = Duration::from_secs(30);
+
 const fn nl_mgrp(group: u32) -> u32 {
     if group > 31 {
         panic!("use netlink_sys::Socket::add_membership() for this group");
@@ -74,13 +85,38 @@ fn setup_netlink() -> std::io::Result<(
     Ok((conn_handle, messages))
 }
 
+/// Addresses seen per interface index, to ignore repeated NEWADDR messages.
+#[derive(Debug, Default)]
+struct AddrCache(HashMap<u32, HashSet<IpAddr>>);
+
+impl AddrCache {
+    /// Records an added address, returning the event unless it was already known.
+    fn added(&mut self, index: u32, addr: IpAddr) -> Option<InterfaceEvent> {
+        self.0
+            .entry(index)
+            .or_default()
+            .insert(addr)
+            .then_some(InterfaceEvent::AddrAdded { index, addr })
+    }
+
+    /// Records a removed address.
+    ///
+    /// The event is returned even for addresses not seen added, as those predate the monitor.
+    fn removed(&mut self, index: u32, addr: IpAddr) -> InterfaceEvent {
+        if let Some(addrs) = self.0.get_mut(&index) {
+            addrs.remove(&addr);
+        }
+        InterfaceEvent::AddrRemoved { index, addr }
+    }
+}
+
 /// Returns `true` if the connection was lost (should reconnect),
 /// `false` if the sender is gone (should shut down).
 async fn process_messages(
     sender: &mpsc::Sender<NetworkMessage>,
     messages: &mut (impl Stream<Item = (NetlinkMessage<RouteNetlinkMessage>, SocketAddr)> + Unpin),
 ) -> bool {
-    let mut addr_cache: HashMap<u32, HashSet<IpAddr>> = HashMap::new();
+    let mut addr_cache = AddrCache::default();
 
     while let Some((message, _)) = messages.next().await {
         match message.payload {
@@ -94,25 +130,22 @@ async fn process_messages(
             NetlinkPayload::InnerMessage(msg) => match msg {
                 RouteNetlinkMessage::NewAddress(msg) => {
                     trace!("NEWADDR: {:?}", msg);
-                    let addrs = addr_cache.entry(msg.header.index).or_default();
-                    if let Some(addr) = get_nla!(msg, address::AddressAttribute::Address) {
-                        if addrs.contains(addr) {
-                            continue;
-                        } else {
-                            addrs.insert(*addr);
-                            if sender.send(NetworkMessage::Change).await.is_err() {
-                                return false;
-                            }
-                        }
+                    if let Some(addr) = get_nla!(msg, address::AddressAttribute::Address)
+                        && let Some(event) = addr_cache.added(msg.header.index, *addr)
+                        && sender.send(NetworkMessage::Interface(event)).await.is_err()
+                    {
+                        return false;
                     }
                 }
                 RouteNetlinkMessage::DelAddress(msg) => {
                     trace!("DELADDR: {:?}", msg);
-                    let addrs = addr_cache.entry(msg.header.index).or_default();
-                    if let Some(addr) = get_nla!(msg, address::AddressAttribute::Address) {
-                        addrs.remove(addr);
-                    }
-                    if sender.send(NetworkMessage::Change).await.is_err() {
+                    let message = match get_nla!(msg, address::AddressAttribute::Address) {
+                        Some(addr) => {
+                            NetworkMessage::Interface(addr_cache.removed(msg.header.index, *addr))
+                        }
+                        None => NetworkMessage::Change,
+                    };
+                    if sender.send(message).await.is_err() {
                         return false;
                     }
                 }
@@ -178,26 +211,28 @@ async fn process_messages(
 impl RouteMonitor {
     pub(super) fn new(sender: mpsc::Sender<NetworkMessage>) -> Result<Self, Error> {
         let handle = tokio::task::spawn(async move {
-            let mut backoff = Duration::from_secs(1);
-            const MAX_BACKOFF: Duration = Duration::from_secs(30);
+            let mut setup_backoff = INITIAL_SETUP_BACKOFF;
 
             loop {
                 match setup_netlink() {
                     Ok((_conn_handle, mut messages)) => {
-                        backoff = Duration::from_secs(1);
+                        setup_backoff = INITIAL_SETUP_BACKOFF;
                         let should_reconnect = process_messages(&sender, &mut messages).await;
                         // _conn_handle dropped here, aborting the connection task
                         if !should_reconnect {
                             break;
                         }
                         warn!("netlink connection lost, reconnecting");
+                        time::sleep(RECONNECT_DELAY).await;
                     }
                     Err(err) => {
                         warn!("failed to setup netlink: {:?}", err);
+                        // many processes starting together (e.g. on container startup) would
+                        // otherwise retry in lockstep
+                        time::sleep(with_jitter(setup_backoff)).await;
+                        setup_backoff = (setup_backoff * 2).min(MAX_SETUP_BACKOFF);
                     }
                 }
-                time::sleep(backoff).await;
-                backoff = (backoff * 2).min(MAX_BACKOFF);
             }
         });
 
@@ -206,3 +241,57 @@ impl RouteMonitor {
         })
     }
 }
+
+/// Randomizes `backoff` to a duration in `[backoff / 2, backoff]`.
+fn with_jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(rand::rng().random_range(0.0..=1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addr_cache() {
+        let addr: IpAddr = "192.168.1.2".parse().unwrap();
+        let mut cache = AddrCache::default();
+        assert_eq!(
+            cache.added(2, addr),
+            Some(InterfaceEvent::AddrAdded { index: 2, addr })
+        );
+        // repeated announcements are ignored, but each interface has its own addresses
+        assert_eq!(cache.added(2, addr), None);
+        assert!(cache.added(3, addr).is_some());
+
+        assert_eq!(
+            cache.removed(2, addr),
+            InterfaceEvent::AddrRemoved { index: 2, addr }
+        );
+        // once removed, the address is reported when added again
+        assert!(cache.added(2, addr).is_some());
+        // addresses that predate the monitor are reported removed too
+        let unseen: IpAddr = "fe80::1".parse().unwrap();
+        assert_eq!(
+            cache.removed(4, unseen),
+            InterfaceEvent::AddrRemoved {
+                index: 4,
+                addr: unseen
+            }
+        );
+    }
+
+    #[test]
+    fn test_with_jitter_bounds() {
+        for backoff in [INITIAL_SETUP_BACKOFF, MAX_SETUP_BACKOFF] {
+            for _ in 0..100 {
+                let jittered = with_jitter(backoff);
+                assert!(
+                    jittered >= backoff / 2,
+                    "{jittered:?} below half of {backoff:?}"
+                );
+                assert!(jittered <= backoff, "{jittered:?} above {backoff:?}");
+            }
+        }
+    }
+}
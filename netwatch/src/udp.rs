@@ -4,7 +4,10 @@ use std::{
     net::SocketAddr,
     num::NonZeroUsize,
     pin::Pin,
-    sync::{Arc, RwLock, RwLockReadGuard, TryLockError, atomic::AtomicBool},
+    sync::{
+        Arc, RwLock, RwLockReadGuard, TryLockError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
     task::{Context, Poll},
 };
 
@@ -23,6 +26,16 @@ pub struct UdpSocket {
     send_waker: AtomicWaker,
     /// Set to true, when an error occurred, that means we need to rebind the socket.
     is_broken: AtomicBool,
+    /// Requested receive buffer size, applied again when rebinding.
+    recv_buffer_size: AtomicUsize,
+    /// Requested send buffer size, applied again when rebinding.
+    send_buffer_size: AtomicUsize,
+    /// Requested `SO_MARK`, applied again when rebinding.
+    #[cfg(target_os = "linux")]
+    mark: std::sync::Mutex<Option<u32>>,
+    /// Requested `SO_BINDTODEVICE` interface, applied again when rebinding.
+    #[cfg(target_os = "linux")]
+    device: std::sync::Mutex<Option<String>>,
 }
 
 /// UDP socket read/write buffer size (7MB). The value of 7MB is chosen as it
@@ -66,6 +79,19 @@ impl UdpSocket {
         Self::bind_raw(addr)
     }
 
+    /// Wrap a socket that was already bound, e.g. one created by a platform specific API.
+    ///
+    /// The socket is made non-blocking. [Rebinding](Self::rebind) binds a fresh socket to the
+    /// same address, without going through whatever created the original one.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        let buffer_sizes = BufferSizes {
+            recv: SOCKET_BUFFER_SIZE,
+            send: SOCKET_BUFFER_SIZE,
+        };
+        let socket = SocketState::from_std(socket)?;
+        Ok(Self::from_state(socket, buffer_sizes))
+    }
+
     /// Is the socket broken and needs a rebind?
     pub fn is_broken(&self) -> bool {
         self.is_broken.load(std::sync::atomic::Ordering::Acquire)
@@ -81,7 +107,9 @@ impl UdpSocket {
     pub fn rebind(&self) -> io::Result<()> {
         {
             let mut guard = self.socket.write().unwrap();
-            guard.rebind()?;
+            guard.rebind(self.buffer_sizes())?;
+            #[cfg(target_os = "linux")]
+            self.restore_linux_options(&guard)?;
 
             // Clear errors
             self.is_broken
@@ -97,14 +125,120 @@ impl UdpSocket {
     }
 
     fn bind_raw(addr: impl Into<SocketAddr>) -> io::Result<Self> {
-        let socket = SocketState::bind(addr.into())?;
+        let buffer_sizes = BufferSizes {
+            recv: SOCKET_BUFFER_SIZE,
+            send: SOCKET_BUFFER_SIZE,
+        };
+        let socket = SocketState::bind(addr.into(), buffer_sizes)?;
+        Ok(Self::from_state(socket, buffer_sizes))
+    }
 
-        Ok(UdpSocket {
+    fn from_state(socket: SocketState, buffer_sizes: BufferSizes) -> Self {
+        UdpSocket {
             socket: RwLock::new(socket),
             recv_waker: AtomicWaker::default(),
             send_waker: AtomicWaker::default(),
             is_broken: AtomicBool::new(false),
-        })
+            recv_buffer_size: AtomicUsize::new(buffer_sizes.recv),
+            send_buffer_size: AtomicUsize::new(buffer_sizes.send),
+            #[cfg(target_os = "linux")]
+            mark: std::sync::Mutex::new(None),
+            #[cfg(target_os = "linux")]
+            device: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Applies the requested mark and device to a freshly bound socket.
+    #[cfg(target_os = "linux")]
+    fn restore_linux_options(&self, state: &SocketState) -> io::Result<()> {
+        let (socket, _state) = state.try_get_connected()?;
+        let socket = socket2::SockRef::from(socket);
+        if let Some(mark) = *self.mark.lock().unwrap() {
+            socket.set_mark(mark)?;
+        }
+        if let Some(device) = self.device.lock().unwrap().as_deref() {
+            socket.bind_device(Some(device.as_bytes()))?;
+        }
+        Ok(())
+    }
+
+    /// Buffer sizes requested for this socket.
+    fn buffer_sizes(&self) -> BufferSizes {
+        BufferSizes {
+            recv: self.recv_buffer_size.load(Ordering::Acquire),
+            send: self.send_buffer_size.load(Ordering::Acquire),
+        }
+    }
+
+    /// Sets the size of the socket's receive buffer (`SO_RCVBUF`).
+    ///
+    /// The OS might adjust the size, e.g. Linux doubles it and clamps it to a system-wide maximum.
+    /// The size is kept when the socket is [rebound](Self::rebind).
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        let guard = self.socket.read().unwrap();
+        let (socket, _state) = guard.try_get_connected()?;
+
+        socket2::SockRef::from(socket).set_recv_buffer_size(size)?;
+        self.recv_buffer_size.store(size, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the size of the socket's receive buffer (`SO_RCVBUF`), as reported by the OS.
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        let guard = self.socket.read().unwrap();
+        let (socket, _state) = guard.try_get_connected()?;
+
+        socket2::SockRef::from(socket).recv_buffer_size()
+    }
+
+    /// Sets the size of the socket's send buffer (`SO_SNDBUF`).
+    ///
+    /// The OS might adjust the size, e.g. Linux doubles it and clamps it to a system-wide maximum.
+    /// The size is kept when the socket is [rebound](Self::rebind).
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        let guard = self.socket.read().unwrap();
+        let (socket, _state) = guard.try_get_connected()?;
+
+        socket2::SockRef::from(socket).set_send_buffer_size(size)?;
+        self.send_buffer_size.store(size, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the size of the socket's send buffer (`SO_SNDBUF`), as reported by the OS.
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        let guard = self.socket.read().unwrap();
+        let (socket, _state) = guard.try_get_connected()?;
+
+        socket2::SockRef::from(socket).send_buffer_size()
+    }
+
+    /// Sets the mark of the packets sent from this socket (`SO_MARK`), e.g. to select a routing
+    /// policy.
+    ///
+    /// Requires the `CAP_NET_ADMIN` capability. The mark is kept when the socket is
+    /// [rebound](Self::rebind).
+    #[cfg(target_os = "linux")]
+    pub fn set_mark(&self, mark: u32) -> io::Result<()> {
+        let guard = self.socket.read().unwrap();
+        let (socket, _state) = guard.try_get_connected()?;
+
+        socket2::SockRef::from(socket).set_mark(mark)?;
+        *self.mark.lock().unwrap() = Some(mark);
+        Ok(())
+    }
+
+    /// Restricts the socket to the given network interface (`SO_BINDTODEVICE`), or lifts the
+    /// restriction if `None`.
+    ///
+    /// The interface is kept when the socket is [rebound](Self::rebind).
+    #[cfg(target_os = "linux")]
+    pub fn bind_device(&self, interface: Option<&str>) -> io::Result<()> {
+        let guard = self.socket.read().unwrap();
+        let (socket, _state) = guard.try_get_connected()?;
+
+        socket2::SockRef::from(socket).bind_device(interface.map(str::as_bytes))?;
+        *self.device.lock().unwrap() = interface.map(ToOwned::to_owned);
+        Ok(())
     }
 
     /// Receives a single datagram message on the socket from the remote address
@@ -294,7 +428,7 @@ impl UdpSocket {
             return Ok(());
         }
 
-        guard.rebind()?;
+        guard.rebind(self.buffer_sizes())?;
         self.is_broken
             .store(false, std::sync::atomic::Ordering::Release);
         drop(guard);
@@ -746,6 +880,13 @@ enum SocketState {
     },
 }
 
+/// Sizes of the socket's receive and send buffers.
+#[derive(Debug, Clone, Copy)]
+struct BufferSizes {
+    recv: usize,
+    send: usize,
+}
+
 impl SocketState {
     fn try_get_connected(&self) -> io::Result<(&tokio::net::UdpSocket, &noq_udp::UdpSocketState)> {
         match self {
@@ -761,7 +902,7 @@ impl SocketState {
         }
     }
 
-    fn bind(addr: SocketAddr) -> io::Result<Self> {
+    fn bind(addr: SocketAddr, buffer_sizes: BufferSizes) -> io::Result<Self> {
         let network = IpFamily::from(addr.ip());
         let socket = socket2::Socket::new(
             network.into(),
@@ -769,16 +910,16 @@ impl SocketState {
             Some(socket2::Protocol::UDP),
         )?;
 
-        if let Err(err) = socket.set_recv_buffer_size(SOCKET_BUFFER_SIZE) {
+        if let Err(err) = socket.set_recv_buffer_size(buffer_sizes.recv) {
             debug!(
                 "failed to set recv_buffer_size to {}: {:?}",
-                SOCKET_BUFFER_SIZE, err
+                buffer_sizes.recv, err
             );
         }
-        if let Err(err) = socket.set_send_buffer_size(SOCKET_BUFFER_SIZE) {
+        if let Err(err) = socket.set_send_buffer_size(buffer_sizes.send) {
             debug!(
                 "failed to set send_buffer_size to {}: {:?}",
-                SOCKET_BUFFER_SIZE, err
+                buffer_sizes.send, err
             );
         }
         if network == IpFamily::V6 {
@@ -790,17 +931,10 @@ impl SocketState {
         // is not yet available on all OSes.
         socket.bind(&addr.into())?;
 
-        // Ensure nonblocking
-        socket.set_nonblocking(true)?;
-
-        let socket: std::net::UdpSocket = socket.into();
-
-        // Convert into tokio UdpSocket
-        let socket = tokio::net::UdpSocket::from_std(socket)?;
-        let socket_ref = noq_udp::UdpSockRef::from(&socket);
-        let socket_state = noq_udp::UdpSocketState::new(socket_ref)?;
-
-        let local_addr = socket.local_addr()?;
+        let state = Self::from_std(socket.into())?;
+        let local_addr = match &state {
+            Self::Connected { addr, .. } | Self::Closed { addr, .. } => *addr,
+        };
         if addr.port() != 0 && local_addr.port() != addr.port() {
             return Err(io::Error::other(format!(
                 "wrong port bound: {:?}: wanted: {} got {}",
@@ -810,6 +944,19 @@ impl SocketState {
             )));
         }
 
+        Ok(state)
+    }
+
+    fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        // Ensure nonblocking
+        socket.set_nonblocking(true)?;
+
+        // Convert into tokio UdpSocket
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let socket_ref = noq_udp::UdpSockRef::from(&socket);
+        let socket_state = noq_udp::UdpSocketState::new(socket_ref)?;
+
+        let local_addr = socket.local_addr()?;
         Ok(Self::Connected {
             socket,
             state: socket_state,
@@ -817,7 +964,7 @@ impl SocketState {
         })
     }
 
-    fn rebind(&mut self) -> io::Result<()> {
+    fn rebind(&mut self, buffer_sizes: BufferSizes) -> io::Result<()> {
         let addr = match self {
             Self::Connected { addr, .. } => *addr,
             Self::Closed { addr, .. } => *addr,
@@ -835,7 +982,7 @@ impl SocketState {
             };
         }
 
-        match Self::bind(addr) {
+        match Self::bind(addr, buffer_sizes) {
             Ok(new_state) => {
                 *self = new_state;
                 Ok(())
@@ -1072,6 +1219,93 @@ mod tests {
 
     use super::*;
 
+    #[tokio::test]
+    async fn test_buffer_sizes() -> TestResult {
+        const SIZE: usize = 64 * 1024;
+        // OSes may double the requested size to account for bookkeeping overhead
+        let within_tolerance = |actual: usize| (SIZE..=2 * SIZE).contains(&actual);
+
+        let socket = UdpSocket::bind_local(IpFamily::V4, 0)?;
+        socket.set_recv_buffer_size(SIZE)?;
+        socket.set_send_buffer_size(SIZE)?;
+        assert!(within_tolerance(socket.recv_buffer_size()?));
+        assert!(within_tolerance(socket.send_buffer_size()?));
+
+        // the sizes survive a rebind
+        socket.rebind()?;
+        assert!(within_tolerance(socket.recv_buffer_size()?));
+        assert!(within_tolerance(socket.send_buffer_size()?));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_set_mark() -> TestResult {
+        let mark = |socket: &UdpSocket| -> io::Result<u32> {
+            let guard = socket.socket.read().unwrap();
+            let (socket, _state) = guard.try_get_connected()?;
+            socket2::SockRef::from(socket).mark()
+        };
+
+        let socket = UdpSocket::bind_local(IpFamily::V4, 0)?;
+        match socket.set_mark(42) {
+            // setting a mark needs CAP_NET_ADMIN
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+            res => res?,
+        }
+        assert_eq!(mark(&socket)?, 42);
+
+        // the mark survives a rebind
+        socket.rebind()?;
+        assert_eq!(mark(&socket)?, 42);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_bind_device() -> TestResult {
+        let device = |socket: &UdpSocket| -> io::Result<Option<Vec<u8>>> {
+            let guard = socket.socket.read().unwrap();
+            let (socket, _state) = guard.try_get_connected()?;
+            socket2::SockRef::from(socket).device()
+        };
+
+        let socket = UdpSocket::bind_local(IpFamily::V4, 0)?;
+        match socket.bind_device(Some("lo")) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+            res => res?,
+        }
+        assert_eq!(device(&socket)?.as_deref(), Some(&b"lo"[..]));
+
+        // the device survives a rebind
+        socket.rebind()?;
+        assert_eq!(device(&socket)?.as_deref(), Some(&b"lo"[..]));
+
+        socket.bind_device(None)?;
+        assert_eq!(device(&socket)?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_from_std() -> TestResult {
+        let socket = std::net::UdpSocket::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+        let addr = socket.local_addr()?;
+        let socket = UdpSocket::from_std(socket)?;
+        assert_eq!(socket.local_addr()?, addr);
+
+        let peer = UdpSocket::bind_local(IpFamily::V4, 0)?;
+        peer.send_to(b"hello", addr).await?;
+        let mut buf = [0u8; 5];
+        let (read, from) = socket.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..read], b"hello");
+        assert_eq!(from, peer.local_addr()?);
+
+        // rebinding keeps the address
+        socket.rebind()?;
+        assert_eq!(socket.local_addr()?, addr);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_reconnect() -> TestResult {
         let (s_b, mut r_b) = tokio::sync::mpsc::channel(16);
@@ -0,0 +1,88 @@
+//! Listening for the unsolicited announcements of NAT-PMP and PCP servers.
+//!
+//! Both protocols multicast an announcement to the clients on the local network when the server
+//! restarts, informing of its new epoch time. See [RFC 6886 Announcing Address
+//! Changes](https://datatracker.ietf.org/doc/html/rfc6886#section-3.2.1) and [RFC 6887
+//! Unsolicited ANNOUNCEs](https://datatracker.ietf.org/doc/html/rfc6887#section-14.1.1).
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use tokio::net::UdpSocket;
+use tracing::trace;
+
+#[cfg(feature = "nat_pmp")]
+use crate::nat_pmp;
+#[cfg(feature = "pcp")]
+use crate::pcp;
+
+/// Port on which clients receive announcements.
+pub(crate) const CLIENT_PORT: u16 = 5350;
+
+/// Group to which announcements are multicast.
+const ALL_HOSTS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+/// Largest announcement we expect, PCP being the biggest of both protocols.
+const MAX_ANNOUNCEMENT_SIZE: usize = 1100;
+
+/// An announcement received from a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Announcement {
+    /// Address of the server sending the announcement.
+    pub(crate) from: IpAddr,
+    /// Seconds since the server's epoch.
+    pub(crate) epoch_time: u32,
+}
+
+/// Binds the socket on which announcements are received.
+///
+/// Other clients on this host might be listening as well, so the port is shared.
+pub(crate) fn bind() -> std::io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, CLIENT_PORT)).into())?;
+    socket.join_multicast_v4(&ALL_HOSTS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Waits for the next announcement on `socket`, pending forever if there is no socket.
+///
+/// Messages that are not announcements are skipped.
+pub(crate) async fn recv(socket: Option<&UdpSocket>) -> std::io::Result<Announcement> {
+    let Some(socket) = socket else {
+        return std::future::pending().await;
+    };
+    let mut buffer = vec![0; MAX_ANNOUNCEMENT_SIZE];
+    loop {
+        let (read, from) = socket.recv_from(&mut buffer).await?;
+        match decode(&buffer[..read]) {
+            Some(epoch_time) => {
+                return Ok(Announcement {
+                    from: from.ip(),
+                    epoch_time,
+                });
+            }
+            None => trace!(%from, "ignoring message that is not an announcement"),
+        }
+    }
+}
+
+/// Decodes the announcement of a single protocol, if the message is one.
+type Decoder = fn(&[u8]) -> Option<u32>;
+
+/// Decodes an announcement of either protocol, returning the server's epoch time.
+///
+/// Announcements of protocols left out of the build are skipped.
+fn decode(buf: &[u8]) -> Option<u32> {
+    let decoders: &[Decoder] = &[
+        #[cfg(feature = "nat_pmp")]
+        nat_pmp::decode_announcement,
+        #[cfg(feature = "pcp")]
+        pcp::decode_announcement,
+    ];
+    decoders.iter().find_map(|decode| decode(buf))
+}
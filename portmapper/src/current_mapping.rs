@@ -2,90 +2,221 @@
 
 use std::{
     future::Future,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
     num::NonZeroU16,
     pin::Pin,
     sync::Arc,
     task::Poll,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use rand::RngExt;
 use tokio::{sync::watch, time};
 use tracing::{debug, trace};
 
-use crate::Metrics;
+use crate::{MappingInfo, MappingProtocol, Metrics};
+
+/// External IP of a mapping, determining how it is reported to watchers.
+pub(super) trait ExternalIp: Copy + PartialEq + std::fmt::Debug {
+    /// Address reported to watchers of the mapping.
+    type SocketAddr: Copy + PartialEq + std::fmt::Debug;
+
+    fn socket_addr(self, port: NonZeroU16) -> Self::SocketAddr;
+
+    /// Details of the mapping, only tracked for IPv4.
+    fn info(
+        _local_port: NonZeroU16,
+        _external: Self::SocketAddr,
+        _protocol: MappingProtocol,
+        _acquired: Instant,
+        _expires: Instant,
+        _lease: Option<Duration>,
+    ) -> Option<MappingInfo> {
+        None
+    }
+}
+
+impl ExternalIp for Ipv4Addr {
+    type SocketAddr = SocketAddrV4;
+
+    fn socket_addr(self, port: NonZeroU16) -> SocketAddrV4 {
+        SocketAddrV4::new(self, port.into())
+    }
+
+    fn info(
+        local_port: NonZeroU16,
+        external: SocketAddrV4,
+        protocol: MappingProtocol,
+        acquired: Instant,
+        expires: Instant,
+        lease: Option<Duration>,
+    ) -> Option<MappingInfo> {
+        Some(MappingInfo {
+            local_port,
+            external,
+            protocol,
+            acquired,
+            expires,
+            lease,
+            reachable: None,
+        })
+    }
+}
+
+impl ExternalIp for Ipv6Addr {
+    type SocketAddr = SocketAddrV6;
+
+    fn socket_addr(self, port: NonZeroU16) -> SocketAddrV6 {
+        SocketAddrV6::new(self, port.into(), 0, 0)
+    }
+}
 
 /// This is an implementation detail to facilitate testing.
 pub(super) trait Mapping: std::fmt::Debug + Unpin {
-    fn external(&self) -> (Ipv4Addr, NonZeroU16);
+    type Ip: ExternalIp;
+
+    fn external(&self) -> (Self::Ip, NonZeroU16);
     /// Half the lifetime of a mapping. This is used to calculate when a mapping should be renewed.
     fn half_lifetime(&self) -> Duration;
+    /// Local port mapped by this mapping.
+    fn local_port(&self) -> NonZeroU16;
+    /// Protocol that produced this mapping.
+    fn protocol(&self) -> MappingProtocol;
+    /// Seconds since the server's epoch when the mapping was created, if the protocol reports it.
+    fn epoch_time(&self) -> Option<u32>;
+    /// Lease granted by the server, `None` if the mapping doesn't expire.
+    fn lease(&self) -> Option<Duration>;
 }
 
 impl Mapping for super::mapping::Mapping {
+    type Ip = Ipv4Addr;
+
     fn external(&self) -> (Ipv4Addr, NonZeroU16) {
         super::mapping::PortMapped::external(self)
     }
     fn half_lifetime(&self) -> Duration {
         super::mapping::PortMapped::half_lifetime(self)
     }
+    fn local_port(&self) -> NonZeroU16 {
+        super::mapping::Mapping::local_port(self)
+    }
+    fn protocol(&self) -> MappingProtocol {
+        super::mapping::Mapping::protocol(self)
+    }
+    fn epoch_time(&self) -> Option<u32> {
+        super::mapping::Mapping::epoch_time(self)
+    }
+    fn lease(&self) -> Option<Duration> {
+        super::mapping::Mapping::lease(self)
+    }
 }
 
+/// Largest fraction by which renewals are moved when jittered, see [`crate::Config::renewal_jitter`].
+const RENEWAL_JITTER: f64 = 0.1;
+
 /// Models the lifetime of an active mapping.
 #[derive(Debug)]
 struct ActiveMapping<M> {
     mapping: M,
     deadline: Pin<Box<time::Sleep>>,
     expire_after: bool,
+    /// Time between the renewal deadline and the mapping's expiry.
+    renewal_window: Duration,
 }
 
 impl<M: Mapping> ActiveMapping<M> {
-    fn new(mapping: M) -> Self {
-        let deadline = Box::pin(time::sleep(mapping.half_lifetime()));
+    /// Tracks `mapping`, due for renewal once `renewal_fraction` of its lifetime has elapsed.
+    ///
+    /// With `jitter` the renewal is randomly moved by up to [`RENEWAL_JITTER`] of that time.
+    fn new(mapping: M, renewal_fraction: f32, jitter: bool) -> Self {
+        let lifetime = mapping.half_lifetime() * 2;
+        let mut renew_after = lifetime.mul_f32(renewal_fraction);
+        if jitter {
+            renew_after = with_jitter(renew_after, lifetime);
+        }
+        let deadline = Box::pin(time::sleep(renew_after));
         ActiveMapping {
             mapping,
             deadline,
             expire_after: false,
+            renewal_window: lifetime.saturating_sub(renew_after),
         }
     }
 }
 
+/// Randomly moves `renew_after` earlier or later by up to [`RENEWAL_JITTER`] of it.
+///
+/// A later renewal is bounded to halfway between the original one and the expiry after
+/// `lifetime`, so that there is still time to renew.
+fn with_jitter(renew_after: Duration, lifetime: Duration) -> Duration {
+    let max_offset = renew_after.mul_f64(RENEWAL_JITTER);
+    let max_delay = max_offset.min(lifetime.saturating_sub(renew_after) / 2);
+    let offset: f64 = rand::rng().random_range(-1.0..=1.0);
+    if offset < 0.0 {
+        renew_after - max_offset.mul_f64(-offset)
+    } else {
+        renew_after + max_delay.mul_f64(offset)
+    }
+}
+
 /// Events in the lifetime of the mapping.
 #[derive(Debug, PartialEq, Eq)]
-pub(super) enum Event {
+pub(super) enum Event<Ip = Ipv4Addr> {
     /// On this event, the mapping is halfway through its lifetime and should be renewed.
     Renew {
-        external_ip: Ipv4Addr,
+        external_ip: Ip,
         external_port: NonZeroU16,
     },
     /// Mapping has expired.
     Expired {
-        external_ip: Ipv4Addr,
+        external_ip: Ip,
         external_port: NonZeroU16,
+        protocol: MappingProtocol,
     },
 }
 
 /// Holds the current mapping value and ensures that any change is reported accordingly.
 #[derive(derive_more::Debug)]
-pub(super) struct CurrentMapping<M = super::mapping::Mapping> {
+pub(super) struct CurrentMapping<M: Mapping = super::mapping::Mapping> {
     /// Active port mapping.
     mapping: Option<ActiveMapping<M>>,
     /// A [`watch::Sender`] that keeps the latest external address for subscribers to changes.
-    address_tx: watch::Sender<Option<SocketAddrV4>>,
+    address_tx: watch::Sender<Option<<M::Ip as ExternalIp>::SocketAddr>>,
+    /// A [`watch::Sender`] that keeps the details of the active mapping.
+    info_tx: watch::Sender<Option<MappingInfo>>,
     /// Waker to ensure this is polled when needed.
     #[debug(skip)]
     waker: Option<std::task::Waker>,
+    /// Fraction of a mapping's lifetime after which it's due for renewal.
+    renewal_fraction: f32,
+    /// Whether to randomly move renewals, see [`crate::Config::renewal_jitter`].
+    renewal_jitter: bool,
+    /// Last known epoch time of the server holding the mapping, and when it was observed.
+    epoch: Option<(u32, time::Instant)>,
     metrics: Arc<Metrics>,
 }
 
 impl<M: Mapping> CurrentMapping<M> {
     /// Creates a new [`CurrentMapping`] and returns the watcher over its external address.
-    pub(super) fn new(metrics: Arc<Metrics>) -> (Self, watch::Receiver<Option<SocketAddrV4>>) {
+    #[allow(clippy::type_complexity)]
+    pub(super) fn new(
+        metrics: Arc<Metrics>,
+        renewal_fraction: f32,
+        renewal_jitter: bool,
+    ) -> (
+        Self,
+        watch::Receiver<Option<<M::Ip as ExternalIp>::SocketAddr>>,
+    ) {
         let (address_tx, address_rx) = watch::channel(None);
+        let (info_tx, _info_rx) = watch::channel(None);
         let wrapper = CurrentMapping {
             mapping: None,
             address_tx,
+            info_tx,
             waker: None,
+            renewal_fraction,
+            renewal_jitter,
+            epoch: None,
             metrics,
         };
         (wrapper, address_rx)
@@ -97,10 +228,46 @@ impl<M: Mapping> CurrentMapping<M> {
         debug!("new port mapping {mapping:?}");
         let maybe_external_addr = mapping.as_ref().map(|mapping| {
             let (ip, port) = mapping.external();
-            SocketAddrV4::new(ip, port.into())
+            ip.socket_addr(port)
         });
-        let old_mapping = std::mem::replace(&mut self.mapping, mapping.map(ActiveMapping::new))
-            .map(|mapping| mapping.mapping);
+        let mut info = mapping
+            .as_ref()
+            .zip(maybe_external_addr)
+            .and_then(|(mapping, external)| {
+                let acquired = Instant::now();
+                let expires = acquired + mapping.half_lifetime() * 2;
+                M::Ip::info(
+                    mapping.local_port(),
+                    external,
+                    mapping.protocol(),
+                    acquired,
+                    expires,
+                    mapping.lease(),
+                )
+            });
+        // a renewal keeping the external address doesn't change whether it can be reached
+        if let (Some(info), Some(old_info)) = (&mut info, &*self.info_tx.borrow())
+            && info.external == old_info.external
+        {
+            info.reachable = old_info.reachable;
+        }
+        self.info_tx.send_replace(info);
+        self.epoch = mapping
+            .as_ref()
+            .and_then(Mapping::epoch_time)
+            .map(|epoch_time| (epoch_time, time::Instant::now()));
+        let old_mapping = std::mem::replace(
+            &mut self.mapping,
+            mapping.map(|mapping| {
+                ActiveMapping::new(mapping, self.renewal_fraction, self.renewal_jitter)
+            }),
+        )
+        .map(|mapping| mapping.mapping);
+        if let Some((old, new)) = old_mapping.as_ref().zip(self.mapping.as_ref())
+            && old.external().1 != new.mapping.external().1
+        {
+            self.metrics.external_port_changed.inc();
+        }
         // mapping changed
         // TODO(@divma): maybe only wake if mapping is some
         if let Some(waker) = &self.waker {
@@ -119,7 +286,7 @@ impl<M: Mapping> CurrentMapping<M> {
         old_mapping
     }
 
-    fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Event> {
+    fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Event<M::Ip>> {
         // grab the waker if needed
         if let Some(waker) = &self.waker {
             if waker.will_wake(cx.waker()) {
@@ -134,6 +301,7 @@ impl<M: Mapping> CurrentMapping<M> {
             mapping,
             deadline,
             expire_after,
+            renewal_window,
         }) = &mut self.mapping
             && deadline.as_mut().poll(cx).is_ready()
         {
@@ -141,14 +309,16 @@ impl<M: Mapping> CurrentMapping<M> {
             // check if the deadline means the mapping is expired or due for renewal
             return if *expire_after {
                 trace!("mapping expired {mapping:?}");
+                let protocol = mapping.protocol();
                 self.update(None);
                 Poll::Ready(Event::Expired {
                     external_ip,
                     external_port,
+                    protocol,
                 })
             } else {
                 // mapping is due for renewal
-                *deadline = Box::pin(time::sleep(mapping.half_lifetime()));
+                *deadline = Box::pin(time::sleep(*renewal_window));
                 *expire_after = true;
                 trace!("due for renewal {mapping:?}");
                 Poll::Ready(Event::Renew {
@@ -160,15 +330,76 @@ impl<M: Mapping> CurrentMapping<M> {
         Poll::Pending
     }
 
-    pub(crate) fn external(&self) -> Option<(Ipv4Addr, NonZeroU16)> {
+    /// Returns a watcher over the external address of the active mapping.
+    #[cfg(feature = "pcp")]
+    pub(super) fn watch_external(
+        &self,
+    ) -> watch::Receiver<Option<<M::Ip as ExternalIp>::SocketAddr>> {
+        self.address_tx.subscribe()
+    }
+
+    /// Returns a watcher over the details of the active mapping.
+    pub(super) fn watch_info(&self) -> watch::Receiver<Option<MappingInfo>> {
+        self.info_tx.subscribe()
+    }
+
+    /// Records whether `external` was found reachable, if it's still the mapping's address.
+    pub(super) fn set_reachable(&self, external: SocketAddrV4, reachable: bool) {
+        self.info_tx.send_if_modified(|info| match info {
+            Some(info) if info.external == external => {
+                info.reachable = Some(reachable);
+                true
+            }
+            _ => false,
+        });
+    }
+
+    /// Records a newly observed epoch time of the server, returning whether it shows the server
+    /// lost its state, and with it the active mapping.
+    ///
+    /// Follows the validation described in [RFC 6887 Epoch
+    /// Time](https://datatracker.ietf.org/doc/html/rfc6887#section-8.5), which also applies to
+    /// NAT-PMP servers.
+    pub(super) fn observe_epoch(&mut self, epoch_time: u32) -> bool {
+        let Some((prev_epoch_time, observed)) = self.epoch else {
+            // no mapping from a server reporting its epoch, nothing to lose
+            return false;
+        };
+        let now = time::Instant::now();
+        if epoch_lost_state(prev_epoch_time, epoch_time, now.duration_since(observed)) {
+            return true;
+        }
+        self.epoch = Some((epoch_time, now));
+        false
+    }
+
+    pub(crate) fn external(&self) -> Option<(M::Ip, NonZeroU16)> {
         self.mapping
             .as_ref()
             .map(|mapping| mapping.mapping.external())
     }
+
+    /// The active mapping, if any.
+    pub(crate) fn mapping(&self) -> Option<&M> {
+        self.mapping.as_ref().map(|mapping| &mapping.mapping)
+    }
+}
+
+/// Whether a server going from `prev_epoch_time` to `epoch_time` in `elapsed` lost its state.
+fn epoch_lost_state(prev_epoch_time: u32, epoch_time: u32, elapsed: Duration) -> bool {
+    // allow for the server's clock to have advanced by up to one second less than ours
+    if epoch_time < prev_epoch_time.saturating_sub(1) {
+        return true;
+    }
+    let client_delta = elapsed.as_secs();
+    let server_delta = u64::from(epoch_time.saturating_sub(prev_epoch_time));
+    // tolerate 1/16 of drift between the clocks, plus the timestamps' rounding errors
+    client_delta + 2 < server_delta - server_delta / 16
+        || server_delta + 2 < client_delta - client_delta / 16
 }
 
 impl<M: Mapping> n0_future::Stream for CurrentMapping<M> {
-    type Item = Event;
+    type Item = Event<M::Ip>;
 
     fn poll_next(
         mut self: Pin<&mut Self>,
@@ -190,12 +421,143 @@ mod tests {
     const HALF_LIFETIME_SECS: u64 = 1;
 
     impl Mapping for M {
+        type Ip = Ipv4Addr;
+
         fn external(&self) -> M {
             *self
         }
         fn half_lifetime(&self) -> Duration {
             Duration::from_secs(HALF_LIFETIME_SECS)
         }
+        fn local_port(&self) -> NonZeroU16 {
+            self.1
+        }
+        fn protocol(&self) -> MappingProtocol {
+            MappingProtocol::Pcp
+        }
+        fn epoch_time(&self) -> Option<u32> {
+            Some(EPOCH_TIME)
+        }
+        fn lease(&self) -> Option<Duration> {
+            Some(self.half_lifetime() * 2)
+        }
+    }
+
+    const EPOCH_TIME: u32 = 1000;
+
+    #[test]
+    fn epoch_validation() {
+        let secs = Duration::from_secs;
+        // clocks advancing together
+        assert!(!epoch_lost_state(100, 100, secs(0)));
+        assert!(!epoch_lost_state(100, 160, secs(60)));
+        // small jitter and drift is tolerated
+        assert!(!epoch_lost_state(100, 99, secs(0)));
+        assert!(!epoch_lost_state(100, 1100, secs(1000 - 60)));
+        assert!(!epoch_lost_state(100, 1100 - 60, secs(1000)));
+        // the server went back in time
+        assert!(epoch_lost_state(100, 98, secs(0)));
+        // the server restarted and has been up less time than has passed for us
+        assert!(epoch_lost_state(100, 110, secs(3600)));
+        // the server advanced way more than we did
+        assert!(epoch_lost_state(100, 3700, secs(10)));
+    }
+
+    #[tokio::test]
+    async fn jittered_renewal_stays_before_expiry() {
+        const TEST_PORT: NonZeroU16 = NonZeroU16::new(9589).unwrap();
+        const TEST_IP: std::net::Ipv4Addr = std::net::Ipv4Addr::LOCALHOST;
+        let lifetime = Duration::from_secs(HALF_LIFETIME_SECS * 2);
+        for _ in 0..100 {
+            let active = ActiveMapping::new((TEST_IP, TEST_PORT), 0.5, true);
+            let renew_after = lifetime - active.renewal_window;
+            assert!(renew_after >= lifetime.mul_f32(0.45), "{renew_after:?}");
+            assert!(renew_after <= lifetime.mul_f32(0.55), "{renew_after:?}");
+
+            // close to the expiry, the renewal is delayed by less
+            let active = ActiveMapping::new((TEST_IP, TEST_PORT), 0.95, true);
+            let renew_after = lifetime - active.renewal_window;
+            assert!(renew_after >= lifetime.mul_f32(0.855), "{renew_after:?}");
+            assert!(renew_after <= lifetime.mul_f32(0.975), "{renew_after:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn epoch_regression_is_detected() {
+        const TEST_PORT: NonZeroU16 = NonZeroU16::new(9585).unwrap();
+        const TEST_IP: std::net::Ipv4Addr = std::net::Ipv4Addr::LOCALHOST;
+        let (mut c, _watcher) = CurrentMapping::<M>::new(Default::default(), 0.5, false);
+        // without a mapping there is nothing to lose
+        assert!(!c.observe_epoch(0));
+
+        c.update(Some((TEST_IP, TEST_PORT)));
+        time::advance(Duration::from_secs(30)).await;
+        assert!(!c.observe_epoch(EPOCH_TIME + 30));
+        time::advance(Duration::from_secs(30)).await;
+        assert!(c.observe_epoch(5));
+    }
+
+    #[tokio::test]
+    async fn mapping_info_follows_mapping() {
+        const TEST_PORT: NonZeroU16 = NonZeroU16::new(9588).unwrap();
+        const TEST_IP: std::net::Ipv4Addr = std::net::Ipv4Addr::LOCALHOST;
+        let (mut c, _watcher) = CurrentMapping::<M>::new(Default::default(), 0.5, false);
+        let info = c.watch_info();
+        assert!(info.borrow().is_none());
+
+        c.update(Some((TEST_IP, TEST_PORT)));
+        let current = info.borrow().clone().expect("mapping is active");
+        assert_eq!(
+            current.external,
+            SocketAddrV4::new(TEST_IP, TEST_PORT.into())
+        );
+        assert_eq!(current.protocol, MappingProtocol::Pcp);
+        assert_eq!(
+            current.expires - current.acquired,
+            Duration::from_secs(2 * HALF_LIFETIME_SECS)
+        );
+
+        c.update(None);
+        assert!(info.borrow().is_none());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn external_port_change_is_counted() {
+        const TEST_IP: std::net::Ipv4Addr = std::net::Ipv4Addr::LOCALHOST;
+        let port = |port| NonZeroU16::new(port).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let (mut c, _watcher) = CurrentMapping::<M>::new(metrics.clone(), 0.5, false);
+
+        // a first mapping is not a change
+        c.update(Some((TEST_IP, port(9590))));
+        assert_eq!(metrics.external_port_changed.get(), 0);
+        c.update(Some((TEST_IP, port(9590))));
+        assert_eq!(metrics.external_port_changed.get(), 0);
+        c.update(Some((TEST_IP, port(9591))));
+        assert_eq!(metrics.external_port_changed.get(), 1);
+
+        // nor is a mapping obtained after losing the previous one
+        c.update(None);
+        c.update(Some((TEST_IP, port(9592))));
+        assert_eq!(metrics.external_port_changed.get(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn renewal_follows_fraction() {
+        const TEST_PORT: NonZeroU16 = NonZeroU16::new(9587).unwrap();
+        const TEST_IP: std::net::Ipv4Addr = std::net::Ipv4Addr::LOCALHOST;
+        let (mut c, _watcher) = CurrentMapping::<M>::new(Default::default(), 0.75, false);
+        let start = time::Instant::now();
+        c.update(Some((TEST_IP, TEST_PORT)));
+
+        let event = c.next().await.expect("Renewal is reported");
+        assert!(matches!(event, Event::Renew { .. }));
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+
+        let event = c.next().await.expect("Expiry is reported");
+        assert!(matches!(event, Event::Expired { .. }));
+        assert_eq!(start.elapsed(), Duration::from_secs(2 * HALF_LIFETIME_SECS));
     }
 
     #[tokio::test]
@@ -203,7 +565,7 @@ mod tests {
     async fn report_renew_expire_report() {
         const TEST_PORT: NonZeroU16 = NonZeroU16::new(9586).unwrap();
         const TEST_IP: std::net::Ipv4Addr = std::net::Ipv4Addr::LOCALHOST;
-        let (mut c, mut watcher) = CurrentMapping::<M>::new(Default::default());
+        let (mut c, mut watcher) = CurrentMapping::<M>::new(Default::default(), 0.5, false);
         let now = std::time::Instant::now();
         c.update(Some((TEST_IP, TEST_PORT)));
 
@@ -238,7 +600,8 @@ mod tests {
             event,
             Event::Expired {
                 external_ip: TEST_IP,
-                external_port: TEST_PORT
+                external_port: TEST_PORT,
+                protocol: MappingProtocol::Pcp,
             }
         );
         assert_eq!(now.elapsed().as_secs(), 2 * HALF_LIFETIME_SECS);
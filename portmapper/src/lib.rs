@@ -1,26 +1,42 @@
 //! Port mapping client and service.
 
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     num::NonZeroU16,
+    pin::Pin,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use current_mapping::CurrentMapping;
 use n0_error::{e, stack_error};
-use n0_future::StreamExt;
-use netwatch::interfaces::HomeRouter;
-use tokio::sync::{mpsc, oneshot, watch};
+use n0_future::{Stream, StreamExt, boxed::BoxFuture};
+use netwatch::interfaces::{HomeRouter, State};
+use rand::RngExt;
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time,
+};
 use tokio_util::task::AbortOnDropHandle;
 use tracing::{Instrument, debug, info_span, trace};
 
+mod announcement;
 mod current_mapping;
 mod mapping;
 mod metrics;
+#[cfg(feature = "nat_pmp")]
 mod nat_pmp;
+#[cfg(feature = "pcp")]
 mod pcp;
-mod upnp;
+mod reachable;
+#[cfg(any(
+    all(test, any(feature = "pcp", feature = "nat_pmp")),
+    feature = "test-util"
+))]
+pub mod test_util;
+#[cfg(feature = "upnp")]
+pub mod upnp;
 mod util;
 mod defaults {
     use std::time::Duration;
@@ -28,11 +44,29 @@ mod defaults {
     /// Maximum duration a UPnP search can take before timing out.
     pub(crate) const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(1);
 
+    /// Maximum duration fetching a UPnP device description can take before timing out.
+    pub(crate) const UPNP_DESCRIPTION_TIMEOUT: Duration = Duration::from_secs(1);
+
     /// Timeout to receive a response from a PCP server.
     pub(crate) const PCP_RECV_TIMEOUT: Duration = Duration::from_millis(500);
 
     /// Timeout to receive a response from a NAT-PMP server.
     pub(crate) const NAT_PMP_RECV_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Maximum duration of a whole mapping attempt.
+    pub(crate) const MAPPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Name with which we register UPnP mappings in the router.
+    pub(crate) const UPNP_MAPPING_DESCRIPTION: &str = "iroh-portmap";
+
+    /// Delay before retrying the first failed mapping attempt.
+    pub(crate) const MAPPING_RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+    /// Maximum delay between retries of failed mapping attempts.
+    pub(crate) const MAPPING_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Fraction of a mapping's lifetime after which it's renewed.
+    pub(crate) const RENEWAL_FRACTION: f32 = 0.5;
 }
 
 pub use metrics::Metrics;
@@ -44,13 +78,31 @@ const AVAILABILITY_TRUST_DURATION: Duration = Duration::from_secs(60 * 10); // 1
 /// Capacity of the channel to communicate with the long-running service.
 const SERVICE_CHANNEL_CAPACITY: usize = 32; // should be plenty
 
+/// Capacity of each channel delivering [`MappingEvent`]s to a subscriber.
+const EVENT_CHANNEL_CAPACITY: usize = 32;
+
+/// Default number of probe results kept, see [`Config::probe_history_capacity`].
+const DEFAULT_PROBE_HISTORY_CAPACITY: usize = 16;
+
+/// Default order in which available protocols are tried, see [`Config::protocol_priority`].
+///
+/// UPnP comes last since it's the most unreliable, but possibly the most deployed one.
+const DEFAULT_PROTOCOL_PRIORITY: [MappingProtocol; 3] = [
+    MappingProtocol::Pcp,
+    MappingProtocol::NatPmp,
+    MappingProtocol::Upnp,
+];
+
 /// If a port mapping service has not been seen within the last [`UNAVAILABILITY_TRUST_DURATION`]
 /// we allow trying a mapping using said protocol.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
 const UNAVAILABILITY_TRUST_DURATION: Duration = Duration::from_secs(5);
 
 /// Output of a port mapping probe.
 #[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[display("portmap={{ UPnP: {upnp}, PMP: {nat_pmp}, PCP: {pcp} }}")]
+#[non_exhaustive]
 pub struct ProbeOutput {
     /// If UPnP can be considered available.
     pub upnp: bool,
@@ -58,13 +110,49 @@ pub struct ProbeOutput {
     pub pcp: bool,
     /// If PMP can be considered available.
     pub nat_pmp: bool,
+    /// Identity reported by the UPnP gateway, if one was found and described itself.
+    pub upnp_gateway: Option<GatewayInfo>,
 }
 
 impl ProbeOutput {
+    /// Creates the output of a probe that found the given protocols available.
+    ///
+    /// No UPnP gateway identity is recorded, see [`ProbeOutput::upnp_gateway`].
+    pub fn new(upnp: bool, pcp: bool, nat_pmp: bool) -> Self {
+        Self {
+            upnp,
+            pcp,
+            nat_pmp,
+            upnp_gateway: None,
+        }
+    }
+
     /// Indicates if all port mapping protocols are available.
     pub fn all_available(&self) -> bool {
         self.upnp && self.pcp && self.nat_pmp
     }
+
+    /// Indicates if the given port mapping protocol is available.
+    pub fn is_available(&self, protocol: MappingProtocol) -> bool {
+        match protocol {
+            MappingProtocol::Upnp => self.upnp,
+            MappingProtocol::Pcp => self.pcp,
+            MappingProtocol::NatPmp => self.nat_pmp,
+        }
+    }
+}
+
+/// Round-trip time of the probes to each port mapping protocol, see [`Client::probe_detailed`].
+///
+/// A value of `None` means the protocol was not probed or did not answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProbeTiming {
+    /// Time taken to find and describe the UPnP gateway.
+    pub upnp: Option<Duration>,
+    /// Time taken for the PCP server to answer.
+    pub pcp: Option<Duration>,
+    /// Time taken for the NAT-PMP server to answer.
+    pub nat_pmp: Option<Duration>,
 }
 
 #[allow(missing_docs)]
@@ -80,8 +168,314 @@ pub enum ProbeError {
     NoGateway,
     #[error("gateway found is ipv6, ignoring")]
     Ipv6Gateway,
+    #[error("all port mapping protocols are disabled, nothing to probe")]
+    AllProtocolsDisabled,
     #[error("Probe task stopped. is_panic: {is_panic}, is_cancelled: {is_cancelled}")]
-    Join { is_panic: bool, is_cancelled: bool },
+    Join {
+        is_panic: bool,
+        is_cancelled: bool,
+        #[error(std_err)]
+        source: Arc<tokio::task::JoinError>,
+    },
+}
+
+impl ProbeError {
+    /// Wraps the error of a probe task that did not finish.
+    fn from_join_error(source: tokio::task::JoinError) -> Self {
+        e!(ProbeError::Join {
+            is_panic: source.is_panic(),
+            is_cancelled: source.is_cancelled(),
+            source: Arc::new(source)
+        })
+    }
+}
+
+/// Result of a probe requested with [`Client::cancellable_probe`].
+///
+/// Dropping the handle gives up on the result, aborting the probe if nobody else is waiting for
+/// it.
+#[derive(Debug)]
+pub struct ProbeHandle {
+    result_rx: oneshot::Receiver<Result<ProbeOutput, ProbeError>>,
+}
+
+impl Future for ProbeHandle {
+    type Output = Result<ProbeOutput, ProbeError>;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        Pin::new(&mut self.result_rx)
+            .poll(cx)
+            .map(|result| result.unwrap_or_else(|_| Err(e!(ProbeError::ChannelClosed))))
+    }
+}
+
+/// Details about the network path used for port mapping, see [`Client::diagnostics`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Diagnostics {
+    /// Local address mappings are made from, if one could be determined.
+    pub local_ip: Option<Ipv4Addr>,
+    /// Gateway probed and mapped with, if one was found.
+    pub gateway: Option<Ipv4Addr>,
+    /// Location of the UPnP gateway's device description, if a UPnP gateway was found.
+    pub upnp_gateway_url: Option<String>,
+    /// Local address the last PCP probe was sent from.
+    ///
+    /// Helps correlating captured packets with a probe, as each probe uses an ephemeral port.
+    pub pcp_probe_addr: Option<SocketAddrV4>,
+    /// Local address the last NAT-PMP probe was sent from.
+    pub nat_pmp_probe_addr: Option<SocketAddrV4>,
+    /// Why the last UPnP probe found no gateway, if it failed.
+    #[cfg(feature = "upnp")]
+    pub upnp_probe_error: Option<Arc<upnp::Error>>,
+    /// Result of probing the port mapping protocols.
+    pub probe: Result<ProbeOutput, ProbeError>,
+}
+
+/// Gateway details known to the service, completed into [`Diagnostics`] by the [`Client`].
+#[derive(Debug, Default)]
+struct GatewayDetails {
+    local_ip: Option<Ipv4Addr>,
+    gateway: Option<Ipv4Addr>,
+    upnp_gateway_url: Option<String>,
+    pcp_probe_addr: Option<SocketAddrV4>,
+    nat_pmp_probe_addr: Option<SocketAddrV4>,
+    #[cfg(feature = "upnp")]
+    upnp_probe_error: Option<Arc<upnp::Error>>,
+    probe_timing: ProbeTiming,
+}
+
+/// What the service knows about reaching the gateway, used by [`Client::external_ip`] to ask for
+/// the external address.
+#[derive(Debug)]
+struct ExternalIpQuery {
+    /// Local ip and gateway to ask with NAT-PMP, if it's available.
+    #[cfg(feature = "nat_pmp")]
+    nat_pmp: Option<(Ipv4Addr, Ipv4Addr)>,
+    #[cfg(feature = "nat_pmp")]
+    nat_pmp_recv_timeout: Duration,
+    #[cfg(feature = "nat_pmp")]
+    sockets: util::SocketOptions,
+    /// UPnP gateway to ask, if it's available.
+    #[cfg(feature = "upnp")]
+    upnp_gateway: Option<upnp::Gateway>,
+    #[cfg(any(feature = "upnp", feature = "nat_pmp"))]
+    allow_private_external: bool,
+}
+
+impl ExternalIpQuery {
+    /// Asks the available protocols in turn, returning the first external address that is
+    /// public, or private when allowed.
+    #[cfg_attr(
+        not(any(feature = "upnp", feature = "nat_pmp")),
+        expect(clippy::unused_async, reason = "PCP can't be asked")
+    )]
+    async fn run(self) -> Option<Ipv4Addr> {
+        #[cfg(feature = "nat_pmp")]
+        if let Some((local_ip, gateway)) = self.nat_pmp {
+            match nat_pmp::external_address(
+                local_ip,
+                gateway,
+                self.nat_pmp_recv_timeout,
+                &self.sockets,
+            )
+            .await
+            {
+                Ok(ip) if mapping::is_valid_external_ip(ip, self.allow_private_external) => {
+                    return Some(ip);
+                }
+                Ok(ip) => debug!(%ip, "NAT-PMP reported an unusable external address"),
+                Err(e) => debug!("NAT-PMP external address query failed: {e}"),
+            }
+        }
+        #[cfg(feature = "upnp")]
+        if let Some(gateway) = &self.upnp_gateway {
+            match upnp::external_address(gateway).await {
+                Ok(ip) if mapping::is_valid_external_ip(ip, self.allow_private_external) => {
+                    return Some(ip);
+                }
+                Ok(ip) => debug!(%ip, "UPnP reported an unusable external address"),
+                Err(e) => debug!("UPnP external address query failed: {e}"),
+            }
+        }
+        None
+    }
+}
+
+/// What the service knows about reaching the gateway with PCP, used by [`Client::map_peer`].
+#[cfg(feature = "pcp")]
+#[derive(Debug)]
+struct PeerMappingRequest {
+    protocol: Protocol,
+    local_ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+    recv_timeout: Duration,
+    sockets: util::SocketOptions,
+}
+
+#[cfg(feature = "pcp")]
+impl PeerMappingRequest {
+    /// Maps `local_port` for traffic with `remote_peer` only.
+    async fn run(
+        self,
+        local_port: NonZeroU16,
+        remote_peer: (Ipv4Addr, NonZeroU16),
+    ) -> Result<PeerMapping, MappingError> {
+        pcp::Mapping::new_peer(
+            self.protocol,
+            self.local_ip,
+            local_port,
+            self.gateway,
+            // only IPv6 link-local gateways need a scope
+            0,
+            remote_peer,
+            self.recv_timeout,
+            self.sockets,
+        )
+        .await
+        .map(PeerMapping)
+        .map_err(|e| {
+            debug!("failed to get a peer mapping {e}");
+            e!(MappingError::Failed {
+                source: Arc::new(e.into())
+            })
+        })
+    }
+}
+
+/// A mapping for traffic with a single remote peer, see [`Client::map_peer`].
+///
+/// The service neither tracks nor renews it, so it lasts for its [lease](PeerMapping::lease)
+/// unless [released](PeerMapping::release) earlier.
+#[cfg(feature = "pcp")]
+#[derive(Debug)]
+pub struct PeerMapping(pcp::Mapping);
+
+#[cfg(feature = "pcp")]
+impl PeerMapping {
+    /// External address the remote peer can reach the local port at.
+    pub fn external(&self) -> SocketAddrV4 {
+        let (ip, port) = self.0.external();
+        SocketAddrV4::new(ip, port.get())
+    }
+
+    /// Lifetime granted by the gateway.
+    pub fn lease(&self) -> Duration {
+        self.0.lease()
+    }
+
+    /// Releases the mapping before its lease expires.
+    pub async fn release(self) {
+        if let Err(e) = self.0.release().await {
+            debug!("failed to release peer mapping: {e}");
+        }
+    }
+}
+
+/// Error queueing a request with [`Client::try_procure_mapping`] or
+/// [`Client::try_update_local_port`].
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta)]
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum ServiceSendError {
+    #[error("Service channel is full")]
+    Full,
+    #[error("Service channel is closed")]
+    Closed,
+}
+
+/// Error obtaining a mapping with [`Client::get_mapping_once`].
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta)]
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum MappingError {
+    #[error("Mapping channel is full")]
+    ChannelFull,
+    #[error("Mapping channel is closed")]
+    ChannelClosed,
+    #[error("No port mapping protocol could be attempted")]
+    NoProtocol,
+    #[error("Local port changed before the mapping was obtained")]
+    LocalPortChanged,
+    #[error("Mappings are capped at {max}, see Config::max_mappings")]
+    MaxMappingsExceeded { max: usize },
+    #[error("Mapping failed")]
+    Failed { source: Arc<mapping::Error> },
+    #[error("Mapping task stopped. is_panic: {is_panic}, is_cancelled: {is_cancelled}")]
+    Join {
+        is_panic: bool,
+        is_cancelled: bool,
+        #[error(std_err)]
+        source: Arc<tokio::task::JoinError>,
+    },
+}
+
+/// Changes in the lifecycle of the port mapping, see [`Client::subscribe_events`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum MappingEvent {
+    /// A new mapping was obtained.
+    Acquired {
+        /// Local port that is mapped.
+        local_port: NonZeroU16,
+        /// External address of the mapping, its port not necessarily matching the local one.
+        addr: SocketAddrV4,
+        /// Protocol that produced the mapping.
+        protocol: MappingProtocol,
+    },
+    /// The active mapping was renewed.
+    Renewed {
+        /// Local port that is mapped.
+        local_port: NonZeroU16,
+        /// External address of the mapping, which might differ from the previous one.
+        addr: SocketAddrV4,
+        /// Protocol that renewed the mapping, which might differ from the previous one.
+        protocol: MappingProtocol,
+    },
+    /// The active mapping expired without being renewed.
+    Expired {
+        /// External address of the expired mapping.
+        addr: SocketAddrV4,
+        /// Protocol that produced the expired mapping.
+        protocol: MappingProtocol,
+    },
+    /// A renewal of the active mapping resulted in a different external port.
+    ///
+    /// Emitted after [`MappingEvent::Renewed`], peers that cached the old address need to learn
+    /// the new one.
+    ExternalPortChanged {
+        /// External address of the mapping before the renewal.
+        old: SocketAddrV4,
+        /// External address of the renewed mapping.
+        new: SocketAddrV4,
+    },
+    /// The active mapping was released.
+    Released,
+    /// The router did not grant the external port requested with
+    /// [`Client::request_external_port`], and mapped a different one instead.
+    ExternalPortSubstituted {
+        /// External port that was requested.
+        requested: NonZeroU16,
+        /// External address that was granted.
+        granted: SocketAddrV4,
+        /// Protocol that granted the mapping.
+        protocol: MappingProtocol,
+    },
+    /// An attempt to obtain a mapping failed.
+    Failed {
+        /// Reason of the failure.
+        error: MappingError,
+    },
+    /// A probe finished.
+    ProbeCompleted {
+        /// Result of the probe.
+        output: ProbeOutput,
+    },
 }
 
 #[derive(derive_more::Debug)]
@@ -94,19 +488,95 @@ enum Message {
     /// [`Client::watch_external_address`].
     /// A value of `None` will deactivate port mapping.
     UpdateLocalPort { local_port: Option<NonZeroU16> },
+    /// Map an additional local port, independently of the one set with
+    /// [`Message::UpdateLocalPort`].
+    AddPort {
+        local_port: NonZeroU16,
+        /// Sender side to communicate whether the port was added.
+        #[debug("_")]
+        result_tx: oneshot::Sender<Result<(), MappingError>>,
+    },
+    /// Stop mapping a port added with [`Message::AddPort`], releasing its mapping.
+    RemovePort { local_port: NonZeroU16 },
+    /// Provide a fresh network [`State`] to derive the gateway and local ip from.
+    ///
+    /// This avoids querying the OS for the home router on every probe and mapping attempt.
+    UpdateInterfaceState { state: Box<State> },
+    /// Discard the probe and mappings obtained so far, probing and mapping again.
+    NetworkChanged,
     /// Request to probe the port mapping protocols.
     ///
     /// The requester should wait for the result at the [`oneshot::Receiver`] counterpart of the
     /// [`oneshot::Sender`].
     Probe {
+        /// Whether to probe again even if recent results can be trusted.
+        force: bool,
+        /// Whether the probe may be aborted once the requester is gone.
+        cancellable: bool,
         /// Sender side to communicate the result of the probe.
         #[debug("_")]
         result_tx: oneshot::Sender<Result<ProbeOutput, ProbeError>>,
     },
+    /// Request a mapping for the given local port, reporting the result of the attempt.
+    ///
+    /// The local port is updated as with [`Message::UpdateLocalPort`].
+    GetMappingOnce {
+        local_port: NonZeroU16,
+        /// Sender side to communicate the result of the mapping attempt.
+        #[debug("_")]
+        result_tx: oneshot::Sender<Result<SocketAddrV4, MappingError>>,
+    },
+    /// Request the gateway details gathered so far.
+    GatewayDetails {
+        /// Sender side to communicate the details.
+        #[debug("_")]
+        result_tx: oneshot::Sender<GatewayDetails>,
+    },
+    /// Request the network [`State`] provided with [`Message::UpdateInterfaceState`], if any.
+    InterfaceState {
+        /// Sender side to communicate the state.
+        #[debug("_")]
+        result_tx: oneshot::Sender<Option<State>>,
+    },
+    /// Request what's needed to ask the gateway for its external address.
+    ExternalIpQuery {
+        /// Whether to accept shared and private addresses regardless of the [`Config`].
+        allow_private: bool,
+        /// Sender side to communicate the query.
+        #[debug("_")]
+        result_tx: oneshot::Sender<ExternalIpQuery>,
+    },
+    /// Request what's needed to map a port for a single remote peer with PCP.
+    #[cfg(feature = "pcp")]
+    PeerMappingRequest {
+        /// Sender side to communicate the request, or why PCP can't be used.
+        #[debug("_")]
+        result_tx: oneshot::Sender<Result<PeerMappingRequest, MappingError>>,
+    },
+    /// Set the external port to request in mappings.
+    RequestExternalPort { external_port: NonZeroU16 },
+    /// Release the mapping of an external port, tracked or not.
+    ReleaseExternalPort {
+        protocol: MappingProtocol,
+        external_port: NonZeroU16,
+    },
+    /// Register a subscriber for [`MappingEvent`]s.
+    Subscribe {
+        #[debug("_")]
+        events_tx: mpsc::Sender<MappingEvent>,
+    },
+    /// Request to release the current mapping and stop the service.
+    ///
+    /// The requester is notified once the mapping has been released.
+    Shutdown {
+        #[debug("_")]
+        done_tx: oneshot::Sender<()>,
+    },
 }
 
 /// Configuration for UDP or TCP network protocol.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Protocol {
     /// UDP protocol.
     Udp,
@@ -114,496 +584,2983 @@ pub enum Protocol {
     Tcp,
 }
 
+/// A port mapping protocol.
+///
+/// Displayed by its conventional name, e.g. `NAT-PMP`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Display)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MappingProtocol {
+    /// UPnP.
+    #[display("UPnP")]
+    Upnp,
+    /// PCP.
+    #[display("PCP")]
+    Pcp,
+    /// NAT-PMP.
+    #[display("NAT-PMP")]
+    NatPmp,
+}
+
+impl MappingProtocol {
+    /// Whether the protocol's feature is enabled.
+    fn is_compiled_in(self) -> bool {
+        match self {
+            MappingProtocol::Upnp => cfg!(feature = "upnp"),
+            MappingProtocol::Pcp => cfg!(feature = "pcp"),
+            MappingProtocol::NatPmp => cfg!(feature = "nat_pmp"),
+        }
+    }
+}
+
+/// Details of the active port mapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingInfo {
+    /// Local port that is mapped.
+    pub local_port: NonZeroU16,
+    /// External address obtained by the mapping, its port not necessarily matching the local
+    /// one.
+    pub external: SocketAddrV4,
+    /// Protocol that produced the mapping.
+    pub protocol: MappingProtocol,
+    /// When the mapping was acquired or last renewed.
+    pub acquired: Instant,
+    /// When the mapping expires unless renewed, see [`Config::renewal_fraction`].
+    pub expires: Instant,
+    /// Lease granted by the gateway, possibly shorter than requested.
+    ///
+    /// `None` if the mapping doesn't expire, which some UPnP routers grant. Such mappings are
+    /// still renewed as if they had the lease we ask for.
+    pub lease: Option<Duration>,
+    /// Whether the external address was found reachable, see [`Config::reachability_check`].
+    ///
+    /// `None` while the address is unverified, because no check is configured or it hasn't
+    /// finished yet.
+    pub reachable: Option<bool>,
+}
+
+impl MappingInfo {
+    /// Whether the external address is a shared (CGNAT, `100.64.0.0/10`) or private one.
+    ///
+    /// Such an address means the router is itself behind another NAT, typically the carrier's, so
+    /// the mapping can't be reached from the internet. These are only reported with
+    /// [`Config::allow_private_external`], otherwise mapping fails instead.
+    pub fn is_cgnat(&self) -> bool {
+        mapping::is_private_or_shared(*self.external.ip())
+    }
+}
+
+/// Identity a UPnP internet gateway device (router) reports about itself.
+///
+/// Taken from the device description fetched during discovery. Routers fill these in
+/// inconsistently, so any of them may be missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GatewayInfo {
+    /// Name of the device meant for display, often set by the user.
+    pub friendly_name: Option<String>,
+    /// Manufacturer of the device.
+    pub manufacturer: Option<String>,
+    /// Model name of the device.
+    pub model_name: Option<String>,
+    /// Model number of the device, often a firmware version or build date.
+    pub model_number: Option<String>,
+}
+
+/// A port mapping protocol known not to work with a specific router.
+///
+/// See [`Config::protocol_denylist`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DenylistEntry {
+    /// Protocol to skip.
+    pub protocol: MappingProtocol,
+    /// Signature of the router, matched case-insensitively against the manufacturer and model
+    /// name the router reports during UPnP discovery, e.g. `"Netgear R7000"`.
+    pub router: String,
+}
+
+#[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+impl DenylistEntry {
+    /// Whether this entry applies to a router reporting the given identity.
+    fn matches(&self, info: &GatewayInfo) -> bool {
+        let signature = self.router.trim().to_lowercase();
+        if signature.is_empty() {
+            return false;
+        }
+        let identity = [info.manufacturer.as_deref(), info.model_name.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        identity.contains(&signature)
+    }
+}
+
 /// Configures which port mapping protocols are enabled in the [`Service`].
+///
+/// Outside of this crate it's created with [`Config::builder`] or [`Config::default`], so that
+/// new options can be added without breaking callers.
+///
+/// With the `serde` feature, fields missing when deserializing take their default value. The
+/// callbacks in `socket_factory`, `packet_capture` and `reachability_check` are skipped, as
+/// closures can't be serialized, and so is the test-only `server_port_override`.
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+#[non_exhaustive]
 pub struct Config {
     /// Whether UPnP is enabled.
+    #[cfg(feature = "upnp")]
     pub enable_upnp: bool,
     /// Whether PCP is enabled.
+    #[cfg(feature = "pcp")]
     pub enable_pcp: bool,
     /// Whether PMP is enabled.
+    #[cfg(feature = "nat_pmp")]
     pub enable_nat_pmp: bool,
     /// Whether to use UDP or TCP.
     pub protocol: Protocol,
+    /// Order in which to try the protocols the last probe found available, first to last.
+    ///
+    /// Must not be empty. Available protocols that are not listed are tried after the listed ones,
+    /// in the default order of PCP, NAT-PMP and UPnP. Listing a protocol that is disabled or whose
+    /// feature is not enabled is a misconfiguration.
+    pub protocol_priority: Vec<MappingProtocol>,
+    /// Protocols to skip on specific routers.
+    ///
+    /// Routers are identified by what they report during UPnP discovery, so entries only take
+    /// effect once UPnP has found the gateway.
+    pub protocol_denylist: Vec<DenylistEntry>,
+    /// Number of probe results to keep, see [`Client::probe_history`].
+    pub probe_history_capacity: usize,
+    /// Maximum number of mappings the service holds at once, unlimited if `None`.
+    ///
+    /// A safety valve against exhausting the router's ports. Counts the local port as well as the
+    /// ports added with [`Client::add_port`]. Once it's reached, further ports are not mapped and
+    /// fail with [`MappingError::MaxMappingsExceeded`]. A cap of zero disables mapping altogether.
+    pub max_mappings: Option<usize>,
+    /// Gateway to probe and map with, instead of the detected home router.
+    ///
+    /// Useful in double-NAT or container setups where the detected gateway is the wrong hop.
+    pub gateway_override: Option<Ipv4Addr>,
+    /// Local address to probe and map from, instead of the detected one.
+    pub local_ip_override: Option<Ipv4Addr>,
+    /// Host to make PCP mappings for with the THIRD_PARTY option, instead of this one.
+    ///
+    /// Meant for hosts mapping ports on behalf of others behind them, which PCP servers only
+    /// accept from hosts they trust. UPnP and NAT-PMP have no equivalent and must be disabled,
+    /// and no IPv6 mappings are made.
+    #[cfg(feature = "pcp")]
+    pub pcp_third_party: Option<Ipv4Addr>,
+    /// Timeouts used when probing and mapping.
+    pub timeouts: Timeouts,
+    /// Name with which UPnP mappings are registered in the router, shown in its admin UI.
+    pub mapping_description: String,
+    /// Whether to remove the UPnP mappings earlier runs left behind before the first mapping.
+    ///
+    /// Clients that are dropped without releasing their mapping leave it on the router until its
+    /// lease expires, which can pile up over restarts. Mappings with our
+    /// [`Config::mapping_description`] that point at our local address are removed, except the
+    /// one for the external port about to be requested again.
+    pub cleanup_stale_mappings: bool,
+    /// How to retry failed mapping attempts, `None` to wait for the next external trigger.
+    pub mapping_retry: Option<RetryBackoff>,
+    /// Fraction of a mapping's lifetime after which it's renewed, between 0 and 1 exclusive.
+    ///
+    /// Renewing earlier leaves more time to retry on unreliable networks.
+    pub renewal_fraction: f32,
+    /// Whether to randomly move each renewal by up to 10% earlier or later.
+    ///
+    /// Spreads out the renewals of many clients that acquired their mappings at the same time,
+    /// e.g. after the router restarted. Renewals are never moved past the mapping's expiry.
+    pub renewal_jitter: bool,
+    /// Whether to listen for the announcements NAT-PMP and PCP servers multicast when they lose
+    /// their mappings, for example after a reboot.
+    ///
+    /// When an announcement shows the server lost the current mapping, it's acquired again right
+    /// away instead of waiting for its renewal.
+    pub listen_for_announcements: bool,
+    /// Whether to accept private and shared (CGNAT) external addresses from UPnP and NAT-PMP.
+    ///
+    /// Such addresses are rejected by default, as they can't be reached from the internet. Allow
+    /// them when the router is intentionally behind another NAT, e.g. to reach peers in the
+    /// outer network.
+    pub allow_private_external: bool,
+    /// Result of an earlier probe to start from, e.g. one persisted before a restart.
+    ///
+    /// The protocols it reports as available are trusted as if they had just been probed,
+    /// skipping the probe that otherwise precedes the first mapping. UPnP can't be restored,
+    /// as the gateway's address is not part of the output, and is found again when mapping.
+    pub initial_probe: Option<ProbeOutput>,
+    /// Creates the sockets PCP and NAT-PMP requests are sent from, instead of binding them
+    /// directly.
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub socket_factory: Option<SocketFactory>,
+    /// Mark applied to the sockets PCP and NAT-PMP requests are sent from (`SO_MARK`), to route
+    /// them towards the gateway under policy routing.
+    ///
+    /// Setting a mark requires the `CAP_NET_ADMIN` capability. UPnP manages its own sockets,
+    /// which are not marked.
+    #[cfg(all(target_os = "linux", any(feature = "pcp", feature = "nat_pmp")))]
+    pub socket_mark: Option<u32>,
+    /// Observes the raw PCP and NAT-PMP packets exchanged with the gateway.
+    ///
+    /// Useful to report a misbehaving router. UPnP is not covered, its HTTP exchanges are left to
+    /// the `igd-next` crate.
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub packet_capture: Option<PacketCapture>,
+    /// Checks whether a newly mapped external address can actually be reached.
+    ///
+    /// A router accepting a mapping doesn't mean traffic gets through, e.g. behind a carrier-grade
+    /// NAT. The outcome is reported in [`MappingInfo::reachable`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reachability_check: Option<ReachabilityCheck>,
+    /// Port on which the gateway's PCP and NAT-PMP servers are reached, instead of the standard
+    /// one.
+    ///
+    /// Only meant for tests, to reach a [`MockGateway`](crate::test_util::MockGateway) listening
+    /// on any free port.
+    #[cfg(any(test, feature = "test-util"))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub server_port_override: Option<u16>,
 }
 
-impl Default for Config {
-    /// By default all port mapping protocols are enabled for UDP.
-    fn default() -> Self {
-        Config {
-            enable_upnp: true,
-            enable_pcp: true,
-            enable_nat_pmp: true,
-            protocol: Protocol::Udp,
-        }
-    }
-}
+/// Creates UDP sockets bound to a given local address, see [`Config::socket_factory`].
+///
+/// Useful on platforms where sockets must be set up in a specific way before use, e.g. protected
+/// from being routed through a VPN on Android.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+#[derive(Clone, derive_more::Debug)]
+#[debug("SocketFactory")]
+pub struct SocketFactory(
+    Arc<dyn Fn(std::net::SocketAddr) -> std::io::Result<std::net::UdpSocket> + Send + Sync>,
+);
 
-/// Port mapping client.
-#[derive(Debug, Clone)]
-pub struct Client {
-    /// A watcher over the most recent external address obtained from port mapping.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+impl SocketFactory {
+    /// Creates a factory from a closure binding a socket to the address it's given.
     ///
-    /// See [`watch::Receiver`].
-    port_mapping: watch::Receiver<Option<SocketAddrV4>>,
-    /// Channel used to communicate with the port mapping service.
-    service_tx: mpsc::Sender<Message>,
-    /// Metrics collected by the service.
-    metrics: Arc<Metrics>,
-    /// A handle to the service that will cancel the spawned task once the client is dropped.
-    _service_handle: std::sync::Arc<AbortOnDropHandle<()>>,
-}
+    /// The address has port zero, letting the OS pick any free port.
+    pub fn new(
+        bind: impl Fn(std::net::SocketAddr) -> std::io::Result<std::net::UdpSocket>
+        + Send
+        + Sync
+        + 'static,
+    ) -> Self {
+        Self(Arc::new(bind))
+    }
 
-impl Default for Client {
-    fn default() -> Self {
-        Self::new(Config::default())
+    /// Creates a socket bound to `addr`.
+    fn bind(&self, addr: std::net::SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+        (self.0)(addr)
     }
 }
 
-impl Client {
-    /// Create a new port mapping client.
-    pub fn new(config: Config) -> Self {
-        Self::with_metrics(config, Default::default())
-    }
+/// Direction of a packet reported to a [`PacketCapture`].
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// Sent to the gateway.
+    Sent,
+    /// Received from the gateway.
+    Received,
+}
 
-    /// Creates a new port mapping client with a previously created metrics collector.
-    pub fn with_metrics(config: Config, metrics: Arc<Metrics>) -> Self {
-        let (service_tx, service_rx) = mpsc::channel(SERVICE_CHANNEL_CAPACITY);
+/// Observes raw PCP and NAT-PMP packets, see [`Config::packet_capture`].
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+#[derive(Clone, derive_more::Debug)]
+#[debug("PacketCapture")]
+pub struct PacketCapture(Arc<CaptureFn>);
 
-        let (service, watcher) = Service::new(config, service_rx, metrics.clone());
+/// Closure behind a [`PacketCapture`].
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+type CaptureFn = dyn Fn(PacketDirection, &[u8]) + Send + Sync;
 
-        let handle = AbortOnDropHandle::new(tokio::spawn(
-            async move { service.run().await }.instrument(info_span!("portmapper.service")),
-        ));
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+impl PacketCapture {
+    /// Creates a capture from a closure called with every packet sent or received.
+    ///
+    /// Retransmissions are reported each time they are sent. The first byte of a packet is its
+    /// version, `0` for NAT-PMP and `2` for PCP. The closure runs on the task exchanging the
+    /// packets, so it should return quickly.
+    pub fn new(capture: impl Fn(PacketDirection, &[u8]) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(capture))
+    }
 
-        Client {
-            port_mapping: watcher,
-            service_tx,
-            metrics,
-            _service_handle: std::sync::Arc::new(handle),
-        }
+    /// Reports `packet`.
+    pub(crate) fn capture(&self, direction: PacketDirection, packet: &[u8]) {
+        (self.0)(direction, packet)
     }
+}
 
-    /// Request a probe to the port mapping protocols.
+/// Checks whether an external address can be reached, see [`Config::reachability_check`].
+#[derive(Clone, derive_more::Debug)]
+#[debug("ReachabilityCheck")]
+pub struct ReachabilityCheck(Arc<dyn Fn(SocketAddrV4) -> BoxFuture<bool> + Send + Sync>);
+
+impl ReachabilityCheck {
+    /// Creates a check from a closure trying to reach the address it's given.
     ///
-    /// Returns the [`oneshot::Receiver`] used to obtain the result of the probe.
-    pub fn probe(&self) -> oneshot::Receiver<Result<ProbeOutput, ProbeError>> {
-        let (result_tx, result_rx) = oneshot::channel();
+    /// The closure is called whenever the external address changes, and should resolve to `true`
+    /// if the address could be reached. It's up to the closure to give up after a while, as a
+    /// check that never finishes leaves the address unverified.
+    pub fn new<F>(check: impl Fn(SocketAddrV4) -> F + Send + Sync + 'static) -> Self
+    where
+        F: Future<Output = bool> + Send + 'static,
+    {
+        Self(Arc::new(move |addr| Box::pin(check(addr))))
+    }
 
-        if let Err(e) = self.service_tx.try_send(Message::Probe { result_tx }) {
-            use mpsc::error::TrySendError::*;
+    /// Checks whether `addr` can be reached.
+    fn check(&self, addr: SocketAddrV4) -> BoxFuture<bool> {
+        (self.0)(addr)
+    }
+}
 
-            // recover the sender and return the error there
-            let (result_tx, e) = match e {
-                Full(Message::Probe { result_tx }) => (result_tx, e!(ProbeError::ChannelFull)),
-                Closed(Message::Probe { result_tx }) => (result_tx, e!(ProbeError::ChannelClosed)),
-                Full(_) | Closed(_) => unreachable!("Sent value is a probe."),
-            };
+/// Timeouts for the individual port mapping protocols.
+///
+/// The defaults suit most home networks, high-latency links might need longer ones to avoid
+/// false negatives when probing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Timeouts {
+    /// Maximum duration of a UPnP gateway search.
+    pub upnp_search: Duration,
+    /// Maximum duration of fetching a UPnP gateway's device description.
+    pub upnp_description: Duration,
+    /// Maximum duration to wait for a response from a PCP server.
+    pub pcp_recv: Duration,
+    /// Maximum duration to wait for a response from a NAT-PMP server.
+    pub nat_pmp_recv: Duration,
+    /// Maximum duration of a whole mapping attempt with any protocol.
+    ///
+    /// Bounds the attempt even when a router stalls in a way the other timeouts don't cover. An
+    /// attempt taking longer fails and is retried like any other failure, so this should exceed
+    /// the other timeouts of the protocol used.
+    pub mapping: Duration,
+}
 
-            // sender was just created. If it's dropped we have two send error and are likely
-            // shutting down
-            // NOTE: second Err is infallible match due to being the sent value
-            if let Err(Err(e)) = result_tx.send(Err(e)) {
-                trace!("Failed to request probe: {e}")
-            }
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            upnp_search: defaults::UPNP_SEARCH_TIMEOUT,
+            upnp_description: defaults::UPNP_DESCRIPTION_TIMEOUT,
+            pcp_recv: defaults::PCP_RECV_TIMEOUT,
+            nat_pmp_recv: defaults::NAT_PMP_RECV_TIMEOUT,
+            mapping: defaults::MAPPING_TIMEOUT,
         }
-        result_rx
     }
+}
 
-    /// Try to get a mapping for the last local port if there isn't one already.
-    pub fn procure_mapping(&self) {
-        // requester can't really do anything with this error if returned, so we log it
-        if let Err(e) = self.service_tx.try_send(Message::ProcureMapping) {
-            trace!("Failed to request mapping {e}")
+/// Exponential backoff between retries of failed mapping attempts.
+///
+/// The delay starts at `initial` and doubles after each failure up to `max`. Each delay is
+/// jittered to avoid retrying in lockstep with other clients behind the same router.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetryBackoff {
+    /// Delay before the first retry.
+    pub initial: Duration,
+    /// Upper bound for the delay.
+    pub max: Duration,
+}
+
+impl Default for RetryBackoff {
+    fn default() -> Self {
+        RetryBackoff {
+            initial: defaults::MAPPING_RETRY_INITIAL_BACKOFF,
+            max: defaults::MAPPING_RETRY_MAX_BACKOFF,
         }
     }
+}
 
-    /// Update the local port.
+/// A [`Config`] with contradictory settings.
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta)]
+#[non_exhaustive]
+pub enum ConfigError {
+    #[error("no port mapping protocol is compiled in, enable the upnp, pcp or nat_pmp feature")]
+    NoProtocolCompiledIn {},
+    #[error("all port mapping protocols are disabled")]
+    NoProtocolEnabled {},
+    #[error("protocol denylist entry {index} has an empty router signature")]
+    EmptyRouterSignature { index: usize },
+    #[error("timeouts must not be zero")]
+    ZeroTimeout {},
+    #[error("retry backoff must be non-zero and not exceed its maximum")]
+    InvalidRetryBackoff {},
+    #[error("renewal fraction {fraction} is not between 0 and 1")]
+    InvalidRenewalFraction { fraction: f32 },
+    #[error("protocol priority contains {protocol:?}, which is not compiled in")]
+    UnsupportedPriorityProtocol { protocol: MappingProtocol },
+    #[error("protocol priority contains {protocol:?}, which is disabled")]
+    DisabledPriorityProtocol { protocol: MappingProtocol },
+    #[error("protocol priority is empty")]
+    EmptyProtocolPriority {},
+    #[error("PCP third party mappings require PCP to be the only enabled protocol")]
+    ThirdPartyRequiresPcpOnly {},
+}
+
+/// Error creating a [`Client`] with [`Client::try_new`].
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta, from_sources)]
+#[non_exhaustive]
+pub enum NewClientError {
+    #[error("no tokio runtime to spawn the service on")]
+    NoRuntime {
+        #[error(std_err)]
+        source: tokio::runtime::TryCurrentError,
+    },
+    #[error("invalid config")]
+    InvalidConfig { source: ConfigError },
+}
+
+impl Config {
+    /// Returns a builder starting from the [default](Config::default) configuration.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Checks the configuration for contradictory settings.
     ///
-    /// If the port changes, this will trigger a port mapping attempt.
-    pub fn update_local_port(&self, local_port: NonZeroU16) {
-        let local_port = Some(local_port);
-        // requester can't really do anything with this error if returned, so we log it
-        if let Err(e) = self
-            .service_tx
-            .try_send(Message::UpdateLocalPort { local_port })
+    /// This is done by [`Client::new`], so that a misconfiguration is reported instead of
+    /// resulting in a service that silently never maps anything.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !cfg!(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")) {
+            return Err(e!(ConfigError::NoProtocolCompiledIn));
+        }
+        if !self.upnp_enabled() && !self.pcp_enabled() && !self.nat_pmp_enabled() {
+            return Err(e!(ConfigError::NoProtocolEnabled));
+        }
+        if self.protocol_priority.is_empty() {
+            return Err(e!(ConfigError::EmptyProtocolPriority));
+        }
+        #[cfg(feature = "pcp")]
+        if self.pcp_third_party.is_some()
+            && (!self.pcp_enabled() || self.upnp_enabled() || self.nat_pmp_enabled())
         {
-            trace!("Failed to update local port {e}")
+            return Err(e!(ConfigError::ThirdPartyRequiresPcpOnly));
         }
-    }
-
-    /// Deactivate port mapping.
-    pub fn deactivate(&self) {
+        if let Some(&protocol) = self
+            .protocol_priority
+            .iter()
+            .find(|protocol| !protocol.is_compiled_in())
+        {
+            return Err(e!(ConfigError::UnsupportedPriorityProtocol { protocol }));
+        }
+        if let Some(&protocol) = self
+            .protocol_priority
+            .iter()
+            .find(|&&protocol| !self.protocol_enabled(protocol))
+        {
+            return Err(e!(ConfigError::DisabledPriorityProtocol { protocol }));
+        }
+        if let Some(index) = self
+            .protocol_denylist
+            .iter()
+            .position(|entry| entry.router.trim().is_empty())
+        {
+            return Err(e!(ConfigError::EmptyRouterSignature { index }));
+        }
+        let Timeouts {
+            upnp_search,
+            upnp_description,
+            pcp_recv,
+            nat_pmp_recv,
+            mapping,
+        } = self.timeouts;
+        if [
+            upnp_search,
+            upnp_description,
+            pcp_recv,
+            nat_pmp_recv,
+            mapping,
+        ]
+        .iter()
+        .any(Duration::is_zero)
+        {
+            return Err(e!(ConfigError::ZeroTimeout));
+        }
+        if let Some(RetryBackoff { initial, max }) = self.mapping_retry
+            && (initial.is_zero() || initial > max)
+        {
+            return Err(e!(ConfigError::InvalidRetryBackoff));
+        }
+        let fraction = self.renewal_fraction;
+        if !(fraction > 0.0 && fraction < 1.0) {
+            return Err(e!(ConfigError::InvalidRenewalFraction { fraction }));
+        }
+        Ok(())
+    }
+
+    /// Whether UPnP is compiled in and enabled.
+    fn upnp_enabled(&self) -> bool {
+        #[cfg(feature = "upnp")]
+        let enabled = self.enable_upnp;
+        #[cfg(not(feature = "upnp"))]
+        let enabled = false;
+        enabled
+    }
+
+    /// Whether `protocol` is compiled in and enabled.
+    fn protocol_enabled(&self, protocol: MappingProtocol) -> bool {
+        match protocol {
+            MappingProtocol::Upnp => self.upnp_enabled(),
+            MappingProtocol::Pcp => self.pcp_enabled(),
+            MappingProtocol::NatPmp => self.nat_pmp_enabled(),
+        }
+    }
+
+    /// Whether PCP is compiled in and enabled.
+    fn pcp_enabled(&self) -> bool {
+        #[cfg(feature = "pcp")]
+        let enabled = self.enable_pcp;
+        #[cfg(not(feature = "pcp"))]
+        let enabled = false;
+        enabled
+    }
+
+    /// Whether NAT-PMP is compiled in and enabled.
+    fn nat_pmp_enabled(&self) -> bool {
+        #[cfg(feature = "nat_pmp")]
+        let enabled = self.enable_nat_pmp;
+        #[cfg(not(feature = "nat_pmp"))]
+        let enabled = false;
+        enabled
+    }
+
+    /// How to create the sockets PCP and NAT-PMP requests are sent from.
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    fn socket_options(&self) -> util::SocketOptions {
+        util::SocketOptions {
+            factory: self.socket_factory.clone(),
+            #[cfg(target_os = "linux")]
+            mark: self.socket_mark,
+            capture: self.packet_capture.clone(),
+            #[cfg(any(test, feature = "test-util"))]
+            server_port_override: self.server_port_override,
+        }
+    }
+
+    /// Whether `protocol` is denylisted for a router reporting the given identity.
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    fn is_denied(&self, protocol: MappingProtocol, router: Option<&GatewayInfo>) -> bool {
+        let Some(router) = router else {
+            return false;
+        };
+        self.protocol_denylist
+            .iter()
+            .any(|entry| entry.protocol == protocol && entry.matches(router))
+    }
+}
+
+impl Default for Config {
+    /// By default all port mapping protocols are enabled for UDP.
+    fn default() -> Self {
+        Config {
+            #[cfg(feature = "upnp")]
+            enable_upnp: true,
+            #[cfg(feature = "pcp")]
+            enable_pcp: true,
+            #[cfg(feature = "nat_pmp")]
+            enable_nat_pmp: true,
+            protocol: Protocol::Udp,
+            protocol_priority: DEFAULT_PROTOCOL_PRIORITY
+                .into_iter()
+                .filter(|protocol| protocol.is_compiled_in())
+                .collect(),
+            protocol_denylist: Vec::new(),
+            probe_history_capacity: DEFAULT_PROBE_HISTORY_CAPACITY,
+            max_mappings: None,
+            gateway_override: None,
+            local_ip_override: None,
+            #[cfg(feature = "pcp")]
+            pcp_third_party: None,
+            timeouts: Timeouts::default(),
+            mapping_description: defaults::UPNP_MAPPING_DESCRIPTION.to_string(),
+            cleanup_stale_mappings: false,
+            mapping_retry: Some(RetryBackoff::default()),
+            renewal_fraction: defaults::RENEWAL_FRACTION,
+            renewal_jitter: false,
+            listen_for_announcements: false,
+            allow_private_external: false,
+            initial_probe: None,
+            #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+            socket_factory: None,
+            #[cfg(all(target_os = "linux", any(feature = "pcp", feature = "nat_pmp")))]
+            socket_mark: None,
+            #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+            packet_capture: None,
+            reachability_check: None,
+            #[cfg(any(test, feature = "test-util"))]
+            server_port_override: None,
+        }
+    }
+}
+
+/// Builds a [`Config`], see [`Config::builder`].
+///
+/// Options that are not set keep their default value.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Sets [`Config::enable_upnp`].
+    ///
+    /// Disabling it also removes it from [`Config::protocol_priority`].
+    #[cfg(feature = "upnp")]
+    pub fn enable_upnp(mut self, enable_upnp: bool) -> Self {
+        self.config.enable_upnp = enable_upnp;
+        if !enable_upnp {
+            self.config
+                .protocol_priority
+                .retain(|&protocol| protocol != MappingProtocol::Upnp);
+        }
+        self
+    }
+
+    /// Sets [`Config::enable_pcp`].
+    ///
+    /// Disabling it also removes it from [`Config::protocol_priority`].
+    #[cfg(feature = "pcp")]
+    pub fn enable_pcp(mut self, enable_pcp: bool) -> Self {
+        self.config.enable_pcp = enable_pcp;
+        if !enable_pcp {
+            self.config
+                .protocol_priority
+                .retain(|&protocol| protocol != MappingProtocol::Pcp);
+        }
+        self
+    }
+
+    /// Sets [`Config::enable_nat_pmp`].
+    ///
+    /// Disabling it also removes it from [`Config::protocol_priority`].
+    #[cfg(feature = "nat_pmp")]
+    pub fn enable_nat_pmp(mut self, enable_nat_pmp: bool) -> Self {
+        self.config.enable_nat_pmp = enable_nat_pmp;
+        if !enable_nat_pmp {
+            self.config
+                .protocol_priority
+                .retain(|&protocol| protocol != MappingProtocol::NatPmp);
+        }
+        self
+    }
+
+    /// Sets [`Config::protocol`].
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = protocol;
+        self
+    }
+
+    /// Sets [`Config::protocol_priority`].
+    pub fn protocol_priority(mut self, protocol_priority: Vec<MappingProtocol>) -> Self {
+        self.config.protocol_priority = protocol_priority;
+        self
+    }
+
+    /// Sets [`Config::protocol_denylist`].
+    pub fn protocol_denylist(mut self, protocol_denylist: Vec<DenylistEntry>) -> Self {
+        self.config.protocol_denylist = protocol_denylist;
+        self
+    }
+
+    /// Sets [`Config::probe_history_capacity`].
+    pub fn probe_history_capacity(mut self, probe_history_capacity: usize) -> Self {
+        self.config.probe_history_capacity = probe_history_capacity;
+        self
+    }
+
+    /// Sets [`Config::max_mappings`].
+    pub fn max_mappings(mut self, max_mappings: Option<usize>) -> Self {
+        self.config.max_mappings = max_mappings;
+        self
+    }
+
+    /// Sets [`Config::gateway_override`].
+    pub fn gateway_override(mut self, gateway_override: Option<Ipv4Addr>) -> Self {
+        self.config.gateway_override = gateway_override;
+        self
+    }
+
+    /// Sets [`Config::local_ip_override`].
+    pub fn local_ip_override(mut self, local_ip_override: Option<Ipv4Addr>) -> Self {
+        self.config.local_ip_override = local_ip_override;
+        self
+    }
+
+    /// Sets [`Config::pcp_third_party`].
+    #[cfg(feature = "pcp")]
+    pub fn pcp_third_party(mut self, pcp_third_party: Option<Ipv4Addr>) -> Self {
+        self.config.pcp_third_party = pcp_third_party;
+        self
+    }
+
+    /// Sets [`Config::timeouts`].
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.config.timeouts = timeouts;
+        self
+    }
+
+    /// Sets [`Config::mapping_description`].
+    pub fn mapping_description(mut self, mapping_description: impl Into<String>) -> Self {
+        self.config.mapping_description = mapping_description.into();
+        self
+    }
+
+    /// Sets [`Config::cleanup_stale_mappings`].
+    pub fn cleanup_stale_mappings(mut self, cleanup_stale_mappings: bool) -> Self {
+        self.config.cleanup_stale_mappings = cleanup_stale_mappings;
+        self
+    }
+
+    /// Sets [`Config::mapping_retry`].
+    pub fn mapping_retry(mut self, mapping_retry: Option<RetryBackoff>) -> Self {
+        self.config.mapping_retry = mapping_retry;
+        self
+    }
+
+    /// Sets [`Config::renewal_fraction`].
+    pub fn renewal_fraction(mut self, renewal_fraction: f32) -> Self {
+        self.config.renewal_fraction = renewal_fraction;
+        self
+    }
+
+    /// Sets [`Config::renewal_jitter`].
+    pub fn renewal_jitter(mut self, renewal_jitter: bool) -> Self {
+        self.config.renewal_jitter = renewal_jitter;
+        self
+    }
+
+    /// Sets [`Config::listen_for_announcements`].
+    pub fn listen_for_announcements(mut self, listen_for_announcements: bool) -> Self {
+        self.config.listen_for_announcements = listen_for_announcements;
+        self
+    }
+
+    /// Sets [`Config::allow_private_external`].
+    pub fn allow_private_external(mut self, allow_private_external: bool) -> Self {
+        self.config.allow_private_external = allow_private_external;
+        self
+    }
+
+    /// Sets [`Config::initial_probe`].
+    pub fn initial_probe(mut self, initial_probe: Option<ProbeOutput>) -> Self {
+        self.config.initial_probe = initial_probe;
+        self
+    }
+
+    /// Sets [`Config::socket_factory`].
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    pub fn socket_factory(mut self, socket_factory: Option<SocketFactory>) -> Self {
+        self.config.socket_factory = socket_factory;
+        self
+    }
+
+    /// Sets [`Config::socket_mark`].
+    #[cfg(all(target_os = "linux", any(feature = "pcp", feature = "nat_pmp")))]
+    pub fn socket_mark(mut self, socket_mark: Option<u32>) -> Self {
+        self.config.socket_mark = socket_mark;
+        self
+    }
+
+    /// Sets [`Config::packet_capture`].
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    pub fn packet_capture(mut self, packet_capture: Option<PacketCapture>) -> Self {
+        self.config.packet_capture = packet_capture;
+        self
+    }
+
+    /// Sets [`Config::reachability_check`].
+    pub fn reachability_check(mut self, reachability_check: Option<ReachabilityCheck>) -> Self {
+        self.config.reachability_check = reachability_check;
+        self
+    }
+
+    /// Sets [`Config::server_port_override`].
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn server_port_override(mut self, server_port_override: Option<u16>) -> Self {
+        self.config.server_port_override = server_port_override;
+        self
+    }
+
+    /// Returns the configuration.
+    ///
+    /// It's not validated until it's passed to [`Client::new`], see [`Config::validate`].
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+/// Port mapping client.
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// A watcher over the most recent external address obtained from port mapping.
+    ///
+    /// See [`watch::Receiver`].
+    port_mapping: watch::Receiver<Option<SocketAddrV4>>,
+    /// A watcher over the most recent external address obtained from PCP over IPv6.
+    port_mapping_v6: watch::Receiver<Option<SocketAddrV6>>,
+    /// A watcher over the details of the active mapping.
+    mapping_info: watch::Receiver<Option<MappingInfo>>,
+    /// A watcher over the external addresses of all mapped ports, keyed by local port.
+    external_addresses: watch::Receiver<BTreeMap<NonZeroU16, SocketAddrV4>>,
+    /// A watcher over the most recent probe results, oldest first.
+    probe_history: watch::Receiver<ProbeHistory>,
+    /// A watcher over the identity of the UPnP gateway, while it's trusted.
+    upnp_gateway_info: watch::Receiver<Option<GatewayInfo>>,
+    /// Channel used to communicate with the port mapping service.
+    service_tx: mpsc::Sender<Message>,
+    /// Metrics collected by the service.
+    metrics: Arc<Metrics>,
+    /// A handle to the service that will cancel the spawned task once the client is dropped.
+    _service_handle: std::sync::Arc<AbortOnDropHandle<()>>,
+}
+
+/// Only available with a port mapping protocol, the default [`Config`] is rejected otherwise.
+#[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+impl Default for Client {
+    fn default() -> Self {
+        Self::new(Config::default()).expect("default config is valid")
+    }
+}
+
+impl Client {
+    /// Create a new port mapping client.
+    ///
+    /// The service is spawned on the current tokio runtime, so this panics outside of one, see
+    /// [`Client::new_in`]. Fails if the [`Config`] is invalid, see [`Config::validate`].
+    pub fn new(config: Config) -> Result<Self, ConfigError> {
+        Self::with_metrics(config, Default::default())
+    }
+
+    /// Like [`Client::new`], but fails instead of panicking outside of a tokio runtime.
+    ///
+    /// Meant for libraries that can't guarantee a runtime when the client is created.
+    pub fn try_new(config: Config) -> Result<Self, NewClientError> {
+        let handle = tokio::runtime::Handle::try_current()?;
+        Ok(Self::spawn(config, Default::default(), &handle)?)
+    }
+
+    /// Creates a new port mapping client with a previously created metrics collector.
+    ///
+    /// Fails if the [`Config`] is invalid, see [`Config::validate`].
+    pub fn with_metrics(config: Config, metrics: Arc<Metrics>) -> Result<Self, ConfigError> {
+        Self::spawn(config, metrics, &tokio::runtime::Handle::current())
+    }
+
+    /// Creates a new port mapping client whose service runs on the runtime of `handle`.
+    ///
+    /// Unlike [`Client::new`] this doesn't need to be called from within a tokio runtime, so that
+    /// the service can be kept on e.g. a dedicated networking runtime.
+    ///
+    /// Fails if the [`Config`] is invalid, see [`Config::validate`].
+    pub fn new_in(config: Config, handle: tokio::runtime::Handle) -> Result<Self, ConfigError> {
+        Self::spawn(config, Default::default(), &handle)
+    }
+
+    /// Spawns the service on the runtime of `handle` and creates its client.
+    fn spawn(
+        config: Config,
+        metrics: Arc<Metrics>,
+        handle: &tokio::runtime::Handle,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let (service_tx, service_rx) = mpsc::channel(SERVICE_CHANNEL_CAPACITY);
+
+        let (service, watcher, probe_history) = Service::new(config, service_rx, metrics.clone());
+        let mapping_info = service.current_mapping.watch_info();
+        let external_addresses = service.external_addresses.subscribe();
+        let port_mapping_v6 = service.mapping_v6.watch_external();
+        let upnp_gateway_info = service.upnp_gateway_info.subscribe();
+
+        let handle = AbortOnDropHandle::new(handle.spawn(
+            async move { service.run().await }.instrument(info_span!("portmapper.service")),
+        ));
+
+        Ok(Client {
+            port_mapping: watcher,
+            port_mapping_v6,
+            mapping_info,
+            external_addresses,
+            probe_history,
+            upnp_gateway_info,
+            service_tx,
+            metrics,
+            _service_handle: std::sync::Arc::new(handle),
+        })
+    }
+
+    /// Request a probe to the port mapping protocols.
+    ///
+    /// Returns the [`oneshot::Receiver`] used to obtain the result of the probe.
+    pub fn probe(&self) -> oneshot::Receiver<Result<ProbeOutput, ProbeError>> {
+        self.send_probe(false, false)
+    }
+
+    /// Request a probe to the port mapping protocols that can be cancelled.
+    ///
+    /// Unlike with [`Client::probe`], dropping the returned [`ProbeHandle`] gives up on the
+    /// result: once every requester of the ongoing probe is gone this way, the probe is aborted.
+    pub fn cancellable_probe(&self) -> ProbeHandle {
+        ProbeHandle {
+            result_rx: self.send_probe(false, true),
+        }
+    }
+
+    /// Request a fresh probe to the port mapping protocols, ignoring previous results.
+    ///
+    /// Useful after a network change, since otherwise protocols seen within the last ten minutes
+    /// are not probed again. A probe already in progress is joined rather than restarted.
+    ///
+    /// Returns the [`oneshot::Receiver`] used to obtain the result of the probe.
+    pub fn force_probe(&self) -> oneshot::Receiver<Result<ProbeOutput, ProbeError>> {
+        self.send_probe(true, false)
+    }
+
+    fn send_probe(
+        &self,
+        force: bool,
+        cancellable: bool,
+    ) -> oneshot::Receiver<Result<ProbeOutput, ProbeError>> {
+        let (result_tx, result_rx) = oneshot::channel();
+
+        if let Err(e) = self.service_tx.try_send(Message::Probe {
+            force,
+            cancellable,
+            result_tx,
+        }) {
+            use mpsc::error::TrySendError::*;
+
+            // recover the sender and return the error there
+            let (result_tx, e) = match e {
+                Full(Message::Probe { result_tx, .. }) => (result_tx, e!(ProbeError::ChannelFull)),
+                Closed(Message::Probe { result_tx, .. }) => {
+                    (result_tx, e!(ProbeError::ChannelClosed))
+                }
+                Full(_) | Closed(_) => unreachable!("Sent value is a probe."),
+            };
+
+            // sender was just created. If it's dropped we have two send error and are likely
+            // shutting down
+            // NOTE: second Err is infallible match due to being the sent value
+            if let Err(Err(e)) = result_tx.send(Err(e)) {
+                trace!("Failed to request probe: {e}")
+            }
+        }
+        result_rx
+    }
+
+    /// Probe the port mapping protocols and report what was found, without creating mappings.
+    ///
+    /// Useful to diagnose which gateway is used and which protocols it supports.
+    pub fn diagnostics(&self) -> impl Future<Output = Diagnostics> + Send + 'static {
+        let probe_rx = self.probe();
+        let service_tx = self.service_tx.clone();
+        async move {
+            let probe = probe_rx
+                .await
+                .unwrap_or_else(|_| Err(e!(ProbeError::ChannelClosed)));
+            // gather the details once the probe has updated them
+            let (result_tx, result_rx) = oneshot::channel();
+            let details = match service_tx.send(Message::GatewayDetails { result_tx }).await {
+                Ok(()) => result_rx.await.unwrap_or_default(),
+                Err(e) => {
+                    trace!("Failed to request gateway details {e}");
+                    GatewayDetails::default()
+                }
+            };
+            let GatewayDetails {
+                local_ip,
+                gateway,
+                upnp_gateway_url,
+                pcp_probe_addr,
+                nat_pmp_probe_addr,
+                #[cfg(feature = "upnp")]
+                upnp_probe_error,
+                probe_timing: _,
+            } = details;
+            Diagnostics {
+                local_ip,
+                gateway,
+                upnp_gateway_url,
+                pcp_probe_addr,
+                nat_pmp_probe_addr,
+                #[cfg(feature = "upnp")]
+                upnp_probe_error,
+                probe,
+            }
+        }
+    }
+
+    /// Probe the port mapping protocols again, reporting how long each of them took to answer.
+    ///
+    /// As with [`Client::force_probe`] previous results are ignored, so that every enabled
+    /// protocol is timed.
+    pub fn probe_detailed(
+        &self,
+    ) -> impl Future<Output = Result<(ProbeOutput, ProbeTiming), ProbeError>> + Send + 'static {
+        let probe_rx = self.force_probe();
+        let service_tx = self.service_tx.clone();
+        async move {
+            let output = probe_rx
+                .await
+                .unwrap_or_else(|_| Err(e!(ProbeError::ChannelClosed)))?;
+            let (result_tx, result_rx) = oneshot::channel();
+            service_tx
+                .send(Message::GatewayDetails { result_tx })
+                .await
+                .map_err(|_| e!(ProbeError::ChannelClosed))?;
+            let details = result_rx.await.map_err(|_| e!(ProbeError::ChannelClosed))?;
+            Ok((output, details.probe_timing))
+        }
+    }
+
+    /// Ask the gateway for its external address, without creating a mapping.
+    ///
+    /// The protocols found available by a probe are asked in turn, NAT-PMP first and then UPnP,
+    /// and the first public address reported is returned. PCP can't be asked without mapping a
+    /// port, so it is not used. Neither the current mapping nor
+    /// [`Client::watch_external_address`] are affected.
+    pub fn external_ip(&self) -> impl Future<Output = Option<Ipv4Addr>> + Send + 'static {
+        self.query_external_ip(false)
+    }
+
+    /// Whether the router is likely behind carrier-grade NAT, in which case port mapping is futile
+    /// and relays should be used instead.
+    ///
+    /// Decided by [`MappingInfo::is_cgnat`] if there is a mapping. Otherwise the gateway is asked
+    /// for its external address as with [`Client::external_ip`], accepting shared and private
+    /// ones regardless of [`Config::allow_private_external`]. `None` if neither is available.
+    pub fn likely_behind_cgnat(&self) -> impl Future<Output = Option<bool>> + Send + 'static {
+        let info = self.mapping_info();
+        let query = info.is_none().then(|| self.query_external_ip(true));
+        async move {
+            match (info, query) {
+                (Some(info), _) => Some(info.is_cgnat()),
+                (None, Some(query)) => query.await.map(mapping::is_private_or_shared),
+                (None, None) => None,
+            }
+        }
+    }
+
+    /// Asks the gateway for its external address, see [`Client::external_ip`].
+    ///
+    /// With `allow_private`, shared and private addresses are returned even if the [`Config`]
+    /// doesn't allow them.
+    fn query_external_ip(
+        &self,
+        allow_private: bool,
+    ) -> impl Future<Output = Option<Ipv4Addr>> + Send + 'static {
+        let probe_rx = self.probe();
+        let service_tx = self.service_tx.clone();
+        async move {
+            // the query depends on which protocols the probe found
+            if let Ok(Err(e)) = probe_rx.await {
+                debug!("probe failed: {e}");
+            }
+            let (result_tx, result_rx) = oneshot::channel();
+            if let Err(e) = service_tx
+                .send(Message::ExternalIpQuery {
+                    allow_private,
+                    result_tx,
+                })
+                .await
+            {
+                trace!("Failed to request external ip query {e}");
+                return None;
+            }
+            result_rx.await.ok()?.run().await
+        }
+    }
+
+    /// Map `local_port` and resolve with the external address once the attempt finishes.
+    ///
+    /// This sets the local port as [`Client::update_local_port`] does, so the mapping is kept
+    /// and renewed afterwards. If there already is a mapping for this port it is returned right
+    /// away.
+    ///
+    /// Only IPv4 mappings are reported, see [`Client::watch_external_address_v6`] for IPv6.
+    pub fn get_mapping_once(
+        &self,
+        local_port: NonZeroU16,
+    ) -> impl Future<Output = Result<SocketAddrV4, MappingError>> + Send + 'static {
+        let (result_tx, result_rx) = oneshot::channel();
+        let sent = self
+            .service_tx
+            .try_send(Message::GetMappingOnce {
+                local_port,
+                result_tx,
+            })
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => e!(MappingError::ChannelFull),
+                mpsc::error::TrySendError::Closed(_) => e!(MappingError::ChannelClosed),
+            });
+        async move {
+            sent?;
+            result_rx
+                .await
+                .map_err(|_| e!(MappingError::ChannelClosed))?
+        }
+    }
+
+    /// Try to get a mapping for the last local port if there isn't one already.
+    ///
+    /// The request is dropped if the service is busy, see [`Client::try_procure_mapping`].
+    pub fn procure_mapping(&self) {
         // requester can't really do anything with this error if returned, so we log it
-        if let Err(e) = self
+        if let Err(e) = self.try_procure_mapping() {
+            trace!("Failed to request mapping {e}")
+        }
+    }
+
+    /// Like [`Client::procure_mapping`], but reports whether the request was queued.
+    ///
+    /// Fails with [`ServiceSendError::Full`] if the service has too many pending requests, in
+    /// which case the request can be retried later.
+    pub fn try_procure_mapping(&self) -> Result<(), ServiceSendError> {
+        self.try_send(Message::ProcureMapping)
+    }
+
+    /// Update the local port.
+    ///
+    /// If the port changes, this will trigger a port mapping attempt. The update is dropped if
+    /// the service is busy, see [`Client::try_update_local_port`].
+    pub fn update_local_port(&self, local_port: NonZeroU16) {
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self.try_update_local_port(local_port) {
+            trace!("Failed to update local port {e}")
+        }
+    }
+
+    /// Like [`Client::update_local_port`], but reports whether the update was queued.
+    ///
+    /// Fails with [`ServiceSendError::Full`] if the service has too many pending requests, in
+    /// which case the update can be retried later.
+    pub fn try_update_local_port(&self, local_port: NonZeroU16) -> Result<(), ServiceSendError> {
+        let local_port = Some(local_port);
+        self.try_send(Message::UpdateLocalPort { local_port })
+    }
+
+    /// Queues `msg` for the service without waiting.
+    fn try_send(&self, msg: Message) -> Result<(), ServiceSendError> {
+        self.service_tx.try_send(msg).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => e!(ServiceSendError::Full),
+            mpsc::error::TrySendError::Closed(_) => e!(ServiceSendError::Closed),
+        })
+    }
+
+    /// Map another local port, besides the one set with [`Client::update_local_port`].
+    ///
+    /// Each added port gets its own mapping, renewed independently of the others. Their external
+    /// addresses are reported by [`Client::watch_external_addresses`], but not by the watchers
+    /// and events of the local port.
+    ///
+    /// The port is added right away, the returned future only reports whether that succeeded.
+    /// Ports beyond [`Config::max_mappings`] are not mapped and fail with
+    /// [`MappingError::MaxMappingsExceeded`]. Adding a port that is already mapped succeeds.
+    pub fn add_port(
+        &self,
+        local_port: NonZeroU16,
+    ) -> impl Future<Output = Result<(), MappingError>> + Send + 'static {
+        let (result_tx, result_rx) = oneshot::channel();
+        let sent = self
             .service_tx
-            .try_send(Message::UpdateLocalPort { local_port: None })
-        {
-            trace!("Failed to deactivate port mapping {e}")
+            .try_send(Message::AddPort {
+                local_port,
+                result_tx,
+            })
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => e!(MappingError::ChannelFull),
+                mpsc::error::TrySendError::Closed(_) => e!(MappingError::ChannelClosed),
+            });
+        async move {
+            sent?;
+            result_rx
+                .await
+                .map_err(|_| e!(MappingError::ChannelClosed))?
+        }
+    }
+
+    /// Map `local_port` for traffic with `remote_ip` on `remote_port` only, with the PCP PEER
+    /// opcode.
+    ///
+    /// Meant for hole punching with a known peer. Fails with [`MappingError::NoProtocol`] unless
+    /// the last probe found PCP available and it is neither disabled nor denylisted. Unlike the
+    /// mappings of [`Client::update_local_port`], the service neither tracks nor renews the
+    /// mapping, see [`PeerMapping`].
+    #[cfg(feature = "pcp")]
+    pub fn map_peer(
+        &self,
+        local_port: NonZeroU16,
+        remote_ip: Ipv4Addr,
+        remote_port: NonZeroU16,
+    ) -> impl Future<Output = Result<PeerMapping, MappingError>> + Send + 'static {
+        let probe_rx = self.probe();
+        let service_tx = self.service_tx.clone();
+        async move {
+            // whether PCP can be used depends on the probe
+            if let Ok(Err(e)) = probe_rx.await {
+                debug!("probe failed: {e}");
+            }
+            let (result_tx, result_rx) = oneshot::channel();
+            service_tx
+                .send(Message::PeerMappingRequest { result_tx })
+                .await
+                .map_err(|_| e!(MappingError::ChannelClosed))?;
+            let request = result_rx
+                .await
+                .map_err(|_| e!(MappingError::ChannelClosed))??;
+            request.run(local_port, (remote_ip, remote_port)).await
+        }
+    }
+
+    /// Stop mapping a port added with [`Client::add_port`], releasing its mapping.
+    pub fn remove_port(&self, local_port: NonZeroU16) {
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self.service_tx.try_send(Message::RemovePort { local_port }) {
+            trace!("Failed to remove port {e}")
+        }
+    }
+
+    /// Request that mappings use the given external port.
+    ///
+    /// The port is asked for with whichever protocol ends up making the mapping, replacing the
+    /// active mapping if it uses a different port. Routers are free to refuse it, in which case
+    /// any port is mapped and [`MappingEvent::ExternalPortSubstituted`] is emitted.
+    pub fn request_external_port(&self, external_port: NonZeroU16) {
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self
+            .service_tx
+            .try_send(Message::RequestExternalPort { external_port })
+        {
+            trace!("Failed to request external port {e}")
+        }
+    }
+
+    /// Release the mapping of `external_port` made with `protocol`, e.g. one left behind by an
+    /// earlier run.
+    ///
+    /// If it's the mapping of the local port or of an added port, that mapping is released and
+    /// not renewed until the next [`Client::procure_mapping`]. Otherwise the release is sent to
+    /// the gateway regardless. NAT-PMP and PCP identify mappings by their internal port, so an
+    /// unknown mapping is only released if it was made for the local port equal to
+    /// `external_port`, and PCP servers checking the mapping's nonce refuse to release it.
+    pub fn release_external_port(&self, protocol: MappingProtocol, external_port: NonZeroU16) {
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self.service_tx.try_send(Message::ReleaseExternalPort {
+            protocol,
+            external_port,
+        }) {
+            trace!("Failed to release external port {e}")
+        }
+    }
+
+    /// Deactivate port mapping.
+    pub fn deactivate(&self) {
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self
+            .service_tx
+            .try_send(Message::UpdateLocalPort { local_port: None })
+        {
+            trace!("Failed to deactivate port mapping {e}")
+        }
+    }
+
+    /// Provide the current network [`State`], e.g. as reported by a `netwatch::netmon::Monitor`.
+    ///
+    /// Once a state has been provided, the gateway and local ip used for probing and mapping
+    /// are derived from the most recent one instead of being queried from the OS each time.
+    pub fn update_interface_state(&self, state: State) {
+        let state = Box::new(state);
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self
+            .service_tx
+            .try_send(Message::UpdateInterfaceState { state })
+        {
+            trace!("Failed to update interface state {e}")
+        }
+    }
+
+    /// Inform the service that the network changed, e.g. as detected by a
+    /// `netwatch::netmon::Monitor`.
+    ///
+    /// The last probe is no longer trusted and the active mappings are released, as they were
+    /// likely made with a different gateway. A new probe is started and all ports are mapped
+    /// again once it completes, with the protocols it found.
+    pub fn on_network_change(&self) {
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self.service_tx.try_send(Message::NetworkChanged) {
+            trace!("Failed to report network change {e}")
+        }
+    }
+
+    /// Release all active mappings and stop the service.
+    ///
+    /// Returns once the release requests have been handed to the OS, so that the router is
+    /// notified even if the process exits right after. Other clones of this client will no longer
+    /// be able to get mappings.
+    ///
+    /// Dropping the last clone of the client stops the service without releasing anything, the
+    /// mappings then linger on the router until they expire.
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if let Err(e) = self.service_tx.send(Message::Shutdown { done_tx }).await {
+            trace!("Failed to request shutdown {e}");
+            return;
+        }
+        // if the service is already gone there is nothing left to wait for
+        let _ = done_rx.await;
+    }
+
+    /// Watch the external address for changes in the mappings.
+    ///
+    /// Use [`Client::watch_mapping_info`] to also learn which protocol produced the mapping.
+    pub fn watch_external_address(&self) -> watch::Receiver<Option<SocketAddrV4>> {
+        self.port_mapping.clone()
+    }
+
+    /// Stream of the external address, see [`Client::watch_external_address`].
+    ///
+    /// Yields the current address first, then every change. Intermediate values are skipped for a
+    /// consumer that does not keep up. The stream ends once the service stops.
+    pub fn external_address_stream(
+        &self,
+    ) -> impl Stream<Item = Option<SocketAddrV4>> + Unpin + use<> {
+        let mut port_mapping = self.port_mapping.clone();
+        port_mapping.mark_changed();
+        Box::pin(n0_future::stream::unfold(
+            port_mapping,
+            |mut port_mapping| async move {
+                port_mapping.changed().await.ok()?;
+                let addr = *port_mapping.borrow_and_update();
+                Some((addr, port_mapping))
+            },
+        ))
+    }
+
+    /// Watch the external addresses of all mapped ports, keyed by local port.
+    ///
+    /// Includes the local port set with [`Client::update_local_port`] as well as the ports added
+    /// with [`Client::add_port`], each while it has a mapping.
+    pub fn watch_external_addresses(&self) -> watch::Receiver<BTreeMap<NonZeroU16, SocketAddrV4>> {
+        self.external_addresses.clone()
+    }
+
+    /// Addresses at which peers can likely reach `local_port` on this machine.
+    ///
+    /// The external address of the port's mapping comes first, if there is one. It is followed by
+    /// the public IPv4, and global and unique local IPv6 addresses of the interfaces that are up,
+    /// paired with `local_port`. Interfaces are taken from the state provided with
+    /// [`Client::update_interface_state`], or queried from the OS if none was.
+    pub fn reachable_addresses(
+        &self,
+        local_port: NonZeroU16,
+    ) -> impl Future<Output = Vec<SocketAddr>> + Send + 'static {
+        let external = self.external_addresses.borrow().get(&local_port).copied();
+        let service_tx = self.service_tx.clone();
+        async move {
+            let (result_tx, result_rx) = oneshot::channel();
+            let state = match service_tx.send(Message::InterfaceState { result_tx }).await {
+                Ok(()) => result_rx.await.ok().flatten(),
+                Err(e) => {
+                    trace!("Failed to request interface state {e}");
+                    None
+                }
+            };
+            let state = match state {
+                Some(state) => state,
+                None => State::new().await,
+            };
+            reachable::reachable_addresses(external, reachable::interface_ips(&state), local_port)
+        }
+    }
+
+    /// Watch the external IPv6 address for changes in the mappings.
+    ///
+    /// When the gateway can only be reached over IPv6, PCP is used to map the port over IPv6
+    /// instead. Such mappings are only reported here.
+    pub fn watch_external_address_v6(&self) -> watch::Receiver<Option<SocketAddrV6>> {
+        self.port_mapping_v6.clone()
+    }
+
+    /// Subscribe to changes in the lifecycle of the port mapping.
+    ///
+    /// Events are dropped for a subscriber that does not keep up. Dropping the receiver
+    /// unsubscribes.
+    pub fn subscribe_events(&self) -> mpsc::Receiver<MappingEvent> {
+        let (events_tx, events_rx) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+        // requester can't really do anything with this error if returned, so we log it
+        if let Err(e) = self.service_tx.try_send(Message::Subscribe { events_tx }) {
+            trace!("Failed to subscribe to events {e}")
+        }
+        events_rx
+    }
+
+    /// Returns the details of the active mapping, if any.
+    pub fn mapping_info(&self) -> Option<MappingInfo> {
+        self.mapping_info.borrow().clone()
+    }
+
+    /// Watch the details of the active mapping.
+    ///
+    /// Updated whenever a mapping is acquired, renewed, expires or is released.
+    pub fn watch_mapping_info(&self) -> watch::Receiver<Option<MappingInfo>> {
+        self.mapping_info.clone()
+    }
+
+    /// Returns the identity of the UPnP gateway found by the last probe, if any.
+    ///
+    /// Useful to correlate mapping failures with specific router models.
+    pub fn upnp_gateway_info(&self) -> Option<GatewayInfo> {
+        self.upnp_gateway_info.borrow().clone()
+    }
+
+    /// Returns the most recent probe results with the time they were obtained, oldest first.
+    ///
+    /// At most [`Config::probe_history_capacity`] results are kept.
+    pub fn probe_history(&self) -> Vec<(Instant, ProbeOutput)> {
+        self.probe_history.borrow().iter().cloned().collect()
+    }
+
+    /// Returns the metrics collected by the service.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+}
+
+/// Port mapping protocol information obtained during a probe.
+#[derive(Debug)]
+struct Probe {
+    /// When was the probe last updated.
+    last_probe: Instant,
+    /// The last [`upnp::Gateway`] and when was it last seen.
+    #[cfg(feature = "upnp")]
+    last_upnp_gateway_addr: Option<(upnp::Gateway, Instant)>,
+    /// Identity reported by the last UPnP gateway, if it could be obtained.
+    upnp_gateway_info: Option<GatewayInfo>,
+    /// Last time PCP was seen.
+    last_pcp: Option<Instant>,
+    /// Last time NAT-PMP was seen.
+    last_nat_pmp: Option<Instant>,
+    /// Local address the last PCP probe was sent from.
+    pcp_probe_addr: Option<SocketAddrV4>,
+    /// Local address the last NAT-PMP probe was sent from.
+    nat_pmp_probe_addr: Option<SocketAddrV4>,
+    /// Why the last UPnP probe found no gateway, cleared once one is found.
+    #[cfg(feature = "upnp")]
+    upnp_probe_error: Option<Arc<upnp::Error>>,
+    /// Round-trip time of the last probe that reached each protocol.
+    timing: ProbeTiming,
+}
+
+impl Probe {
+    /// An empty probe set to `now`.
+    fn empty() -> Self {
+        Self {
+            last_probe: Instant::now(),
+            #[cfg(feature = "upnp")]
+            last_upnp_gateway_addr: None,
+            upnp_gateway_info: None,
+            last_pcp: None,
+            last_nat_pmp: None,
+            pcp_probe_addr: None,
+            nat_pmp_probe_addr: None,
+            #[cfg(feature = "upnp")]
+            upnp_probe_error: None,
+            timing: ProbeTiming::default(),
+        }
+    }
+
+    /// An empty probe old enough that it's no longer trusted.
+    fn expired() -> Self {
+        let mut probe = Self::empty();
+        if let Some(in_the_past) = probe.last_probe.checked_sub(AVAILABILITY_TRUST_DURATION) {
+            probe.last_probe = in_the_past;
+        }
+        probe
+    }
+
+    /// A probe set to `now`, with the enabled protocols `output` reports as available seen now.
+    ///
+    /// UPnP is never considered available, since `output` lacks the gateway's address.
+    fn seeded(output: &ProbeOutput, config: &Config) -> Self {
+        let mut probe = Probe::empty();
+        let now = probe.last_probe;
+        probe.last_pcp = (output.pcp && config.pcp_enabled()).then_some(now);
+        probe.last_nat_pmp = (output.nat_pmp && config.nat_pmp_enabled()).then_some(now);
+        probe.upnp_gateway_info = output.upnp_gateway.clone();
+        probe
+    }
+
+    /// Create a new probe based on a previous output.
+    async fn from_output(
+        config: Config,
+        #[cfg_attr(
+            not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "nothing is probed without a protocol")
+        )]
+        output: ProbeOutput,
+        #[cfg_attr(
+            not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "nothing is probed without a protocol")
+        )]
+        local_ip: Ipv4Addr,
+        #[cfg_attr(
+            not(any(feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "UPnP discovers the gateway on its own")
+        )]
+        gateway: Ipv4Addr,
+        metrics: Arc<Metrics>,
+    ) -> Probe {
+        #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+        let sockets = config.socket_options();
+        let Config {
+            #[cfg(feature = "upnp")]
+            enable_upnp,
+            #[cfg(feature = "pcp")]
+            enable_pcp,
+            #[cfg(feature = "nat_pmp")]
+            enable_nat_pmp,
+            protocol: _,
+            protocol_priority: _,
+            protocol_denylist: _,
+            probe_history_capacity: _,
+            max_mappings: _,
+            gateway_override: _,
+            local_ip_override: _,
+            #[cfg(feature = "pcp")]
+                pcp_third_party: _,
+            #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+            timeouts,
+            #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+                timeouts: _,
+            mapping_description: _,
+            cleanup_stale_mappings: _,
+            mapping_retry: _,
+            renewal_fraction: _,
+            renewal_jitter: _,
+            listen_for_announcements: _,
+            allow_private_external: _,
+            initial_probe: _,
+            #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+                socket_factory: _,
+            #[cfg(all(target_os = "linux", any(feature = "pcp", feature = "nat_pmp")))]
+                socket_mark: _,
+            #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+                packet_capture: _,
+            reachability_check: _,
+            #[cfg(any(test, feature = "test-util"))]
+                server_port_override: _,
+        } = config;
+        #[cfg(feature = "upnp")]
+        let mut upnp_probing_task = util::MaybeFuture {
+            inner: (enable_upnp && !output.upnp).then(|| {
+                let metrics = metrics.clone();
+                Box::pin(async move {
+                    let start = Instant::now();
+                    upnp::probe_available(
+                        &metrics,
+                        local_ip,
+                        timeouts.upnp_search,
+                        timeouts.upnp_description,
+                    )
+                    .await
+                    .map(|(addr, info)| (addr, info, start, Instant::now()))
+                })
+            }),
+        };
+        #[cfg(not(feature = "upnp"))]
+        let mut upnp_probing_task = util::MaybeFuture::<std::future::Pending<()>>::default();
+
+        #[cfg(feature = "pcp")]
+        let mut pcp_probing_task = util::MaybeFuture {
+            inner: (enable_pcp && !output.pcp).then(|| {
+                let metrics = metrics.clone();
+                let sockets = sockets.clone();
+                Box::pin(async move {
+                    metrics.pcp_probes.inc();
+                    let start = Instant::now();
+                    let (available, local_addr) =
+                        pcp::probe_available(local_ip, gateway, timeouts.pcp_recv, &sockets).await;
+                    (available.then(|| (start, Instant::now())), local_addr)
+                })
+            }),
+        };
+        #[cfg(not(feature = "pcp"))]
+        let mut pcp_probing_task = util::MaybeFuture::<
+            std::future::Pending<(Option<(Instant, Instant)>, Option<SocketAddrV4>)>,
+        >::default();
+
+        #[cfg(feature = "nat_pmp")]
+        let mut nat_pmp_probing_task = util::MaybeFuture {
+            inner: (enable_nat_pmp && !output.nat_pmp).then(|| {
+                let sockets = sockets.clone();
+                Box::pin(async move {
+                    let start = Instant::now();
+                    let (available, local_addr) = nat_pmp::probe_available(
+                        local_ip,
+                        gateway,
+                        timeouts.nat_pmp_recv,
+                        &sockets,
+                    )
+                    .await;
+                    (available.then(|| (start, Instant::now())), local_addr)
+                })
+            }),
+        };
+        #[cfg(not(feature = "nat_pmp"))]
+        let mut nat_pmp_probing_task = util::MaybeFuture::<
+            std::future::Pending<(Option<(Instant, Instant)>, Option<SocketAddrV4>)>,
+        >::default();
+
+        if upnp_probing_task.inner.is_some() {
+            metrics.upnp_probes.inc();
+        }
+
+        let mut upnp_done = upnp_probing_task.inner.is_none();
+        let mut pcp_done = pcp_probing_task.inner.is_none();
+        let mut nat_pmp_done = nat_pmp_probing_task.inner.is_none();
+
+        let mut probe = Probe::empty();
+
+        while !upnp_done || !pcp_done || !nat_pmp_done {
+            tokio::select! {
+                upnp_result = &mut upnp_probing_task, if !upnp_done => {
+                    trace!("tick: upnp probe ready");
+                    #[cfg(feature = "upnp")]
+                    match upnp_result {
+                        Ok((gateway, info, start, last_seen)) => {
+                            probe.last_upnp_gateway_addr = Some((gateway, last_seen));
+                            probe.upnp_gateway_info = info;
+                            probe.timing.upnp = Some(last_seen - start);
+                        }
+                        Err(e) => probe.upnp_probe_error = Some(Arc::new(e)),
+                    }
+                    #[cfg(not(feature = "upnp"))]
+                    let _: () = upnp_result;
+                    upnp_done = true;
+                },
+                (seen, local_addr) = &mut nat_pmp_probing_task, if !nat_pmp_done => {
+                    trace!("tick: nat_pmp probe ready");
+                    if let Some((start, last_seen)) = seen {
+                        probe.last_nat_pmp = Some(last_seen);
+                        probe.timing.nat_pmp = Some(last_seen - start);
+                    }
+                    probe.nat_pmp_probe_addr = local_addr;
+                    nat_pmp_done = true;
+                },
+                (seen, local_addr) = &mut pcp_probing_task, if !pcp_done => {
+                    trace!("tick: pcp probe ready");
+                    if let Some((start, last_seen)) = seen {
+                        probe.last_pcp = Some(last_seen);
+                        probe.timing.pcp = Some(last_seen - start);
+                    }
+                    probe.pcp_probe_addr = local_addr;
+                    pcp_done = true;
+                },
+            }
+        }
+
+        probe
+    }
+
+    /// Returns a [`ProbeOutput`] indicating which services can be considered available.
+    fn output(&self) -> ProbeOutput {
+        let now = Instant::now();
+
+        // check if the last UPnP gateway is valid
+        #[cfg(feature = "upnp")]
+        let upnp = self
+            .last_upnp_gateway_addr
+            .as_ref()
+            .map(|(_gateway_addr, last_probed)| *last_probed + AVAILABILITY_TRUST_DURATION > now)
+            .unwrap_or_default();
+        #[cfg(not(feature = "upnp"))]
+        let upnp = false;
+
+        let pcp = self
+            .last_pcp
+            .as_ref()
+            .map(|last_probed| *last_probed + AVAILABILITY_TRUST_DURATION > now)
+            .unwrap_or_default();
+
+        let nat_pmp = self
+            .last_nat_pmp
+            .as_ref()
+            .map(|last_probed| *last_probed + AVAILABILITY_TRUST_DURATION > now)
+            .unwrap_or_default();
+
+        // only report the identity while the gateway it belongs to is trusted
+        let upnp_gateway = upnp.then(|| self.upnp_gateway_info.clone()).flatten();
+
+        ProbeOutput {
+            upnp,
+            pcp,
+            nat_pmp,
+            upnp_gateway,
+        }
+    }
+
+    /// Updates a probe with the `Some` values of another probe that is _assumed_ newer.
+    fn update(&mut self, probe: Probe, metrics: &Arc<Metrics>) {
+        let Probe {
+            last_probe,
+            #[cfg(feature = "upnp")]
+            last_upnp_gateway_addr,
+            #[cfg_attr(
+                not(feature = "upnp"),
+                expect(unused_variables, reason = "only set by UPnP")
+            )]
+            upnp_gateway_info,
+            last_pcp,
+            last_nat_pmp,
+            pcp_probe_addr,
+            nat_pmp_probe_addr,
+            #[cfg(feature = "upnp")]
+            upnp_probe_error,
+            timing,
+        } = probe;
+        #[cfg(feature = "upnp")]
+        if last_upnp_gateway_addr.is_some() {
+            metrics.upnp_available.inc();
+            let new_gateway = last_upnp_gateway_addr
+                .as_ref()
+                .map(|(addr, _last_seen)| addr);
+            let old_gateway = self
+                .last_upnp_gateway_addr
+                .as_ref()
+                .map(|(addr, _last_seen)| addr);
+            if new_gateway != old_gateway {
+                metrics.upnp_gateway_updated.inc();
+                debug!(
+                    "upnp gateway changed {:?} -> {:?}",
+                    old_gateway
+                        .map(|gw| gw.to_string())
+                        .unwrap_or("None".into()),
+                    new_gateway
+                        .map(|gw| gw.to_string())
+                        .unwrap_or("None".into())
+                )
+            };
+            self.last_upnp_gateway_addr = last_upnp_gateway_addr;
+            self.upnp_gateway_info = upnp_gateway_info;
+            self.upnp_probe_error = None;
+        }
+        #[cfg(feature = "upnp")]
+        if upnp_probe_error.is_some() {
+            self.upnp_probe_error = upnp_probe_error;
+        }
+        if last_pcp.is_some() {
+            metrics.pcp_available.inc();
+            self.last_pcp = last_pcp;
+        }
+        if last_nat_pmp.is_some() {
+            self.last_nat_pmp = last_nat_pmp;
+        }
+        if pcp_probe_addr.is_some() {
+            self.pcp_probe_addr = pcp_probe_addr;
+        }
+        if nat_pmp_probe_addr.is_some() {
+            self.nat_pmp_probe_addr = nat_pmp_probe_addr;
+        }
+        let ProbeTiming { upnp, pcp, nat_pmp } = timing;
+        self.timing.upnp = upnp.or(self.timing.upnp);
+        self.timing.pcp = pcp.or(self.timing.pcp);
+        self.timing.nat_pmp = nat_pmp.or(self.timing.nat_pmp);
+
+        self.last_probe = last_probe;
+    }
+}
+
+/// Waits for the next mapping result or lifecycle event of any of the added ports.
+///
+/// Pending forever if there are no added ports.
+fn next_added_port_event(
+    added_ports: &mut HashMap<NonZeroU16, AddedPort>,
+) -> impl Future<Output = (NonZeroU16, AddedPortEvent)> + '_ {
+    std::future::poll_fn(move |cx| {
+        for (local_port, added) in added_ports.iter_mut() {
+            if let Some(task) = added.mapping_task.as_mut()
+                && let std::task::Poll::Ready(result) = Pin::new(task).poll(cx)
+            {
+                added.mapping_task = None;
+                return std::task::Poll::Ready((
+                    *local_port,
+                    AddedPortEvent::MappingResult(result),
+                ));
+            }
+            if let std::task::Poll::Ready(Some(event)) = added.current_mapping.poll_next(cx) {
+                return std::task::Poll::Ready((*local_port, AddedPortEvent::Mapping(event)));
+            }
+        }
+        std::task::Poll::Pending
+    })
+}
+
+// mainly to make clippy happy
+type ProbeResult = Result<ProbeOutput, ProbeError>;
+
+/// A requester waiting for the result of the probing task.
+#[derive(Debug)]
+struct ProbeWaiter {
+    result_tx: oneshot::Sender<ProbeResult>,
+    /// Whether the probe may be aborted once this requester is gone, see
+    /// [`Client::cancellable_probe`].
+    cancellable: bool,
+}
+
+/// The probing task with the requesters waiting for its result.
+type ProbingTask = (AbortOnDropHandle<Probe>, Vec<ProbeWaiter>);
+
+/// Waits for the probing task to finish, or to be abandoned by its requesters.
+///
+/// Resolves to `None` once every requester cancelled, see [`Client::cancellable_probe`].
+/// Cancellable requesters that went away are removed along the way. Pending forever if there is
+/// no probing task.
+fn next_probing_result(
+    probing_task: &mut Option<ProbingTask>,
+) -> impl Future<Output = Option<Result<Probe, tokio::task::JoinError>>> + '_ {
+    std::future::poll_fn(move |cx| {
+        let Some((task, waiters)) = probing_task.as_mut() else {
+            return std::task::Poll::Pending;
+        };
+        if let std::task::Poll::Ready(result) = Pin::new(task).poll(cx) {
+            return std::task::Poll::Ready(Some(result));
+        }
+        // polling registers interest in the remaining requesters going away
+        waiters.retain_mut(|waiter| {
+            !waiter.cancellable || waiter.result_tx.poll_closed(cx).is_pending()
+        });
+        if waiters.is_empty() {
+            return std::task::Poll::Ready(None);
+        }
+        std::task::Poll::Pending
+    })
+}
+
+/// Probe results with the time they were obtained, oldest first.
+type ProbeHistory = VecDeque<(Instant, ProbeOutput)>;
+
+/// A port added with [`Client::add_port`], mapped independently of the local port.
+#[derive(Debug)]
+struct AddedPort {
+    /// Currently active mapping.
+    current_mapping: CurrentMapping,
+    /// Task attempting to get a port mapping.
+    mapping_task: Option<AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>>>,
+    /// When the current mapping task started.
+    mapping_started: Option<Instant>,
+}
+
+/// Outcome of polling the ports added with [`Client::add_port`].
+#[derive(Debug)]
+enum AddedPortEvent {
+    /// A mapping task finished.
+    MappingResult(Result<Result<mapping::Mapping, mapping::Error>, tokio::task::JoinError>),
+    /// The active mapping is due for renewal or expired.
+    Mapping(current_mapping::Event),
+}
+
+/// PCP mapping over IPv6, made when the gateway is only reachable over IPv6.
+///
+/// Only PCP supports IPv6, so without it no mapping is ever made.
+#[derive(Debug)]
+struct MappingV6 {
+    /// Currently active mapping.
+    #[cfg(feature = "pcp")]
+    current_mapping: CurrentMapping<pcp::Mapping<Ipv6Addr>>,
+    /// Task attempting to get a mapping.
+    #[cfg(feature = "pcp")]
+    mapping_task: Option<AbortOnDropHandle<Result<pcp::Mapping<Ipv6Addr>, pcp::Error>>>,
+    /// External address, which is never set.
+    #[cfg(not(feature = "pcp"))]
+    address_tx: watch::Sender<Option<SocketAddrV6>>,
+}
+
+/// Outcome of polling the [`MappingV6`].
+#[derive(Debug)]
+enum MappingV6Event {
+    /// The mapping task finished.
+    #[cfg(feature = "pcp")]
+    MappingResult(Result<Result<pcp::Mapping<Ipv6Addr>, pcp::Error>, tokio::task::JoinError>),
+    /// The active mapping is due for renewal or expired.
+    #[cfg(feature = "pcp")]
+    Mapping(current_mapping::Event<Ipv6Addr>),
+}
+
+#[cfg(feature = "pcp")]
+impl MappingV6 {
+    fn new(metrics: Arc<Metrics>, renewal_fraction: f32, renewal_jitter: bool) -> Self {
+        let (current_mapping, _watcher) =
+            CurrentMapping::new(metrics, renewal_fraction, renewal_jitter);
+        MappingV6 {
+            current_mapping,
+            mapping_task: None,
+        }
+    }
+
+    /// Returns a watcher over the external address of the active mapping.
+    fn watch_external(&self) -> watch::Receiver<Option<SocketAddrV6>> {
+        self.current_mapping.watch_external()
+    }
+
+    /// External address of the active mapping, if any.
+    fn external(&self) -> Option<(Ipv6Addr, NonZeroU16)> {
+        self.current_mapping.external()
+    }
+
+    /// Whether a task attempting to get a mapping is underway.
+    fn is_pending(&self) -> bool {
+        self.mapping_task.is_some()
+    }
+
+    /// Stops the task attempting to get a mapping, if any.
+    fn cancel(&mut self) {
+        self.mapping_task = None;
+    }
+
+    /// Clears the active mapping and releases it.
+    async fn release(&mut self) {
+        if let Some(old_mapping) = self.current_mapping.update(None)
+            && let Err(e) = old_mapping.release().await
+        {
+            debug!("failed to release ipv6 mapping {e}");
+        }
+    }
+
+    /// Waits for the mapping task to finish or the active mapping to need attention.
+    fn next(&mut self) -> impl Future<Output = MappingV6Event> + '_ {
+        std::future::poll_fn(move |cx| {
+            if let Some(task) = self.mapping_task.as_mut()
+                && let std::task::Poll::Ready(result) = Pin::new(task).poll(cx)
+            {
+                self.mapping_task = None;
+                return std::task::Poll::Ready(MappingV6Event::MappingResult(result));
+            }
+            if let std::task::Poll::Ready(Some(event)) = self.current_mapping.poll_next(cx) {
+                return std::task::Poll::Ready(MappingV6Event::Mapping(event));
+            }
+            std::task::Poll::Pending
+        })
+    }
+}
+
+#[cfg(not(feature = "pcp"))]
+impl MappingV6 {
+    fn new(_metrics: Arc<Metrics>, _renewal_fraction: f32, _renewal_jitter: bool) -> Self {
+        MappingV6 {
+            address_tx: watch::Sender::new(None),
+        }
+    }
+
+    fn watch_external(&self) -> watch::Receiver<Option<SocketAddrV6>> {
+        self.address_tx.subscribe()
+    }
+
+    fn external(&self) -> Option<(Ipv6Addr, NonZeroU16)> {
+        None
+    }
+
+    fn is_pending(&self) -> bool {
+        false
+    }
+
+    fn cancel(&mut self) {}
+
+    #[expect(clippy::unused_async, reason = "mirrors the PCP version")]
+    async fn release(&mut self) {}
+
+    async fn next(&mut self) -> MappingV6Event {
+        std::future::pending().await
+    }
+}
+
+/// A port mapping client.
+#[derive(Debug)]
+pub struct Service {
+    config: Config,
+    /// Local port to map.
+    local_port: Option<NonZeroU16>,
+    /// Most recent network state provided by the user, if any.
+    interface_state: Option<State>,
+    /// Channel over which the service is informed of messages.
+    ///
+    /// The service will stop when all senders are gone.
+    rx: mpsc::Receiver<Message>,
+    /// Currently active mapping.
+    current_mapping: CurrentMapping,
+    /// Last updated probe.
+    full_probe: Probe,
+    /// Task attempting to get a port mapping.
+    ///
+    /// This task will be cancelled if a request to set the local port arrives before it's
+    /// finished.
+    mapping_task: Option<AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>>>,
+    /// When the current mapping task started, to measure how long acquiring the mapping took.
+    mapping_started: Option<Instant>,
+    /// Identifier of the last mapping attempt, recorded in its span.
+    ///
+    /// Increases with every attempt, renewals included, so that the logs of one attempt can be
+    /// told apart from those of the others.
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    last_attempt_id: u64,
+    /// Task checking whether the external address of the mapping can be reached, see
+    /// [`Config::reachability_check`].
+    reachability_task: Option<AbortOnDropHandle<(SocketAddrV4, bool)>>,
+    /// PCP mapping over IPv6, made when the gateway is only reachable over IPv6.
+    mapping_v6: MappingV6,
+    /// Ports mapped besides the local port, see [`Client::add_port`].
+    added_ports: HashMap<NonZeroU16, AddedPort>,
+    /// External addresses of all mapped ports, keyed by local port.
+    external_addresses: watch::Sender<BTreeMap<NonZeroU16, SocketAddrV4>>,
+    /// Requesters waiting for the result of the ongoing mapping task.
+    mapping_waiters: Vec<oneshot::Sender<Result<SocketAddrV4, MappingError>>>,
+    /// Task probing the necessary protocols.
+    ///
+    /// Requests for a probe that arrive while this task is still in progress will receive the same
+    /// result.
+    /// The task is aborted once every requester that may cancel is gone.
+    probing_task: Option<ProbingTask>,
+    /// Whether the probing task disregards previous results.
+    forced_probe: bool,
+    /// Result of the probe started by a network change, mapping resumes once it's ready.
+    network_change_probe: Option<oneshot::Receiver<ProbeResult>>,
+    /// External port to request in mappings, if any.
+    preferred_external_port: Option<NonZeroU16>,
+    /// Pending retry of a failed mapping attempt.
+    retry: Option<Pin<Box<time::Sleep>>>,
+    /// Delay to use for the next retry, reset once a mapping is obtained.
+    retry_backoff: Option<Duration>,
+    /// Subscribers to [`MappingEvent`]s.
+    event_subscribers: Vec<mpsc::Sender<MappingEvent>>,
+    /// Most recent probe results, bounded by [`Config::probe_history_capacity`].
+    probe_history: watch::Sender<ProbeHistory>,
+    /// Identity of the UPnP gateway, as reported by the last probe.
+    upnp_gateway_info: watch::Sender<Option<GatewayInfo>>,
+    /// Socket receiving server announcements, see [`Config::listen_for_announcements`].
+    announcements: Option<tokio::net::UdpSocket>,
+    /// Whether stale UPnP mappings were already looked for, see
+    /// [`Config::cleanup_stale_mappings`].
+    #[cfg(feature = "upnp")]
+    stale_mappings_cleaned: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl Service {
+    fn new(
+        config: Config,
+        rx: mpsc::Receiver<Message>,
+        metrics: Arc<Metrics>,
+    ) -> (
+        Self,
+        watch::Receiver<Option<SocketAddrV4>>,
+        watch::Receiver<ProbeHistory>,
+    ) {
+        let (current_mapping, watcher) = CurrentMapping::new(
+            metrics.clone(),
+            config.renewal_fraction,
+            config.renewal_jitter,
+        );
+        let mapping_v6 = MappingV6::new(
+            metrics.clone(),
+            config.renewal_fraction,
+            config.renewal_jitter,
+        );
+        let (probe_history, probe_history_watcher) = watch::channel(VecDeque::new());
+        let full_probe = match &config.initial_probe {
+            Some(output) => Probe::seeded(output, &config),
+            // we want to do a first full probe, so set is as expired on start-up
+            None => Probe::expired(),
+        };
+        let upnp_gateway_info = watch::Sender::new(full_probe.upnp_gateway_info.clone());
+        let service = Service {
+            config,
+            local_port: None,
+            interface_state: None,
+            rx,
+            current_mapping,
+            full_probe,
+            mapping_task: None,
+            mapping_started: None,
+            #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+            last_attempt_id: 0,
+            reachability_task: None,
+            mapping_v6,
+            added_ports: HashMap::new(),
+            external_addresses: watch::Sender::new(BTreeMap::new()),
+            mapping_waiters: Vec::new(),
+            probing_task: None,
+            forced_probe: false,
+            network_change_probe: None,
+            preferred_external_port: None,
+            retry: None,
+            retry_backoff: None,
+            event_subscribers: Vec::new(),
+            probe_history,
+            upnp_gateway_info,
+            announcements: None,
+            #[cfg(feature = "upnp")]
+            stale_mappings_cleaned: false,
+            metrics,
+        };
+
+        (service, watcher, probe_history_watcher)
+    }
+
+    /// Clears the current mapping and releases it.
+    async fn invalidate_mapping(&mut self) {
+        self.reachability_task = None;
+        if let Some(old_mapping) = self.current_mapping.update(None) {
+            if let Err(e) = old_mapping.release().await {
+                debug!("failed to release mapping {e}");
+            }
+            self.emit(MappingEvent::Released);
+        }
+        self.mapping_v6.release().await;
+    }
+
+    /// Delivers an event to all subscribers, forgetting those that are gone.
+    fn emit(&mut self, event: MappingEvent) {
+        self.event_subscribers
+            .retain(|tx| match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    trace!("event subscriber is lagging, dropping event");
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+    }
+
+    async fn run(mut self) {
+        debug!("portmap starting");
+        if self.config.listen_for_announcements {
+            match announcement::bind() {
+                Ok(socket) => self.announcements = Some(socket),
+                Err(e) => debug!("failed to listen for announcements: {e}"),
+            }
+        }
+        loop {
+            tokio::select! {
+                msg = self.rx.recv() => {
+                    trace!("tick: msg {msg:?}");
+                    match msg {
+                        Some(Message::Shutdown { done_tx }) => {
+                            self.shutdown().await;
+                            // we don't care if the requester is no longer there
+                            let _ = done_tx.send(());
+                            break;
+                        },
+                        Some(msg) => {
+                            self.handle_msg(msg).await;
+                        },
+                        None => {
+                            debug!("portmap service channel dropped. Likely shutting down.");
+                            break;
+                        }
+                    }
+                }
+                mapping_result = util::MaybeFuture{ inner: self.mapping_task.as_mut() } => {
+                    trace!("tick: mapping ready");
+                    // regardless of outcome, the task is finished, clear it
+                    self.mapping_task = None;
+                    // there isn't really a way to react to a join error here. Flatten it to make
+                    // it easier to work with
+                    self.on_mapping_result(mapping_result);
+                }
+                result = util::MaybeFuture{ inner: self.reachability_task.as_mut() } => {
+                    trace!("tick: reachability check ready");
+                    self.reachability_task = None;
+                    match result {
+                        Ok((addr, reachable)) => {
+                            debug!(%addr, reachable, "checked reachability of the mapping");
+                            self.current_mapping.set_reachable(addr, reachable);
+                        }
+                        Err(e) => debug!("reachability check failed: {e}"),
+                    }
+                }
+                event = self.mapping_v6.next() => {
+                    trace!("tick: ipv6 mapping event {event:?}");
+                    self.on_mapping_v6_event(event).await;
+                }
+                probe_result = next_probing_result(&mut self.probing_task) => {
+                    // retrieve the receivers and clear the task
+                    let receivers = self.probing_task.take().expect("is some").1;
+                    match probe_result {
+                        Some(probe_result) => {
+                            trace!("tick: probe ready");
+                            let probe_result = probe_result.map_err(ProbeError::from_join_error);
+                            self.on_probe_result(probe_result, receivers);
+                        }
+                        None => {
+                            debug!("probe cancelled by all requesters, aborting");
+                            self.forced_probe = false;
+                        }
+                    }
+                }
+                _ = util::MaybeFuture{ inner: self.network_change_probe.as_mut() } => {
+                    trace!("tick: probe after network change ready");
+                    self.network_change_probe = None;
+                    self.on_network_change_probed().await;
+                }
+                _ = util::MaybeFuture{ inner: self.retry.as_mut() } => {
+                    trace!("tick: retrying mapping");
+                    self.retry = None;
+                    // the retry might be due to an added port, keep a working mapping
+                    if self.mapping_task.is_none()
+                        && self.current_mapping.external().is_none()
+                        && self.mapping_v6.external().is_none()
+                    {
+                        self.get_mapping(None).await;
+                    }
+                    self.procure_added_ports().await;
+                }
+                (local_port, event) = next_added_port_event(&mut self.added_ports) => {
+                    trace!(%local_port, "tick: added port event {event:?}");
+                    self.on_added_port_event(local_port, event).await;
+                }
+                Some(event) = self.current_mapping.next() => {
+                    trace!("tick: mapping event {event:?}");
+                    match event {
+                        current_mapping::Event::Renew { external_ip, external_port } => {
+                            self.get_mapping(Some((external_ip, external_port))).await;
+                        },
+                        current_mapping::Event::Expired {
+                            external_ip,
+                            external_port,
+                            protocol,
+                        } => {
+                            let addr = SocketAddrV4::new(external_ip, external_port.into());
+                            self.emit(MappingEvent::Expired { addr, protocol });
+                            self.get_mapping(Some((external_ip, external_port))).await;
+                        },
+                    }
+
+                }
+                announcement = announcement::recv(self.announcements.as_ref()) => {
+                    trace!("tick: announcement {announcement:?}");
+                    match announcement {
+                        Ok(announcement) => self.on_announcement(announcement).await,
+                        Err(e) => {
+                            debug!("stopped listening for announcements: {e}");
+                            self.announcements = None;
+                        }
+                    }
+                }
+            }
+            self.publish_external_addresses();
         }
     }
 
-    /// Watch the external address for changes in the mappings.
-    pub fn watch_external_address(&self) -> watch::Receiver<Option<SocketAddrV4>> {
-        self.port_mapping.clone()
+    /// Publishes the external addresses of all mapped ports, if they changed.
+    fn publish_external_addresses(&self) {
+        let local_port = self.local_port.zip(self.current_mapping.external());
+        let added_ports = self.added_ports.iter().filter_map(|(local_port, added)| {
+            added
+                .current_mapping
+                .external()
+                .map(|external| (*local_port, external))
+        });
+        let addresses: BTreeMap<_, _> = local_port
+            .into_iter()
+            .chain(added_ports)
+            .map(|(local_port, (ip, port))| (local_port, SocketAddrV4::new(ip, port.into())))
+            .collect();
+        self.external_addresses.send_if_modified(|current| {
+            let changed = *current != addresses;
+            *current = addresses;
+            changed
+        });
     }
 
-    /// Returns the metrics collected by the service.
-    pub fn metrics(&self) -> &Arc<Metrics> {
-        &self.metrics
+    /// Re-acquires the mapping if the gateway announces it lost it.
+    async fn on_announcement(&mut self, announcement: announcement::Announcement) {
+        let announcement::Announcement { from, epoch_time } = announcement;
+        match ip_and_gateway(self.interface_state.as_ref(), &self.config).await {
+            Ok((_local_ip, gateway)) if from == gateway => {}
+            _ => {
+                trace!(%from, "ignoring announcement from a host other than the gateway");
+                return;
+            }
+        }
+        if self.current_mapping.observe_epoch(epoch_time) {
+            debug!(
+                epoch_time,
+                "gateway lost its state, acquiring a new mapping"
+            );
+            self.invalidate_mapping().await;
+            self.get_mapping(None).await;
+        }
+        let mut lost = Vec::new();
+        for (local_port, added) in &mut self.added_ports {
+            if added.current_mapping.observe_epoch(epoch_time) {
+                lost.push(*local_port);
+            }
+        }
+        for local_port in lost {
+            debug!(%local_port, epoch_time, "gateway lost its state, acquiring a new mapping");
+            self.release_added_port_mapping(local_port).await;
+            self.get_added_port_mapping(local_port, None).await;
+        }
     }
-}
-
-/// Port mapping protocol information obtained during a probe.
-#[derive(Debug)]
-struct Probe {
-    /// When was the probe last updated.
-    last_probe: Instant,
-    /// The last [`upnp::Gateway`] and when was it last seen.
-    last_upnp_gateway_addr: Option<(upnp::Gateway, Instant)>,
-    /// Last time PCP was seen.
-    last_pcp: Option<Instant>,
-    /// Last time NAT-PMP was seen.
-    last_nat_pmp: Option<Instant>,
-}
 
-impl Probe {
-    /// An empty probe set to `now`.
-    fn empty() -> Self {
-        Self {
-            last_probe: Instant::now(),
-            last_upnp_gateway_addr: None,
-            last_pcp: None,
-            last_nat_pmp: None,
+    fn on_probe_result(&mut self, result: Result<Probe, ProbeError>, receivers: Vec<ProbeWaiter>) {
+        let forced = std::mem::take(&mut self.forced_probe);
+        let result = result.map(|probe| {
+            if forced {
+                // previous results are stale, only keep what was just seen
+                self.full_probe = Probe::empty();
+            }
+            self.full_probe.update(probe, &self.metrics);
+            // TODO(@divma): the gateway of the current mapping could have changed. Tailscale
+            // still assumes the current mapping is valid/active and will return it even after
+            // this
+            let output = self.full_probe.output();
+            trace!(?output, "probe output");
+            self.upnp_gateway_info
+                .send_replace(output.upnp_gateway.clone());
+            self.record_probe(output.clone());
+            output
+        });
+        if let Ok(output) = &result {
+            self.emit(MappingEvent::ProbeCompleted {
+                output: output.clone(),
+            });
+        }
+        for waiter in receivers {
+            // ignore the error. If the receiver is no longer there we don't really care
+            let _ = waiter.result_tx.send(result.clone());
         }
     }
-    /// Create a new probe based on a previous output.
-    async fn from_output(
-        config: Config,
-        output: ProbeOutput,
-        local_ip: Ipv4Addr,
-        gateway: Ipv4Addr,
-        metrics: Arc<Metrics>,
-    ) -> Probe {
-        let ProbeOutput { upnp, pcp, nat_pmp } = output;
-        let Config {
-            enable_upnp,
-            enable_pcp,
-            enable_nat_pmp,
-            protocol: _,
-        } = config;
-        let mut upnp_probing_task = util::MaybeFuture {
-            inner: (enable_upnp && !upnp).then(|| {
-                let metrics = metrics.clone();
-                Box::pin(async move {
-                    upnp::probe_available(&metrics)
-                        .await
-                        .map(|addr| (addr, Instant::now()))
-                })
-            }),
-        };
 
-        let mut pcp_probing_task = util::MaybeFuture {
-            inner: (enable_pcp && !pcp).then(|| {
-                let metrics = metrics.clone();
-                Box::pin(async move {
-                    metrics.pcp_probes.inc();
-                    pcp::probe_available(local_ip, gateway)
-                        .await
-                        .then(Instant::now)
-                })
-            }),
+    /// Starts checking whether `addr` can be reached, if a check is configured.
+    ///
+    /// A check of a previous address still underway is abandoned.
+    fn check_reachability(&mut self, addr: SocketAddrV4) {
+        let Some(check) = &self.config.reachability_check else {
+            return;
         };
+        let check = check.check(addr);
+        self.reachability_task = Some(AbortOnDropHandle::new(tokio::spawn(
+            async move { (addr, check.await) }.instrument(info_span!("reachability")),
+        )));
+    }
 
-        let mut nat_pmp_probing_task = util::MaybeFuture {
-            inner: (enable_nat_pmp && !nat_pmp).then(|| {
-                Box::pin(async {
-                    nat_pmp::probe_available(local_ip, gateway)
-                        .await
-                        .then(Instant::now)
-                })
-            }),
-        };
+    /// Adds a probe result to the history, dropping the oldest ones beyond capacity.
+    fn record_probe(&self, output: ProbeOutput) {
+        let capacity = self.config.probe_history_capacity;
+        self.probe_history.send_modify(|history| {
+            history.push_back((Instant::now(), output));
+            while history.len() > capacity {
+                history.pop_front();
+            }
+        });
+    }
 
-        if upnp_probing_task.inner.is_some() {
-            metrics.upnp_probes.inc();
+    fn on_mapping_result(
+        &mut self,
+        result: Result<Result<mapping::Mapping, mapping::Error>, tokio::task::JoinError>,
+    ) {
+        let result = match result {
+            Ok(Ok(mapping)) => {
+                let (ip, port) = mapping::PortMapped::external(&mapping);
+                let addr = SocketAddrV4::new(ip, port.into());
+                let protocol = mapping.protocol();
+                let local_port = mapping.local_port();
+                debug!(%local_port, %addr, ?protocol, "obtained port mapping");
+                let started = self.mapping_started.take();
+                self.record_mapping_success(protocol, started);
+                if let Some(requested) = self.preferred_external_port
+                    && requested != port
+                {
+                    debug!(%requested, "router did not grant the requested external port");
+                    self.emit(MappingEvent::ExternalPortSubstituted {
+                        requested,
+                        granted: addr,
+                        protocol,
+                    });
+                }
+                let old_mapping = self.current_mapping.update(Some(mapping));
+                let addr_changed = old_mapping
+                    .as_ref()
+                    .is_none_or(|old| mapping::PortMapped::external(old) != (ip, port));
+                if addr_changed {
+                    self.check_reachability(addr);
+                }
+                let event = match &old_mapping {
+                    Some(_old_mapping) => MappingEvent::Renewed {
+                        local_port,
+                        addr,
+                        protocol,
+                    },
+                    None => MappingEvent::Acquired {
+                        local_port,
+                        addr,
+                        protocol,
+                    },
+                };
+                self.emit(event);
+                if let Some(old_mapping) = old_mapping {
+                    let (old_ip, old_port) = mapping::PortMapped::external(&old_mapping);
+                    if old_port != port {
+                        self.emit(MappingEvent::ExternalPortChanged {
+                            old: SocketAddrV4::new(old_ip, old_port.into()),
+                            new: addr,
+                        });
+                    }
+                }
+                self.cancel_retry();
+                Ok(addr)
+            }
+            Ok(Err(e)) => {
+                debug!("failed to get a port mapping {e}");
+                self.metrics.mapping_failures.inc();
+                Err(e!(MappingError::Failed {
+                    source: Arc::new(e)
+                }))
+            }
+            Err(e) => {
+                debug!("failed to get a port mapping {e}");
+                self.metrics.mapping_failures.inc();
+                Err(e!(MappingError::Join {
+                    is_panic: e.is_panic(),
+                    is_cancelled: e.is_cancelled(),
+                    source: Arc::new(e)
+                }))
+            }
+        };
+        if let Err(error) = &result {
+            self.emit(MappingEvent::Failed {
+                error: error.clone(),
+            });
+            self.schedule_retry();
         }
+        self.notify_mapping_waiters(result);
+    }
 
-        let mut upnp_done = upnp_probing_task.inner.is_none();
-        let mut pcp_done = pcp_probing_task.inner.is_none();
-        let mut nat_pmp_done = nat_pmp_probing_task.inner.is_none();
-
-        let mut probe = Probe::empty();
+    /// Counts a mapping obtained with `protocol` and how long it took since `started`.
+    fn record_mapping_success(&self, protocol: MappingProtocol, started: Option<Instant>) {
+        let (successes, latency) = match protocol {
+            MappingProtocol::Upnp => (
+                &self.metrics.upnp_mapping_success,
+                &self.metrics.upnp_mapping_latency,
+            ),
+            MappingProtocol::Pcp => (
+                &self.metrics.pcp_mapping_success,
+                &self.metrics.pcp_mapping_latency,
+            ),
+            MappingProtocol::NatPmp => (
+                &self.metrics.nat_pmp_mapping_success,
+                &self.metrics.nat_pmp_mapping_latency,
+            ),
+        };
+        successes.inc();
+        if let Some(started) = started {
+            latency.observe(started.elapsed().as_secs_f64());
+        }
+    }
 
-        while !upnp_done || !pcp_done || !nat_pmp_done {
-            tokio::select! {
-                last_upnp_gateway_addr = &mut upnp_probing_task, if !upnp_done => {
-                    trace!("tick: upnp probe ready");
-                    probe.last_upnp_gateway_addr = last_upnp_gateway_addr;
-                    upnp_done = true;
-                },
-                last_nat_pmp = &mut nat_pmp_probing_task, if !nat_pmp_done => {
-                    trace!("tick: nat_pmp probe ready");
-                    probe.last_nat_pmp = last_nat_pmp;
-                    nat_pmp_done = true;
-                },
-                last_pcp = &mut pcp_probing_task, if !pcp_done => {
-                    trace!("tick: pcp probe ready");
-                    probe.last_pcp = last_pcp;
-                    pcp_done = true;
-                },
+    /// Handles a finished ipv6 mapping task or a lifecycle event of the active ipv6 mapping.
+    #[cfg_attr(
+        not(feature = "pcp"),
+        expect(clippy::unused_async, reason = "there are no events without PCP")
+    )]
+    async fn on_mapping_v6_event(&mut self, event: MappingV6Event) {
+        match event {
+            #[cfg(feature = "pcp")]
+            MappingV6Event::MappingResult(result) => self.on_mapping_result_v6(result),
+            #[cfg(feature = "pcp")]
+            MappingV6Event::Mapping(_event) => {
+                // on renewal, the active ipv6 mapping is used as hint for the new one
+                self.get_mapping(None).await;
             }
         }
+    }
 
-        probe
+    #[cfg(feature = "pcp")]
+    fn on_mapping_result_v6(
+        &mut self,
+        result: Result<Result<pcp::Mapping<Ipv6Addr>, pcp::Error>, tokio::task::JoinError>,
+    ) {
+        match result {
+            Ok(Ok(mapping)) => {
+                let (ip, port) = mapping.external();
+                debug!(%ip, %port, "obtained ipv6 port mapping");
+                self.mapping_v6.current_mapping.update(Some(mapping));
+                self.cancel_retry();
+            }
+            Ok(Err(e)) => {
+                debug!("failed to get an ipv6 port mapping {e}");
+                self.metrics.mapping_failures.inc();
+                self.schedule_retry();
+            }
+            Err(e) => {
+                debug!("failed to get an ipv6 port mapping {e}");
+                self.metrics.mapping_failures.inc();
+                self.schedule_retry();
+            }
+        }
     }
 
-    /// Returns a [`ProbeOutput`] indicating which services can be considered available.
-    fn output(&self) -> ProbeOutput {
-        let now = Instant::now();
+    /// Schedules another mapping attempt after a failed one, backing off exponentially.
+    fn schedule_retry(&mut self) {
+        let Some(RetryBackoff { initial, max }) = self.config.mapping_retry else {
+            return;
+        };
+        if self.local_port.is_none() && self.added_ports.is_empty() {
+            return;
+        }
+        let backoff = self.retry_backoff.unwrap_or(initial);
+        let delay = with_jitter(backoff);
+        debug!("retrying mapping in {delay:?}");
+        self.retry = Some(Box::pin(time::sleep(delay)));
+        self.retry_backoff = Some(backoff.saturating_mul(2).min(max));
+    }
 
-        // check if the last UPnP gateway is valid
-        let upnp = self
-            .last_upnp_gateway_addr
-            .as_ref()
-            .map(|(_gateway_addr, last_probed)| *last_probed + AVAILABILITY_TRUST_DURATION > now)
-            .unwrap_or_default();
+    /// Cancels a pending retry and resets the backoff.
+    fn cancel_retry(&mut self) {
+        self.retry = None;
+        self.retry_backoff = None;
+    }
 
-        let pcp = self
-            .last_pcp
-            .as_ref()
-            .map(|last_probed| *last_probed + AVAILABILITY_TRUST_DURATION > now)
-            .unwrap_or_default();
+    /// The cap on mappings if the added ports leave no room to map the local port, see
+    /// [`Config::max_mappings`].
+    fn local_port_cap(&self) -> Option<usize> {
+        self.config
+            .max_mappings
+            .filter(|max| self.added_ports.len() >= *max)
+    }
 
-        let nat_pmp = self
-            .last_nat_pmp
-            .as_ref()
-            .map(|last_probed| *last_probed + AVAILABILITY_TRUST_DURATION > now)
-            .unwrap_or_default();
+    /// Informs anyone waiting for a mapping of the result.
+    fn notify_mapping_waiters(&mut self, result: Result<SocketAddrV4, MappingError>) {
+        for tx in self.mapping_waiters.drain(..) {
+            // ignore the error. If the receiver is no longer there we don't really care
+            let _ = tx.send(result.clone());
+        }
+    }
 
-        ProbeOutput { upnp, pcp, nat_pmp }
+    /// Handles a request for a single mapping attempt.
+    async fn get_mapping_once(
+        &mut self,
+        local_port: NonZeroU16,
+        result_tx: oneshot::Sender<Result<SocketAddrV4, MappingError>>,
+    ) {
+        self.update_local_port(Some(local_port)).await;
+        if self.mapping_task.is_some() {
+            self.mapping_waiters.push(result_tx);
+            return;
+        }
+        // no attempt is underway, either because there already is a mapping or none can be made
+        let result = match (self.current_mapping.external(), self.local_port_cap()) {
+            (Some((ip, port)), _) => Ok(SocketAddrV4::new(ip, port.into())),
+            (None, Some(max)) => Err(e!(MappingError::MaxMappingsExceeded { max })),
+            (None, None) => Err(e!(MappingError::NoProtocol)),
+        };
+        // we don't care if the requester is no longer there
+        let _ = result_tx.send(result);
     }
 
-    /// Updates a probe with the `Some` values of another probe that is _assumed_ newer.
-    fn update(&mut self, probe: Probe, metrics: &Arc<Metrics>) {
-        let Probe {
-            last_probe,
-            last_upnp_gateway_addr,
-            last_pcp,
-            last_nat_pmp,
-        } = probe;
-        if last_upnp_gateway_addr.is_some() {
-            metrics.upnp_available.inc();
-            let new_gateway = last_upnp_gateway_addr
-                .as_ref()
-                .map(|(addr, _last_seen)| addr);
-            let old_gateway = self
-                .last_upnp_gateway_addr
-                .as_ref()
-                .map(|(addr, _last_seen)| addr);
-            if new_gateway != old_gateway {
-                metrics.upnp_gateway_updated.inc();
-                debug!(
-                    "upnp gateway changed {:?} -> {:?}",
-                    old_gateway
-                        .map(|gw| gw.to_string())
-                        .unwrap_or("None".into()),
-                    new_gateway
-                        .map(|gw| gw.to_string())
-                        .unwrap_or("None".into())
-                )
-            };
-            self.last_upnp_gateway_addr = last_upnp_gateway_addr;
+    async fn handle_msg(&mut self, msg: Message) {
+        match msg {
+            Message::ProcureMapping => {
+                self.update_local_port(self.local_port).await;
+                self.procure_added_ports().await;
+            }
+            Message::AddPort {
+                local_port,
+                result_tx,
+            } => {
+                let result = self.add_port(local_port).await;
+                // we don't care if the requester is no longer there
+                let _ = result_tx.send(result);
+            }
+            Message::RemovePort { local_port } => self.remove_port(local_port).await,
+            Message::UpdateLocalPort { local_port } => self.update_local_port(local_port).await,
+            Message::UpdateInterfaceState { state } => self.interface_state = Some(*state),
+            Message::NetworkChanged => self.on_network_change().await,
+            Message::Probe {
+                force,
+                cancellable,
+                result_tx,
+            } => {
+                let waiter = ProbeWaiter {
+                    result_tx,
+                    cancellable,
+                };
+                self.probe_request(force, waiter).await
+            }
+            Message::GetMappingOnce {
+                local_port,
+                result_tx,
+            } => self.get_mapping_once(local_port, result_tx).await,
+            Message::GatewayDetails { result_tx } => {
+                // we don't care if the requester is no longer there
+                let _ = result_tx.send(self.gateway_details().await);
+            }
+            Message::InterfaceState { result_tx } => {
+                // we don't care if the requester is no longer there
+                let _ = result_tx.send(self.interface_state.clone());
+            }
+            Message::ExternalIpQuery {
+                allow_private,
+                result_tx,
+            } => {
+                // we don't care if the requester is no longer there
+                let _ = result_tx.send(self.external_ip_query(allow_private).await);
+            }
+            #[cfg(feature = "pcp")]
+            Message::PeerMappingRequest { result_tx } => {
+                // we don't care if the requester is no longer there
+                let _ = result_tx.send(self.peer_mapping_request().await);
+            }
+            Message::RequestExternalPort { external_port } => {
+                self.request_external_port(external_port).await
+            }
+            Message::ReleaseExternalPort {
+                protocol,
+                external_port,
+            } => self.release_external_port(protocol, external_port).await,
+            Message::Subscribe { events_tx } => self.event_subscribers.push(events_tx),
+            Message::Shutdown { .. } => unreachable!("handled by the run loop"),
         }
-        if last_pcp.is_some() {
-            metrics.pcp_available.inc();
-            self.last_pcp = last_pcp;
+    }
+
+    /// Stops any ongoing task and releases the current mapping.
+    async fn shutdown(&mut self) {
+        debug!("portmap shutting down");
+        self.mapping_task = None;
+        self.mapping_v6.cancel();
+        self.probing_task = None;
+        self.invalidate_mapping().await;
+        let added_ports: Vec<_> = self.added_ports.keys().copied().collect();
+        for local_port in added_ports {
+            self.remove_port(local_port).await;
         }
-        if last_nat_pmp.is_some() {
-            self.last_nat_pmp = last_nat_pmp;
+    }
+
+    /// Discards what was learned about the previous network and starts over.
+    ///
+    /// Requesters waiting for a probe or a mapping get the result of the new attempts instead.
+    async fn on_network_change(&mut self) {
+        debug!("network changed, probing and mapping again");
+        let probe_receivers = self
+            .probing_task
+            .take()
+            .map(|(_task, receivers)| receivers)
+            .unwrap_or_default();
+        self.forced_probe = false;
+        self.full_probe = Probe::expired();
+        self.upnp_gateway_info.send_replace(None);
+
+        self.mapping_task = None;
+        self.mapping_v6.cancel();
+        self.cancel_retry();
+        self.invalidate_mapping().await;
+        let added_ports: Vec<_> = self.added_ports.keys().copied().collect();
+        for local_port in added_ports {
+            self.release_added_port_mapping(local_port).await;
         }
 
-        self.last_probe = last_probe;
+        for waiter in probe_receivers {
+            self.probe_request(false, waiter).await;
+        }
+        // mapping based on the stale probe would try protocols the new network may not have
+        let (result_tx, result_rx) = oneshot::channel();
+        let waiter = ProbeWaiter {
+            result_tx,
+            cancellable: false,
+        };
+        self.probe_request(false, waiter).await;
+        self.network_change_probe = Some(result_rx);
     }
-}
-
-// mainly to make clippy happy
-type ProbeResult = Result<ProbeOutput, ProbeError>;
 
-/// A port mapping client.
-#[derive(Debug)]
-pub struct Service {
-    config: Config,
-    /// Local port to map.
-    local_port: Option<NonZeroU16>,
-    /// Channel over which the service is informed of messages.
-    ///
-    /// The service will stop when all senders are gone.
-    rx: mpsc::Receiver<Message>,
-    /// Currently active mapping.
-    current_mapping: CurrentMapping,
-    /// Last updated probe.
-    full_probe: Probe,
-    /// Task attempting to get a port mapping.
-    ///
-    /// This task will be cancelled if a request to set the local port arrives before it's
-    /// finished.
-    mapping_task: Option<AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>>>,
-    /// Task probing the necessary protocols.
+    /// Maps the local port and the added ports again, once the network change was probed.
     ///
-    /// Requests for a probe that arrive while this task is still in progress will receive the same
-    /// result.
-    probing_task: Option<(AbortOnDropHandle<Probe>, Vec<oneshot::Sender<ProbeResult>>)>,
-    metrics: Arc<Metrics>,
-}
+    /// Mapping is attempted even if probing failed, the mapping attempt then reports why.
+    /// Ports mapped meanwhile, e.g. on request of [`Client::procure_mapping`], are kept.
+    async fn on_network_change_probed(&mut self) {
+        if self.mapping_task.is_none()
+            && self.current_mapping.external().is_none()
+            && self.mapping_v6.external().is_none()
+        {
+            self.get_mapping(None).await;
+        }
+        self.procure_added_ports().await;
+    }
 
-impl Service {
-    fn new(
-        config: Config,
-        rx: mpsc::Receiver<Message>,
-        metrics: Arc<Metrics>,
-    ) -> (Self, watch::Receiver<Option<SocketAddrV4>>) {
-        let (current_mapping, watcher) = CurrentMapping::new(metrics.clone());
-        let mut full_probe = Probe::empty();
-        if let Some(in_the_past) = full_probe
-            .last_probe
-            .checked_sub(AVAILABILITY_TRUST_DURATION)
+    /// Starts mapping an additional local port, unless it's already mapped.
+    ///
+    /// Fails if the cap on mappings is reached.
+    async fn add_port(&mut self, local_port: NonZeroU16) -> Result<(), MappingError> {
+        if self.local_port == Some(local_port) || self.added_ports.contains_key(&local_port) {
+            trace!(%local_port, "port is already mapped");
+            return Ok(());
+        }
+        let mapped = self.added_ports.len() + usize::from(self.local_port.is_some());
+        if let Some(max) = self.config.max_mappings
+            && mapped >= max
         {
-            // we want to do a first full probe, so set is as expired on start-up
-            full_probe.last_probe = in_the_past;
+            debug!(%local_port, "can't add port: mappings are capped at {max}");
+            return Err(e!(MappingError::MaxMappingsExceeded { max }));
         }
-        let service = Service {
-            config,
-            local_port: None,
-            rx,
-            current_mapping,
-            full_probe,
-            mapping_task: None,
-            probing_task: None,
-            metrics,
-        };
+        let (current_mapping, _watcher) = CurrentMapping::new(
+            self.metrics.clone(),
+            self.config.renewal_fraction,
+            self.config.renewal_jitter,
+        );
+        self.added_ports.insert(
+            local_port,
+            AddedPort {
+                current_mapping,
+                mapping_task: None,
+                mapping_started: None,
+            },
+        );
+        self.get_added_port_mapping(local_port, None).await;
+        Ok(())
+    }
 
-        (service, watcher)
+    /// Stops mapping an added port, releasing its mapping.
+    async fn remove_port(&mut self, local_port: NonZeroU16) {
+        self.release_added_port_mapping(local_port).await;
+        self.added_ports.remove(&local_port);
     }
 
-    /// Clears the current mapping and releases it.
-    async fn invalidate_mapping(&mut self) {
-        if let Some(old_mapping) = self.current_mapping.update(None)
+    /// Clears the mapping of an added port and releases it.
+    async fn release_added_port_mapping(&mut self, local_port: NonZeroU16) {
+        let Some(added) = self.added_ports.get_mut(&local_port) else {
+            return;
+        };
+        added.mapping_task = None;
+        if let Some(old_mapping) = added.current_mapping.update(None)
             && let Err(e) = old_mapping.release().await
         {
-            debug!("failed to release mapping {e}");
+            debug!(%local_port, "failed to release mapping {e}");
         }
     }
 
-    async fn run(mut self) {
-        debug!("portmap starting");
-        loop {
-            tokio::select! {
-                msg = self.rx.recv() => {
-                    trace!("tick: msg {msg:?}");
-                    match msg {
-                        Some(msg) => {
-                            self.handle_msg(msg).await;
-                        },
-                        None => {
-                            debug!("portmap service channel dropped. Likely shutting down.");
-                            break;
-                        }
-                    }
-                }
-                mapping_result = util::MaybeFuture{ inner: self.mapping_task.as_mut() } => {
-                    trace!("tick: mapping ready");
-                    // regardless of outcome, the task is finished, clear it
-                    self.mapping_task = None;
-                    // there isn't really a way to react to a join error here. Flatten it to make
-                    // it easier to work with
-                    self.on_mapping_result(mapping_result);
-                }
-                probe_result = util::MaybeFuture{ inner: self.probing_task.as_mut().map(|(fut, _rec)| fut) } => {
-                    trace!("tick: probe ready");
-                    // retrieve the receivers and clear the task
-                    let receivers = self.probing_task.take().expect("is some").1;
-                    let probe_result = probe_result.map_err(|e| e!(ProbeError::Join { is_panic: e.is_panic(), is_cancelled: e.is_cancelled() }));
-                    self.on_probe_result(probe_result, receivers);
+    /// Releases the mapping of `external_port` made with `protocol`.
+    ///
+    /// Mappings we track are cleared, anything else is released with the protocol directly.
+    async fn release_external_port(
+        &mut self,
+        protocol: MappingProtocol,
+        external_port: NonZeroU16,
+    ) {
+        let is_released = |current_mapping: &CurrentMapping| {
+            current_mapping.mapping().is_some_and(|mapping| {
+                mapping.protocol() == protocol
+                    && mapping::PortMapped::external(mapping).1 == external_port
+            })
+        };
+        if is_released(&self.current_mapping) {
+            debug!(%protocol, %external_port, "releasing the mapping of the local port");
+            self.reachability_task = None;
+            if let Some(old_mapping) = self.current_mapping.update(None) {
+                if let Err(e) = old_mapping.release().await {
+                    debug!("failed to release mapping {e}");
                 }
-                Some(event) = self.current_mapping.next() => {
-                    trace!("tick: mapping event {event:?}");
-                    match event {
-                        current_mapping::Event::Renew { external_ip, external_port } | current_mapping::Event::Expired { external_ip, external_port } => {
-                            self.get_mapping(Some((external_ip, external_port)));
-                        },
-                    }
+                self.emit(MappingEvent::Released);
+            }
+            return;
+        }
+        let added_port = self
+            .added_ports
+            .iter()
+            .find(|(_local_port, added)| is_released(&added.current_mapping))
+            .map(|(local_port, _added)| *local_port);
+        if let Some(local_port) = added_port {
+            debug!(%protocol, %external_port, %local_port, "releasing the mapping of an added port");
+            return self.release_added_port_mapping(local_port).await;
+        }
 
+        debug!(%protocol, %external_port, "releasing an untracked mapping");
+        if let Err(e) = self.release_untracked_port(protocol, external_port).await {
+            debug!(%protocol, %external_port, "failed to release mapping {e}");
+        }
+    }
+
+    /// Asks the gateway to release the mapping of `external_port`, which we know nothing about.
+    async fn release_untracked_port(
+        &self,
+        protocol: MappingProtocol,
+        external_port: NonZeroU16,
+    ) -> Result<(), mapping::Error> {
+        #[cfg_attr(
+            not(any(feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "UPnP discovers the gateway on its own")
+        )]
+        let (local_ip, gateway) =
+            match ip_and_gateway(self.interface_state.as_ref(), &self.config).await {
+                Ok(ip_and_gw) => ip_and_gw,
+                Err(e) => {
+                    debug!(%external_port, "can't release mapping: {e}");
+                    return Ok(());
                 }
+            };
+        match protocol {
+            #[cfg(feature = "upnp")]
+            MappingProtocol::Upnp => {
+                let gateway = match &self.full_probe.last_upnp_gateway_addr {
+                    Some((gateway, _last_seen)) => gateway.clone(),
+                    None => {
+                        upnp::probe_available_result(local_ip, self.config.timeouts.upnp_search)
+                            .await?
+                    }
+                };
+                upnp::release_port(&gateway, self.config.protocol, external_port).await?;
+            }
+            #[cfg(feature = "pcp")]
+            MappingProtocol::Pcp => {
+                pcp::release_port(
+                    self.config.protocol,
+                    local_ip,
+                    external_port,
+                    gateway,
+                    &self.config.socket_options(),
+                )
+                .await?;
+            }
+            #[cfg(feature = "nat_pmp")]
+            MappingProtocol::NatPmp => {
+                nat_pmp::release_port(
+                    self.config.protocol,
+                    local_ip,
+                    external_port,
+                    gateway,
+                    &self.config.socket_options(),
+                )
+                .await?;
             }
+            #[allow(unreachable_patterns)]
+            _ => debug!(%protocol, "can't release mapping: protocol is not compiled in"),
         }
+        Ok(())
     }
 
-    fn on_probe_result(
-        &mut self,
-        result: Result<Probe, ProbeError>,
-        receivers: Vec<oneshot::Sender<ProbeResult>>,
-    ) {
-        let result = result.map(|probe| {
-            self.full_probe.update(probe, &self.metrics);
-            // TODO(@divma): the gateway of the current mapping could have changed. Tailscale
-            // still assumes the current mapping is valid/active and will return it even after
-            // this
-            let output = self.full_probe.output();
-            trace!(?output, "probe output");
-            output
-        });
-        for tx in receivers {
-            // ignore the error. If the receiver is no longer there we don't really care
-            let _ = tx.send(result.clone());
+    /// Attempts to map the added ports that have neither a mapping nor an attempt underway.
+    async fn procure_added_ports(&mut self) {
+        let unmapped: Vec<_> = self
+            .added_ports
+            .iter()
+            .filter(|(_local_port, added)| {
+                added.mapping_task.is_none() && added.current_mapping.external().is_none()
+            })
+            .map(|(local_port, _added)| *local_port)
+            .collect();
+        for local_port in unmapped {
+            self.get_added_port_mapping(local_port, None).await;
         }
     }
 
-    fn on_mapping_result(
-        &mut self,
-        result: Result<Result<mapping::Mapping, mapping::Error>, tokio::task::JoinError>,
-    ) {
-        match result {
-            Ok(Ok(mapping)) => {
-                self.current_mapping.update(Some(mapping));
+    /// Handles a mapping result or lifecycle event of an added port.
+    async fn on_added_port_event(&mut self, local_port: NonZeroU16, event: AddedPortEvent) {
+        match event {
+            AddedPortEvent::MappingResult(Ok(Ok(mapping))) => {
+                let Some(added) = self.added_ports.get_mut(&local_port) else {
+                    return;
+                };
+                let started = added.mapping_started.take();
+                let protocol = mapping.protocol();
+                debug!(%local_port, ?protocol, "obtained port mapping");
+                added.current_mapping.update(Some(mapping));
+                self.record_mapping_success(protocol, started);
             }
-            Ok(Err(e)) => {
-                debug!("failed to get a port mapping {e}");
+            AddedPortEvent::MappingResult(Ok(Err(e))) => {
+                debug!(%local_port, "failed to get a port mapping {e}");
                 self.metrics.mapping_failures.inc();
+                self.schedule_retry();
             }
-            Err(e) => {
-                debug!("failed to get a port mapping {e}");
+            AddedPortEvent::MappingResult(Err(e)) => {
+                debug!(%local_port, "failed to get a port mapping {e}");
                 self.metrics.mapping_failures.inc();
+                self.schedule_retry();
+            }
+            AddedPortEvent::Mapping(
+                current_mapping::Event::Renew {
+                    external_ip,
+                    external_port,
+                }
+                | current_mapping::Event::Expired {
+                    external_ip,
+                    external_port,
+                    ..
+                },
+            ) => {
+                self.get_added_port_mapping(local_port, Some((external_ip, external_port)))
+                    .await;
             }
         }
     }
 
-    async fn handle_msg(&mut self, msg: Message) {
-        match msg {
-            Message::ProcureMapping => self.update_local_port(self.local_port).await,
-            Message::UpdateLocalPort { local_port } => self.update_local_port(local_port).await,
-            Message::Probe { result_tx } => self.probe_request(result_tx),
+    /// Starts a task to map an added port, hinting at the external address to get.
+    async fn get_added_port_mapping(
+        &mut self,
+        local_port: NonZeroU16,
+        external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+    ) {
+        if !self.added_ports.contains_key(&local_port) {
+            return;
+        }
+        self.metrics.mapping_attempts.inc();
+        let (local_ip, gateway) =
+            match ip_and_gateway(self.interface_state.as_ref(), &self.config).await {
+                Ok(ip_and_gw) => ip_and_gw,
+                Err(e) => return debug!(%local_port, "can't get mapping: {e}"),
+            };
+        debug!("getting a port mapping for {local_ip}:{local_port} -> {external_addr:?}");
+        let Some(task) = self.spawn_mapping_task(local_ip, gateway, local_port, external_addr)
+        else {
+            return;
+        };
+        if let Some(added) = self.added_ports.get_mut(&local_port) {
+            added.mapping_task = Some(task);
+            added.mapping_started = Some(Instant::now());
         }
     }
 
     /// Updates the local port of the port mapping service.
     ///
     /// If the port changed, any port mapping task is cancelled. If the new port is some, it will
-    /// start a new port mapping task.
+    /// start a new port mapping task. Requests for the unchanged port are folded into the task
+    /// already underway, if any.
     async fn update_local_port(&mut self, local_port: Option<NonZeroU16>) {
         // ignore requests to update the local port in a way that does not produce a change
         if local_port != self.local_port {
             self.metrics.local_port_updates.inc();
             let old_port = std::mem::replace(&mut self.local_port, local_port);
+            // a port is mapped once, as the local port takes over an added one
+            if let Some(local_port) = local_port {
+                self.remove_port(local_port).await;
+            }
 
             // clear the current mapping task if any
 
+            self.mapping_v6.cancel();
             let dropped_task = self.mapping_task.take();
             // check if the dropped task had finished to reduce log noise
             let did_cancel = dropped_task
                 .map(|task| !task.is_finished())
                 .unwrap_or_default();
 
+            if !self.mapping_waiters.is_empty() {
+                self.notify_mapping_waiters(Err(e!(MappingError::LocalPortChanged)));
+            }
+            self.cancel_retry();
+
             if did_cancel {
                 debug!(
                     "canceled mapping task due to local port update. Old: {:?} New: {:?}",
@@ -616,113 +3573,496 @@ impl Service {
 
             // since the port has changed, the current mapping is no longer valid and should be
             // released
-
-            if external_addr.is_some() {
-                self.invalidate_mapping().await;
-            }
+            self.invalidate_mapping().await;
 
             // start a new mapping task to account for the new port if necessary
-            self.get_mapping(external_addr);
-        } else if self.current_mapping.external().is_none() {
+            self.get_mapping(external_addr).await;
+        } else if self.mapping_task.is_some() || self.mapping_v6.is_pending() {
+            trace!(?local_port, "mapping already underway");
+        } else if self.current_mapping.external().is_none() && self.mapping_v6.external().is_none()
+        {
             // if the local port has not changed, but there is no active mapping try to get one
-            self.get_mapping(None)
+            self.get_mapping(None).await
+        }
+    }
+
+    async fn gateway_details(&self) -> GatewayDetails {
+        let (local_ip, gateway) =
+            match ip_and_gateway(self.interface_state.as_ref(), &self.config).await {
+                Ok((local_ip, gateway)) => (Some(local_ip), Some(gateway)),
+                Err(e) => {
+                    debug!("no gateway details: {e}");
+                    (None, None)
+                }
+            };
+        #[cfg(feature = "upnp")]
+        let upnp_gateway_url = self
+            .full_probe
+            .last_upnp_gateway_addr
+            .as_ref()
+            .map(|(gateway, _last_seen)| upnp::description_url(gateway));
+        #[cfg(not(feature = "upnp"))]
+        let upnp_gateway_url = None;
+        GatewayDetails {
+            local_ip,
+            gateway,
+            upnp_gateway_url,
+            pcp_probe_addr: self.full_probe.pcp_probe_addr,
+            nat_pmp_probe_addr: self.full_probe.nat_pmp_probe_addr,
+            #[cfg(feature = "upnp")]
+            upnp_probe_error: self.full_probe.upnp_probe_error.clone(),
+            probe_timing: self.full_probe.timing,
+        }
+    }
+
+    /// Gathers what's needed to ask the available protocols for the external address.
+    #[cfg_attr(
+        not(feature = "nat_pmp"),
+        expect(clippy::unused_async, reason = "only NAT-PMP needs the gateway")
+    )]
+    async fn external_ip_query(
+        &self,
+        #[cfg_attr(
+            not(any(feature = "upnp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "PCP can't be asked")
+        )]
+        allow_private: bool,
+    ) -> ExternalIpQuery {
+        #[cfg(any(feature = "upnp", feature = "nat_pmp"))]
+        let output = self.full_probe.output();
+        #[cfg(feature = "nat_pmp")]
+        let nat_pmp = if output.nat_pmp {
+            match ip_and_gateway(self.interface_state.as_ref(), &self.config).await {
+                Ok(ip_and_gateway) => Some(ip_and_gateway),
+                Err(e) => {
+                    debug!("can't query NAT-PMP: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        #[cfg(feature = "upnp")]
+        let upnp_gateway = self
+            .full_probe
+            .last_upnp_gateway_addr
+            .as_ref()
+            .filter(|_| output.upnp)
+            .map(|(gateway, _last_seen)| gateway.clone());
+        ExternalIpQuery {
+            #[cfg(feature = "nat_pmp")]
+            nat_pmp,
+            #[cfg(feature = "nat_pmp")]
+            nat_pmp_recv_timeout: self.config.timeouts.nat_pmp_recv,
+            #[cfg(feature = "nat_pmp")]
+            sockets: self.config.socket_options(),
+            #[cfg(feature = "upnp")]
+            upnp_gateway,
+            #[cfg(any(feature = "upnp", feature = "nat_pmp"))]
+            allow_private_external: self.config.allow_private_external || allow_private,
+        }
+    }
+
+    /// Collects what's needed to map a port for a single remote peer, see [`Client::map_peer`].
+    #[cfg(feature = "pcp")]
+    async fn peer_mapping_request(&self) -> Result<PeerMappingRequest, MappingError> {
+        if !self.full_probe.output().pcp {
+            debug!("can't get peer mapping: PCP is not available");
+            return Err(e!(MappingError::NoProtocol));
+        }
+        let router = self.full_probe.upnp_gateway_info.as_ref();
+        if self.config.is_denied(MappingProtocol::Pcp, router) {
+            debug!(?router, "can't get peer mapping: PCP is denylisted");
+            return Err(e!(MappingError::NoProtocol));
+        }
+        let (local_ip, gateway) = ip_and_gateway(self.interface_state.as_ref(), &self.config)
+            .await
+            .map_err(|e| {
+                debug!("can't get peer mapping: {e}");
+                e!(MappingError::NoProtocol)
+            })?;
+        Ok(PeerMappingRequest {
+            protocol: self.config.protocol,
+            local_ip,
+            gateway,
+            recv_timeout: self.config.timeouts.pcp_recv,
+            sockets: self.config.socket_options(),
+        })
+    }
+
+    /// Sets the external port to request, remapping if the active mapping uses another one.
+    async fn request_external_port(&mut self, external_port: NonZeroU16) {
+        if self.preferred_external_port.replace(external_port) == Some(external_port) {
+            return;
+        }
+        let current_port = self
+            .current_mapping
+            .external()
+            .map(|(_ip, port)| port)
+            .or(self.mapping_v6.external().map(|(_ip, port)| port));
+        if current_port.is_some_and(|port| port != external_port) {
+            self.invalidate_mapping().await;
+        }
+        if current_port != Some(external_port) {
+            self.get_mapping(None).await;
+        }
+    }
+
+    /// Gets a PCP mapping over IPv6, for gateways that are only reachable over IPv6.
+    #[cfg(not(feature = "pcp"))]
+    #[expect(clippy::unused_async, reason = "mirrors the PCP version")]
+    async fn get_mapping_v6(&mut self, _local_port: NonZeroU16) {
+        debug!("can't get ipv6 mapping: PCP is not supported");
+    }
+
+    /// Gets a PCP mapping over IPv6, for gateways that are only reachable over IPv6.
+    #[cfg(feature = "pcp")]
+    async fn get_mapping_v6(&mut self, local_port: NonZeroU16) {
+        if !self.config.enable_pcp {
+            return debug!("can't get ipv6 mapping: PCP is disabled");
         }
+        if self.config.pcp_third_party.is_some() {
+            return debug!("can't get ipv6 mapping: mapping for a third party");
+        }
+        let router = self.full_probe.upnp_gateway_info.as_ref();
+        if self.config.is_denied(MappingProtocol::Pcp, router) {
+            return debug!(?router, "can't get ipv6 mapping: PCP is denylisted");
+        }
+        let Some((local_ip, gateway, scope_id)) =
+            ipv6_and_gateway(self.interface_state.as_ref(), &self.config).await
+        else {
+            return debug!("can't get ipv6 mapping: no address suitable for port mapping found");
+        };
+
+        let external_addr = self.mapping_v6.external();
+        let external_addr = match self.preferred_external_port {
+            Some(port) => Some((
+                external_addr.map_or(Ipv6Addr::UNSPECIFIED, |(ip, _port)| ip),
+                port,
+            )),
+            None => external_addr,
+        };
+        debug!("getting an ipv6 port mapping for [{local_ip}]:{local_port} -> {external_addr:?}");
+        let nonce = self
+            .mapping_v6
+            .current_mapping
+            .mapping()
+            .and_then(|mapping| mapping.renewal_nonce(local_ip, None, gateway, local_port));
+        let task = pcp::Mapping::new(
+            self.config.protocol,
+            local_ip,
+            local_port,
+            gateway,
+            scope_id,
+            external_addr,
+            nonce,
+            self.config.timeouts.pcp_recv,
+            self.config.socket_options(),
+        );
+        let attempt_id = self.next_attempt_id();
+        self.mapping_v6.mapping_task = Some(AbortOnDropHandle::new(tokio::spawn(
+            task.instrument(info_span!("pcp", %local_port, %gateway, attempt_id)),
+        )));
     }
 
-    fn get_mapping(&mut self, external_addr: Option<(Ipv4Addr, NonZeroU16)>) {
+    async fn get_mapping(&mut self, external_addr: Option<(Ipv4Addr, NonZeroU16)>) {
+        // a requested external port takes precedence over keeping the previous one
+        let external_addr = match self.preferred_external_port {
+            Some(port) => Some((
+                external_addr.map_or(Ipv4Addr::UNSPECIFIED, |(ip, _port)| ip),
+                port,
+            )),
+            None => external_addr,
+        };
         if let Some(local_port) = self.local_port {
+            if let Some(max) = self.local_port_cap() {
+                debug!("can't get mapping: mappings are capped at {max}");
+                let error = e!(MappingError::MaxMappingsExceeded { max });
+                self.emit(MappingEvent::Failed {
+                    error: error.clone(),
+                });
+                return self.notify_mapping_waiters(Err(error));
+            }
             self.metrics.mapping_attempts.inc();
 
-            let (local_ip, gateway) = match ip_and_gateway() {
-                Ok(ip_and_gw) => ip_and_gw,
-                Err(e) => return debug!("can't get mapping: {e}"),
-            };
-
-            let ProbeOutput { upnp, pcp, nat_pmp } = self.full_probe.output();
+            let (local_ip, gateway) =
+                match ip_and_gateway(self.interface_state.as_ref(), &self.config).await {
+                    Ok(ip_and_gw) => ip_and_gw,
+                    Err(ProbeError::Ipv6Gateway { .. }) => {
+                        return self.get_mapping_v6(local_port).await;
+                    }
+                    Err(e) => return debug!("can't get mapping: {e}"),
+                };
 
             debug!("getting a port mapping for {local_ip}:{local_port} -> {external_addr:?}");
-            let recently_probed =
-                self.full_probe.last_probe + UNAVAILABILITY_TRUST_DURATION > Instant::now();
-            let protocol = self.config.protocol;
-            // strategy:
-            // 1. check the available services and prefer pcp, then nat_pmp then upnp since it's
-            //    the most unreliable, but possibly the most deployed one
-            // 2. if no service was available, fallback to upnp if enabled, followed by pcp and
-            //    nat_pmp
-            self.mapping_task = if pcp {
-                // try pcp if available first
-                let task = mapping::Mapping::new_pcp(
-                    protocol,
-                    local_ip,
-                    local_port,
-                    gateway,
-                    external_addr,
-                );
-                Some(AbortOnDropHandle::new(tokio::spawn(
-                    task.instrument(info_span!("pcp")),
-                )))
-            } else if nat_pmp {
-                // next nat_pmp if available
-                let task = mapping::Mapping::new_nat_pmp(
-                    protocol,
-                    local_ip,
-                    local_port,
-                    gateway,
-                    external_addr,
-                );
-                Some(AbortOnDropHandle::new(tokio::spawn(
-                    task.instrument(info_span!("pmp")),
-                )))
-            } else if upnp || self.config.enable_upnp {
-                // next upnp if available or enabled
-                let external_port = external_addr.map(|(_addr, port)| port);
-                let gateway = self
-                    .full_probe
-                    .last_upnp_gateway_addr
-                    .as_ref()
-                    .map(|(gateway, _last_seen)| gateway.clone());
-                let task = mapping::Mapping::new_upnp(
-                    protocol,
-                    local_ip,
-                    local_port,
-                    gateway,
-                    external_port,
-                );
-
-                Some(AbortOnDropHandle::new(tokio::spawn(
-                    task.instrument(info_span!("upnp")),
-                )))
-            } else if !recently_probed && self.config.enable_pcp {
-                // if no service is available and the default fallback (upnp) is disabled, try pcp
-                // first
-                let task = mapping::Mapping::new_pcp(
-                    protocol,
-                    local_ip,
-                    local_port,
-                    gateway,
-                    external_addr,
-                );
-
-                Some(AbortOnDropHandle::new(tokio::spawn(
-                    task.instrument(info_span!("pcp")),
-                )))
-            } else if !recently_probed && self.config.enable_nat_pmp {
-                // finally try nat_pmp if enabled
-                let task = mapping::Mapping::new_nat_pmp(
-                    protocol,
-                    local_ip,
-                    local_port,
-                    gateway,
-                    external_addr,
-                );
-                Some(AbortOnDropHandle::new(tokio::spawn(
-                    task.instrument(info_span!("pmp")),
-                )))
-            } else {
-                // give up
+            let Some(task) = self.spawn_mapping_task(local_ip, gateway, local_port, external_addr)
+            else {
                 return;
+            };
+            self.mapping_task = Some(task);
+            self.mapping_started = Some(Instant::now());
+        }
+    }
+
+    /// Starts a task mapping `local_port`, with the protocol chosen based on the last probe.
+    ///
+    /// Returns `None` if no protocol can be attempted.
+    fn spawn_mapping_task(
+        &mut self,
+        local_ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        local_port: NonZeroU16,
+        external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+    ) -> Option<AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>>> {
+        let output = self.full_probe.output();
+
+        // skip protocols known not to work with this router
+        #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+        let router = self.full_probe.upnp_gateway_info.as_ref();
+        #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+        let allowed = |protocol| {
+            let denied = self.config.is_denied(protocol, router);
+            if denied {
+                debug!(?protocol, ?router, "skipping denylisted protocol");
+            }
+            !denied
+        };
+        #[cfg(feature = "upnp")]
+        let allow_upnp = allowed(MappingProtocol::Upnp);
+        #[cfg(feature = "pcp")]
+        let allow_pcp = allowed(MappingProtocol::Pcp);
+        #[cfg(feature = "nat_pmp")]
+        let allow_nat_pmp = allowed(MappingProtocol::NatPmp);
+
+        let is_allowed = |protocol| match protocol {
+            #[cfg(feature = "upnp")]
+            MappingProtocol::Upnp => allow_upnp,
+            #[cfg(feature = "pcp")]
+            MappingProtocol::Pcp => allow_pcp,
+            #[cfg(feature = "nat_pmp")]
+            MappingProtocol::NatPmp => allow_nat_pmp,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        };
+
+        #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+        let recently_probed =
+            self.full_probe.last_probe + UNAVAILABILITY_TRUST_DURATION > Instant::now();
+        // strategy:
+        // 1. check the available services in the configured order of priority, by default pcp,
+        //    then nat_pmp then upnp
+        // 2. if no service was available, fallback to upnp if enabled, followed by pcp and
+        //    nat_pmp
+        let preferred = self
+            .config
+            .protocol_priority
+            .iter()
+            .chain(&DEFAULT_PROTOCOL_PRIORITY)
+            .copied()
+            .find(|&protocol| output.is_available(protocol) && is_allowed(protocol));
+        if let Some(protocol) = preferred {
+            debug!(?protocol, "mapping with the preferred available protocol");
+            return self.spawn_protocol_mapping_task(
+                protocol,
+                local_ip,
+                gateway,
+                local_port,
+                external_addr,
+            );
+        }
+        #[cfg(feature = "upnp")]
+        if self.config.enable_upnp && allow_upnp {
+            // next upnp if enabled
+            return Some(self.spawn_upnp_mapping_task(local_ip, local_port, external_addr));
+        }
+        #[cfg(feature = "pcp")]
+        if !recently_probed && self.config.enable_pcp && allow_pcp {
+            // if no service is available and the default fallback (upnp) is disabled, try pcp
+            // first
+            return Some(self.spawn_pcp_mapping_task(local_ip, gateway, local_port, external_addr));
+        }
+        #[cfg(feature = "nat_pmp")]
+        if !recently_probed && self.config.enable_nat_pmp && allow_nat_pmp {
+            // finally try nat_pmp if enabled
+            return Some(self.spawn_nat_pmp_mapping_task(
+                local_ip,
+                gateway,
+                local_port,
+                external_addr,
+            ));
+        }
+        // give up
+        None
+    }
+
+    /// Starts a task mapping `local_port` with the given protocol.
+    ///
+    /// Returns `None` if the protocol is not compiled in.
+    fn spawn_protocol_mapping_task(
+        &mut self,
+        protocol: MappingProtocol,
+        #[cfg_attr(
+            not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "nothing is mapped without a protocol")
+        )]
+        local_ip: Ipv4Addr,
+        #[cfg_attr(
+            not(any(feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "UPnP discovers the gateway on its own")
+        )]
+        gateway: Ipv4Addr,
+        #[cfg_attr(
+            not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "nothing is mapped without a protocol")
+        )]
+        local_port: NonZeroU16,
+        #[cfg_attr(
+            not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")),
+            expect(unused_variables, reason = "nothing is mapped without a protocol")
+        )]
+        external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+    ) -> Option<AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>>> {
+        match protocol {
+            #[cfg(feature = "upnp")]
+            MappingProtocol::Upnp => {
+                Some(self.spawn_upnp_mapping_task(local_ip, local_port, external_addr))
             }
+            #[cfg(feature = "pcp")]
+            MappingProtocol::Pcp => {
+                Some(self.spawn_pcp_mapping_task(local_ip, gateway, local_port, external_addr))
+            }
+            #[cfg(feature = "nat_pmp")]
+            MappingProtocol::NatPmp => {
+                Some(self.spawn_nat_pmp_mapping_task(local_ip, gateway, local_port, external_addr))
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Starts a task mapping `local_port` with UPnP.
+    #[cfg(feature = "upnp")]
+    fn spawn_upnp_mapping_task(
+        &mut self,
+        local_ip: Ipv4Addr,
+        local_port: NonZeroU16,
+        external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+    ) -> AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>> {
+        let external_port = external_addr.map(|(_addr, port)| port);
+        let gateway = self
+            .full_probe
+            .last_upnp_gateway_addr
+            .as_ref()
+            .map(|(gateway, _last_seen)| gateway.clone());
+        // the gateway is only known once found, either by the probe or by the attempt itself
+        let span = info_span!(
+            "upnp",
+            %local_port,
+            gateway = tracing::field::Empty,
+            attempt_id = self.next_attempt_id(),
+        );
+        if let Some(gateway) = &gateway {
+            span.record("gateway", tracing::field::display(gateway.addr));
         }
+        // only the first attempt can find mappings left behind by earlier runs
+        let cleanup_stale = self.config.cleanup_stale_mappings
+            && !std::mem::replace(&mut self.stale_mappings_cleaned, true);
+        let task = mapping::Mapping::new_upnp(
+            self.config.protocol,
+            local_ip,
+            local_port,
+            gateway,
+            external_port,
+            self.config.timeouts.upnp_search,
+            self.config.mapping_description.clone(),
+            self.config.allow_private_external,
+            cleanup_stale,
+            self.metrics.clone(),
+        );
+        self.spawn_mapping(task, span)
+    }
+
+    /// Starts a task mapping `local_port` with PCP.
+    #[cfg(feature = "pcp")]
+    fn spawn_pcp_mapping_task(
+        &mut self,
+        local_ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        local_port: NonZeroU16,
+        external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+    ) -> AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>> {
+        // renewing with the nonce of the current mapping keeps its external port
+        let current_mapping = if self.local_port == Some(local_port) {
+            Some(&self.current_mapping)
+        } else {
+            self.added_ports
+                .get(&local_port)
+                .map(|added| &added.current_mapping)
+        };
+        let nonce = current_mapping
+            .and_then(CurrentMapping::mapping)
+            .and_then(|mapping| {
+                mapping.pcp_renewal_nonce(
+                    local_ip,
+                    self.config.pcp_third_party,
+                    gateway,
+                    local_port,
+                )
+            });
+        let task = mapping::Mapping::new_pcp(
+            self.config.protocol,
+            local_ip,
+            self.config.pcp_third_party,
+            local_port,
+            gateway,
+            external_addr,
+            nonce,
+            self.config.timeouts.pcp_recv,
+            self.config.socket_options(),
+        );
+        let attempt_id = self.next_attempt_id();
+        self.spawn_mapping(task, info_span!("pcp", %local_port, %gateway, attempt_id))
+    }
+
+    /// Starts a task mapping `local_port` with NAT-PMP.
+    #[cfg(feature = "nat_pmp")]
+    fn spawn_nat_pmp_mapping_task(
+        &mut self,
+        local_ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        local_port: NonZeroU16,
+        external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+    ) -> AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>> {
+        let task = mapping::Mapping::new_nat_pmp(
+            self.config.protocol,
+            local_ip,
+            local_port,
+            gateway,
+            external_addr,
+            self.config.timeouts.nat_pmp_recv,
+            self.config.allow_private_external,
+            self.config.socket_options(),
+        );
+        let attempt_id = self.next_attempt_id();
+        self.spawn_mapping(task, info_span!("pmp", %local_port, %gateway, attempt_id))
+    }
+
+    /// Returns the identifier of a new mapping attempt, see [`Service::last_attempt_id`].
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    fn next_attempt_id(&mut self) -> u64 {
+        self.last_attempt_id += 1;
+        self.last_attempt_id
+    }
+
+    /// Spawns a mapping attempt, failing it once it exceeds [`Timeouts::mapping`].
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    fn spawn_mapping(
+        &self,
+        task: impl Future<Output = Result<mapping::Mapping, mapping::Error>> + Send + 'static,
+        span: tracing::Span,
+    ) -> AbortOnDropHandle<Result<mapping::Mapping, mapping::Error>> {
+        let task = mapping::Mapping::with_timeout(task, self.config.timeouts.mapping);
+        AbortOnDropHandle::new(tokio::spawn(task.instrument(span)))
     }
 
     /// Handles a probe request.
@@ -730,26 +4070,46 @@ impl Service {
     /// If there is a task getting a probe, the receiver will be added with any other waiting for a
     /// result. If no probe is underway, a result can be returned immediately if it's still
     /// considered valid. Otherwise, a new probe task will be started.
-    fn probe_request(&mut self, result_tx: oneshot::Sender<Result<ProbeOutput, ProbeError>>) {
+    async fn probe_request(&mut self, force: bool, waiter: ProbeWaiter) {
+        if !self.config.upnp_enabled()
+            && !self.config.pcp_enabled()
+            && !self.config.nat_pmp_enabled()
+        {
+            // we don't care if the requester is no longer there
+            let _ = waiter
+                .result_tx
+                .send(Err(e!(ProbeError::AllProtocolsDisabled)));
+            return;
+        }
         match self.probing_task.as_mut() {
-            Some((_task_handle, receivers)) => receivers.push(result_tx),
+            Some((_task_handle, receivers)) => receivers.push(waiter),
             None => {
-                let probe_output = self.full_probe.output();
+                let probe_output = if force {
+                    ProbeOutput {
+                        upnp: false,
+                        pcp: false,
+                        nat_pmp: false,
+                        upnp_gateway: None,
+                    }
+                } else {
+                    self.full_probe.output()
+                };
                 if probe_output.all_available() {
                     // we don't care if the requester is no longer there
-                    let _ = result_tx.send(Ok(probe_output));
+                    let _ = waiter.result_tx.send(Ok(probe_output));
                 } else {
                     self.metrics.probes_started.inc();
 
-                    let (local_ip, gateway) = match ip_and_gateway() {
-                        Ok(ip_and_gw) => ip_and_gw,
-                        Err(e) => {
-                            // there is no guarantee this will be displayed, so log it anyway
-                            debug!("could not start probe: {e}");
-                            let _ = result_tx.send(Err(e));
-                            return;
-                        }
-                    };
+                    let (local_ip, gateway) =
+                        match ip_and_gateway(self.interface_state.as_ref(), &self.config).await {
+                            Ok(ip_and_gw) => ip_and_gw,
+                            Err(e) => {
+                                // there is no guarantee this will be displayed, so log it anyway
+                                debug!("could not start probe: {e}");
+                                let _ = waiter.result_tx.send(Err(e));
+                                return;
+                            }
+                        };
 
                     let config = self.config.clone();
                     let metrics = self.metrics.clone();
@@ -760,35 +4120,1330 @@ impl Service {
                         }
                         .instrument(info_span!("portmapper.probe")),
                     );
-                    let receivers = vec![result_tx];
+                    let receivers = vec![waiter];
                     self.probing_task = Some((AbortOnDropHandle::new(handle), receivers));
+                    self.forced_probe = force;
                 }
             }
         }
     }
 }
 
+/// Gets the local ip, gateway and gateway scope for PCP over IPv6.
+///
+/// Returns `None` unless the home router is only known by an IPv6 address and a local address
+/// reachable from beyond the local link is available. Overrides in the [`Config`] are IPv4 only,
+/// so none can be set either.
+#[cfg(feature = "pcp")]
+async fn ipv6_and_gateway(
+    state: Option<&State>,
+    config: &Config,
+) -> Option<(Ipv6Addr, Ipv6Addr, u32)> {
+    if config.gateway_override.is_some() || config.local_ip_override.is_some() {
+        return None;
+    }
+    let home_router = home_router(state).await?;
+    let IpAddr::V6(gateway) = home_router.gateway else {
+        return None;
+    };
+    let mappable = |ip: IpAddr| match ip {
+        IpAddr::V6(ip)
+            if !ip.is_unspecified()
+                && !ip.is_loopback()
+                && !ip.is_multicast()
+                && !ip.is_unicast_link_local() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    };
+
+    // gateways are usually known by their link-local address, which is only reachable through
+    // the interface it's scoped to
+    let interface = state.and_then(|state| {
+        state
+            .interfaces
+            .values()
+            .filter(|iface| iface.is_up())
+            .find(|iface| {
+                iface.addrs().any(|net| {
+                    Some(net.addr()) == home_router.my_ip
+                        || (!gateway.is_unicast_link_local() && net.contains(gateway.into()))
+                })
+            })
+    });
+    let scope_id = interface
+        .map(netwatch::interfaces::Interface::index)
+        .unwrap_or_default();
+    let local_ip = home_router
+        .my_ip
+        .and_then(mappable)
+        .or_else(|| interface?.addrs().find_map(|net| mappable(net.addr())))?;
+
+    Some((local_ip, gateway, scope_id))
+}
+
+/// Finds the home router, derived from the network [`State`] if one is given.
+///
+/// Not every platform reports gateways along with the interfaces, in which case the OS is queried
+/// after all.
+async fn home_router(state: Option<&State>) -> Option<HomeRouter> {
+    match state.and_then(HomeRouter::from_state) {
+        Some(home_router) => Some(home_router),
+        None => HomeRouter::new().await,
+    }
+}
+
+/// Randomizes `backoff` to somewhere between half and all of it.
+fn with_jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(rand::rng().random_range(0.0..=1.0))
+}
+
 /// Gets the local ip and gateway address for port mapping.
-fn ip_and_gateway() -> Result<(Ipv4Addr, Ipv4Addr), ProbeError> {
-    let Some(HomeRouter { gateway, my_ip }) = HomeRouter::new() else {
-        return Err(e!(ProbeError::NoGateway));
+///
+/// The overrides in the [`Config`] take precedence over detection. If a network [`State`] is
+/// given, the home router is taken from it rather than queried from the OS, and the local ip is
+/// the one on the gateway's network.
+async fn ip_and_gateway(
+    state: Option<&State>,
+    config: &Config,
+) -> Result<(Ipv4Addr, Ipv4Addr), ProbeError> {
+    if let (Some(local_ip), Some(gateway)) = (config.local_ip_override, config.gateway_override) {
+        return Ok((local_ip, gateway));
+    }
+
+    let (gateway, my_ip) = match (config.gateway_override, home_router(state).await) {
+        (Some(gateway), home_router) => (
+            gateway.into(),
+            home_router.and_then(|home_router| home_router.my_ip),
+        ),
+        (None, Some(HomeRouter { gateway, my_ip })) => (gateway, my_ip),
+        (None, None) => return Err(e!(ProbeError::NoGateway)),
     };
+    // the gateway only accepts requests from its own network, which on multi-homed hosts is not
+    // necessarily where the preferred local address is
+    let my_ip = state
+        .and_then(|state| state.local_ip_for(gateway))
+        .or(my_ip);
 
-    let local_ip = match my_ip {
-        Some(std::net::IpAddr::V4(ip))
+    let local_ip = match (config.local_ip_override, my_ip) {
+        (Some(ip), _) => ip,
+        (None, Some(IpAddr::V4(ip)))
             if !ip.is_unspecified() && !ip.is_loopback() && !ip.is_multicast() =>
         {
             ip
         }
-        other => {
+        (None, other) => {
             debug!("no address suitable for port mapping found ({other:?}), using localhost");
             Ipv4Addr::LOCALHOST
         }
     };
 
-    let std::net::IpAddr::V4(gateway) = gateway else {
+    let IpAddr::V4(gateway) = gateway else {
         return Err(e!(ProbeError::Ipv6Gateway));
     };
 
     Ok((local_ip, gateway))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    #[test]
+    fn test_protocol_denylist() {
+        let config = Config {
+            protocol_denylist: vec![DenylistEntry {
+                protocol: MappingProtocol::Upnp,
+                router: "acme rt-100".to_string(),
+            }],
+            ..Default::default()
+        };
+        let router = GatewayInfo {
+            manufacturer: Some("ACME".to_string()),
+            model_name: Some("RT-1000".to_string()),
+            model_number: None,
+            friendly_name: None,
+        };
+        let other = GatewayInfo {
+            manufacturer: Some("ACME".to_string()),
+            model_name: Some("RT-2000".to_string()),
+            model_number: None,
+            friendly_name: None,
+        };
+
+        assert!(config.is_denied(MappingProtocol::Upnp, Some(&router)));
+        assert!(!config.is_denied(MappingProtocol::Pcp, Some(&router)));
+        assert!(!config.is_denied(MappingProtocol::Upnp, Some(&other)));
+        assert!(!config.is_denied(MappingProtocol::Upnp, None));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_roundtrip() {
+        let config = Config {
+            #[cfg(feature = "upnp")]
+            enable_upnp: false,
+            protocol: Protocol::Tcp,
+            protocol_denylist: vec![DenylistEntry {
+                protocol: MappingProtocol::NatPmp,
+                router: "acme rt-100".to_string(),
+            }],
+            gateway_override: Some(Ipv4Addr::new(192, 168, 1, 1)),
+            mapping_retry: None,
+            renewal_fraction: 0.75,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        let restored: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(format!("{restored:?}"), format!("{config:?}"));
+
+        // missing fields take their default
+        let restored: Config = serde_json::from_str(r#"{"max_mappings":3}"#).unwrap();
+        assert_eq!(restored.max_mappings, Some(3));
+        assert_eq!(
+            restored.renewal_fraction,
+            Config::default().renewal_fraction
+        );
+        assert_eq!(restored.timeouts, Timeouts::default());
+
+        let output = ProbeOutput {
+            upnp: true,
+            pcp: false,
+            nat_pmp: true,
+            upnp_gateway: Some(GatewayInfo {
+                manufacturer: Some("ACME".to_string()),
+                ..Default::default()
+            }),
+        };
+        let json = serde_json::to_string(&output).unwrap();
+        assert_eq!(serde_json::from_str::<ProbeOutput>(&json).unwrap(), output);
+    }
+
+    #[cfg(feature = "pcp")]
+    #[tokio::test]
+    async fn test_ipv6_and_gateway() {
+        let mut state = State::fake();
+        // an ipv4 gateway is handled by ip_and_gateway
+        assert_eq!(
+            ipv6_and_gateway(Some(&state), &Config::default()).await,
+            None
+        );
+
+        let gateway: Ipv6Addr = "fe80::1".parse().unwrap();
+        let local_ip: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        state = State::fake_with_home_router(&HomeRouter {
+            gateway: gateway.into(),
+            my_ip: Some(local_ip.into()),
+        });
+        // the gateway is reached through the fake interface
+        let scope_id = state.interfaces["wifi0"].index();
+        assert_eq!(
+            ipv6_and_gateway(Some(&state), &Config::default()).await,
+            Some((local_ip, gateway, scope_id))
+        );
+        assert!(matches!(
+            ip_and_gateway(Some(&state), &Config::default()).await,
+            Err(ProbeError::Ipv6Gateway { .. })
+        ));
+
+        // a link-local address can't be mapped
+        state = State::fake_with_home_router(&HomeRouter {
+            gateway: gateway.into(),
+            my_ip: Some("fe80::2".parse::<Ipv6Addr>().unwrap().into()),
+        });
+        assert_eq!(
+            ipv6_and_gateway(Some(&state), &Config::default()).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ip_and_gateway_overrides() {
+        let state = State::fake();
+        let local_ip = Ipv4Addr::new(192, 168, 0, 189);
+
+        let config = Config::default();
+        assert_eq!(
+            ip_and_gateway(Some(&state), &config).await.unwrap(),
+            (local_ip, Ipv4Addr::new(192, 168, 0, 1))
+        );
+
+        // the local ip follows the overridden gateway's network
+        let gateway = Ipv4Addr::new(192, 168, 0, 254);
+        let config = Config {
+            gateway_override: Some(gateway),
+            ..Default::default()
+        };
+        assert_eq!(
+            ip_and_gateway(Some(&state), &config).await.unwrap(),
+            (local_ip, gateway)
+        );
+
+        let overridden_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let config = Config {
+            gateway_override: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            local_ip_override: Some(overridden_ip),
+            ..Default::default()
+        };
+        let mut state = state;
+        state.interfaces.clear();
+        assert_eq!(
+            ip_and_gateway(Some(&state), &config).await.unwrap(),
+            (overridden_ip, Ipv4Addr::new(10, 0, 0, 1))
+        );
+    }
+
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_external_address_stream() {
+        let client = Client::new(Config::default()).unwrap();
+        let mut addresses = client.external_address_stream();
+        assert_eq!(addresses.next().await, Some(None));
+        client.shutdown().await;
+        assert_eq!(addresses.next().await, None);
+    }
+
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    #[test]
+    fn test_try_new() {
+        let res = Client::try_new(Config::default());
+        assert!(
+            matches!(res, Err(NewClientError::NoRuntime { .. })),
+            "{res:?}"
+        );
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = rt.enter();
+        let res = Client::try_new(Config {
+            renewal_fraction: 2.0,
+            ..Default::default()
+        });
+        assert!(
+            matches!(res, Err(NewClientError::InvalidConfig { .. })),
+            "{res:?}"
+        );
+        let client = Client::try_new(Config::default()).unwrap();
+        rt.block_on(client.shutdown());
+    }
+
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    #[test]
+    #[ntest::timeout(2000)]
+    fn test_new_in() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // no runtime is entered here
+        let client = Client::new_in(Config::default(), rt.handle().clone()).unwrap();
+        // shutting down waits for the service, so it must be running on the given runtime
+        rt.block_on(client.shutdown());
+        let res = client.try_procure_mapping();
+        assert!(
+            matches!(res, Err(ServiceSendError::Closed { .. })),
+            "{res:?}"
+        );
+    }
+
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_try_update_local_port() {
+        let client = Client::new(Config::default()).unwrap();
+        let local_port = NonZeroU16::new(9586).unwrap();
+        client.try_update_local_port(local_port).unwrap();
+        client.try_procure_mapping().unwrap();
+
+        client.shutdown().await;
+        let res = client.try_update_local_port(local_port);
+        assert!(
+            matches!(res, Err(ServiceSendError::Closed { .. })),
+            "{res:?}"
+        );
+        let res = client.try_procure_mapping();
+        assert!(
+            matches!(res, Err(ServiceSendError::Closed { .. })),
+            "{res:?}"
+        );
+    }
+
+    #[cfg(feature = "nat_pmp")]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_get_mapping_once_failure() {
+        use crate::test_util::unresponsive_gateway;
+
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(100));
+        let client = Client::new(config).unwrap();
+        let res = client
+            .get_mapping_once(NonZeroU16::new(9589).unwrap())
+            .await;
+        assert!(matches!(res, Err(MappingError::Failed { .. })), "{res:?}");
+        // the protocol error is reachable through the source chain
+        let error = res.unwrap_err();
+        let source = std::error::Error::source(&error).expect("mapping error is the source");
+        assert!(source.is::<mapping::Error>(), "{source:?}");
+    }
+
+    #[tokio::test]
+    async fn test_probe_join_error_source() {
+        let join_error = tokio::spawn(std::future::pending::<()>());
+        join_error.abort();
+        let join_error = join_error.await.unwrap_err();
+        let error = ProbeError::from_join_error(join_error);
+        assert!(matches!(
+            error,
+            ProbeError::Join {
+                is_panic: false,
+                is_cancelled: true,
+                ..
+            }
+        ));
+        let source = std::error::Error::source(&error).expect("join error is the source");
+        assert!(source.to_string().contains("cancelled"), "{source}");
+    }
+
+    #[cfg(feature = "nat_pmp")]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_subscribe_events_failure() {
+        use crate::test_util::unresponsive_gateway;
+
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(100));
+        let client = Client::new(config).unwrap();
+        let mut events = client.subscribe_events();
+        client.update_local_port(NonZeroU16::new(9590).unwrap());
+        let event = events.recv().await;
+        assert!(
+            matches!(event, Some(MappingEvent::Failed { .. })),
+            "{event:?}"
+        );
+    }
+
+    #[cfg(feature = "nat_pmp")]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_mapping_retry() {
+        use crate::test_util::unresponsive_gateway;
+
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(50));
+        let client = Client::new(Config {
+            mapping_retry: Some(RetryBackoff {
+                initial: Duration::from_millis(20),
+                max: Duration::from_millis(40),
+            }),
+            ..config
+        })
+        .unwrap();
+        let mut events = client.subscribe_events();
+        client.update_local_port(NonZeroU16::new(9591).unwrap());
+        // the initial attempt and at least one retry fail
+        for _ in 0..2 {
+            let event = events.recv().await;
+            assert!(
+                matches!(event, Some(MappingEvent::Failed { .. })),
+                "{event:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "nat_pmp")]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_on_network_change() {
+        use crate::test_util::unresponsive_gateway;
+
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(50));
+        let client = Client::new(config).unwrap();
+        let mut events = client.subscribe_events();
+        client.update_local_port(NonZeroU16::new(9592).unwrap());
+        let event = events.recv().await;
+        assert!(
+            matches!(event, Some(MappingEvent::Failed { .. })),
+            "{event:?}"
+        );
+
+        // a pending probe is answered by the one started after the change
+        let probe = client.probe();
+        client.on_network_change();
+        let output = probe.await.unwrap().unwrap();
+        assert!(!output.nat_pmp);
+
+        // the new probe found nothing, so no mapping is attempted blindly
+        let event = events.recv().await;
+        assert!(
+            matches!(event, Some(MappingEvent::ProbeCompleted { .. })),
+            "{event:?}"
+        );
+        let event = time::timeout(Duration::from_millis(200), events.recv()).await;
+        assert!(event.is_err(), "{event:?}");
+    }
+
+    #[cfg(all(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_on_network_change_maps_after_probe() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let gateway = MockGateway::spawn(MockGatewayConfig {
+            pcp: false,
+            ..Default::default()
+        })
+        .expect("gateway is spawned");
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+        let mut events = client.subscribe_events();
+        client.update_local_port(NonZeroU16::new(9593).unwrap());
+        loop {
+            match events.recv().await {
+                Some(MappingEvent::Acquired { .. }) => break,
+                Some(_) => {}
+                None => panic!("service stopped"),
+            }
+        }
+
+        client.on_network_change();
+        let mut probed = false;
+        loop {
+            match events.recv().await {
+                Some(MappingEvent::ProbeCompleted { .. }) => probed = true,
+                Some(MappingEvent::Acquired { .. }) => break,
+                Some(_) => {}
+                None => panic!("service stopped"),
+            }
+        }
+        assert!(probed, "mapped before the new probe completed");
+    }
+
+    #[cfg(feature = "nat_pmp")]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_force_probe_coalesces() {
+        use crate::test_util::unresponsive_gateway;
+
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(50));
+        let client = Client::new(config).unwrap();
+        let forced = client.force_probe();
+        let regular = client.probe();
+        let forced = forced.await.unwrap().unwrap();
+        let regular = regular.await.unwrap().unwrap();
+        assert!(!forced.nat_pmp);
+        assert_eq!(forced, regular);
+    }
+
+    #[cfg(all(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_socket_factory() {
+        use crate::test_util::unresponsive_gateway;
+
+        let bound = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let socket_factory = SocketFactory::new({
+            let bound = bound.clone();
+            move |addr| {
+                bound.lock().unwrap().push(addr);
+                std::net::UdpSocket::bind(addr)
+            }
+        });
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(50));
+        let client = Client::new(Config {
+            enable_pcp: true,
+            timeouts: Timeouts {
+                pcp_recv: Duration::from_millis(50),
+                ..config.timeouts
+            },
+            socket_factory: Some(socket_factory),
+            ..config
+        })
+        .unwrap();
+        client.probe().await.unwrap().unwrap();
+        // one socket for each of the PCP and NAT-PMP probes
+        let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        assert_eq!(*bound.lock().unwrap(), vec![local_addr, local_addr]);
+    }
+
+    #[cfg(all(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_packet_capture() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let gateway = MockGateway::spawn(MockGatewayConfig::default()).expect("gateway is spawned");
+        let captured = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let packet_capture = PacketCapture::new({
+            let captured = captured.clone();
+            move |direction, packet| captured.lock().unwrap().push((direction, packet.to_vec()))
+        });
+        let client = Client::new(Config {
+            packet_capture: Some(packet_capture),
+            ..gateway.client_config()
+        })
+        .expect("config is valid");
+        client.update_interface_state(gateway.state());
+        client
+            .probe()
+            .await
+            .expect("service is running")
+            .expect("probe succeeds");
+
+        // a request and its response for each of the PCP and NAT-PMP probes, told apart by version
+        let mut versions: Vec<_> = captured
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(direction, packet)| (packet[0], *direction))
+            .collect();
+        versions.sort_by_key(|(version, direction)| {
+            (*version, *direction == PacketDirection::Received)
+        });
+        assert_eq!(
+            versions,
+            [
+                (0, PacketDirection::Sent),
+                (0, PacketDirection::Received),
+                (2, PacketDirection::Sent),
+                (2, PacketDirection::Received),
+            ]
+        );
+        client.shutdown().await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_socket_mark() {
+        use crate::test_util::unresponsive_gateway;
+
+        const MARK: u32 = 42;
+        // setting a mark needs CAP_NET_ADMIN
+        let probe = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        if socket2::SockRef::from(&probe).set_mark(MARK).is_err() {
+            return;
+        }
+
+        // keep a handle to each socket to check its mark
+        let sockets = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let socket_factory = SocketFactory::new({
+            let sockets = sockets.clone();
+            move |addr| {
+                let socket = std::net::UdpSocket::bind(addr)?;
+                sockets.lock().unwrap().push(socket.try_clone()?);
+                Ok(socket)
+            }
+        });
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(50));
+        let client = Client::new(Config {
+            enable_pcp: true,
+            timeouts: Timeouts {
+                pcp_recv: Duration::from_millis(50),
+                ..config.timeouts
+            },
+            socket_factory: Some(socket_factory),
+            socket_mark: Some(MARK),
+            ..config
+        })
+        .unwrap();
+        client.probe().await.unwrap().unwrap();
+        let sockets = sockets.lock().unwrap();
+        assert_eq!(sockets.len(), 2);
+        for socket in sockets.iter() {
+            assert_eq!(socket2::SockRef::from(socket).mark().unwrap(), MARK);
+        }
+    }
+
+    #[cfg(feature = "nat_pmp")]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_diagnostics() {
+        use crate::test_util::unresponsive_gateway;
+
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(50));
+        let gateway = config.gateway_override;
+        let client = Client::new(config).unwrap();
+        let diagnostics = client.diagnostics().await;
+        assert_eq!(diagnostics.local_ip, Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(diagnostics.gateway, gateway);
+        assert_eq!(diagnostics.upnp_gateway_url, None);
+        // UPnP is disabled, so it was never probed
+        #[cfg(feature = "upnp")]
+        assert!(diagnostics.upnp_probe_error.is_none());
+        // the probe failed, but it's still known where it was sent from
+        let probe_addr = diagnostics
+            .nat_pmp_probe_addr
+            .expect("probe socket was bound");
+        assert_eq!(probe_addr.ip(), &Ipv4Addr::LOCALHOST);
+        assert_ne!(probe_addr.port(), 0);
+        assert_eq!(diagnostics.pcp_probe_addr, None);
+        assert!(!diagnostics.probe.unwrap().nat_pmp);
+    }
+
+    #[cfg(feature = "nat_pmp")]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_probe_detailed_unanswered() {
+        use crate::test_util::unresponsive_gateway;
+
+        let (_gateway, config) = unresponsive_gateway(Duration::from_millis(50));
+        let client = Client::new(config).unwrap();
+        let (output, timing) = client.probe_detailed().await.unwrap();
+        assert!(!output.nat_pmp);
+        // protocols that were not probed or did not answer are not timed
+        assert_eq!(timing, ProbeTiming::default());
+    }
+
+    #[test]
+    fn test_with_jitter_bounds() {
+        let backoff = Duration::from_secs(4);
+        for _ in 0..100 {
+            let delay = with_jitter(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_mapping_info_is_cgnat() {
+        let info = |ip| MappingInfo {
+            local_port: NonZeroU16::new(9606).unwrap(),
+            external: SocketAddrV4::new(ip, 19606),
+            protocol: MappingProtocol::NatPmp,
+            acquired: Instant::now(),
+            expires: Instant::now(),
+            lease: None,
+            reachable: None,
+        };
+        assert!(info(Ipv4Addr::new(100, 64, 0, 1)).is_cgnat());
+        assert!(info(Ipv4Addr::new(100, 127, 255, 254)).is_cgnat());
+        assert!(info(Ipv4Addr::new(192, 168, 1, 2)).is_cgnat());
+        assert!(!info(Ipv4Addr::new(100, 128, 0, 1)).is_cgnat());
+        assert!(!info(Ipv4Addr::new(203, 0, 113, 1)).is_cgnat());
+    }
+
+    #[cfg(all(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_protocol_priority() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let gateway = MockGateway::spawn(MockGatewayConfig::default()).expect("gateway is spawned");
+
+        // the gateway answers both, by default PCP would be used
+        let client = Client::new(Config {
+            protocol_priority: vec![MappingProtocol::NatPmp],
+            ..gateway.client_config()
+        })
+        .expect("config is valid");
+        client.update_interface_state(gateway.state());
+        let output = client
+            .probe()
+            .await
+            .expect("service is running")
+            .expect("probe succeeds");
+        assert!(output.pcp && output.nat_pmp);
+
+        let local_port = NonZeroU16::new(9596).unwrap();
+        client
+            .get_mapping_once(local_port)
+            .await
+            .expect("mapping succeeds");
+        let info = client.mapping_info().expect("mapping is active");
+        assert_eq!(info.protocol, MappingProtocol::NatPmp);
+        client.shutdown().await;
+    }
+
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_reachability_check() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let gateway_config = MockGatewayConfig::default();
+        let external_ip = gateway_config.external_ip;
+        let gateway = MockGateway::spawn(gateway_config).expect("gateway is spawned");
+
+        let (checked_tx, mut checked_rx) = mpsc::unbounded_channel();
+        let check = ReachabilityCheck::new(move |addr| {
+            checked_tx.send(addr).ok();
+            async { true }
+        });
+        let client = Client::new(Config {
+            reachability_check: Some(check),
+            ..gateway.client_config()
+        })
+        .expect("config is valid");
+        client.update_interface_state(gateway.state());
+        let mut info_rx = client.watch_mapping_info();
+        let addr = client
+            .get_mapping_once(NonZeroU16::new(9597).unwrap())
+            .await
+            .expect("mapping succeeds");
+        assert_eq!(*addr.ip(), external_ip);
+
+        let info = info_rx
+            .wait_for(|info| info.as_ref().is_some_and(|info| info.reachable.is_some()))
+            .await
+            .expect("service is running")
+            .clone()
+            .expect("mapping is active");
+        assert_eq!(info.external, addr);
+        assert_eq!(info.reachable, Some(true));
+        assert_eq!(checked_rx.recv().await, Some(addr));
+        client.shutdown().await;
+    }
+
+    #[cfg(all(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_protocol_selection() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let cases = [
+            (true, false, MappingProtocol::Pcp),
+            (false, true, MappingProtocol::NatPmp),
+        ];
+        for (local_port, (pcp, nat_pmp, expected)) in (9598..).zip(cases) {
+            let gateway = MockGateway::spawn(MockGatewayConfig {
+                pcp,
+                nat_pmp,
+                ..Default::default()
+            })
+            .expect("gateway is spawned");
+            let client = Client::new(gateway.client_config()).expect("config is valid");
+            client.update_interface_state(gateway.state());
+            let output = client
+                .probe()
+                .await
+                .expect("service is running")
+                .expect("probe succeeds");
+            assert_eq!((output.pcp, output.nat_pmp), (pcp, nat_pmp));
+
+            client
+                .get_mapping_once(NonZeroU16::new(local_port).unwrap())
+                .await
+                .expect("mapping succeeds");
+            let info = client.mapping_info().expect("mapping is active");
+            assert_eq!(info.protocol, expected);
+            client.shutdown().await;
+        }
+    }
+
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_reachable_addresses() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let gateway = MockGateway::spawn(MockGatewayConfig::default()).expect("gateway is spawned");
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+
+        // the fake interface state has no public addresses
+        let local_port = NonZeroU16::new(9600).unwrap();
+        assert!(client.reachable_addresses(local_port).await.is_empty());
+
+        let external = client
+            .get_mapping_once(local_port)
+            .await
+            .expect("mapping succeeds");
+        assert_eq!(
+            client.reachable_addresses(local_port).await,
+            vec![SocketAddr::V4(external)]
+        );
+        // other ports are not mapped
+        let other_port = NonZeroU16::new(9601).unwrap();
+        assert!(client.reachable_addresses(other_port).await.is_empty());
+        client.shutdown().await;
+    }
+
+    #[cfg(feature = "pcp")]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_map_peer() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let local_port = NonZeroU16::new(9603).unwrap();
+        let remote_port = NonZeroU16::new(9604).unwrap();
+        let remote_ip = Ipv4Addr::new(198, 51, 100, 1);
+        let gateway = MockGateway::spawn(MockGatewayConfig {
+            nat_pmp: false,
+            external_port: NonZeroU16::new(19603),
+            ..Default::default()
+        })
+        .expect("gateway is spawned");
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+
+        let mapping = client
+            .map_peer(local_port, remote_ip, remote_port)
+            .await
+            .expect("peer mapping succeeds");
+        assert_eq!(mapping.external().port(), 19603);
+        mapping.release().await;
+        // the service doesn't track peer mappings
+        assert!(client.mapping_info().is_none());
+        client.shutdown().await;
+
+        // PCP must be available, not only enabled
+        let gateway = MockGateway::spawn(MockGatewayConfig {
+            pcp: false,
+            ..Default::default()
+        })
+        .expect("gateway is spawned");
+        let client = Client::new(Config {
+            enable_pcp: true,
+            protocol_priority: vec![MappingProtocol::Pcp],
+            ..gateway.client_config()
+        })
+        .expect("config is valid");
+        client.update_interface_state(gateway.state());
+        let res = client.map_peer(local_port, remote_ip, remote_port).await;
+        assert!(
+            matches!(res, Err(MappingError::NoProtocol { .. })),
+            "{res:?}"
+        );
+        client.shutdown().await;
+    }
+
+    #[cfg(feature = "pcp")]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_pcp_third_party() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let gateway = MockGateway::spawn(MockGatewayConfig {
+            nat_pmp: false,
+            ..Default::default()
+        })
+        .expect("gateway is spawned");
+        // NAT-PMP is disabled, as the gateway doesn't answer it
+        let config = Config {
+            pcp_third_party: Some(Ipv4Addr::new(192, 168, 0, 3)),
+            ..gateway.client_config()
+        };
+        // NAT-PMP can't map for a third party
+        #[cfg(feature = "nat_pmp")]
+        assert!(matches!(
+            Config {
+                enable_nat_pmp: true,
+                ..config.clone()
+            }
+            .validate(),
+            Err(ConfigError::ThirdPartyRequiresPcpOnly { .. })
+        ));
+        let client = Client::new(config).expect("config is valid");
+        client.update_interface_state(gateway.state());
+
+        let local_port = NonZeroU16::new(9605).unwrap();
+        client
+            .get_mapping_once(local_port)
+            .await
+            .expect("mapping succeeds");
+        let info = client.mapping_info().expect("mapping is active");
+        assert_eq!(info.protocol, MappingProtocol::Pcp);
+        client.shutdown().await;
+    }
+
+    #[cfg(all(any(feature = "pcp", feature = "nat_pmp"), feature = "metrics"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_redundant_mapping_requests_coalesce() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let gateway = MockGateway::spawn(MockGatewayConfig::default()).expect("gateway is spawned");
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+        client
+            .probe()
+            .await
+            .expect("service is running")
+            .expect("probe succeeds");
+
+        // both requests are handled before the first attempt can finish
+        let local_port = NonZeroU16::new(9602).unwrap();
+        let first = client.get_mapping_once(local_port);
+        client.procure_mapping();
+        let second = client.get_mapping_once(local_port);
+        let (first, second) = tokio::join!(first, second);
+        let external = first.expect("mapping succeeds");
+        assert_eq!(second.expect("mapping succeeds"), external);
+        assert_eq!(client.metrics().mapping_attempts.get(), 1);
+        client.shutdown().await;
+    }
+
+    #[cfg(feature = "pcp")]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_pcp_renewal_reuses_nonce() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let config = MockGatewayConfig {
+            nat_pmp: false,
+            // renewed after a second
+            lifetime_seconds: 2,
+            ..Default::default()
+        };
+        let gateway = MockGateway::spawn(config).expect("gateway is spawned");
+        // nonces of the PCP MAP requests sent
+        let nonces = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let packet_capture = PacketCapture::new({
+            let nonces = nonces.clone();
+            move |direction, packet: &[u8]| {
+                if direction == PacketDirection::Sent && packet[..2] == [2, 1] {
+                    nonces.lock().unwrap().push(packet[24..36].to_vec());
+                }
+            }
+        });
+        let client = Client::new(Config {
+            packet_capture: Some(packet_capture),
+            ..gateway.client_config()
+        })
+        .expect("config is valid");
+        client.update_interface_state(gateway.state());
+        client
+            .probe()
+            .await
+            .expect("service is running")
+            .expect("probe succeeds");
+
+        client.update_local_port(NonZeroU16::new(9604).unwrap());
+        while nonces.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let nonces = nonces.lock().unwrap().clone();
+        assert_eq!(nonces[0], nonces[1]);
+        client.shutdown().await;
+    }
+
+    #[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_external_port_changed() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let config = MockGatewayConfig {
+            external_port: NonZeroU16::new(19605),
+            // renewed after a second
+            lifetime_seconds: 2,
+            ..Default::default()
+        };
+        let external_ip = config.external_ip;
+        let gateway = MockGateway::spawn(config).expect("gateway is spawned");
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+        client
+            .probe()
+            .await
+            .expect("service is running")
+            .expect("probe succeeds");
+
+        let mut events = client.subscribe_events();
+        client.update_local_port(NonZeroU16::new(9605).unwrap());
+        let event = events.recv().await;
+        assert!(
+            matches!(event, Some(MappingEvent::Acquired { .. })),
+            "{event:?}"
+        );
+
+        gateway.set_external_port(NonZeroU16::new(29605));
+        let event = events.recv().await;
+        assert!(
+            matches!(event, Some(MappingEvent::Renewed { .. })),
+            "{event:?}"
+        );
+        let event = events.recv().await;
+        let Some(MappingEvent::ExternalPortChanged { old, new }) = event else {
+            panic!("unexpected event {event:?}");
+        };
+        assert_eq!(old, SocketAddrV4::new(external_ip, 19605));
+        assert_eq!(new, SocketAddrV4::new(external_ip, 29605));
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().external_port_changed.get(), 1);
+        client.shutdown().await;
+    }
+
+    #[test]
+    fn test_mapping_protocol_display() {
+        assert_eq!(MappingProtocol::Upnp.to_string(), "UPnP");
+        assert_eq!(MappingProtocol::Pcp.to_string(), "PCP");
+        assert_eq!(MappingProtocol::NatPmp.to_string(), "NAT-PMP");
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let config = Config::builder()
+            .protocol(Protocol::Tcp)
+            .max_mappings(Some(2))
+            .mapping_description("test")
+            .renewal_fraction(0.75)
+            .build();
+        assert_eq!(config.protocol, Protocol::Tcp);
+        assert_eq!(config.max_mappings, Some(2));
+        assert_eq!(config.mapping_description, "test");
+        assert_eq!(config.renewal_fraction, 0.75);
+        assert_eq!(config.timeouts, Timeouts::default());
+        #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+        assert!(config.validate().is_ok());
+
+        // each protocol is toggled on its own
+        #[cfg(all(feature = "upnp", feature = "pcp"))]
+        {
+            let config = Config::builder().enable_upnp(false).build();
+            assert!(!config.enable_upnp);
+            assert!(config.enable_pcp);
+        }
+    }
+
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    #[ntest::timeout(2000)]
+    async fn test_max_mappings_exceeded() {
+        let client = Client::new(Config {
+            max_mappings: Some(1),
+            ..Default::default()
+        })
+        .expect("config is valid");
+        client.update_local_port(NonZeroU16::new(9600).unwrap());
+        let err = client
+            .add_port(NonZeroU16::new(9601).unwrap())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MappingError::MaxMappingsExceeded { max: 1, .. }
+        ));
+        client.shutdown().await;
+
+        let client = Client::new(Config {
+            max_mappings: Some(0),
+            ..Default::default()
+        })
+        .expect("config is valid");
+        let err = client
+            .get_mapping_once(NonZeroU16::new(9600).unwrap())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MappingError::MaxMappingsExceeded { max: 0, .. }
+        ));
+        client.shutdown().await;
+    }
+
+    #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+    #[tokio::test]
+    async fn test_no_protocol_compiled_in() {
+        let res = Client::new(Config::default());
+        assert!(
+            matches!(res, Err(ConfigError::NoProtocolCompiledIn { .. })),
+            "{res:?}"
+        );
+    }
+
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    #[test]
+    fn test_config_validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            #[cfg(feature = "upnp")]
+            enable_upnp: false,
+            #[cfg(feature = "pcp")]
+            enable_pcp: false,
+            #[cfg(feature = "nat_pmp")]
+            enable_nat_pmp: false,
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::NoProtocolEnabled { .. })
+        ));
+
+        let config = Config {
+            protocol_priority: Vec::new(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::EmptyProtocolPriority { .. })
+        ));
+
+        #[cfg(all(feature = "pcp", feature = "nat_pmp"))]
+        {
+            let config = Config {
+                enable_pcp: false,
+                ..Default::default()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(ConfigError::DisabledPriorityProtocol {
+                    protocol: MappingProtocol::Pcp,
+                    ..
+                })
+            ));
+            // the builder drops disabled protocols from the priority
+            let config = Config::builder().enable_pcp(false).build();
+            assert!(!config.protocol_priority.contains(&MappingProtocol::Pcp));
+            assert!(config.validate().is_ok());
+        }
+
+        let config = Config {
+            protocol_denylist: vec![DenylistEntry {
+                protocol: MappingProtocol::Pcp,
+                router: " ".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::EmptyRouterSignature { index: 0, .. })
+        ));
+
+        let config = Config {
+            mapping_retry: Some(RetryBackoff {
+                initial: Duration::from_secs(2),
+                max: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidRetryBackoff { .. })
+        ));
+
+        for fraction in [0.0, 1.0, f32::NAN] {
+            let config = Config {
+                renewal_fraction: fraction,
+                ..Default::default()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(ConfigError::InvalidRenewalFraction { .. })
+            ));
+        }
+    }
+
+    #[cfg(all(feature = "nat_pmp", feature = "metrics"))]
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_cancellable_probe() {
+        use crate::test_util::unresponsive_gateway;
+
+        // the gateway never answers, so that probes stay in progress
+        let (_gateway, config) = unresponsive_gateway(Duration::from_secs(10));
+        let client = Client::new(config).unwrap();
+        let metrics = client.metrics();
+        let probes_started = async |count| {
+            while metrics.probes_started.get() < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        // dropping the only requester aborts the probe, so the next request starts another
+        let cancellable = client.cancellable_probe();
+        probes_started(1).await;
+        drop(cancellable);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let regular = client.probe();
+        probes_started(2).await;
+
+        // dropping a regular requester keeps the probe going, so the next request joins it
+        drop(regular);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let cancellable = client.cancellable_probe();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(metrics.probes_started.get(), 2);
+        drop(cancellable);
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_probe_all_protocols_disabled() {
+        let config = Config {
+            #[cfg(feature = "upnp")]
+            enable_upnp: false,
+            #[cfg(feature = "pcp")]
+            enable_pcp: false,
+            #[cfg(feature = "nat_pmp")]
+            enable_nat_pmp: false,
+            ..Default::default()
+        };
+        let (_tx, rx) = mpsc::channel(1);
+        let (mut service, _watcher, _history) = Service::new(config, rx, Default::default());
+        let (result_tx, result_rx) = oneshot::channel();
+        let waiter = ProbeWaiter {
+            result_tx,
+            cancellable: false,
+        };
+        service.probe_request(false, waiter).await;
+        let res = result_rx.await.unwrap();
+        assert!(
+            matches!(res, Err(ProbeError::AllProtocolsDisabled { .. })),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_history_capacity() {
+        let config = Config {
+            probe_history_capacity: 2,
+            ..Default::default()
+        };
+        let (_tx, rx) = mpsc::channel(1);
+        let (service, _watcher, history) = Service::new(config, rx, Default::default());
+
+        for pcp in [false, true, false] {
+            service.record_probe(ProbeOutput {
+                upnp: false,
+                pcp,
+                nat_pmp: false,
+                upnp_gateway: None,
+            });
+        }
+
+        let history = history.borrow();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].1.pcp);
+        assert!(!history[1].1.pcp);
+        assert!(history[0].0 <= history[1].0);
+    }
+
+    #[cfg(all(feature = "pcp", feature = "nat_pmp"))]
+    #[tokio::test]
+    async fn test_initial_probe() {
+        let gateway_info = GatewayInfo {
+            manufacturer: Some("ACME".to_string()),
+            ..Default::default()
+        };
+        let config = Config {
+            enable_nat_pmp: false,
+            initial_probe: Some(ProbeOutput {
+                upnp: true,
+                pcp: true,
+                nat_pmp: true,
+                upnp_gateway: Some(gateway_info.clone()),
+            }),
+            ..Default::default()
+        };
+        let (_tx, rx) = mpsc::channel(1);
+        let (service, _watcher, _history) = Service::new(config, rx, Default::default());
+
+        let output = service.full_probe.output();
+        // the gateway's address is unknown, so UPnP needs probing again
+        assert!(!output.upnp);
+        assert!(output.pcp);
+        // disabled protocols are not trusted
+        assert!(!output.nat_pmp);
+        // the identity is still known for the denylist
+        let router = service.full_probe.upnp_gateway_info.as_ref();
+        assert_eq!(router, Some(&gateway_info));
+        assert_eq!(*service.upnp_gateway_info.borrow(), Some(gateway_info));
+        // no need to probe again before mapping
+        assert!(service.full_probe.last_probe + UNAVAILABILITY_TRUST_DURATION > Instant::now());
+    }
+}
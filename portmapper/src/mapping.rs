@@ -1,11 +1,26 @@
 //! A port mapping created with one of the supported protocols.
 
+#[cfg(feature = "upnp")]
+use std::sync::Arc;
 use std::{net::Ipv4Addr, num::NonZeroU16, time::Duration};
 
+#[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+use n0_error::e;
 use n0_error::stack_error;
 
-use super::{nat_pmp, pcp, upnp};
+#[cfg(feature = "nat_pmp")]
+use super::nat_pmp;
+#[cfg(feature = "pcp")]
+use super::pcp;
+#[cfg(feature = "upnp")]
+use super::upnp;
+use crate::MappingProtocol;
+#[cfg(feature = "upnp")]
+use crate::Metrics;
+#[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
 use crate::Protocol;
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+use crate::util::SocketOptions;
 
 pub(super) trait PortMapped: std::fmt::Debug + Unpin {
     fn external(&self) -> (Ipv4Addr, NonZeroU16);
@@ -17,10 +32,13 @@ pub(super) trait PortMapped: std::fmt::Debug + Unpin {
 #[derive(derive_more::Debug)]
 pub enum Mapping {
     /// A UPnP mapping.
+    #[cfg(feature = "upnp")]
     Upnp(upnp::Mapping),
     /// A PCP mapping.
+    #[cfg(feature = "pcp")]
     Pcp(pcp::Mapping),
     /// A NAT-PMP mapping.
+    #[cfg(feature = "nat_pmp")]
     NatPmp(nat_pmp::Mapping),
 }
 
@@ -29,36 +47,84 @@ pub enum Mapping {
 #[stack_error(derive, add_meta, from_sources)]
 #[non_exhaustive]
 pub enum Error {
+    #[cfg(feature = "pcp")]
     #[error("PCP mapping failed")]
     Pcp { source: pcp::Error },
+    #[cfg(feature = "nat_pmp")]
     #[error("NAT-PMP mapping failed")]
     NatPmp { source: nat_pmp::Error },
+    #[cfg(feature = "upnp")]
     #[error("UPnP mapping failed")]
     Upnp { source: upnp::Error },
+    #[error("Mapping timed out after {timeout:?}")]
+    TimedOut { timeout: Duration },
 }
 
 impl Mapping {
-    /// Create a new PCP mapping.
+    /// Create a new PCP mapping, renewing an existing one if its `nonce` is given.
+    ///
+    /// With `third_party`, the port is mapped for that host instead of `local_ip`.
+    #[cfg(feature = "pcp")]
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new_pcp(
         protocol: Protocol,
         local_ip: Ipv4Addr,
+        third_party: Option<Ipv4Addr>,
         local_port: NonZeroU16,
         gateway: Ipv4Addr,
         external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+        nonce: Option<[u8; 12]>,
+        recv_timeout: Duration,
+        sockets: SocketOptions,
     ) -> Result<Self, Error> {
-        pcp::Mapping::new(protocol, local_ip, local_port, gateway, external_addr)
-            .await
-            .map(Self::Pcp)
-            .map_err(Error::from)
+        // only IPv6 link-local gateways need a scope
+        let scope_id = 0;
+        let mapping = match third_party {
+            Some(internal_ip) => {
+                pcp::Mapping::new_third_party(
+                    protocol,
+                    local_ip,
+                    internal_ip,
+                    local_port,
+                    gateway,
+                    scope_id,
+                    external_addr,
+                    nonce,
+                    recv_timeout,
+                    sockets,
+                )
+                .await
+            }
+            None => {
+                pcp::Mapping::new(
+                    protocol,
+                    local_ip,
+                    local_port,
+                    gateway,
+                    scope_id,
+                    external_addr,
+                    nonce,
+                    recv_timeout,
+                    sockets,
+                )
+                .await
+            }
+        };
+        mapping.map(Self::Pcp).map_err(Error::from)
     }
 
     /// Create a new NAT-PMP mapping.
+    #[cfg(feature = "nat_pmp")]
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new_nat_pmp(
         protocol: Protocol,
         local_ip: Ipv4Addr,
         local_port: NonZeroU16,
         gateway: Ipv4Addr,
         external_addr: Option<(Ipv4Addr, NonZeroU16)>,
+        recv_timeout: Duration,
+        allow_private_external: bool,
+        sockets: SocketOptions,
     ) -> Result<Self, Error> {
         nat_pmp::Mapping::new(
             protocol,
@@ -66,6 +132,9 @@ impl Mapping {
             local_port,
             gateway,
             external_addr.map(|(_addr, port)| port),
+            recv_timeout,
+            allow_private_external,
+            sockets,
         )
         .await
         .map(Self::NatPmp)
@@ -73,44 +142,227 @@ impl Mapping {
     }
 
     /// Create a new UPnP mapping.
+    #[cfg(feature = "upnp")]
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new_upnp(
         protocol: Protocol,
         local_ip: Ipv4Addr,
         local_port: NonZeroU16,
         gateway: Option<upnp::Gateway>,
         external_port: Option<NonZeroU16>,
+        search_timeout: Duration,
+        description: String,
+        allow_private_external: bool,
+        cleanup_stale: bool,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        upnp::Mapping::new(
+            protocol,
+            local_ip,
+            local_port,
+            gateway,
+            external_port,
+            search_timeout,
+            description,
+            allow_private_external,
+            cleanup_stale,
+            metrics,
+        )
+        .await
+        .map(Self::Upnp)
+        .map_err(Error::from)
+    }
+
+    /// Fails a mapping `attempt` that doesn't complete within `timeout`.
+    #[cfg(any(feature = "upnp", feature = "pcp", feature = "nat_pmp"))]
+    pub(crate) async fn with_timeout(
+        attempt: impl Future<Output = Result<Self, Error>>,
+        timeout: Duration,
     ) -> Result<Self, Error> {
-        upnp::Mapping::new(protocol, local_ip, local_port, gateway, external_port)
+        tokio::time::timeout(timeout, attempt)
             .await
-            .map(Self::Upnp)
-            .map_err(Error::from)
+            .map_err(|_| e!(Error::TimedOut { timeout }))?
+    }
+
+    /// Nonce to renew this mapping with PCP, see [`pcp::Mapping::renewal_nonce`].
+    #[cfg(feature = "pcp")]
+    pub(crate) fn pcp_renewal_nonce(
+        &self,
+        local_ip: Ipv4Addr,
+        third_party: Option<Ipv4Addr>,
+        gateway: Ipv4Addr,
+        local_port: NonZeroU16,
+    ) -> Option<[u8; 12]> {
+        match self {
+            Mapping::Pcp(m) => m.renewal_nonce(local_ip, third_party, gateway, local_port),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Protocol that produced this mapping.
+    pub(crate) fn protocol(&self) -> MappingProtocol {
+        match self {
+            #[cfg(feature = "upnp")]
+            Mapping::Upnp(_) => MappingProtocol::Upnp,
+            #[cfg(feature = "pcp")]
+            Mapping::Pcp(_) => MappingProtocol::Pcp,
+            #[cfg(feature = "nat_pmp")]
+            Mapping::NatPmp(_) => MappingProtocol::NatPmp,
+            #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+            _ => unreachable!("no mapping can be created without a protocol"),
+        }
+    }
+
+    /// Local port mapped by this mapping.
+    pub(crate) fn local_port(&self) -> NonZeroU16 {
+        match self {
+            #[cfg(feature = "upnp")]
+            Mapping::Upnp(m) => m.local_port(),
+            #[cfg(feature = "pcp")]
+            Mapping::Pcp(m) => m.local_port(),
+            #[cfg(feature = "nat_pmp")]
+            Mapping::NatPmp(m) => m.local_port(),
+            #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+            _ => unreachable!("no mapping can be created without a protocol"),
+        }
+    }
+
+    /// Seconds since the server's epoch when the mapping was created, if the protocol reports it.
+    pub(crate) fn epoch_time(&self) -> Option<u32> {
+        match self {
+            #[cfg(feature = "upnp")]
+            Mapping::Upnp(_) => None,
+            #[cfg(feature = "pcp")]
+            Mapping::Pcp(m) => Some(m.epoch_time()),
+            #[cfg(feature = "nat_pmp")]
+            Mapping::NatPmp(m) => Some(m.epoch_time()),
+            #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+            _ => unreachable!("no mapping can be created without a protocol"),
+        }
+    }
+
+    /// Lease granted by the server, `None` if the mapping doesn't expire.
+    pub(crate) fn lease(&self) -> Option<Duration> {
+        match self {
+            #[cfg(feature = "upnp")]
+            Mapping::Upnp(m) => m.lease(),
+            #[cfg(feature = "pcp")]
+            Mapping::Pcp(m) => Some(m.lease()),
+            #[cfg(feature = "nat_pmp")]
+            Mapping::NatPmp(m) => Some(m.lease()),
+            #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+            _ => unreachable!("no mapping can be created without a protocol"),
+        }
     }
 
     /// Release the mapping.
+    #[cfg_attr(
+        not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")),
+        expect(clippy::unused_async, reason = "no mapping exists without a protocol")
+    )]
     pub(crate) async fn release(self) -> Result<(), Error> {
         match self {
-            Mapping::Upnp(m) => m.release().await?,
-            Mapping::Pcp(m) => m.release().await?,
-            Mapping::NatPmp(m) => m.release().await?,
+            #[cfg(feature = "upnp")]
+            Mapping::Upnp(m) => Ok(m.release().await?),
+            #[cfg(feature = "pcp")]
+            Mapping::Pcp(m) => Ok(m.release().await?),
+            #[cfg(feature = "nat_pmp")]
+            Mapping::NatPmp(m) => Ok(m.release().await?),
         }
-        Ok(())
     }
 }
 
+/// Whether `ip` can be the external address of a mapping.
+///
+/// Some routers report addresses that can't be reached from the internet, such as `0.0.0.0`
+/// before they are connected. Private and shared (CGNAT) addresses are reported by routers behind
+/// another NAT, and only accepted with `allow_private`.
+#[cfg(any(feature = "upnp", feature = "nat_pmp"))]
+pub(super) fn is_valid_external_ip(ip: Ipv4Addr, allow_private: bool) -> bool {
+    if ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+    {
+        return false;
+    }
+    allow_private || !is_private_or_shared(ip)
+}
+
+/// Whether `ip` is a private or shared (CGNAT) address, which only a router behind another NAT
+/// reports as its external address.
+pub(super) fn is_private_or_shared(ip: Ipv4Addr) -> bool {
+    // 100.64.0.0/10, see RFC 6598
+    let [a, b, ..] = ip.octets();
+    let is_shared = a == 100 && b & 0xc0 == 64;
+    ip.is_private() || is_shared
+}
+
 impl PortMapped for Mapping {
     fn external(&self) -> (Ipv4Addr, NonZeroU16) {
         match self {
+            #[cfg(feature = "upnp")]
             Mapping::Upnp(m) => m.external(),
+            #[cfg(feature = "pcp")]
             Mapping::Pcp(m) => m.external(),
+            #[cfg(feature = "nat_pmp")]
             Mapping::NatPmp(m) => m.external(),
+            #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+            _ => unreachable!("no mapping can be created without a protocol"),
         }
     }
 
     fn half_lifetime(&self) -> Duration {
         match self {
+            #[cfg(feature = "upnp")]
             Mapping::Upnp(m) => m.half_lifetime(),
+            #[cfg(feature = "pcp")]
             Mapping::Pcp(m) => m.half_lifetime(),
+            #[cfg(feature = "nat_pmp")]
             Mapping::NatPmp(m) => m.half_lifetime(),
+            #[cfg(not(any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+            _ => unreachable!("no mapping can be created without a protocol"),
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "upnp", feature = "pcp", feature = "nat_pmp")))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let timeout = Duration::from_millis(10);
+        let res = Mapping::with_timeout(std::future::pending(), timeout).await;
+        assert!(matches!(res, Err(Error::TimedOut { timeout: t, .. }) if t == timeout));
+    }
+
+    #[cfg(any(feature = "upnp", feature = "nat_pmp"))]
+    #[test]
+    fn test_is_valid_external_ip() {
+        for bogus in [
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::BROADCAST,
+            Ipv4Addr::new(169, 254, 1, 1),
+            Ipv4Addr::new(224, 0, 0, 1),
+        ] {
+            assert!(!is_valid_external_ip(bogus, true), "{bogus}");
+        }
+        for private in [
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(172, 16, 0, 1),
+            Ipv4Addr::new(192, 168, 1, 1),
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(100, 127, 255, 254),
+        ] {
+            assert!(!is_valid_external_ip(private, false), "{private}");
+            assert!(is_valid_external_ip(private, true), "{private}");
+        }
+        for public in [Ipv4Addr::new(203, 0, 113, 1), Ipv4Addr::new(100, 128, 0, 1)] {
+            assert!(is_valid_external_ip(public, false), "{public}");
         }
     }
 }
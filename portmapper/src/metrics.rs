@@ -1,9 +1,16 @@
-use iroh_metrics::{Counter, MetricsGroup};
+use iroh_metrics::{Counter, Histogram, MetricsGroup};
 use serde::{Deserialize, Serialize};
 
+/// Upper bounds, in seconds, of the buckets tracking how long acquiring a mapping took.
+const MAPPING_LATENCY_BUCKETS: [f64; 9] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+fn mapping_latency() -> Histogram {
+    Histogram::new(MAPPING_LATENCY_BUCKETS.to_vec())
+}
+
 /// Enum of metrics for the module
-#[derive(Debug, Default, MetricsGroup, Serialize, Deserialize)]
-#[metrics(name = "portmap")]
+#[derive(Debug, MetricsGroup, Serialize, Deserialize)]
+#[metrics(name = "portmap", default)]
 pub struct Metrics {
     /*
      * General port mapping metrics
@@ -18,6 +25,8 @@ pub struct Metrics {
     pub mapping_failures: Counter,
     /// Number of times the external address obtained via port mapping was updated.
     pub external_address_updated: Counter,
+    /// Number of renewals that resulted in a different external port.
+    pub external_port_changed: Counter,
 
     /*
      * UPnP metrics
@@ -30,6 +39,13 @@ pub struct Metrics {
     pub upnp_available: Counter,
     /// Number of UPnP probes that resulted in a gateway different to the previous one,
     pub upnp_gateway_updated: Counter,
+    /// Number of mappings obtained with UPnP.
+    pub upnp_mapping_success: Counter,
+    /// Number of UPnP mapping requests the gateway rejected for the lease asked for.
+    pub upnp_lease_rejected: Counter,
+    /// Seconds it took to obtain mappings with UPnP.
+    #[default(mapping_latency())]
+    pub upnp_mapping_latency: Histogram,
 
     /*
      * PCP metrics
@@ -38,4 +54,18 @@ pub struct Metrics {
     pub pcp_probes: Counter,
     /// Number of PCP probes that found it available.
     pub pcp_available: Counter,
+    /// Number of mappings obtained with PCP.
+    pub pcp_mapping_success: Counter,
+    /// Seconds it took to obtain mappings with PCP.
+    #[default(mapping_latency())]
+    pub pcp_mapping_latency: Histogram,
+
+    /*
+     * NAT-PMP metrics
+     */
+    /// Number of mappings obtained with NAT-PMP.
+    pub nat_pmp_mapping_success: Counter,
+    /// Seconds it took to obtain mappings with NAT-PMP.
+    #[default(mapping_latency())]
+    pub nat_pmp_mapping_latency: Histogram,
 }
@@ -1,13 +1,17 @@
 //! Definitions and utilities to interact with a NAT-PMP server.
 
-use std::{net::Ipv4Addr, num::NonZeroU16, time::Duration};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    num::NonZeroU16,
+    time::Duration,
+};
 
 use n0_error::{e, stack_error};
 use netwatch::UdpSocket;
 use tracing::{debug, trace};
 
 use self::protocol::{MapProtocol, Request, Response};
-use crate::{Protocol, defaults::NAT_PMP_RECV_TIMEOUT as RECV_TIMEOUT};
+use crate::{Protocol, util::SocketOptions};
 
 mod protocol;
 
@@ -30,6 +34,10 @@ pub struct Mapping {
     external_addr: Ipv4Addr,
     /// Allowed time for this mapping as informed by the server.
     lifetime_seconds: u32,
+    /// Seconds since the server's epoch, as reported when creating the mapping.
+    epoch_time: u32,
+    /// How to create the socket the release is sent from.
+    sockets: SocketOptions,
 }
 
 #[allow(missing_docs)]
@@ -40,6 +48,8 @@ pub enum Error {
     UnexpectedServerResponse {},
     #[error("received 0 port from server as external port")]
     ZeroExternalPort {},
+    #[error("server reported {addr} as external address, which is not reachable from outside")]
+    InvalidExternalAddr { addr: Ipv4Addr },
     #[error(transparent)]
     Io {
         #[error(std_err)]
@@ -61,16 +71,22 @@ impl super::mapping::PortMapped for Mapping {
 
 impl Mapping {
     /// Attempt to register a new mapping with the NAT-PMP server on the provided gateway.
+    ///
+    /// Private external addresses are rejected unless `allow_private_external` is set.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         protocol: Protocol,
         local_ip: Ipv4Addr,
         local_port: NonZeroU16,
         gateway: Ipv4Addr,
         external_port: Option<NonZeroU16>,
+        recv_timeout: Duration,
+        allow_private_external: bool,
+        sockets: SocketOptions,
     ) -> Result<Self, Error> {
         // create the socket and send the request
-        let socket = UdpSocket::bind_full((local_ip, 0))?;
-        socket.connect((gateway, protocol::SERVER_PORT).into())?;
+        let socket = sockets.bind((local_ip, 0))?;
+        socket.connect(sockets.server_addr(gateway, protocol::SERVER_PORT))?;
 
         let proto = match protocol {
             Protocol::Udp => MapProtocol::Udp,
@@ -83,26 +99,27 @@ impl Mapping {
             lifetime_seconds: MAPPING_REQUESTED_LIFETIME_SECONDS,
         };
 
-        socket.send(&req.encode()).await?;
-
-        // wait for the response and decode it
+        // send the request until it's answered and decode the response
         let mut buffer = vec![0; Response::MAX_SIZE];
-        let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer))
-            .await
-            .map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout".to_string())
-            })??;
+        let read = crate::util::request_with_retransmissions(
+            &socket,
+            &req.encode(),
+            &mut buffer,
+            recv_timeout,
+            sockets.capture.as_ref(),
+        )
+        .await?;
         let response = Response::decode(&buffer[..read])?;
 
-        let (external_port, lifetime_seconds) = match response {
+        let (external_port, lifetime_seconds, epoch_time) = match response {
             Response::PortMap {
                 proto: proto_rcvd,
-                epoch_time: _,
+                epoch_time,
                 private_port,
                 external_port,
                 lifetime_seconds,
             } if private_port == Into::<u16>::into(local_port) && proto == proto_rcvd => {
-                (external_port, lifetime_seconds)
+                (external_port, lifetime_seconds, epoch_time)
             }
             _ => return Err(e!(Error::UnexpectedServerResponse)),
         };
@@ -113,15 +130,16 @@ impl Mapping {
 
         // now send the second request to get the external address
         let req = Request::ExternalAddress;
-        socket.send(&req.encode()).await?;
-
-        // wait for the response and decode it
+        // send the request until it's answered and decode the response
         let mut buffer = vec![0; Response::MAX_SIZE];
-        let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer))
-            .await
-            .map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout".to_string())
-            })??;
+        let read = crate::util::request_with_retransmissions(
+            &socket,
+            &req.encode(),
+            &mut buffer,
+            recv_timeout,
+            sockets.capture.as_ref(),
+        )
+        .await?;
         let response = Response::decode(&buffer[..read])?;
 
         let external_addr = match response {
@@ -132,50 +150,150 @@ impl Mapping {
             _ => return Err(e!(Error::UnexpectedServerResponse)),
         };
 
-        Ok(Mapping {
+        let mapping = Mapping {
             external_port,
             external_addr,
             lifetime_seconds,
+            epoch_time,
             local_ip,
             local_port,
             gateway,
-        })
+            sockets,
+        };
+        if !crate::mapping::is_valid_external_ip(external_addr, allow_private_external) {
+            // the port is already mapped, don't leave it behind
+            if let Err(e) = mapping.release().await {
+                debug!("failed to release mapping with invalid external address: {e}");
+            }
+            return Err(e!(Error::InvalidExternalAddr {
+                addr: external_addr
+            }));
+        }
+        Ok(mapping)
+    }
+
+    /// Local port mapped by this mapping.
+    pub fn local_port(&self) -> NonZeroU16 {
+        self.local_port
+    }
+
+    /// Seconds since the server's epoch, as reported when creating the mapping.
+    pub fn epoch_time(&self) -> u32 {
+        self.epoch_time
+    }
+
+    /// Lifetime granted by the server.
+    pub fn lease(&self) -> Duration {
+        Duration::from_secs(self.lifetime_seconds.into())
     }
 
     /// Releases the mapping.
     pub(crate) async fn release(self) -> Result<(), Error> {
-        // A client requests explicit deletion of a mapping by sending a message to the NAT gateway
-        // requesting the mapping, with the Requested Lifetime in Seconds set to zero. The
-        // Suggested External Port MUST be set to zero by the client on sending
-
         let Mapping {
             local_ip,
             local_port,
             gateway,
+            sockets,
             ..
         } = self;
+        send_release(MapProtocol::Udp, local_ip, local_port, gateway, &sockets).await
+    }
+}
 
-        // create the socket and send the request
-        let socket = UdpSocket::bind_full((local_ip, 0))?;
-        socket.connect((gateway, protocol::SERVER_PORT).into())?;
+/// Releases the mapping of `local_port` on `local_ip`, whether or not we know of it.
+///
+/// NAT-PMP identifies mappings by their internal port, the external one can't be named.
+pub(crate) async fn release_port(
+    protocol: Protocol,
+    local_ip: Ipv4Addr,
+    local_port: NonZeroU16,
+    gateway: Ipv4Addr,
+    sockets: &SocketOptions,
+) -> Result<(), Error> {
+    let proto = match protocol {
+        Protocol::Udp => MapProtocol::Udp,
+        Protocol::Tcp => MapProtocol::Tcp,
+    };
+    send_release(proto, local_ip, local_port, gateway, sockets).await
+}
 
-        let req = Request::Mapping {
-            proto: MapProtocol::Udp,
-            local_port: local_port.into(),
-            external_port: 0,
-            lifetime_seconds: 0,
-        };
+/// Sends the request deleting the mapping of `local_port`.
+async fn send_release(
+    proto: MapProtocol,
+    local_ip: Ipv4Addr,
+    local_port: NonZeroU16,
+    gateway: Ipv4Addr,
+    sockets: &SocketOptions,
+) -> Result<(), Error> {
+    // A client requests explicit deletion of a mapping by sending a message to the NAT gateway
+    // requesting the mapping, with the Requested Lifetime in Seconds set to zero. The
+    // Suggested External Port MUST be set to zero by the client on sending
+
+    // create the socket and send the request
+    let socket = sockets.bind((local_ip, 0))?;
+    socket.connect(sockets.server_addr(gateway, protocol::SERVER_PORT))?;
 
-        socket.send(&req.encode()).await?;
+    let req = Request::Mapping {
+        proto,
+        local_port: local_port.into(),
+        external_port: 0,
+        lifetime_seconds: 0,
+    };
 
-        // mapping deletion is a notification, no point in waiting for the response
-        Ok(())
+    crate::util::send(&socket, &req.encode(), sockets.capture.as_ref()).await?;
+
+    // mapping deletion is a notification, no point in waiting for the response
+    Ok(())
+}
+
+/// Decodes an unsolicited announcement, returning the server's epoch time.
+///
+/// Servers announce their external address when it changes or when they restart. See [RFC 6886
+/// Announcing Address Changes](https://datatracker.ietf.org/doc/html/rfc6886#section-3.2.1).
+pub fn decode_announcement(buf: &[u8]) -> Option<u32> {
+    match Response::decode(buf).ok()? {
+        Response::PublicAddress { epoch_time, .. } => Some(epoch_time),
+        _ => None,
+    }
+}
+
+/// Asks the NAT-PMP server on `gateway` for its external address, without creating a mapping.
+pub(crate) async fn external_address(
+    local_ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+    recv_timeout: Duration,
+    sockets: &SocketOptions,
+) -> Result<Ipv4Addr, Error> {
+    let socket = sockets.bind((local_ip, 0))?;
+    match probe_available_fallible(&socket, gateway, recv_timeout, sockets).await? {
+        Response::PublicAddress { public_ip, .. } => Ok(public_ip),
+        _ => Err(e!(Error::UnexpectedServerResponse)),
     }
 }
 
 /// Probes the local gateway for NAT-PMP support.
-pub async fn probe_available(local_ip: Ipv4Addr, gateway: Ipv4Addr) -> bool {
-    match probe_available_fallible(local_ip, gateway).await {
+///
+/// Returns whether NAT-PMP is available, along with the local address the probe was sent from if
+/// a socket could be bound.
+pub async fn probe_available(
+    local_ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+    recv_timeout: Duration,
+    sockets: &SocketOptions,
+) -> (bool, Option<SocketAddrV4>) {
+    let socket = match sockets.bind((local_ip, 0)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("probe failed: {e}");
+            return (false, None);
+        }
+    };
+    let local_addr = match socket.local_addr() {
+        Ok(SocketAddr::V4(addr)) => Some(addr),
+        _ => None,
+    };
+    trace!(?local_addr, "probing");
+    let available = match probe_available_fallible(&socket, gateway, recv_timeout, sockets).await {
         Ok(response) => {
             trace!("probe response: {response:?}");
             match response {
@@ -191,27 +309,507 @@ pub async fn probe_available(local_ip: Ipv4Addr, gateway: Ipv4Addr) -> bool {
             debug!("probe failed: {e}");
             false
         }
-    }
+    };
+    (available, local_addr)
 }
 
 async fn probe_available_fallible(
-    local_ip: Ipv4Addr,
+    socket: &UdpSocket,
     gateway: Ipv4Addr,
+    recv_timeout: Duration,
+    sockets: &SocketOptions,
 ) -> Result<Response, Error> {
-    // create the socket and send the request
-    let socket = UdpSocket::bind_full((local_ip, 0))?;
-    socket.connect((gateway, protocol::SERVER_PORT).into())?;
+    socket.connect(sockets.server_addr(gateway, protocol::SERVER_PORT))?;
     let req = Request::ExternalAddress;
-    socket.send(&req.encode()).await?;
 
-    // wait for the response and decode it
+    // send the request until it's answered and decode the response
     let mut buffer = vec![0; Response::MAX_SIZE];
-    let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer))
-        .await
-        .map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout".to_string())
-        })??;
+    let read = crate::util::request_with_retransmissions(
+        socket,
+        &req.encode(),
+        &mut buffer,
+        recv_timeout,
+        sockets.capture.as_ref(),
+    )
+    .await?;
     let response = Response::decode(&buffer[..read])?;
 
     Ok(response)
 }
+
+/// Answers a request the way a NAT-PMP server would, for a
+/// [`crate::test_util::MockGateway`].
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn mock_answer(
+    request: &[u8],
+    config: &crate::test_util::MockGatewayConfig,
+) -> Option<Vec<u8>> {
+    use crate::test_util::MockAnswer;
+
+    let crate::test_util::MockGatewayConfig {
+        external_ip,
+        external_port,
+        lifetime_seconds,
+        epoch_time,
+        answer,
+        ..
+    } = *config;
+    // decoding panics on requests the client would never send
+    let well_formed = match request.get(1) {
+        Some(0) => true,
+        Some(1 | 2) => request.len() >= 12,
+        _ => false,
+    };
+    if !well_formed {
+        return None;
+    }
+    let response = match Request::decode(request) {
+        Request::ExternalAddress => Response::PublicAddress {
+            epoch_time,
+            public_ip: external_ip,
+        },
+        Request::Mapping {
+            proto,
+            local_port,
+            lifetime_seconds: requested_lifetime,
+            ..
+        } => {
+            let proto = match (answer, proto) {
+                (MockAnswer::Ignore, _) => return None,
+                (MockAnswer::Malformed, _) => return Some(vec![0, Response::RESPONSE_INDICATOR]),
+                (MockAnswer::WrongProtocol, MapProtocol::Udp) => MapProtocol::Tcp,
+                (MockAnswer::WrongProtocol, MapProtocol::Tcp) => MapProtocol::Udp,
+                (_, proto) => proto,
+            };
+            let external_port = match (answer, external_port) {
+                (MockAnswer::ZeroPort, _) => 0,
+                (_, Some(port)) => port.get(),
+                (_, None) => local_port,
+            };
+            Response::PortMap {
+                proto,
+                epoch_time,
+                private_port: local_port,
+                external_port,
+                // releases are granted a lifetime of zero
+                lifetime_seconds: lifetime_seconds.min(requested_lifetime),
+            }
+        }
+    };
+    Some(response.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use netwatch::interfaces::{HomeRouter, State};
+
+    use super::*;
+    use crate::{Client, Config, MappingError};
+
+    #[test]
+    fn test_decode_announcement() {
+        let announce = Response::PublicAddress {
+            epoch_time: 42,
+            public_ip: Ipv4Addr::new(203, 0, 113, 1),
+        };
+        assert_eq!(decode_announcement(&announce.encode()), Some(42));
+
+        let port_map = Response::PortMap {
+            proto: MapProtocol::Udp,
+            epoch_time: 42,
+            private_port: 1,
+            external_port: 1,
+            lifetime_seconds: 1,
+        };
+        assert_eq!(decode_announcement(&port_map.encode()), None);
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_shutdown_flushes_release() {
+        const LOCAL_PORT: NonZeroU16 = NonZeroU16::new(9587).unwrap();
+        const EXTERNAL_PORT: u16 = 19587;
+        const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+
+        let server = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        // answer the mapping and external address requests, then hand the socket back
+        let server = std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            let Request::Mapping {
+                proto,
+                local_port,
+                lifetime_seconds,
+                ..
+            } = Request::decode(&buf[..len])
+            else {
+                panic!("expected a mapping request");
+            };
+            let response = Response::PortMap {
+                proto,
+                epoch_time: 0,
+                private_port: local_port,
+                external_port: EXTERNAL_PORT,
+                lifetime_seconds,
+            };
+            server.send_to(&response.encode(), client).unwrap();
+
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(Request::decode(&buf[..len]), Request::ExternalAddress);
+            let response = Response::PublicAddress {
+                epoch_time: 0,
+                public_ip: EXTERNAL_IP,
+            };
+            server.send_to(&response.encode(), client).unwrap();
+            server
+        });
+
+        let client = Client::new(Config {
+            #[cfg(feature = "upnp")]
+            enable_upnp: false,
+            #[cfg(feature = "pcp")]
+            enable_pcp: false,
+            protocol_priority: vec![crate::MappingProtocol::NatPmp],
+            server_port_override: Some(server_port),
+            ..Default::default()
+        })
+        .expect("config is valid");
+        client.update_interface_state(State::fake_with_home_router(&HomeRouter {
+            gateway: Ipv4Addr::LOCALHOST.into(),
+            my_ip: Some(Ipv4Addr::LOCALHOST.into()),
+        }));
+        let mut external = client.watch_external_address();
+        client.update_local_port(LOCAL_PORT);
+        let external = external
+            .wait_for(Option::is_some)
+            .await
+            .expect("service is running")
+            .expect("mapping was obtained");
+        assert_eq!(*external.ip(), EXTERNAL_IP);
+        assert_eq!(external.port(), EXTERNAL_PORT);
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = client.metrics();
+            assert_eq!(metrics.nat_pmp_mapping_success.get(), 1);
+            assert_eq!(metrics.nat_pmp_mapping_latency.count(), 1);
+            assert_eq!(metrics.pcp_mapping_success.get(), 0);
+        }
+
+        client.shutdown().await;
+
+        // the release must already be there, without waiting for it
+        let server = server.join().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, _) = server
+            .recv_from(&mut buf)
+            .expect("release was sent before shutdown returned");
+        assert_eq!(
+            Request::decode(&buf[..len]),
+            Request::Mapping {
+                proto: MapProtocol::Udp,
+                local_port: LOCAL_PORT.into(),
+                external_port: 0,
+                lifetime_seconds: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_added_ports_are_mapped_independently() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let first = NonZeroU16::new(9588).unwrap();
+        let second = NonZeroU16::new(9589).unwrap();
+        let over_cap = NonZeroU16::new(9590).unwrap();
+        let config = MockGatewayConfig {
+            pcp: false,
+            ..Default::default()
+        };
+        let external_ip = config.external_ip;
+        let gateway = MockGateway::spawn(config).expect("gateway is spawned");
+        let client = Client::new(Config {
+            max_mappings: Some(2),
+            ..gateway.client_config()
+        })
+        .expect("config is valid");
+        client.update_interface_state(gateway.state());
+        let mut addresses = client.watch_external_addresses();
+        client.add_port(first).await.expect("below the cap");
+        client.add_port(second).await.expect("below the cap");
+        let err = client.add_port(over_cap).await.unwrap_err();
+        assert!(matches!(
+            err,
+            MappingError::MaxMappingsExceeded { max: 2, .. }
+        ));
+        let mapped = addresses
+            .wait_for(|addresses| addresses.len() == 2)
+            .await
+            .expect("service is running")
+            .clone();
+        assert_eq!(
+            mapped.get(&first),
+            Some(&SocketAddrV4::new(external_ip, first.get()))
+        );
+        assert_eq!(
+            mapped.get(&second),
+            Some(&SocketAddrV4::new(external_ip, second.get()))
+        );
+        // only the added ports are reported, there is no local port
+        assert_eq!(*client.watch_external_address().borrow(), None);
+        let requests = gateway.requests();
+
+        client.remove_port(first);
+        let remaining = addresses
+            .wait_for(|addresses| addresses.len() == 1)
+            .await
+            .expect("service is running")
+            .clone();
+        assert!(remaining.contains_key(&second));
+
+        // the removed port is released right away, the other one on shutdown
+        client.shutdown().await;
+        while gateway.requests() < requests + 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(gateway.requests(), requests + 2);
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_release_external_port() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let local_port = NonZeroU16::new(9591).unwrap();
+        let leftover = NonZeroU16::new(9592).unwrap();
+        let gateway = MockGateway::spawn(MockGatewayConfig {
+            pcp: false,
+            ..Default::default()
+        })
+        .expect("gateway is spawned");
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+        let mut external = client.watch_external_address();
+        client.update_local_port(local_port);
+        let mapped = external
+            .wait_for(Option::is_some)
+            .await
+            .expect("service is running")
+            .expect("mapping was obtained");
+        let requests = gateway.requests();
+
+        // a mapping the client doesn't know about is released by its internal port
+        client.release_external_port(crate::MappingProtocol::NatPmp, leftover);
+        let mapped_port = NonZeroU16::new(mapped.port()).unwrap();
+        client.release_external_port(crate::MappingProtocol::NatPmp, mapped_port);
+        external
+            .wait_for(Option::is_none)
+            .await
+            .expect("service is running");
+
+        // both releases are sent, and nothing is left to release on shutdown
+        client.shutdown().await;
+        while gateway.requests() < requests + 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(gateway.requests(), requests + 2);
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_mock_gateway_map() {
+        use crate::test_util::{MockAnswer, MockGateway, MockGatewayConfig};
+
+        const LOCAL_PORT: NonZeroU16 = NonZeroU16::new(9592).unwrap();
+        let config = MockGatewayConfig {
+            pcp: false,
+            ..Default::default()
+        };
+        let external_ip = config.external_ip;
+        let gateway = MockGateway::spawn(config).expect("gateway is spawned");
+
+        let new_mapping = |recv_timeout| {
+            Mapping::new(
+                Protocol::Udp,
+                Ipv4Addr::LOCALHOST,
+                LOCAL_PORT,
+                *gateway.addr().ip(),
+                None,
+                recv_timeout,
+                false,
+                gateway.socket_options(),
+            )
+        };
+        let recv_timeout = Duration::from_secs(1);
+
+        let mapping = new_mapping(recv_timeout).await.expect("mapping is granted");
+        assert_eq!(mapping.external_addr, external_ip);
+        assert_eq!(mapping.external_port, LOCAL_PORT);
+        assert!(
+            probe_available(
+                Ipv4Addr::LOCALHOST,
+                *gateway.addr().ip(),
+                recv_timeout,
+                &gateway.socket_options()
+            )
+            .await
+            .0
+        );
+
+        gateway.set_answer(MockAnswer::WrongProtocol);
+        let res = new_mapping(recv_timeout).await;
+        assert!(
+            matches!(res, Err(Error::UnexpectedServerResponse { .. })),
+            "{res:?}"
+        );
+
+        gateway.set_answer(MockAnswer::ZeroPort);
+        let res = new_mapping(recv_timeout).await;
+        assert!(
+            matches!(res, Err(Error::ZeroExternalPort { .. })),
+            "{res:?}"
+        );
+
+        gateway.set_answer(MockAnswer::Malformed);
+        let res = new_mapping(recv_timeout).await;
+        assert!(matches!(res, Err(Error::Protocol { .. })), "{res:?}");
+
+        gateway.set_answer(MockAnswer::Ignore);
+        let res = new_mapping(Duration::from_millis(50)).await;
+        assert!(
+            matches!(&res, Err(Error::Io { source, .. }) if source.kind() == std::io::ErrorKind::TimedOut),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_mapping_reports_local_and_external_port() {
+        use crate::{
+            MappingEvent,
+            test_util::{MockGateway, MockGatewayConfig},
+        };
+
+        const LOCAL_PORT: NonZeroU16 = NonZeroU16::new(9594).unwrap();
+        const EXTERNAL_PORT: NonZeroU16 = NonZeroU16::new(51234).unwrap();
+        let gateway = MockGateway::spawn(MockGatewayConfig {
+            pcp: false,
+            external_port: Some(EXTERNAL_PORT),
+            // shorter than the lease we ask for
+            lifetime_seconds: 600,
+            ..Default::default()
+        })
+        .expect("gateway is spawned");
+
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+        let mut events = client.subscribe_events();
+        client.update_local_port(LOCAL_PORT);
+
+        loop {
+            match events.recv().await.expect("service is running") {
+                MappingEvent::Acquired {
+                    local_port, addr, ..
+                } => {
+                    assert_eq!(local_port, LOCAL_PORT);
+                    assert_eq!(addr.port(), EXTERNAL_PORT.get());
+                    break;
+                }
+                MappingEvent::ProbeCompleted { .. } => continue,
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        let info = client.mapping_info().expect("mapping is active");
+        assert_eq!(info.local_port, LOCAL_PORT);
+        assert_eq!(info.external.port(), EXTERNAL_PORT.get());
+        assert_eq!(info.lease, Some(Duration::from_secs(600)));
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_external_ip_without_mapping() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let config = MockGatewayConfig {
+            pcp: false,
+            ..Default::default()
+        };
+        let external_ip = config.external_ip;
+        let gateway = MockGateway::spawn(config).expect("gateway is spawned");
+
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+
+        assert_eq!(client.external_ip().await, Some(external_ip));
+        // the probe and the external address request, no mapping
+        assert_eq!(gateway.requests(), 2);
+        assert!(client.mapping_info().is_none());
+        assert!(client.watch_external_address().borrow().is_none());
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_likely_behind_cgnat() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        let config = MockGatewayConfig {
+            pcp: false,
+            external_ip: Ipv4Addr::new(100, 64, 0, 1),
+            ..Default::default()
+        };
+        let gateway = MockGateway::spawn(config).expect("gateway is spawned");
+
+        let client = Client::new(gateway.client_config()).expect("config is valid");
+        client.update_interface_state(gateway.state());
+
+        // the shared address is not usable, but gives the carrier's NAT away
+        assert_eq!(client.external_ip().await, None);
+        assert_eq!(client.likely_behind_cgnat().await, Some(true));
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_lost_requests_are_retransmitted() {
+        use crate::test_util::{MockGateway, MockGatewayConfig};
+
+        const LOCAL_PORT: NonZeroU16 = NonZeroU16::new(9593).unwrap();
+        let gateway = MockGateway::spawn(MockGatewayConfig::default()).expect("gateway is spawned");
+
+        // lose the first mapping request
+        gateway.drop_requests(1);
+        Mapping::new(
+            Protocol::Udp,
+            Ipv4Addr::LOCALHOST,
+            LOCAL_PORT,
+            *gateway.addr().ip(),
+            None,
+            Duration::from_secs(2),
+            false,
+            gateway.socket_options(),
+        )
+        .await
+        .expect("mapping is granted once the request is sent again");
+        // both mapping requests, then the external address request
+        assert_eq!(gateway.requests(), 3);
+
+        // lose the first probe, the second one is answered
+        gateway.drop_requests(1);
+        let (available, _) = probe_available(
+            Ipv4Addr::LOCALHOST,
+            *gateway.addr().ip(),
+            Duration::from_secs(2),
+            &gateway.socket_options(),
+        )
+        .await;
+        assert!(available);
+        assert_eq!(gateway.requests(), 5);
+    }
+}
@@ -82,7 +82,7 @@ impl Request {
         }
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "test-util"))]
     /// Decode a map request.
     fn decode_map(buf: &[u8], proto: MapProtocol) -> Request {
         // buf[2] reserved
@@ -104,9 +104,9 @@ impl Request {
         }
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "test-util"))]
     #[track_caller]
-    fn decode(buf: &[u8]) -> Self {
+    pub(crate) fn decode(buf: &[u8]) -> Self {
         let _version: Version = buf[0].try_into().unwrap();
         let opcode: super::Opcode = buf[1].try_into().unwrap();
         // check if this is a mapping request, or an external address request
@@ -212,8 +212,8 @@ impl Response {
         }
     }
 
-    #[cfg(test)]
-    fn encode(&self) -> Vec<u8> {
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn encode(&self) -> Vec<u8> {
         match self {
             Response::PublicAddress {
                 epoch_time,
@@ -241,7 +241,7 @@ impl Response {
                 buf
             }
             Response::PortMap {
-                proto: _,
+                proto,
                 epoch_time,
                 private_port,
                 external_port,
@@ -251,7 +251,11 @@ impl Response {
                 // version
                 buf.push(Version::NatPmp.into());
                 // response indicator and opcode
-                let opcode: u8 = Opcode::MapUdp.into();
+                let opcode: u8 = match proto {
+                    MapProtocol::Udp => Opcode::MapUdp,
+                    MapProtocol::Tcp => Opcode::MapTcp,
+                }
+                .into();
                 buf.push(Response::RESPONSE_INDICATOR | opcode);
                 // result code
                 let result_code: u16 = ResultCode::Success.into();
@@ -1,13 +1,17 @@
 //! Definitions and utilities to interact with a PCP server.
 
-use std::{net::Ipv4Addr, num::NonZeroU16, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    num::NonZeroU16,
+    time::Duration,
+};
 
 use n0_error::{e, stack_error};
 use netwatch::UdpSocket;
 use rand::Rng;
 use tracing::{debug, trace};
 
-use crate::{Protocol, defaults::PCP_RECV_TIMEOUT as RECV_TIMEOUT};
+use crate::{Protocol, util::SocketOptions};
 
 mod protocol;
 
@@ -15,26 +19,77 @@ mod protocol;
 /// <https://datatracker.ietf.org/doc/html/rfc6886#section-3.3>
 const MAPPING_REQUESTED_LIFETIME_SECONDS: u32 = 60 * 60;
 
+/// Address family in which PCP mappings are made.
+///
+/// PCP carries all addresses as IPv6, with IPv4 addresses represented as IPv4-mapped IPv6
+/// addresses.
+pub trait Family:
+    Copy + PartialEq + std::fmt::Debug + Into<IpAddr> + Send + Unpin + 'static
+{
+    /// The unspecified address of the family.
+    const UNSPECIFIED: Self;
+
+    /// Represents the address as carried in PCP messages.
+    fn to_pcp(self) -> Ipv6Addr;
+    /// Recovers an address of this family from a PCP message.
+    fn from_pcp(addr: Ipv6Addr) -> Option<Self>;
+}
+
+impl Family for Ipv4Addr {
+    const UNSPECIFIED: Self = Ipv4Addr::UNSPECIFIED;
+
+    fn to_pcp(self) -> Ipv6Addr {
+        self.to_ipv6_mapped()
+    }
+
+    fn from_pcp(addr: Ipv6Addr) -> Option<Self> {
+        addr.to_ipv4_mapped()
+    }
+}
+
+impl Family for Ipv6Addr {
+    const UNSPECIFIED: Self = Ipv6Addr::UNSPECIFIED;
+
+    fn to_pcp(self) -> Ipv6Addr {
+        self
+    }
+
+    fn from_pcp(addr: Ipv6Addr) -> Option<Self> {
+        addr.to_ipv4_mapped().is_none().then_some(addr)
+    }
+}
+
 /// A mapping successfully registered with a PCP server.
 #[derive(Debug)]
-pub struct Mapping {
+pub struct Mapping<Ip = Ipv4Addr> {
     /// Protocol for this mapping.
     protocol: protocol::MapProtocol,
     /// Local ip used to create this mapping.
-    local_ip: Ipv4Addr,
+    local_ip: Ip,
     /// Local port used to create this mapping.
     local_port: NonZeroU16,
     /// Gateway address used to registered this mapping.
-    gateway: Ipv4Addr,
+    gateway: Ip,
+    /// Scope of the gateway address, needed to reach IPv6 link-local gateways.
+    gateway_scope_id: u32,
     /// External port of the mapping.
     external_port: NonZeroU16,
     /// External address of the mapping.
-    external_address: Ipv4Addr,
+    external_address: Ip,
     /// Allowed time for this mapping as informed by the server.
     lifetime_seconds: u32,
+    /// Seconds since the server's epoch, as reported when creating the mapping.
+    epoch_time: u32,
     /// The nonce of the mapping, used for modifications with the PCP server, for example releasing
     /// the mapping.
     nonce: [u8; 12],
+    /// Remote peer the mapping is restricted to, for mappings made with the PEER opcode.
+    remote_peer: Option<(Ip, NonZeroU16)>,
+    /// Internal address of the mapping, for mappings made on behalf of another host with the
+    /// THIRD_PARTY option.
+    third_party: Option<Ip>,
+    /// How to create the socket the release is sent from.
+    sockets: SocketOptions,
 }
 
 #[allow(missing_docs)]
@@ -47,10 +102,14 @@ pub enum Error {
     ProtocolMissmatch {},
     #[error("received mapping is for a local port that does not match the requested one")]
     PortMissmatch {},
+    #[error("received mapping is for a remote peer that does not match the requested one")]
+    PeerMissmatch {},
+    #[error("received a response for a different opcode than the request")]
+    OpcodeMissmatch {},
     #[error("received 0 external port for mapping")]
     ZeroExternalPort {},
-    #[error("received external address is not ipv4")]
-    NotIpv4 {},
+    #[error("received external address is not of the requested address family")]
+    FamilyMismatch {},
     #[error("received an announce response for a map request")]
     InvalidAnnounce {},
     #[error("IO error during PCP")]
@@ -62,66 +121,186 @@ pub enum Error {
     Protocol { source: protocol::Error },
 }
 
-impl super::mapping::PortMapped for Mapping {
+impl super::mapping::PortMapped for Mapping<Ipv4Addr> {
     fn external(&self) -> (Ipv4Addr, NonZeroU16) {
-        (self.external_address, self.external_port)
+        self.external()
     }
 
     fn half_lifetime(&self) -> Duration {
-        Duration::from_secs((self.lifetime_seconds / 2).into())
+        self.half_lifetime()
     }
 }
 
-impl Mapping {
+impl super::current_mapping::Mapping for Mapping<Ipv6Addr> {
+    type Ip = Ipv6Addr;
+
+    fn external(&self) -> (Ipv6Addr, NonZeroU16) {
+        self.external()
+    }
+
+    fn half_lifetime(&self) -> Duration {
+        self.half_lifetime()
+    }
+
+    fn local_port(&self) -> NonZeroU16 {
+        self.local_port()
+    }
+
+    fn protocol(&self) -> crate::MappingProtocol {
+        crate::MappingProtocol::Pcp
+    }
+
+    fn epoch_time(&self) -> Option<u32> {
+        Some(self.epoch_time())
+    }
+
+    fn lease(&self) -> Option<Duration> {
+        Some(self.lease())
+    }
+}
+
+impl<Ip: Family> Mapping<Ip> {
+    /// External address and port of the mapping.
+    pub fn external(&self) -> (Ip, NonZeroU16) {
+        (self.external_address, self.external_port)
+    }
+
+    /// Local port mapped by this mapping.
+    pub fn local_port(&self) -> NonZeroU16 {
+        self.local_port
+    }
+
+    /// Nonce to renew this mapping with, if it's the MAP mapping of `local_port` on `local_ip`,
+    /// or on `third_party` if given, made through `gateway`.
+    ///
+    /// Reusing the nonce makes the server treat the request as a refresh of the same mapping,
+    /// keeping its external port, see [RFC 6887 Renewing a
+    /// Mapping](https://datatracker.ietf.org/doc/html/rfc6887#section-11.2.1).
+    pub(crate) fn renewal_nonce(
+        &self,
+        local_ip: Ip,
+        third_party: Option<Ip>,
+        gateway: Ip,
+        local_port: NonZeroU16,
+    ) -> Option<[u8; 12]> {
+        let is_same = self.remote_peer.is_none()
+            && self.third_party == third_party
+            && self.local_ip == local_ip
+            && self.gateway == gateway
+            && self.local_port == local_port;
+        is_same.then_some(self.nonce)
+    }
+
     /// Attempt to registered a new mapping with the PCP server on the provided gateway.
+    ///
+    /// Passing the `nonce` of an existing mapping renews it, otherwise a fresh one is generated.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         protocol: Protocol,
-        local_ip: Ipv4Addr,
+        local_ip: Ip,
         local_port: NonZeroU16,
-        gateway: Ipv4Addr,
-        preferred_external_address: Option<(Ipv4Addr, NonZeroU16)>,
+        gateway: Ip,
+        gateway_scope_id: u32,
+        preferred_external_address: Option<(Ip, NonZeroU16)>,
+        nonce: Option<[u8; 12]>,
+        recv_timeout: Duration,
+        sockets: SocketOptions,
     ) -> Result<Self, Error> {
-        // create the socket and send the request
-        let socket = UdpSocket::bind_full((local_ip, 0))?;
-        socket.connect((gateway, protocol::SERVER_PORT).into())?;
+        Self::map(
+            protocol,
+            local_ip,
+            None,
+            nonce.unwrap_or_else(new_nonce),
+            local_port,
+            gateway,
+            gateway_scope_id,
+            preferred_external_address,
+            recv_timeout,
+            sockets,
+        )
+        .await
+    }
 
-        let mut nonce = [0u8; 12];
-        rand::rng().fill_bytes(&mut nonce);
+    /// Attempt to register a new mapping for another host, using the THIRD_PARTY option.
+    ///
+    /// The request is sent from `requesting_ip`, while the mapping forwards to `internal_port` on
+    /// `internal_ip`. Servers only accept these from hosts they are configured to trust, see [RFC
+    /// 6887 THIRD_PARTY Option](https://datatracker.ietf.org/doc/html/rfc6887#section-13.1).
+    ///
+    /// Passing the `nonce` of an existing mapping renews it, as with [`Mapping::new`].
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new_third_party(
+        protocol: Protocol,
+        requesting_ip: Ip,
+        internal_ip: Ip,
+        internal_port: NonZeroU16,
+        gateway: Ip,
+        gateway_scope_id: u32,
+        preferred_external_address: Option<(Ip, NonZeroU16)>,
+        nonce: Option<[u8; 12]>,
+        recv_timeout: Duration,
+        sockets: SocketOptions,
+    ) -> Result<Self, Error> {
+        Self::map(
+            protocol,
+            requesting_ip,
+            Some(internal_ip),
+            nonce.unwrap_or_else(new_nonce),
+            internal_port,
+            gateway,
+            gateway_scope_id,
+            preferred_external_address,
+            recv_timeout,
+            sockets,
+        )
+        .await
+    }
 
+    /// Registers a mapping with the MAP opcode, on behalf of `third_party` if given.
+    #[allow(clippy::too_many_arguments)]
+    async fn map(
+        protocol: Protocol,
+        local_ip: Ip,
+        third_party: Option<Ip>,
+        nonce: [u8; 12],
+        local_port: NonZeroU16,
+        gateway: Ip,
+        gateway_scope_id: u32,
+        preferred_external_address: Option<(Ip, NonZeroU16)>,
+        recv_timeout: Duration,
+        sockets: SocketOptions,
+    ) -> Result<Self, Error> {
         let (requested_address, requested_port) = match preferred_external_address {
-            Some((ip, port)) => (Some(ip), Some(port.into())),
+            Some((ip, port)) => (Some(ip.to_pcp()), Some(port.into())),
             None => (None, None),
         };
 
-        let protocol = match protocol {
-            Protocol::Udp => protocol::MapProtocol::Udp,
-            Protocol::Tcp => protocol::MapProtocol::Tcp,
-        };
+        let protocol = map_protocol(protocol);
         let req = protocol::Request::mapping(
             nonce,
             protocol,
             local_port.into(),
-            local_ip,
+            local_ip.to_pcp(),
             requested_port,
             requested_address,
             MAPPING_REQUESTED_LIFETIME_SECONDS,
         );
+        let req = with_third_party(req, third_party);
 
-        socket.send(&req.encode()).await?;
-
-        // wait for the response and decode it
-        let mut buffer = vec![0; protocol::Response::MAX_SIZE];
-        let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer))
-            .await
-            .map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout".to_string())
-            })??;
-        let response = protocol::Response::decode(&buffer[..read])?;
+        let response = request(
+            local_ip,
+            gateway,
+            gateway_scope_id,
+            &req,
+            recv_timeout,
+            &sockets,
+        )
+        .await?;
 
         // verify that the response is correct and matches the request
         let protocol::Response {
             lifetime_seconds,
-            epoch_time: _,
+            epoch_time,
             data,
         } = response;
 
@@ -135,41 +314,145 @@ impl Mapping {
                     external_address,
                 } = map_data;
 
-                if nonce != received_nonce {
-                    return Err(e!(Error::NonceMissmatch));
-                }
+                let (external_address, external_port) = verify_granted(
+                    (nonce, protocol, local_port),
+                    (received_nonce, received_protocol, received_local_port),
+                    external_address,
+                    external_port,
+                )?;
 
-                if received_protocol != protocol {
-                    return Err(e!(Error::ProtocolMissmatch));
-                }
+                Ok(Mapping {
+                    protocol,
+                    external_port,
+                    external_address,
+                    lifetime_seconds,
+                    epoch_time,
+                    nonce,
+                    local_ip,
+                    local_port,
+                    gateway,
+                    gateway_scope_id,
+                    remote_peer: None,
+                    third_party,
+                    sockets,
+                })
+            }
+            protocol::OpcodeData::PeerData(_) => Err(e!(Error::OpcodeMissmatch)),
+            protocol::OpcodeData::Announce => Err(e!(Error::InvalidAnnounce)),
+        }
+    }
 
-                let sent_port: u16 = local_port.into();
-                if received_local_port != sent_port {
-                    return Err(e!(Error::PortMissmatch));
-                }
-                let external_port = external_port
-                    .try_into()
-                    .map_err(|_| e!(Error::ZeroExternalPort))?;
+    /// Attempt to register a new mapping for traffic with a single remote peer, using the PEER
+    /// opcode.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new_peer(
+        protocol: Protocol,
+        local_ip: Ip,
+        local_port: NonZeroU16,
+        gateway: Ip,
+        gateway_scope_id: u32,
+        remote_peer: (Ip, NonZeroU16),
+        recv_timeout: Duration,
+        sockets: SocketOptions,
+    ) -> Result<Self, Error> {
+        let nonce = new_nonce();
 
-                let external_address = external_address
-                    .to_ipv4_mapped()
-                    .ok_or(e!(Error::NotIpv4))?;
+        let protocol = map_protocol(protocol);
+        let (remote_peer_ip, remote_peer_port) = remote_peer;
+        let req = protocol::Request::peer(
+            local_ip.to_pcp(),
+            protocol::PeerData {
+                nonce,
+                protocol,
+                local_port: local_port.into(),
+                // no preference for the external address
+                external_port: 0,
+                external_address: Ip::UNSPECIFIED.to_pcp(),
+                remote_peer_port: remote_peer_port.into(),
+                remote_peer_address: remote_peer_ip.to_pcp(),
+            },
+            MAPPING_REQUESTED_LIFETIME_SECONDS,
+        );
 
-                Ok(Mapping {
+        let response = request(
+            local_ip,
+            gateway,
+            gateway_scope_id,
+            &req,
+            recv_timeout,
+            &sockets,
+        )
+        .await?;
+
+        // verify that the response is correct and matches the request
+        let protocol::Response {
+            lifetime_seconds,
+            epoch_time,
+            data,
+        } = response;
+
+        match data {
+            protocol::OpcodeData::PeerData(peer_data) => {
+                let protocol::PeerData {
+                    nonce: received_nonce,
                     protocol: received_protocol,
+                    local_port: received_local_port,
+                    external_port,
+                    external_address,
+                    remote_peer_port: received_peer_port,
+                    remote_peer_address: received_peer_address,
+                } = peer_data;
+
+                let (external_address, external_port) = verify_granted(
+                    (nonce, protocol, local_port),
+                    (received_nonce, received_protocol, received_local_port),
+                    external_address,
+                    external_port,
+                )?;
+
+                let sent_peer_port: u16 = remote_peer_port.into();
+                if received_peer_port != sent_peer_port
+                    || received_peer_address != remote_peer_ip.to_pcp()
+                {
+                    return Err(e!(Error::PeerMissmatch));
+                }
+
+                Ok(Mapping {
+                    protocol,
                     external_port,
                     external_address,
                     lifetime_seconds,
+                    epoch_time,
                     nonce,
                     local_ip,
                     local_port,
                     gateway,
+                    gateway_scope_id,
+                    remote_peer: Some(remote_peer),
+                    third_party: None,
+                    sockets,
                 })
             }
+            protocol::OpcodeData::MapData(_) => Err(e!(Error::OpcodeMissmatch)),
             protocol::OpcodeData::Announce => Err(e!(Error::InvalidAnnounce)),
         }
     }
 
+    /// Seconds since the server's epoch, as reported when creating the mapping.
+    pub fn epoch_time(&self) -> u32 {
+        self.epoch_time
+    }
+
+    /// Lifetime granted by the server.
+    pub fn lease(&self) -> Duration {
+        Duration::from_secs(self.lifetime_seconds.into())
+    }
+
+    /// Half the lifetime granted by the server.
+    fn half_lifetime(&self) -> Duration {
+        Duration::from_secs((self.lifetime_seconds / 2).into())
+    }
+
     pub async fn release(self) -> Result<(), Error> {
         let Mapping {
             protocol,
@@ -177,70 +460,591 @@ impl Mapping {
             local_ip,
             local_port,
             gateway,
+            gateway_scope_id,
+            remote_peer,
+            third_party,
+            sockets,
             ..
         } = self;
 
         // create the socket and send the request
-        let socket = UdpSocket::bind_full((local_ip, 0))?;
-        socket.connect((gateway, protocol::SERVER_PORT).into())?;
+        let socket = sockets.bind(SocketAddr::new(local_ip.into(), 0))?;
+        socket.connect(server_addr(gateway, gateway_scope_id, &sockets))?;
 
         let local_port = local_port.into();
-        let req = protocol::Request::mapping(nonce, protocol, local_port, local_ip, None, None, 0);
+        // a lifetime of 0 deletes the mapping
+        let req = match remote_peer {
+            None => protocol::Request::mapping(
+                nonce,
+                protocol,
+                local_port,
+                local_ip.to_pcp(),
+                None,
+                None,
+                0,
+            ),
+            Some((remote_peer_ip, remote_peer_port)) => protocol::Request::peer(
+                local_ip.to_pcp(),
+                protocol::PeerData {
+                    nonce,
+                    protocol,
+                    local_port,
+                    external_port: 0,
+                    external_address: Ip::UNSPECIFIED.to_pcp(),
+                    remote_peer_port: remote_peer_port.into(),
+                    remote_peer_address: remote_peer_ip.to_pcp(),
+                },
+                0,
+            ),
+        };
+        let req = with_third_party(req, third_party);
 
-        socket.send(&req.encode()).await?;
+        crate::util::send(&socket, &req.encode(), sockets.capture.as_ref()).await?;
 
         // mapping deletion is a notification, no point in waiting for the response
         Ok(())
     }
 }
 
+/// Releases the MAP mapping of `local_port` on `local_ip`, whether or not we know of it.
+///
+/// PCP identifies mappings by their internal port. Without the nonce the mapping was made with,
+/// a fresh one is sent, which servers checking it answer with NOT_AUTHORIZED, see [RFC 6887
+/// Processing a MAP Request](https://datatracker.ietf.org/doc/html/rfc6887#section-11.3).
+pub(crate) async fn release_port(
+    protocol: Protocol,
+    local_ip: Ipv4Addr,
+    local_port: NonZeroU16,
+    gateway: Ipv4Addr,
+    sockets: &SocketOptions,
+) -> Result<(), Error> {
+    let socket = sockets.bind(SocketAddr::new(local_ip.into(), 0))?;
+    socket.connect(server_addr(gateway, 0, sockets))?;
+
+    // a lifetime of 0 deletes the mapping
+    let req = protocol::Request::mapping(
+        new_nonce(),
+        map_protocol(protocol),
+        local_port.into(),
+        local_ip.to_pcp(),
+        None,
+        None,
+        0,
+    );
+    crate::util::send(&socket, &req.encode(), sockets.capture.as_ref()).await?;
+
+    // mapping deletion is a notification, no point in waiting for the response
+    Ok(())
+}
+
+/// Generates a random nonce for a new mapping.
+fn new_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    rand::rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn map_protocol(protocol: Protocol) -> protocol::MapProtocol {
+    match protocol {
+        Protocol::Udp => protocol::MapProtocol::Udp,
+        Protocol::Tcp => protocol::MapProtocol::Tcp,
+    }
+}
+
+/// Adds the THIRD_PARTY option to `req` for mappings on behalf of another host.
+fn with_third_party<Ip: Family>(
+    req: protocol::Request,
+    third_party: Option<Ip>,
+) -> protocol::Request {
+    match third_party {
+        Some(internal_ip) => req.with_option(protocol::PcpOption::ThirdParty(internal_ip.to_pcp())),
+        None => req,
+    }
+}
+
+/// Sends a request to the PCP server on the gateway and waits for its response.
+async fn request<Ip: Family>(
+    local_ip: Ip,
+    gateway: Ip,
+    gateway_scope_id: u32,
+    req: &protocol::Request,
+    recv_timeout: Duration,
+    sockets: &SocketOptions,
+) -> Result<protocol::Response, Error> {
+    // create the socket and send the request
+    let socket = sockets.bind(SocketAddr::new(local_ip.into(), 0))?;
+    socket.connect(server_addr(gateway, gateway_scope_id, sockets))?;
+
+    // send the request until it's answered and decode the response
+    let mut buffer = vec![0; protocol::Response::MAX_SIZE];
+    let read = crate::util::request_with_retransmissions(
+        &socket,
+        &req.encode(),
+        &mut buffer,
+        recv_timeout,
+        sockets.capture.as_ref(),
+    )
+    .await?;
+    let response = protocol::Response::decode(&buffer[..read])?;
+    Ok(response)
+}
+
+/// Verifies that a response is for the sent request and returns the granted external address.
+fn verify_granted<Ip: Family>(
+    (nonce, protocol, local_port): ([u8; 12], protocol::MapProtocol, NonZeroU16),
+    (received_nonce, received_protocol, received_local_port): (
+        [u8; 12],
+        protocol::MapProtocol,
+        u16,
+    ),
+    external_address: Ipv6Addr,
+    external_port: u16,
+) -> Result<(Ip, NonZeroU16), Error> {
+    if nonce != received_nonce {
+        return Err(e!(Error::NonceMissmatch));
+    }
+
+    if received_protocol != protocol {
+        return Err(e!(Error::ProtocolMissmatch));
+    }
+
+    let sent_port: u16 = local_port.into();
+    if received_local_port != sent_port {
+        return Err(e!(Error::PortMissmatch));
+    }
+    let external_port = external_port
+        .try_into()
+        .map_err(|_| e!(Error::ZeroExternalPort))?;
+
+    let external_address = Ip::from_pcp(external_address).ok_or(e!(Error::FamilyMismatch))?;
+
+    Ok((external_address, external_port))
+}
+
+/// Address of the PCP server on the gateway.
+fn server_addr(gateway: impl Into<IpAddr>, scope_id: u32, sockets: &SocketOptions) -> SocketAddr {
+    let mut addr = sockets.server_addr(gateway, protocol::SERVER_PORT);
+    if let SocketAddr::V6(addr) = &mut addr {
+        addr.set_scope_id(scope_id);
+    }
+    addr
+}
+
+/// Decodes an unsolicited ANNOUNCE response, returning the server's epoch time.
+///
+/// Servers multicast these when they restart and lose their mappings. See [RFC 6887 Unsolicited
+/// ANNOUNCEs](https://datatracker.ietf.org/doc/html/rfc6887#section-14.1.1).
+pub fn decode_announcement(buf: &[u8]) -> Option<u32> {
+    let response = protocol::Response::decode(buf).ok()?;
+    matches!(response.data, protocol::OpcodeData::Announce).then_some(response.epoch_time)
+}
+
 /// Probes the local gateway for PCP support.
-pub async fn probe_available(local_ip: Ipv4Addr, gateway: Ipv4Addr) -> bool {
-    match probe_available_fallible(local_ip, gateway).await {
-        Ok(response) => {
-            trace!("probe response: {response:?}");
-            let protocol::Response {
-                lifetime_seconds: _,
-                epoch_time: _,
-                data,
-            } = response;
-            match data {
-                protocol::OpcodeData::Announce => true,
-                _ => {
-                    debug!("server returned an unexpected response type for probe");
-                    // missbehaving server is not useful
-                    false
-                }
-            }
-        }
+///
+/// Returns whether PCP is available, along with the local address the probe was sent from if
+/// a socket could be bound.
+pub async fn probe_available(
+    local_ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+    recv_timeout: Duration,
+    sockets: &SocketOptions,
+) -> (bool, Option<SocketAddrV4>) {
+    let socket = match sockets.bind((local_ip, 0)) {
+        Ok(socket) => socket,
         Err(e) => {
             debug!("probe failed: {e}");
-            false
+            return (false, None);
         }
-    }
+    };
+    let local_addr = match socket.local_addr() {
+        Ok(SocketAddr::V4(addr)) => Some(addr),
+        _ => None,
+    };
+    trace!(?local_addr, "probing");
+    let available =
+        match probe_available_fallible(&socket, local_ip, gateway, recv_timeout, sockets).await {
+            Ok(response) => {
+                trace!("probe response: {response:?}");
+                let protocol::Response {
+                    lifetime_seconds: _,
+                    epoch_time: _,
+                    data,
+                } = response;
+                match data {
+                    protocol::OpcodeData::Announce => true,
+                    _ => {
+                        debug!("server returned an unexpected response type for probe");
+                        // missbehaving server is not useful
+                        false
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("probe failed: {e}");
+                false
+            }
+        };
+    (available, local_addr)
 }
 
 async fn probe_available_fallible(
+    socket: &UdpSocket,
     local_ip: Ipv4Addr,
     gateway: Ipv4Addr,
+    recv_timeout: Duration,
+    sockets: &SocketOptions,
 ) -> Result<protocol::Response, Error> {
-    // create the socket and send the request
-    let socket = UdpSocket::bind_full((local_ip, 0))?;
-    socket.connect((gateway, protocol::SERVER_PORT).into())?;
+    socket.connect(server_addr(gateway, 0, sockets))?;
     let req = protocol::Request::announce(local_ip.to_ipv6_mapped());
-    socket.send(&req.encode()).await?;
 
-    // wait for the response and decode it
+    // send the request until it's answered and decode the response
     let mut buffer = vec![0; protocol::Response::MAX_SIZE];
-    let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer))
-        .await
-        .map_err(|_| {
-            e!(
-                Error::Io,
-                std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout".to_string())
-            )
-        })??;
+    let read = crate::util::request_with_retransmissions(
+        socket,
+        &req.encode(),
+        &mut buffer,
+        recv_timeout,
+        sockets.capture.as_ref(),
+    )
+    .await?;
     let response = protocol::Response::decode(&buffer[..read])?;
 
     Ok(response)
 }
+
+/// Answers a request the way a PCP server would, for a [`crate::test_util::MockGateway`].
+///
+/// MAP and PEER requests are granted alike, ANNOUNCE requests are answered as well.
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn mock_answer(
+    request: &[u8],
+    config: &crate::test_util::MockGatewayConfig,
+) -> Option<Vec<u8>> {
+    use crate::test_util::MockAnswer;
+
+    let crate::test_util::MockGatewayConfig {
+        external_ip,
+        external_port,
+        lifetime_seconds,
+        epoch_time,
+        answer,
+        ..
+    } = *config;
+    // fills in the granted mapping, tampered with as configured
+    let grant = |nonce: &mut [u8; 12],
+                 protocol: &mut protocol::MapProtocol,
+                 local_port: u16,
+                 granted_port: &mut u16,
+                 granted_address: &mut Ipv6Addr| {
+        match answer {
+            MockAnswer::WrongNonce => nonce[0] ^= 1,
+            MockAnswer::WrongProtocol => {
+                *protocol = match protocol {
+                    protocol::MapProtocol::Udp => protocol::MapProtocol::Tcp,
+                    protocol::MapProtocol::Tcp => protocol::MapProtocol::Udp,
+                }
+            }
+            MockAnswer::Grant
+            | MockAnswer::ZeroPort
+            | MockAnswer::Malformed
+            | MockAnswer::Ignore => {}
+        }
+        *granted_port = match (answer, external_port) {
+            (MockAnswer::ZeroPort, _) => 0,
+            (_, Some(port)) => port.get(),
+            (_, None) => local_port,
+        };
+        *granted_address = external_ip.to_ipv6_mapped();
+    };
+    let opcode = protocol::Opcode::try_from(*request.get(1)?).ok()?;
+    if opcode != protocol::Opcode::Announce {
+        match answer {
+            MockAnswer::Ignore => return None,
+            MockAnswer::Malformed => return Some(vec![2, protocol::Response::RESPONSE_INDICATOR]),
+            _ => {}
+        }
+    }
+    let data = match opcode {
+        protocol::Opcode::Announce => protocol::OpcodeData::Announce,
+        protocol::Opcode::Map => {
+            let mut map_data =
+                protocol::MapData::decode(request.get(protocol::Request::MIN_SIZE..)?).ok()?;
+            let protocol::MapData {
+                nonce,
+                protocol,
+                local_port,
+                external_port,
+                external_address,
+            } = &mut map_data;
+            grant(
+                nonce,
+                protocol,
+                *local_port,
+                external_port,
+                external_address,
+            );
+            protocol::OpcodeData::MapData(map_data)
+        }
+        protocol::Opcode::Peer => {
+            let mut peer_data =
+                protocol::PeerData::decode(request.get(protocol::Request::MIN_SIZE..)?).ok()?;
+            let protocol::PeerData {
+                nonce,
+                protocol,
+                local_port,
+                external_port,
+                external_address,
+                ..
+            } = &mut peer_data;
+            grant(
+                nonce,
+                protocol,
+                *local_port,
+                external_port,
+                external_address,
+            );
+            protocol::OpcodeData::PeerData(peer_data)
+        }
+    };
+    // releases are granted a lifetime of zero, like announcements
+    let requested_lifetime = u32::from_be_bytes(request.get(4..8)?.try_into().ok()?);
+    let response = protocol::Response {
+        lifetime_seconds: lifetime_seconds.min(requested_lifetime),
+        epoch_time,
+        data,
+    };
+    Some(response.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_announcement() {
+        let announce = protocol::Response {
+            lifetime_seconds: 0,
+            epoch_time: 42,
+            data: protocol::OpcodeData::Announce,
+        };
+        assert_eq!(decode_announcement(&announce.encode()), Some(42));
+
+        // an announce request is not mistaken for the server's announcement
+        let request = protocol::Request::announce(Ipv4Addr::LOCALHOST.to_pcp());
+        assert_eq!(decode_announcement(&request.encode()), None);
+        assert_eq!(decode_announcement(&[0; 4]), None);
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_peer_mapping() {
+        const LOCAL_PORT: NonZeroU16 = NonZeroU16::new(9589).unwrap();
+        const EXTERNAL_PORT: u16 = 19589;
+        const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+        const REMOTE_PEER: (Ipv4Addr, NonZeroU16) = (
+            Ipv4Addr::new(198, 51, 100, 7),
+            NonZeroU16::new(9000).unwrap(),
+        );
+
+        let server = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let sockets = SocketOptions {
+            server_port_override: Some(server.local_addr().unwrap().port()),
+            ..Default::default()
+        };
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        // grant the first request, answer the second one with the wrong nonce
+        let server = std::thread::spawn(move || {
+            let mut buf = [0u8; protocol::Response::MAX_SIZE];
+            for tamper in [false, true] {
+                let (len, client) = server.recv_from(&mut buf).unwrap();
+                let mut peer_data =
+                    protocol::PeerData::decode(&buf[protocol::Request::MIN_SIZE..len]).unwrap();
+                assert_eq!(
+                    peer_data.remote_peer_address,
+                    REMOTE_PEER.0.to_ipv6_mapped()
+                );
+                peer_data.external_port = EXTERNAL_PORT;
+                peer_data.external_address = EXTERNAL_IP.to_ipv6_mapped();
+                if tamper {
+                    peer_data.nonce[0] ^= 1;
+                }
+                let response = protocol::Response {
+                    lifetime_seconds: 7200,
+                    epoch_time: 0,
+                    data: protocol::OpcodeData::PeerData(peer_data),
+                };
+                server.send_to(&response.encode(), client).unwrap();
+            }
+        });
+
+        let new_peer = || {
+            Mapping::new_peer(
+                Protocol::Udp,
+                Ipv4Addr::LOCALHOST,
+                LOCAL_PORT,
+                Ipv4Addr::LOCALHOST,
+                0,
+                REMOTE_PEER,
+                Duration::from_secs(1),
+                sockets.clone(),
+            )
+        };
+        let mapping = new_peer().await.expect("peer mapping is granted");
+        assert_eq!(
+            mapping.external(),
+            (EXTERNAL_IP, NonZeroU16::new(EXTERNAL_PORT).unwrap())
+        );
+        assert_eq!(mapping.remote_peer, Some(REMOTE_PEER));
+
+        let res = new_peer().await;
+        assert!(matches!(res, Err(Error::NonceMissmatch { .. })), "{res:?}");
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_third_party_mapping() {
+        const INTERNAL_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 3);
+        const INTERNAL_PORT: NonZeroU16 = NonZeroU16::new(9595).unwrap();
+        const EXTERNAL_PORT: u16 = 19595;
+
+        let server = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let sockets = SocketOptions {
+            server_port_override: Some(server.local_addr().unwrap().port()),
+            ..Default::default()
+        };
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        // grant the mapping, then receive its release
+        let server = std::thread::spawn(move || {
+            let mut buf = [0u8; protocol::Response::MAX_SIZE];
+            let options_start = protocol::Request::MIN_SIZE + protocol::MapData::ENCODED_SIZE;
+            for release in [false, true] {
+                let (len, client) = server.recv_from(&mut buf).unwrap();
+                let lifetime = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+                assert_eq!(lifetime == 0, release);
+                let mut map_data =
+                    protocol::MapData::decode(&buf[protocol::Request::MIN_SIZE..len]).unwrap();
+                assert_eq!(map_data.local_port, INTERNAL_PORT.get());
+                let (option, _) = protocol::PcpOption::decode(&buf[options_start..len]).unwrap();
+                assert_eq!(
+                    option,
+                    protocol::PcpOption::ThirdParty(INTERNAL_IP.to_ipv6_mapped())
+                );
+                if release {
+                    break;
+                }
+                map_data.external_port = EXTERNAL_PORT;
+                map_data.external_address = Ipv4Addr::new(203, 0, 113, 1).to_ipv6_mapped();
+                let response = protocol::Response {
+                    lifetime_seconds: 7200,
+                    epoch_time: 0,
+                    data: protocol::OpcodeData::MapData(map_data),
+                };
+                // the option is echoed in the response
+                let mut response = response.encode();
+                option.encode_into(&mut response);
+                server.send_to(&response, client).unwrap();
+            }
+        });
+
+        let mapping = Mapping::new_third_party(
+            Protocol::Udp,
+            Ipv4Addr::LOCALHOST,
+            INTERNAL_IP,
+            INTERNAL_PORT,
+            Ipv4Addr::LOCALHOST,
+            0,
+            None,
+            None,
+            Duration::from_secs(1),
+            sockets,
+        )
+        .await
+        .expect("mapping is granted");
+        assert_eq!(mapping.external().1.get(), EXTERNAL_PORT);
+        assert_eq!(mapping.third_party, Some(INTERNAL_IP));
+
+        mapping.release().await.expect("release is sent");
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_mock_gateway_map() {
+        use crate::test_util::{MockAnswer, MockGateway, MockGatewayConfig};
+
+        const LOCAL_PORT: NonZeroU16 = NonZeroU16::new(9591).unwrap();
+        let config = MockGatewayConfig {
+            nat_pmp: false,
+            external_port: NonZeroU16::new(19591),
+            ..Default::default()
+        };
+        let external_ip = config.external_ip;
+        let gateway = MockGateway::spawn(config).expect("gateway is spawned");
+
+        let new_mapping = |recv_timeout| {
+            Mapping::new(
+                Protocol::Udp,
+                Ipv4Addr::LOCALHOST,
+                LOCAL_PORT,
+                *gateway.addr().ip(),
+                0,
+                None,
+                None,
+                recv_timeout,
+                gateway.socket_options(),
+            )
+        };
+        let recv_timeout = Duration::from_secs(1);
+
+        let mapping = new_mapping(recv_timeout).await.expect("mapping is granted");
+        assert_eq!(
+            mapping.external(),
+            (external_ip, NonZeroU16::new(19591).unwrap())
+        );
+        assert!(
+            probe_available(
+                Ipv4Addr::LOCALHOST,
+                *gateway.addr().ip(),
+                recv_timeout,
+                &gateway.socket_options()
+            )
+            .await
+            .0
+        );
+
+        gateway.set_answer(MockAnswer::WrongNonce);
+        let res = new_mapping(recv_timeout).await;
+        assert!(matches!(res, Err(Error::NonceMissmatch { .. })), "{res:?}");
+
+        gateway.set_answer(MockAnswer::WrongProtocol);
+        let res = new_mapping(recv_timeout).await;
+        assert!(
+            matches!(res, Err(Error::ProtocolMissmatch { .. })),
+            "{res:?}"
+        );
+
+        gateway.set_answer(MockAnswer::ZeroPort);
+        let res = new_mapping(recv_timeout).await;
+        assert!(
+            matches!(res, Err(Error::ZeroExternalPort { .. })),
+            "{res:?}"
+        );
+
+        gateway.set_answer(MockAnswer::Malformed);
+        let res = new_mapping(recv_timeout).await;
+        assert!(matches!(res, Err(Error::Protocol { .. })), "{res:?}");
+
+        gateway.set_answer(MockAnswer::Ignore);
+        let res = new_mapping(Duration::from_millis(50)).await;
+        assert!(
+            matches!(&res, Err(Error::Io { source, .. }) if source.kind() == std::io::ErrorKind::TimedOut),
+            "{res:?}"
+        );
+        assert_eq!(gateway.requests(), 7);
+    }
+}
@@ -3,10 +3,12 @@
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 pub mod opcode_data;
+pub mod option;
 pub mod request;
 pub mod response;
 
 pub use opcode_data::*;
+pub use option::*;
 pub use request::*;
 pub use response::*;
 
@@ -26,7 +28,6 @@ pub enum Version {
 }
 
 /// Opcode as defined in [RFC 6887 IANA Considerations](https://datatracker.ietf.org/doc/html/rfc6887#section-19)
-// NOTE: PEER is not used, therefore not implemented.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Opcode {
@@ -43,4 +44,10 @@ pub enum Opcode {
     ///
     /// See [RFC 6887 MAP Opcode](https://datatracker.ietf.org/doc/html/rfc6887#section-11)
     Map = 1,
+    /// Peer Opcode.
+    ///
+    /// Used to deal with mappings tied to a specific remote peer.
+    ///
+    /// See [RFC 6887 PEER Opcode](https://datatracker.ietf.org/doc/html/rfc6887#section-12)
+    Peer = 2,
 }
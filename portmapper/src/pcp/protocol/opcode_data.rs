@@ -13,6 +13,8 @@ pub enum OpcodeData {
     Announce,
     /// Data for an [`Opcode::Map`] request.
     MapData(MapData),
+    /// Data for an [`Opcode::Peer`] request.
+    PeerData(PeerData),
 }
 
 /// [`OpcodeData`] associated to a [`Opcode::Map`].
@@ -31,6 +33,26 @@ pub struct MapData {
     pub external_address: Ipv6Addr,
 }
 
+/// [`OpcodeData`] associated to a [`Opcode::Peer`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct PeerData {
+    /// Nonce of the request. Used to verify responses in the client side, and modifications in the
+    /// server side.
+    pub nonce: [u8; 12],
+    /// Protocol for which the mapping is being requested.
+    pub protocol: MapProtocol,
+    /// Local port for the mapping.
+    pub local_port: u16,
+    /// External port of the mapping.
+    pub external_port: u16,
+    /// External ip of the mapping.
+    pub external_address: Ipv6Addr,
+    /// Port of the remote peer the mapping is for.
+    pub remote_peer_port: u16,
+    /// Ip of the remote peer the mapping is for.
+    pub remote_peer_address: Ipv6Addr,
+}
+
 /// Protocol for which a port mapping is requested.
 // NOTE: technically any IANA protocol is allowed
 #[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
@@ -74,7 +96,7 @@ impl MapData {
         buf
     }
 
-    /// Decode a [`MapData`].
+    /// Decode a [`MapData`], ignoring anything following it such as options.
     pub fn decode(buf: &[u8]) -> Result<Self, InvalidOpcodeData> {
         if buf.len() < Self::ENCODED_SIZE {
             return Err(InvalidOpcodeData);
@@ -92,7 +114,9 @@ impl MapData {
         let external_port_bytes = buf[18..20].try_into().expect("slice has the right size");
         let external_port = u16::from_be_bytes(external_port_bytes);
 
-        let external_addr_bytes: [u8; 16] = buf[20..].try_into().expect("buffer size was verified");
+        let external_addr_bytes: [u8; 16] = buf[20..Self::ENCODED_SIZE]
+            .try_into()
+            .expect("buffer size was verified");
         let external_address = Ipv6Addr::from(external_addr_bytes);
 
         Ok(MapData {
@@ -119,12 +143,106 @@ impl MapData {
     }
 }
 
+impl PeerData {
+    /// Size of the opcode-specific data of a [`Opcode::Peer`] request.
+    pub const ENCODED_SIZE: usize = // parts
+        MapData::ENCODED_SIZE + // same fields as a map request
+        2 + // remote peer port
+        2 + // reserved
+        16; // remote peer address
+
+    /// Encode this [`PeerData`].
+    pub fn encode(&self) -> [u8; Self::ENCODED_SIZE] {
+        let PeerData {
+            nonce,
+            protocol,
+            local_port,
+            external_port,
+            external_address,
+            remote_peer_port,
+            remote_peer_address,
+        } = self;
+        let mut buf = [0; Self::ENCODED_SIZE];
+        buf[0..12].copy_from_slice(nonce);
+        buf[12] = (*protocol).into();
+        // buf[13..16] reserved
+        buf[16..18].copy_from_slice(&local_port.to_be_bytes());
+        buf[18..20].copy_from_slice(&external_port.to_be_bytes());
+        buf[20..36].copy_from_slice(&external_address.octets());
+        buf[36..38].copy_from_slice(&remote_peer_port.to_be_bytes());
+        // buf[38..40] reserved
+        buf[40..].copy_from_slice(&remote_peer_address.octets());
+
+        buf
+    }
+
+    /// Decode a [`PeerData`].
+    pub fn decode(buf: &[u8]) -> Result<Self, InvalidOpcodeData> {
+        if buf.len() < Self::ENCODED_SIZE {
+            return Err(InvalidOpcodeData);
+        }
+
+        // the first fields are laid out as in a map request
+        let MapData {
+            nonce,
+            protocol,
+            local_port,
+            external_port,
+            external_address,
+        } = MapData::decode(&buf[..MapData::ENCODED_SIZE])?;
+
+        let remote_peer_port_bytes = buf[36..38].try_into().expect("slice has the right size");
+        let remote_peer_port = u16::from_be_bytes(remote_peer_port_bytes);
+
+        // buf[38..40] reserved
+
+        let remote_peer_addr_bytes: [u8; 16] = buf[40..Self::ENCODED_SIZE]
+            .try_into()
+            .expect("buffer size was verified");
+        let remote_peer_address = Ipv6Addr::from(remote_peer_addr_bytes);
+
+        Ok(PeerData {
+            nonce,
+            protocol,
+            local_port,
+            external_port,
+            external_address,
+            remote_peer_port,
+            remote_peer_address,
+        })
+    }
+
+    #[cfg(test)]
+    fn random<R: rand::Rng>(rng: &mut R) -> PeerData {
+        use rand::RngExt;
+
+        let MapData {
+            nonce,
+            protocol,
+            local_port,
+            external_port,
+            external_address,
+        } = MapData::random(rng);
+        let octets: [u8; 16] = rng.random();
+        PeerData {
+            nonce,
+            protocol,
+            local_port,
+            external_port,
+            external_address,
+            remote_peer_port: rng.random(),
+            remote_peer_address: octets.into(),
+        }
+    }
+}
+
 impl OpcodeData {
     /// Get the associated [`Opcode`].
     pub fn opcode(&self) -> Opcode {
         match self {
             OpcodeData::Announce => Opcode::Announce,
             OpcodeData::MapData(_) => Opcode::Map,
+            OpcodeData::PeerData(_) => Opcode::Peer,
         }
     }
 
@@ -133,6 +251,7 @@ impl OpcodeData {
         match self {
             OpcodeData::Announce => {}
             OpcodeData::MapData(map_data) => buf.extend_from_slice(&map_data.encode()),
+            OpcodeData::PeerData(peer_data) => buf.extend_from_slice(&peer_data.encode()),
         }
     }
 
@@ -141,6 +260,7 @@ impl OpcodeData {
         match self {
             OpcodeData::Announce => 0,
             OpcodeData::MapData(_) => MapData::ENCODED_SIZE,
+            OpcodeData::PeerData(_) => PeerData::ENCODED_SIZE,
         }
     }
 
@@ -152,6 +272,10 @@ impl OpcodeData {
                 let map_data = MapData::decode(buf)?;
                 Ok(OpcodeData::MapData(map_data))
             }
+            Opcode::Peer => {
+                let peer_data = PeerData::decode(buf)?;
+                Ok(OpcodeData::PeerData(peer_data))
+            }
         }
     }
 
@@ -160,6 +284,7 @@ impl OpcodeData {
         match opcode {
             Opcode::Announce => OpcodeData::Announce,
             Opcode::Map => OpcodeData::MapData(MapData::random(rng)),
+            Opcode::Peer => OpcodeData::PeerData(PeerData::random(rng)),
         }
     }
 }
@@ -0,0 +1,122 @@
+//! Encoding and decoding of PCP options.
+//!
+//! See [RFC 6887 Options](https://datatracker.ietf.org/doc/html/rfc6887#section-7.3).
+
+use std::net::Ipv6Addr;
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// Code of an option, as defined in [RFC 6887 IANA
+/// Considerations](https://datatracker.ietf.org/doc/html/rfc6887#section-19.4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum OptionCode {
+    /// THIRD_PARTY option.
+    ///
+    /// See [RFC 6887 THIRD_PARTY Option for MAP and PEER
+    /// Opcodes](https://datatracker.ietf.org/doc/html/rfc6887#section-13.1)
+    ThirdParty = 1,
+}
+
+/// An option following the opcode data of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcpOption {
+    /// Requests the mapping for another host than the one sending the request.
+    ///
+    /// Holds the internal address of the mapping, which replaces the client address of the
+    /// request as the one the mapping is for. IPv4 addresses are represented as IPv4-mapped IPv6
+    /// addresses.
+    ThirdParty(Ipv6Addr),
+}
+
+/// Generic error returned when decoding a [`PcpOption`] fails.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct InvalidOption;
+
+impl PcpOption {
+    /// Size of the header preceding the data of every option.
+    pub const HEADER_SIZE: usize = // parts
+        1 + // option code
+        1 + // reserved
+        2; // option length
+
+    /// Get the associated [`OptionCode`].
+    pub fn code(&self) -> OptionCode {
+        match self {
+            PcpOption::ThirdParty(_) => OptionCode::ThirdParty,
+        }
+    }
+
+    /// Exact size an encoded [`PcpOption`] will have.
+    pub const fn encoded_size(&self) -> usize {
+        match self {
+            PcpOption::ThirdParty(_) => Self::HEADER_SIZE + 16,
+        }
+    }
+
+    /// Encode this [`PcpOption`] into the buffer.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        // buf[0]
+        buf.push(self.code().into());
+        // buf[1] reserved
+        buf.push(0);
+        // buf[2..4], the length of the data, which needs no padding for any known option
+        let data_len = (self.encoded_size() - Self::HEADER_SIZE) as u16;
+        buf.extend_from_slice(&data_len.to_be_bytes());
+        // buf[4..]
+        match self {
+            PcpOption::ThirdParty(internal_address) => {
+                buf.extend_from_slice(&internal_address.octets())
+            }
+        }
+    }
+
+    /// Decode a [`PcpOption`], returning it along with the number of bytes it took.
+    #[cfg(test)]
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), InvalidOption> {
+        if buf.len() < Self::HEADER_SIZE {
+            return Err(InvalidOption);
+        }
+        let code: OptionCode = buf[0].try_into().map_err(|_| InvalidOption)?;
+        // buf[1] reserved
+        let data_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        // options are padded to a multiple of four bytes
+        let padded_len = data_len.next_multiple_of(4);
+        let data = buf
+            .get(Self::HEADER_SIZE..Self::HEADER_SIZE + data_len)
+            .ok_or(InvalidOption)?;
+        let option = match code {
+            OptionCode::ThirdParty => {
+                let octets: [u8; 16] = data.try_into().map_err(|_| InvalidOption)?;
+                PcpOption::ThirdParty(octets.into())
+            }
+        };
+        Ok((option, Self::HEADER_SIZE + padded_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_third_party_layout() {
+        // RFC 6887 section 13.1
+        let option = PcpOption::ThirdParty(Ipv4Addr::new(192, 168, 0, 3).to_ipv6_mapped());
+        let mut encoded = Vec::new();
+        option.encode_into(&mut encoded);
+        let expected: Vec<u8> = [
+            // option code, reserved, option length
+            &[1, 0, 0, 16][..],
+            // internal ip address
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 168, 0, 3],
+        ]
+        .concat();
+        assert_eq!(encoded, expected);
+        assert_eq!(encoded.len(), option.encoded_size());
+        assert_eq!(PcpOption::decode(&encoded).unwrap(), (option, 20));
+    }
+}
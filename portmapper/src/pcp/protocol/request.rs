@@ -4,13 +4,13 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 
 use super::{
     Version,
-    opcode_data::{MapData, MapProtocol, OpcodeData},
+    opcode_data::{MapData, MapProtocol, OpcodeData, PeerData},
+    option::PcpOption,
 };
 
 /// A PCP Request.
 ///
 /// See [RFC 6887 Request Header](https://datatracker.ietf.org/doc/html/rfc6887#section-7.1)
-// NOTE: PCP Options are optional, and currently not used in this code, thus not implemented
 #[derive(Debug, PartialEq, Eq)]
 pub struct Request {
     /// [`Version`] to use in this request.
@@ -23,6 +23,8 @@ pub struct Request {
     pub(super) client_addr: Ipv6Addr,
     /// Data associated to the [`super::Opcode`] in this request.
     pub(super) opcode_data: OpcodeData,
+    /// Options following the opcode data.
+    pub(super) options: Vec<PcpOption>,
 }
 
 impl Request {
@@ -41,8 +43,11 @@ impl Request {
             lifetime_seconds,
             client_addr,
             opcode_data,
+            options,
         } = self;
-        let mut buf = Vec::with_capacity(Self::MIN_SIZE + opcode_data.encoded_size());
+        let options_size: usize = options.iter().map(PcpOption::encoded_size).sum();
+        let mut buf =
+            Vec::with_capacity(Self::MIN_SIZE + opcode_data.encoded_size() + options_size);
         // buf[0]
         buf.push((*version).into());
         // buf[1]
@@ -57,10 +62,19 @@ impl Request {
         buf.extend_from_slice(&client_addr.octets());
         // buf[24..]
         opcode_data.encode_into(&mut buf);
+        for option in options {
+            option.encode_into(&mut buf);
+        }
 
         buf
     }
 
+    /// Adds an option to this request.
+    pub fn with_option(mut self, option: PcpOption) -> Request {
+        self.options.push(option);
+        self
+    }
+
     /// Create an announce request.
     pub fn announce(client_addr: Ipv6Addr) -> Request {
         Request {
@@ -70,6 +84,7 @@ impl Request {
             client_addr,
             // the pcp announce opcode requests and responses have no opcode-specific payload
             opcode_data: OpcodeData::Announce,
+            options: Vec::new(),
         }
     }
 
@@ -78,15 +93,21 @@ impl Request {
         nonce: [u8; 12],
         protocol: MapProtocol,
         local_port: u16,
-        local_ip: Ipv4Addr,
+        client_addr: Ipv6Addr,
         preferred_external_port: Option<u16>,
-        preferred_external_address: Option<Ipv4Addr>,
+        preferred_external_address: Option<Ipv6Addr>,
         lifetime_seconds: u32,
     ) -> Request {
+        // without a preference, the external address must be the unspecified address of the
+        // client's family
+        let unspecified = match client_addr.to_ipv4_mapped() {
+            Some(_) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+            None => Ipv6Addr::UNSPECIFIED,
+        };
         Request {
             version: Version::Pcp,
             lifetime_seconds,
-            client_addr: local_ip.to_ipv6_mapped(),
+            client_addr,
             opcode_data: OpcodeData::MapData(MapData {
                 nonce,
                 protocol,
@@ -94,10 +115,20 @@ impl Request {
                 // if the pcp client does not know the external port, or does not have a
                 // preference, it must use 0.
                 external_port: preferred_external_port.unwrap_or_default(),
-                external_address: preferred_external_address
-                    .unwrap_or(Ipv4Addr::UNSPECIFIED)
-                    .to_ipv6_mapped(),
+                external_address: preferred_external_address.unwrap_or(unspecified),
             }),
+            options: Vec::new(),
+        }
+    }
+
+    /// Create a peer request.
+    pub fn peer(client_addr: Ipv6Addr, peer_data: PeerData, lifetime_seconds: u32) -> Request {
+        Request {
+            version: Version::Pcp,
+            lifetime_seconds,
+            client_addr,
+            opcode_data: OpcodeData::PeerData(peer_data),
+            options: Vec::new(),
         }
     }
 
@@ -112,6 +143,7 @@ impl Request {
             lifetime_seconds: rng.random(),
             client_addr: Ipv6Addr::from(addr_octets),
             opcode_data,
+            options: Vec::new(),
         }
     }
 
@@ -129,11 +161,20 @@ impl Request {
         let client_addr: Ipv6Addr = local_ip_bytes.into();
 
         let opcode_data = OpcodeData::decode(opcode, &buf[24..]).unwrap();
+
+        let mut options = Vec::new();
+        let mut rest = &buf[24 + opcode_data.encoded_size()..];
+        while !rest.is_empty() {
+            let (option, len) = PcpOption::decode(rest).unwrap();
+            options.push(option);
+            rest = &rest[len..];
+        }
         Self {
             version,
             lifetime_seconds,
             client_addr,
             opcode_data,
+            options,
         }
     }
 }
@@ -153,6 +194,131 @@ mod tests {
         assert_eq!(request, Request::decode(&encoded));
     }
 
+    #[test]
+    fn test_encode_decode_peer_request() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+
+        let request = Request::random(super::super::Opcode::Peer, &mut rng);
+        let encoded = request.encode();
+        assert_eq!(request, Request::decode(&encoded));
+    }
+
+    #[test]
+    fn test_peer_request_layout() {
+        // RFC 6887 section 7.1 request header followed by the PEER opcode data of section 12.1
+        let request = Request::peer(
+            Ipv4Addr::new(192, 168, 0, 2).to_ipv6_mapped(),
+            PeerData {
+                nonce: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+                protocol: MapProtocol::Udp,
+                local_port: 4433,
+                external_port: 0,
+                external_address: Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+                remote_peer_port: 9000,
+                remote_peer_address: Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped(),
+            },
+            7200,
+        );
+        let expected: Vec<u8> = [
+            // version, opcode, reserved
+            &[2, 2, 0, 0][..],
+            // requested lifetime
+            &[0, 0, 0x1c, 0x20],
+            // client address
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 168, 0, 2],
+            // mapping nonce
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            // protocol, reserved
+            &[17, 0, 0, 0],
+            // internal port, suggested external port
+            &[0x11, 0x51, 0, 0],
+            // suggested external address
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0],
+            // remote peer port, reserved
+            &[0x23, 0x28, 0, 0],
+            // remote peer address
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 203, 0, 113, 7],
+        ]
+        .concat();
+        assert_eq!(request.encode(), expected);
+        assert_eq!(Request::decode(&expected), request);
+    }
+
+    #[test]
+    fn test_third_party_map_request_layout() {
+        // RFC 6887 section 7.1 request header followed by the MAP opcode data of section 11.1 and
+        // the THIRD_PARTY option of section 13.1
+        let request = Request::mapping(
+            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            MapProtocol::Tcp,
+            8080,
+            Ipv4Addr::new(192, 168, 0, 2).to_ipv6_mapped(),
+            None,
+            None,
+            3600,
+        )
+        .with_option(PcpOption::ThirdParty(
+            Ipv4Addr::new(192, 168, 0, 3).to_ipv6_mapped(),
+        ));
+        let expected: Vec<u8> = [
+            // version, opcode, reserved
+            &[2, 1, 0, 0][..],
+            // requested lifetime
+            &[0, 0, 0x0e, 0x10],
+            // client address
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 168, 0, 2],
+            // mapping nonce
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            // protocol, reserved
+            &[6, 0, 0, 0],
+            // internal port, suggested external port
+            &[0x1f, 0x90, 0, 0],
+            // suggested external address
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0],
+            // option code, reserved, option length
+            &[1, 0, 0, 16],
+            // internal ip address
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 168, 0, 3],
+        ]
+        .concat();
+        assert_eq!(request.encode(), expected);
+        assert_eq!(Request::decode(&expected), request);
+    }
+
+    #[test]
+    fn test_mapping_request_unspecified_external_address() {
+        let v4 = Request::mapping(
+            [0; 12],
+            MapProtocol::Udp,
+            9586,
+            Ipv4Addr::LOCALHOST.to_ipv6_mapped(),
+            None,
+            None,
+            0,
+        );
+        let OpcodeData::MapData(data) = v4.opcode_data else {
+            panic!("not a map request");
+        };
+        assert_eq!(
+            data.external_address,
+            Ipv4Addr::UNSPECIFIED.to_ipv6_mapped()
+        );
+
+        let v6 = Request::mapping(
+            [0; 12],
+            MapProtocol::Udp,
+            9586,
+            Ipv6Addr::LOCALHOST,
+            None,
+            None,
+            0,
+        );
+        let OpcodeData::MapData(data) = v6.opcode_data else {
+            panic!("not a map request");
+        };
+        assert_eq!(data.external_address, Ipv6Addr::UNSPECIFIED);
+    }
+
     #[test]
     fn test_encode_decode_map_request() {
         let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
@@ -235,8 +235,8 @@ impl Response {
         }
     }
 
-    #[cfg(test)]
-    fn encode(&self) -> Vec<u8> {
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn encode(&self) -> Vec<u8> {
         let Response {
             lifetime_seconds,
             epoch_time,
@@ -296,6 +296,27 @@ mod tests {
         assert_eq!(&response.encode(), &encoded);
     }
 
+    #[test]
+    fn test_decode_map_response_with_option() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+
+        // servers echo the THIRD_PARTY option of a request in the response
+        let response = Response::random(Opcode::Map, &mut rng);
+        let mut encoded = response.encode();
+        super::super::PcpOption::ThirdParty(std::net::Ipv6Addr::LOCALHOST)
+            .encode_into(&mut encoded);
+        assert_eq!(response, Response::decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn test_encode_decode_peer_response() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+
+        let response = Response::random(Opcode::Peer, &mut rng);
+        let encoded = response.encode();
+        assert_eq!(response, Response::decode(&encoded).unwrap());
+    }
+
     #[test]
     fn test_encode_decode_map_response() {
         let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
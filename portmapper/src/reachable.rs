@@ -0,0 +1,149 @@
+//! Addresses at which peers can likely reach a local port.
+//!
+//! Combines the external address of a mapping with the addresses of the local interfaces that
+//! are routable beyond the local network: public IPv4 addresses, and IPv6 global and unique local
+//! addresses.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    num::NonZeroU16,
+};
+
+use netwatch::interfaces::State;
+
+/// Addresses of the interfaces in `state` that are up and usable by peers.
+pub(crate) fn interface_ips(state: &State) -> impl Iterator<Item = IpAddr> + '_ {
+    state
+        .interfaces
+        .values()
+        .filter(|iface| iface.is_up() && !iface.is_loopback())
+        .flat_map(|iface| iface.addrs())
+        .map(|net| net.addr())
+        .filter(is_reachable_ip)
+}
+
+/// Merges the `external` address of a mapping with the interface addresses `ips`.
+///
+/// The mapped address comes first, followed by the sorted interface addresses paired with
+/// `local_port`. Duplicates are removed.
+pub(crate) fn reachable_addresses(
+    external: Option<SocketAddrV4>,
+    ips: impl IntoIterator<Item = IpAddr>,
+    local_port: NonZeroU16,
+) -> Vec<SocketAddr> {
+    let mut interface_addrs: Vec<SocketAddr> = ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, local_port.get()))
+        .collect();
+    interface_addrs.sort();
+    interface_addrs.dedup();
+
+    let external = external.map(SocketAddr::V4);
+    external
+        .into_iter()
+        .chain(
+            interface_addrs
+                .into_iter()
+                .filter(|addr| Some(*addr) != external),
+        )
+        .collect()
+}
+
+/// Whether `ip` can be reached from beyond the local network.
+fn is_reachable_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_reachable_v4(*ip),
+        IpAddr::V6(ip) => is_reachable_v6(*ip),
+    }
+}
+
+/// Whether `ip` is a public IPv4 address.
+fn is_reachable_v4(ip: Ipv4Addr) -> bool {
+    // 100.64.0.0/10, see RFC 6598
+    let [a, b, ..] = ip.octets();
+    let is_shared = a == 100 && b & 0xc0 == 64;
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_private()
+        || is_shared)
+}
+
+/// Whether `ip` is an IPv6 global or unique local unicast address.
+fn is_reachable_v6(ip: Ipv6Addr) -> bool {
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || netwatch::ip::is_unicast_link_local(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL_PORT: NonZeroU16 = NonZeroU16::new(9000).unwrap();
+
+    #[test]
+    fn test_reachable_addresses() {
+        let external = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 1), 40000);
+        let ips = [
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).into(),
+            Ipv4Addr::new(198, 51, 100, 7).into(),
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+            Ipv4Addr::new(198, 51, 100, 7).into(),
+        ];
+        assert_eq!(
+            reachable_addresses(Some(external), ips, LOCAL_PORT),
+            vec![
+                external.into(),
+                "198.51.100.7:9000".parse().unwrap(),
+                "[2001:db8::1]:9000".parse().unwrap(),
+                "[fd00::1]:9000".parse().unwrap(),
+            ]
+        );
+
+        // an interface holding the external address itself, e.g. without NAT
+        let ips = [IpAddr::V4(*external.ip())];
+        let external = SocketAddrV4::new(*external.ip(), LOCAL_PORT.get());
+        assert_eq!(
+            reachable_addresses(Some(external), ips, LOCAL_PORT),
+            vec![SocketAddr::V4(external)]
+        );
+
+        assert!(reachable_addresses(None, [], LOCAL_PORT).is_empty());
+    }
+
+    #[test]
+    fn test_is_reachable_ip() {
+        let reachable: [IpAddr; 3] = [
+            Ipv4Addr::new(198, 51, 100, 7).into(),
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).into(),
+        ];
+        for ip in reachable {
+            assert!(is_reachable_ip(&ip), "{ip}");
+        }
+
+        let unreachable: [IpAddr; 8] = [
+            Ipv4Addr::UNSPECIFIED.into(),
+            Ipv4Addr::LOCALHOST.into(),
+            Ipv4Addr::new(169, 254, 1, 1).into(),
+            Ipv4Addr::new(192, 168, 0, 189).into(),
+            Ipv4Addr::new(100, 64, 0, 1).into(),
+            Ipv6Addr::LOCALHOST.into(),
+            Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).into(),
+            Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1).into(),
+        ];
+        for ip in unreachable {
+            assert!(!is_reachable_ip(&ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_interface_ips() {
+        // the fake interface only has a private address
+        assert_eq!(interface_ips(&State::fake()).count(), 0);
+    }
+}
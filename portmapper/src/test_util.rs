@@ -0,0 +1,282 @@
+//! Utilities to test port mapping without a real router.
+//!
+//! A [`MockGateway`] answers PCP and NAT-PMP requests on a free port of the IPv4 loopback
+//! address. A [`Client`] created with [`MockGateway::client_config`] sends its requests to that
+//! port, and finds the gateway as its home router once handed [`MockGateway::state`] with
+//! [`Client::update_interface_state`].
+//!
+//! [`Client`]: crate::Client
+//! [`Client::update_interface_state`]: crate::Client::update_interface_state
+
+#[cfg(feature = "nat_pmp")]
+use std::time::Duration;
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    num::NonZeroU16,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use netwatch::interfaces::{HomeRouter, State};
+use tokio::net::UdpSocket;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{Instrument, debug, info_span, trace};
+
+use crate::Config;
+#[cfg(feature = "nat_pmp")]
+use crate::Timeouts;
+#[cfg(feature = "nat_pmp")]
+use crate::nat_pmp;
+#[cfg(feature = "pcp")]
+use crate::pcp;
+
+/// Largest request we expect, PCP being the biggest of both protocols.
+const MAX_REQUEST_SIZE: usize = 1100;
+
+/// How a [`MockGateway`] answers mapping requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MockAnswer {
+    /// Grant the requested mapping.
+    #[default]
+    Grant,
+    /// Grant the mapping, but with a PCP nonce different from the one in the request.
+    ///
+    /// NAT-PMP has no nonce, so its mappings are granted as usual.
+    WrongNonce,
+    /// Grant a mapping for the other transport protocol.
+    WrongProtocol,
+    /// Grant the mapping with a zero external port.
+    ZeroPort,
+    /// Answer with a response that can't be decoded.
+    Malformed,
+    /// Don't answer at all, so that the client times out.
+    Ignore,
+}
+
+/// Configuration of a [`MockGateway`].
+///
+/// Start from [`MockGatewayConfig::default`] and change the fields a test needs, so that fields
+/// added later don't break it.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct MockGatewayConfig {
+    /// Whether to answer PCP requests.
+    pub pcp: bool,
+    /// Whether to answer NAT-PMP requests.
+    pub nat_pmp: bool,
+    /// External address reported to clients.
+    pub external_ip: Ipv4Addr,
+    /// External port granted to mappings, the local port if `None`.
+    pub external_port: Option<NonZeroU16>,
+    /// Lifetime granted to mappings.
+    pub lifetime_seconds: u32,
+    /// Seconds since the gateway's epoch, reported in every response.
+    pub epoch_time: u32,
+    /// How mapping requests are answered.
+    pub answer: MockAnswer,
+    /// Number of upcoming requests to drop without answering, simulating a lossy link.
+    pub drop_requests: usize,
+}
+
+impl Default for MockGatewayConfig {
+    /// Answers both protocols, granting two hour mappings from a documentation address.
+    fn default() -> Self {
+        MockGatewayConfig {
+            pcp: true,
+            nat_pmp: true,
+            external_ip: Ipv4Addr::new(203, 0, 113, 1),
+            external_port: None,
+            lifetime_seconds: 60 * 60 * 2,
+            epoch_time: 0,
+            answer: MockAnswer::Grant,
+            drop_requests: 0,
+        }
+    }
+}
+
+/// A fake gateway answering PCP and NAT-PMP requests.
+///
+/// The gateway stops once dropped.
+#[derive(Debug)]
+pub struct MockGateway {
+    /// Address the gateway is listening on.
+    addr: SocketAddrV4,
+    /// Configuration shared with the task answering requests.
+    config: Arc<Mutex<MockGatewayConfig>>,
+    /// Number of requests received so far.
+    requests: Arc<AtomicUsize>,
+    _handle: AbortOnDropHandle<()>,
+}
+
+impl MockGateway {
+    /// Starts a gateway listening on a free port of the IPv4 loopback address.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn spawn(config: MockGatewayConfig) -> std::io::Result<Self> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_nonblocking(true)?;
+        let SocketAddr::V4(addr) = socket.local_addr()? else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let socket = UdpSocket::from_std(socket)?;
+        let config = Arc::new(Mutex::new(config));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let handle = tokio::spawn(
+            serve(socket, config.clone(), requests.clone())
+                .instrument(info_span!("portmapper.mock_gateway", %addr)),
+        );
+        Ok(MockGateway {
+            addr,
+            config,
+            requests,
+            _handle: AbortOnDropHandle::new(handle),
+        })
+    }
+
+    /// Address the gateway is listening on.
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// The gateway as a home router, reached from the IPv4 loopback address.
+    pub fn home_router(&self) -> HomeRouter {
+        HomeRouter {
+            gateway: (*self.addr.ip()).into(),
+            my_ip: Some(Ipv4Addr::LOCALHOST.into()),
+        }
+    }
+
+    /// A configuration mapping with the protocols this gateway was spawned to answer, sending the
+    /// requests to its port.
+    pub fn client_config(&self) -> Config {
+        let answered = self.config.lock().expect("poisoned").clone();
+        let mut builder = Config::builder().server_port_override(Some(self.addr.port()));
+        #[cfg(feature = "upnp")]
+        {
+            builder = builder.enable_upnp(false);
+        }
+        #[cfg(feature = "pcp")]
+        {
+            builder = builder.enable_pcp(answered.pcp);
+        }
+        #[cfg(feature = "nat_pmp")]
+        {
+            builder = builder.enable_nat_pmp(answered.nat_pmp);
+        }
+        builder.build()
+    }
+
+    /// Options sending requests of the protocol clients to this gateway's port.
+    #[cfg(test)]
+    pub(crate) fn socket_options(&self) -> crate::util::SocketOptions {
+        crate::util::SocketOptions {
+            server_port_override: Some(self.addr.port()),
+            ..Default::default()
+        }
+    }
+
+    /// A fake interface state with this gateway as its home router.
+    ///
+    /// Allows exercising the client without a real network, see
+    /// [`Client::update_interface_state`](crate::Client::update_interface_state).
+    pub fn state(&self) -> State {
+        State::fake_with_home_router(&self.home_router())
+    }
+
+    /// Changes how further mapping requests are answered.
+    pub fn set_answer(&self, answer: MockAnswer) {
+        self.config.lock().expect("poisoned").answer = answer;
+    }
+
+    /// Drops the next `count` requests without answering them, simulating a lossy link.
+    pub fn drop_requests(&self, count: usize) {
+        self.config.lock().expect("poisoned").drop_requests = count;
+    }
+
+    /// Changes the external port granted to further mappings, the local port if `None`.
+    pub fn set_external_port(&self, external_port: Option<NonZeroU16>) {
+        self.config.lock().expect("poisoned").external_port = external_port;
+    }
+
+    /// Changes the gateway's epoch time, e.g. to simulate a reboot.
+    pub fn set_epoch_time(&self, epoch_time: u32) {
+        self.config.lock().expect("poisoned").epoch_time = epoch_time;
+    }
+
+    /// Number of requests received so far, including the ones that were not answered.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+/// Spawns a gateway that never answers, along with a NAT-PMP only configuration pointed at it.
+///
+/// Probes and mapping attempts fail once `recv` has elapsed. Tests override the fields they vary,
+/// e.g. to enable PCP as well. Must be called within a tokio runtime.
+#[cfg(feature = "nat_pmp")]
+pub fn unresponsive_gateway(recv: Duration) -> (MockGateway, Config) {
+    let gateway = MockGateway::spawn(MockGatewayConfig {
+        pcp: false,
+        drop_requests: usize::MAX,
+        ..Default::default()
+    })
+    .expect("loopback address can be bound");
+    let config = Config {
+        gateway_override: Some(*gateway.addr().ip()),
+        local_ip_override: Some(Ipv4Addr::LOCALHOST),
+        timeouts: Timeouts {
+            nat_pmp_recv: recv,
+            ..Default::default()
+        },
+        ..gateway.client_config()
+    };
+    (gateway, config)
+}
+
+/// Answers requests arriving at `socket` until the socket fails.
+async fn serve(
+    socket: UdpSocket,
+    config: Arc<Mutex<MockGatewayConfig>>,
+    requests: Arc<AtomicUsize>,
+) {
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => return debug!("mock gateway stopped: {e}"),
+        };
+        requests.fetch_add(1, Ordering::Relaxed);
+        let config = {
+            let mut config = config.lock().expect("poisoned");
+            if config.drop_requests > 0 {
+                config.drop_requests -= 1;
+                trace!(%from, "dropping request");
+                continue;
+            }
+            config.clone()
+        };
+        let Some(response) = answer(&buf[..len], &config) else {
+            trace!(%from, "not answering request");
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response, from).await {
+            debug!(%from, "failed to answer request: {e}");
+        }
+    }
+}
+
+/// Answers a request of either protocol, based on its version.
+///
+/// Requests of protocols left out of the build are not answered.
+fn answer(request: &[u8], config: &MockGatewayConfig) -> Option<Vec<u8>> {
+    match request.first()? {
+        #[cfg(feature = "nat_pmp")]
+        0 if config.nat_pmp => nat_pmp::mock_answer(request, config),
+        #[cfg(feature = "pcp")]
+        2 if config.pcp => pcp::mock_answer(request, config),
+        _ => None,
+    }
+}
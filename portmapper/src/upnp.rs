@@ -1,3 +1,8 @@
+//! Inspecting and cleaning up the port mappings of a UPnP gateway.
+//!
+//! Find a gateway with [`probe_available_result`], then list its mappings with [`list_mappings`] or
+//! remove those left behind by another application with [`remove_mappings_by_description`].
+
 use std::{
     net::{Ipv4Addr, SocketAddrV4},
     num::NonZeroU16,
@@ -5,37 +10,144 @@ use std::{
     time::Duration,
 };
 
-use igd_next::{AddAnyPortError, GetExternalIpError, RemovePortError, SearchError, aio as aigd};
+use igd_next::{
+    AddAnyPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError,
+    RequestError, SearchError,
+    aio::{self as aigd, Provider},
+};
 use n0_error::{e, stack_error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::debug;
 
 use super::Metrics;
 
+/// A UPnP Internet Gateway Device.
 pub type Gateway = aigd::Gateway<aigd::tokio::Tokio>;
 
-use crate::{Protocol, defaults::UPNP_SEARCH_TIMEOUT as SEARCH_TIMEOUT};
+use crate::{GatewayInfo, Protocol};
 
 /// Seconds we ask the router to maintain the port mapping. Use 2 hours for now.
 const PORT_MAPPING_LEASE_DURATION_SECONDS: u32 = 2 * 60 * 60;
 
-/// Tailscale uses the recommended port mapping lifetime for PMP, which is 2 hours. So we assume a
-/// half lifetime of 1h. See <https://datatracker.ietf.org/doc/html/rfc6886#section-3.3>
-const HALF_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// IGD error code of a gateway that only supports permanent leases.
+const ONLY_PERMANENT_LEASES_SUPPORTED: u16 = 725;
+
+/// IGD error code for invalid arguments, which some gateways return for leases they don't support.
+const INVALID_ARGS: u16 = 402;
+
+/// Maximum duration to wait for the gateway to report the lease it granted.
+const LEASE_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum number of mappings read from a gateway, in case it never reports the end of the list.
+const MAX_LISTED_MAPPINGS: u32 = 1024;
 
-/// Name with which we register the mapping in the router.
-const PORT_MAPPING_DESCRIPTION: &str = "iroh-portmap";
+/// Maximum duration of removing the mappings left behind by earlier runs.
+const STALE_CLEANUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum size of the device description we are willing to read.
+const MAX_DESCRIPTION_SIZE: u64 = 64 * 1024;
+
+impl GatewayInfo {
+    /// Parses the root device's identity from a UPnP device description.
+    ///
+    /// The root device's fields come before any embedded device in the description, so the first
+    /// occurrence of each element is the one we are interested in.
+    fn from_description(description: &str) -> Self {
+        GatewayInfo {
+            friendly_name: xml_element(description, "friendlyName"),
+            manufacturer: xml_element(description, "manufacturer"),
+            model_name: xml_element(description, "modelName"),
+            model_number: xml_element(description, "modelNumber"),
+        }
+    }
+}
+
+/// Returns the trimmed text of the first `<name>` element, if present and not empty.
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{name}>"))?;
+    let text = xml[start..start + len].trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Location of the gateway's device description.
+pub(crate) fn description_url(gateway: &Gateway) -> String {
+    format!("http://{}{}", gateway.addr, gateway.root_url)
+}
+
+/// Fetches the gateway's device description and parses its identity from it.
+async fn fetch_gateway_info(gateway: &Gateway) -> Result<GatewayInfo, Error> {
+    let mut stream = tokio::net::TcpStream::connect(gateway.addr).await?;
+    // HTTP/1.0 so that the body is neither chunked nor kept alive
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n",
+        gateway.root_url, gateway.addr
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_DESCRIPTION_SIZE)
+        .read_to_end(&mut response)
+        .await?;
+    let response = String::from_utf8_lossy(&response);
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_headers, body)| body)
+        .unwrap_or_default();
+    Ok(GatewayInfo::from_description(body))
+}
 
 #[derive(derive_more::Debug, Clone)]
-pub struct Mapping {
+pub(crate) struct Mapping {
     /// Protocol for this mapping.
     protocol: igd_next::PortMappingProtocol,
     /// The internet Gateway device (router) used to create this mapping.
     #[debug("{}", gateway)]
     gateway: Gateway,
+    /// Local port mapped by this mapping.
+    local_port: NonZeroU16,
     /// The external address obtained by this mapping.
     external_ip: Ipv4Addr,
     /// External port obtained by this mapping.
     external_port: NonZeroU16,
+    /// Seconds the gateway maintains this mapping, zero if it doesn't expire.
+    lease_seconds: u32,
+}
+
+/// A port mapping found on a gateway, not necessarily created by us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PortMappingEntry {
+    /// External port of the mapping.
+    pub external_port: u16,
+    /// Host the mapping forwards to, an IP address or a host name.
+    pub internal_client: String,
+    /// Port the mapping forwards to.
+    pub internal_port: u16,
+    /// Protocol of the mapping.
+    pub protocol: Protocol,
+    /// Description given when the mapping was created.
+    pub description: String,
+    /// Remaining lease of the mapping, `None` if it doesn't expire.
+    pub lease: Option<Duration>,
+}
+
+impl From<igd_next::PortMappingEntry> for PortMappingEntry {
+    fn from(entry: igd_next::PortMappingEntry) -> Self {
+        PortMappingEntry {
+            external_port: entry.external_port,
+            internal_client: entry.internal_client,
+            internal_port: entry.internal_port,
+            protocol: match entry.protocol {
+                igd_next::PortMappingProtocol::TCP => Protocol::Tcp,
+                igd_next::PortMappingProtocol::UDP => Protocol::Udp,
+            },
+            description: entry.port_mapping_description,
+            lease: (entry.lease_duration != 0)
+                .then(|| Duration::from_secs(entry.lease_duration.into())),
+        }
+    }
 }
 
 #[allow(missing_docs)]
@@ -46,6 +158,8 @@ pub enum Error {
     ZeroExternalPort {},
     #[error("igd device's external ip is ipv6")]
     NotIpv4 {},
+    #[error("igd device reported {addr} as external ip, which is not reachable from outside")]
+    InvalidExternalAddr { addr: Ipv4Addr },
     #[error("Remove Port")]
     RemovePort { source: RemovePortError },
     #[error("Search")]
@@ -54,41 +168,65 @@ pub enum Error {
     GetExternalIp { source: GetExternalIpError },
     #[error("Add any port")]
     AddAnyPort { source: AddAnyPortError },
+    #[error("igd device rejected the lease with error code {code}")]
+    LeaseRejected { code: u16 },
+    #[error("Get port mapping entry")]
+    GetPortMappingEntry { source: RequestError },
+    #[error("Port mapping entry lacks a lease duration")]
+    MissingLeaseDuration {},
+    #[error("Get generic port mapping entry")]
+    GetGenericPortMappingEntry {
+        source: GetGenericPortMappingEntryError,
+    },
     #[error("IO")]
     Io { source: std::io::Error },
 }
 
 impl Mapping {
+    /// Maps `port` with the given gateway, or the first one found.
+    ///
+    /// Private external addresses are rejected unless `allow_private_external` is set. With
+    /// `cleanup_stale`, mappings left behind by earlier runs are removed first, see
+    /// [`crate::Config::cleanup_stale_mappings`].
+    ///
+    /// Gateways rejecting the lease we ask for are asked for a permanent one instead.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         protocol: Protocol,
         local_addr: Ipv4Addr,
         port: NonZeroU16,
         gateway: Option<Gateway>,
         preferred_port: Option<NonZeroU16>,
+        search_timeout: Duration,
+        description: String,
+        allow_private_external: bool,
+        cleanup_stale: bool,
+        metrics: Arc<Metrics>,
     ) -> Result<Self, Error> {
         let local_addr = SocketAddrV4::new(local_addr, port.into());
 
         // search for a gateway if there is not one already
-        let gateway = if let Some(known_gateway) = gateway {
-            known_gateway
-        } else {
-            // Wrap in manual timeout, because igd_next doesn't respect the set timeout
-            tokio::time::timeout(
-                SEARCH_TIMEOUT,
-                aigd::tokio::search_gateway(igd_next::SearchOptions {
-                    timeout: Some(SEARCH_TIMEOUT),
-                    ..Default::default()
-                }),
-            )
-            .await
-            .map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout".to_string())
-            })??
+        let gateway = match gateway {
+            Some(known_gateway) => known_gateway,
+            None => probe_available_result(*local_addr.ip(), search_timeout).await?,
         };
 
-        let std::net::IpAddr::V4(external_ip) = gateway.get_external_ip().await? else {
-            return Err(e!(Error::NotIpv4));
-        };
+        let external_ip = external_address(&gateway).await?;
+        // check before mapping, so that there is nothing to clean up
+        if !crate::mapping::is_valid_external_ip(external_ip, allow_private_external) {
+            return Err(e!(Error::InvalidExternalAddr { addr: external_ip }));
+        }
+
+        if cleanup_stale {
+            remove_stale_mappings(
+                &gateway,
+                protocol,
+                *local_addr.ip(),
+                &description,
+                preferred_port,
+            )
+            .await;
+        }
 
         let protocol = match protocol {
             Protocol::Udp => igd_next::PortMappingProtocol::UDP,
@@ -104,40 +242,84 @@ impl Mapping {
                     external_port.into(),
                     local_addr.into(),
                     PORT_MAPPING_LEASE_DURATION_SECONDS,
-                    PORT_MAPPING_DESCRIPTION,
+                    &description,
                 )
                 .await
                 .is_ok()
         {
+            let lease_seconds = granted_lease(
+                &gateway,
+                protocol,
+                external_port,
+                PORT_MAPPING_LEASE_DURATION_SECONDS,
+            )
+            .await;
             return Ok(Mapping {
                 protocol,
                 gateway,
+                local_port: port,
                 external_ip,
                 external_port,
+                lease_seconds,
             });
         }
 
-        let external_port = gateway
-            .add_any_port(
-                protocol,
-                local_addr.into(),
-                PORT_MAPPING_LEASE_DURATION_SECONDS,
-                PORT_MAPPING_DESCRIPTION,
-            )
-            .await?
-            .try_into()
-            .map_err(|_| e!(Error::ZeroExternalPort))?;
+        let mut requested_lease = PORT_MAPPING_LEASE_DURATION_SECONDS;
+        let external_port = match add_any_port(
+            &gateway,
+            protocol,
+            local_addr,
+            requested_lease,
+            &description,
+            &metrics,
+        )
+        .await
+        {
+            Err(Error::LeaseRejected { code, .. }) => {
+                debug!(
+                    code,
+                    "upnp gateway rejected the lease, asking for a permanent one"
+                );
+                requested_lease = 0;
+                add_any_port(
+                    &gateway,
+                    protocol,
+                    local_addr,
+                    requested_lease,
+                    &description,
+                    &metrics,
+                )
+                .await?
+            }
+            res => res?,
+        };
 
+        let lease_seconds = granted_lease(&gateway, protocol, external_port, requested_lease).await;
         Ok(Mapping {
             protocol,
             gateway,
+            local_port: port,
             external_ip,
             external_port,
+            lease_seconds,
         })
     }
 
-    pub fn half_lifetime(&self) -> Duration {
-        HALF_LIFETIME
+    /// Half the lease granted by the gateway.
+    ///
+    /// Mappings that don't expire are still renewed as often as the lease we ask for, in case the
+    /// router forgets them.
+    pub(crate) fn half_lifetime(&self) -> Duration {
+        let lease_seconds = match self.lease_seconds {
+            0 => PORT_MAPPING_LEASE_DURATION_SECONDS,
+            lease_seconds => lease_seconds,
+        };
+        Duration::from_secs((lease_seconds / 2).into())
+    }
+
+    /// Lease granted by the gateway, `None` if the mapping doesn't expire.
+    pub(crate) fn lease(&self) -> Option<Duration> {
+        (self.lease_seconds != 0).then(|| Duration::from_secs(self.lease_seconds.into()))
     }
 
     /// Releases the mapping.
@@ -153,36 +335,418 @@ impl Mapping {
     }
 
     /// Returns the external gateway ip and port that can be used to contact this node.
-    pub fn external(&self) -> (Ipv4Addr, NonZeroU16) {
+    pub(crate) fn external(&self) -> (Ipv4Addr, NonZeroU16) {
         (self.external_ip, self.external_port)
     }
+
+    /// Local port mapped by this mapping.
+    pub(crate) fn local_port(&self) -> NonZeroU16 {
+        self.local_port
+    }
+}
+
+/// Maps any external port to `local_addr`, asking for a lease of `lease_seconds`.
+async fn add_any_port(
+    gateway: &Gateway,
+    protocol: igd_next::PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_seconds: u32,
+    description: &str,
+    metrics: &Metrics,
+) -> Result<NonZeroU16, Error> {
+    let external_port = gateway
+        .add_any_port(protocol, local_addr.into(), lease_seconds, description)
+        .await
+        .map_err(|source| match lease_rejection_code(&source) {
+            Some(code) => {
+                metrics.upnp_lease_rejected.inc();
+                e!(Error::LeaseRejected { code })
+            }
+            None => e!(Error::AddAnyPort { source }),
+        })?;
+    external_port
+        .try_into()
+        .map_err(|_| e!(Error::ZeroExternalPort))
+}
+
+/// Returns the IGD error code if `err` is the gateway rejecting the lease we asked for.
+fn lease_rejection_code(err: &AddAnyPortError) -> Option<u16> {
+    match err {
+        AddAnyPortError::OnlyPermanentLeasesSupported => Some(ONLY_PERMANENT_LEASES_SUPPORTED),
+        AddAnyPortError::RequestError(RequestError::ErrorCode(INVALID_ARGS, _)) => {
+            Some(INVALID_ARGS)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the seconds of the lease the gateway granted to the mapping of `external_port`.
+///
+/// Routers may grant a shorter lease than requested. If the gateway can't tell, the `requested`
+/// lease is assumed.
+async fn granted_lease(
+    gateway: &Gateway,
+    protocol: igd_next::PortMappingProtocol,
+    external_port: NonZeroU16,
+    requested: u32,
+) -> u32 {
+    match tokio::time::timeout(
+        LEASE_QUERY_TIMEOUT,
+        query_lease(gateway, protocol, external_port),
+    )
+    .await
+    {
+        Ok(Ok(lease_seconds)) => {
+            debug!(lease_seconds, "upnp gateway reported the granted lease");
+            lease_seconds
+        }
+        Ok(Err(e)) => {
+            debug!("failed to query the granted upnp lease: {e}");
+            requested
+        }
+        Err(_) => {
+            debug!("querying the granted upnp lease timed out");
+            requested
+        }
+    }
+}
+
+/// Asks the gateway for the lease of the mapping of `external_port`, with
+/// `GetSpecificPortMappingEntry`.
+async fn query_lease(
+    gateway: &Gateway,
+    protocol: igd_next::PortMappingProtocol,
+    external_port: NonZeroU16,
+) -> Result<u32, Error> {
+    let action = r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetSpecificPortMappingEntry""#;
+    let body = format!(
+        r#"<?xml version="1.0"?>
+<s:Envelope s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/" xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body>
+<u:GetSpecificPortMappingEntry xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewRemoteHost></NewRemoteHost>
+<NewExternalPort>{external_port}</NewExternalPort>
+<NewProtocol>{protocol}</NewProtocol>
+</u:GetSpecificPortMappingEntry>
+</s:Body>
+</s:Envelope>"#
+    );
+    let response = aigd::tokio::Tokio::send_async(&gateway.to_string(), action, &body).await?;
+    parse_lease_duration(&response).ok_or_else(|| e!(Error::MissingLeaseDuration))
+}
+
+/// Asks `gateway` for its external address, without creating a mapping.
+pub(crate) async fn external_address(gateway: &Gateway) -> Result<Ipv4Addr, Error> {
+    let std::net::IpAddr::V4(external_ip) = gateway.get_external_ip().await? else {
+        return Err(e!(Error::NotIpv4));
+    };
+    Ok(external_ip)
+}
+
+/// Parses the lease from a `GetSpecificPortMappingEntry` response.
+fn parse_lease_duration(response: &str) -> Option<u32> {
+    xml_element(response, "NewLeaseDuration")?.parse().ok()
+}
+
+/// Removes the mapping of `external_port` from the gateway, whether or not we made it.
+pub(crate) async fn release_port(
+    gateway: &Gateway,
+    protocol: Protocol,
+    external_port: NonZeroU16,
+) -> Result<(), Error> {
+    let protocol = match protocol {
+        Protocol::Udp => igd_next::PortMappingProtocol::UDP,
+        Protocol::Tcp => igd_next::PortMappingProtocol::TCP,
+    };
+    gateway.remove_port(protocol, external_port.into()).await?;
+    Ok(())
+}
+
+/// Lists the port mappings on the gateway, with `GetGenericPortMappingEntry`.
+///
+/// Gateways may only report the mappings of the host asking.
+pub async fn list_mappings(gateway: &Gateway) -> Result<Vec<PortMappingEntry>, Error> {
+    let mut mappings = Vec::new();
+    for index in 0..MAX_LISTED_MAPPINGS {
+        match gateway.get_generic_port_mapping_entry(index).await {
+            Ok(entry) => mappings.push(entry.into()),
+            // the index is past the end of the list
+            Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(mappings)
+}
+
+/// Removes the port mappings on the gateway with the given description, returning how many were
+/// removed.
+///
+/// Mappings that fail to be removed are skipped.
+pub async fn remove_mappings_by_description(
+    gateway: &Gateway,
+    description: &str,
+) -> Result<usize, Error> {
+    remove_mappings_where(gateway, |entry| entry.description == description).await
+}
+
+/// Removes the mappings earlier runs left behind on the gateway.
+///
+/// These have our `description` and point at `local_ip`. The mapping of `keep_port`, about to be
+/// requested again, is left in place. Failures are only logged, as they must not prevent mapping.
+async fn remove_stale_mappings(
+    gateway: &Gateway,
+    protocol: Protocol,
+    local_ip: Ipv4Addr,
+    description: &str,
+    keep_port: Option<NonZeroU16>,
+) {
+    let is_stale = |entry: &PortMappingEntry| {
+        is_stale_mapping(entry, protocol, local_ip, description, keep_port)
+    };
+    match tokio::time::timeout(
+        STALE_CLEANUP_TIMEOUT,
+        remove_mappings_where(gateway, is_stale),
+    )
+    .await
+    {
+        Ok(Ok(0)) => {}
+        Ok(Ok(removed)) => debug!(removed, "removed stale upnp mappings"),
+        Ok(Err(e)) => debug!("failed to list upnp mappings: {e}"),
+        Err(_) => debug!("removing stale upnp mappings timed out"),
+    }
+}
+
+/// Whether `entry` is a mapping of ours left behind by an earlier run, see
+/// [`remove_stale_mappings`].
+fn is_stale_mapping(
+    entry: &PortMappingEntry,
+    protocol: Protocol,
+    local_ip: Ipv4Addr,
+    description: &str,
+    keep_port: Option<NonZeroU16>,
+) -> bool {
+    entry.description == description
+        && entry.protocol == protocol
+        && entry.internal_client.parse() == Ok(local_ip)
+        && Some(entry.external_port) != keep_port.map(u16::from)
+}
+
+/// Removes the port mappings on the gateway matching `predicate`, returning how many were removed.
+///
+/// Mappings that fail to be removed are skipped.
+async fn remove_mappings_where(
+    gateway: &Gateway,
+    predicate: impl Fn(&PortMappingEntry) -> bool,
+) -> Result<usize, Error> {
+    let mut removed = 0;
+    for entry in list_mappings(gateway).await? {
+        if !predicate(&entry) {
+            continue;
+        }
+        let protocol = match entry.protocol {
+            Protocol::Udp => igd_next::PortMappingProtocol::UDP,
+            Protocol::Tcp => igd_next::PortMappingProtocol::TCP,
+        };
+        match gateway.remove_port(protocol, entry.external_port).await {
+            Ok(()) => removed += 1,
+            Err(e) => debug!(?entry, "failed to remove upnp mapping: {e}"),
+        }
+    }
+    Ok(removed)
 }
 
 /// Searches for UPnP gateways.
-pub async fn probe_available(metrics: &Arc<Metrics>) -> Option<Gateway> {
+///
+/// Along with the gateway, returns the identity it reports, if it could be obtained. Fails like
+/// [`probe_available_result`] if no gateway is found.
+pub(crate) async fn probe_available(
+    metrics: &Arc<Metrics>,
+    local_ip: Ipv4Addr,
+    search_timeout: Duration,
+    description_timeout: Duration,
+) -> Result<(Gateway, Option<GatewayInfo>), Error> {
     metrics.upnp_probes.inc();
 
+    let gateway = probe_available_result(local_ip, search_timeout)
+        .await
+        .inspect_err(|e| {
+            metrics.upnp_probes_failed.inc();
+            debug!("upnp probe failed: {e}");
+        })?;
+    let info = match tokio::time::timeout(description_timeout, fetch_gateway_info(&gateway)).await {
+        Ok(Ok(info)) => Some(info),
+        Ok(Err(e)) => {
+            debug!("failed to fetch upnp device description: {e}");
+            None
+        }
+        Err(_) => {
+            debug!("fetching upnp device description timed out");
+            None
+        }
+    };
+    Ok((gateway, info))
+}
+
+/// Searches for a UPnP gateway, returning the error if none was found.
+///
+/// The search is sent from `local_ip`, so that it leaves through the interface of the local
+/// network rather than e.g. a VPN holding the default route.
+///
+/// A search without answer within `search_timeout` fails with a [`std::io::ErrorKind::TimedOut`]
+/// [`Error::Io`], other failures, e.g. a malformed answer, with [`Error::Search`].
+pub async fn probe_available_result(
+    local_ip: Ipv4Addr,
+    search_timeout: Duration,
+) -> Result<Gateway, Error> {
     // Wrap in manual timeout, because igd_next doesn't respect the set timeout
-    let res = tokio::time::timeout(
-        SEARCH_TIMEOUT,
+    let gateway = tokio::time::timeout(
+        search_timeout,
         aigd::tokio::search_gateway(igd_next::SearchOptions {
-            timeout: Some(SEARCH_TIMEOUT),
+            bind_addr: SocketAddrV4::new(local_ip, 0).into(),
+            timeout: Some(search_timeout),
             ..Default::default()
         }),
     )
-    .await;
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout".to_string()))??;
+    Ok(gateway)
+}
 
-    match res {
-        Ok(Ok(gateway)) => Some(gateway),
-        Err(e) => {
-            metrics.upnp_probes_failed.inc();
-            debug!("upnp probe timed out: {e}");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <friendlyName>Home Router</friendlyName>
+    <manufacturer> MiniUPnP </manufacturer>
+    <modelName>MiniUPnP daemon</modelName>
+    <modelNumber>20180615</modelNumber>
+    <deviceList>
+      <device>
+        <manufacturer>Embedded</manufacturer>
+        <modelName>WAN Device</modelName>
+      </device>
+    </deviceList>
+  </device>
+</root>"#;
+
+    #[test]
+    fn test_gateway_info_from_description() {
+        let info = GatewayInfo::from_description(DESCRIPTION);
+        assert_eq!(info.friendly_name.as_deref(), Some("Home Router"));
+        assert_eq!(info.manufacturer.as_deref(), Some("MiniUPnP"));
+        assert_eq!(info.model_name.as_deref(), Some("MiniUPnP daemon"));
+        assert_eq!(info.model_number.as_deref(), Some("20180615"));
+    }
+
+    #[test]
+    fn test_lease_rejection_code() {
+        assert_eq!(
+            lease_rejection_code(&AddAnyPortError::OnlyPermanentLeasesSupported),
+            Some(ONLY_PERMANENT_LEASES_SUPPORTED)
+        );
+        let invalid_args = RequestError::ErrorCode(INVALID_ARGS, "Invalid Args".to_string());
+        assert_eq!(
+            lease_rejection_code(&AddAnyPortError::RequestError(invalid_args)),
+            Some(INVALID_ARGS)
+        );
+        let other = RequestError::ErrorCode(501, "Action Failed".to_string());
+        assert_eq!(
+            lease_rejection_code(&AddAnyPortError::RequestError(other)),
             None
-        }
-        Ok(Err(e)) => {
-            metrics.upnp_probes_failed.inc();
-            debug!("upnp probe failed: {e}");
+        );
+        assert_eq!(
+            lease_rejection_code(&AddAnyPortError::NoPortsAvailable),
             None
-        }
+        );
+    }
+
+    #[test]
+    fn test_parse_lease_duration() {
+        let response = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetSpecificPortMappingEntryResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewInternalPort>4242</NewInternalPort>
+<NewInternalClient>192.168.1.2</NewInternalClient>
+<NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>portmapper</NewPortMappingDescription>
+<NewLeaseDuration>3600</NewLeaseDuration>
+</u:GetSpecificPortMappingEntryResponse>
+</s:Body>
+</s:Envelope>"#;
+        assert_eq!(parse_lease_duration(response), Some(3600));
+        assert_eq!(parse_lease_duration("<s:Fault></s:Fault>"), None);
+    }
+
+    #[test]
+    fn test_port_mapping_entry_from_igd() {
+        let igd_entry = |lease_duration| igd_next::PortMappingEntry {
+            remote_host: String::new(),
+            external_port: 51234,
+            protocol: igd_next::PortMappingProtocol::UDP,
+            internal_port: 4242,
+            internal_client: "192.168.1.2".to_string(),
+            enabled: true,
+            port_mapping_description: "portmapper".to_string(),
+            lease_duration,
+        };
+        let entry = PortMappingEntry::from(igd_entry(3600));
+        assert_eq!(entry.external_port, 51234);
+        assert_eq!(entry.internal_client, "192.168.1.2");
+        assert_eq!(entry.internal_port, 4242);
+        assert_eq!(entry.protocol, Protocol::Udp);
+        assert_eq!(entry.description, "portmapper");
+        assert_eq!(entry.lease, Some(Duration::from_secs(3600)));
+
+        // a zero lease is permanent
+        assert_eq!(PortMappingEntry::from(igd_entry(0)).lease, None);
+    }
+
+    #[test]
+    fn test_is_stale_mapping() {
+        let local_ip = Ipv4Addr::new(192, 168, 1, 2);
+        let entry = PortMappingEntry {
+            external_port: 51234,
+            internal_client: "192.168.1.2".to_string(),
+            internal_port: 4242,
+            protocol: Protocol::Udp,
+            description: "portmapper".to_string(),
+            lease: None,
+        };
+        let is_stale = |entry: &PortMappingEntry, keep_port: Option<u16>| {
+            let keep_port = keep_port.and_then(NonZeroU16::new);
+            is_stale_mapping(entry, Protocol::Udp, local_ip, "portmapper", keep_port)
+        };
+        assert!(is_stale(&entry, None));
+        assert!(is_stale(&entry, Some(40000)));
+        // the mapping about to be requested again is kept
+        assert!(!is_stale(&entry, Some(51234)));
+
+        // mappings of other clients are left alone
+        let other_description = PortMappingEntry {
+            description: "other".to_string(),
+            ..entry.clone()
+        };
+        assert!(!is_stale(&other_description, None));
+        let other_host = PortMappingEntry {
+            internal_client: "192.168.1.3".to_string(),
+            ..entry.clone()
+        };
+        assert!(!is_stale(&other_host, None));
+        let other_protocol = PortMappingEntry {
+            protocol: Protocol::Tcp,
+            ..entry
+        };
+        assert!(!is_stale(&other_protocol, None));
+    }
+
+    #[test]
+    fn test_gateway_info_missing_elements() {
+        let info = GatewayInfo::from_description("<root><device><modelName></modelName></device>");
+        assert_eq!(info, GatewayInfo::default());
     }
 }
@@ -3,6 +3,31 @@ use std::{
     pin::Pin,
     task::{Context, Poll},
 };
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+use netwatch::UdpSocket;
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+use tokio::time;
+
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+use crate::{PacketCapture, PacketDirection, SocketFactory};
+
+/// Interval after which an unanswered PCP or NAT-PMP request is first sent again.
+///
+/// Doubles after each retransmission. This is the interval of [RFC 6886 Requesting a
+/// Mapping](https://datatracker.ietf.org/doc/html/rfc6886#section-3.1). PCP starts at three
+/// seconds instead, longer than we are willing to wait for a router in total.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+const INITIAL_RETRANSMISSION_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Maximum number of times a request is sent, as given by RFC 6886.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+const MAX_TRANSMISSIONS: usize = 9;
 
 /// Resolves to pending if the inner is `None`.
 #[derive(Debug)]
@@ -28,3 +53,91 @@ impl<T: Future + Unpin> Future for MaybeFuture<T> {
         }
     }
 }
+
+/// Sends `request` on the connected `socket` and receives the response into `buffer`.
+///
+/// UDP datagrams get lost, so the request is sent again with doubling intervals until a response
+/// arrives or `timeout` elapses. Every packet sent and received is reported to `capture`.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+pub(crate) async fn request_with_retransmissions(
+    socket: &UdpSocket,
+    request: &[u8],
+    buffer: &mut [u8],
+    timeout: Duration,
+    capture: Option<&PacketCapture>,
+) -> std::io::Result<usize> {
+    let deadline = time::Instant::now() + timeout;
+    let mut interval = INITIAL_RETRANSMISSION_INTERVAL;
+    for _ in 0..MAX_TRANSMISSIONS {
+        send(socket, request, capture).await?;
+        let retransmit_at = (time::Instant::now() + interval).min(deadline);
+        if let Ok(read) = time::timeout_at(retransmit_at, socket.recv(buffer)).await {
+            if let (Ok(len), Some(capture)) = (&read, capture) {
+                capture.capture(PacketDirection::Received, &buffer[..*len]);
+            }
+            return read;
+        }
+        if retransmit_at == deadline {
+            break;
+        }
+        interval *= 2;
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "read timeout".to_string(),
+    ))
+}
+
+/// Sends `packet` on the connected `socket`, reporting it to `capture`.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+pub(crate) async fn send(
+    socket: &UdpSocket,
+    packet: &[u8],
+    capture: Option<&PacketCapture>,
+) -> std::io::Result<()> {
+    if let Some(capture) = capture {
+        capture.capture(PacketDirection::Sent, packet);
+    }
+    socket.send(packet).await?;
+    Ok(())
+}
+
+/// How the sockets PCP and NAT-PMP requests are sent from are created.
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SocketOptions {
+    /// Creates the sockets instead of binding them directly, see [`crate::Config::socket_factory`].
+    pub(crate) factory: Option<SocketFactory>,
+    /// Mark applied to the sockets, see [`crate::Config::socket_mark`].
+    #[cfg(target_os = "linux")]
+    pub(crate) mark: Option<u32>,
+    /// Observes the packets exchanged, see [`crate::Config::packet_capture`].
+    pub(crate) capture: Option<PacketCapture>,
+    /// Port the requests are sent to instead of the standard one, see
+    /// [`crate::Config::server_port_override`].
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) server_port_override: Option<u16>,
+}
+
+#[cfg(any(feature = "pcp", feature = "nat_pmp"))]
+impl SocketOptions {
+    /// Binds a socket to `addr`.
+    pub(crate) fn bind(&self, addr: impl Into<SocketAddr>) -> std::io::Result<UdpSocket> {
+        let socket = match &self.factory {
+            Some(factory) => UdpSocket::from_std(factory.bind(addr.into())?)?,
+            None => UdpSocket::bind_full(addr)?,
+        };
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = self.mark {
+            socket.set_mark(mark)?;
+        }
+        Ok(socket)
+    }
+
+    /// Address of the server listening on `port` of `gateway`, unless overridden for testing.
+    pub(crate) fn server_addr(&self, gateway: impl Into<IpAddr>, port: u16) -> SocketAddr {
+        #[cfg(any(test, feature = "test-util"))]
+        let port = self.server_port_override.unwrap_or(port);
+        SocketAddr::new(gateway.into(), port)
+    }
+}